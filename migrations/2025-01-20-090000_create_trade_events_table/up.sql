@@ -0,0 +1,11 @@
+-- Append-only audit trail of the actions taken in trade sessions
+CREATE TABLE trade_events (
+    id BIGSERIAL PRIMARY KEY,
+    session_id UUID NOT NULL,
+    actor TEXT NOT NULL,
+    action TEXT NOT NULL,
+    payload JSONB,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX trade_events_session_id_idx ON trade_events (session_id, id);
@@ -0,0 +1 @@
+ALTER TABLE Trades DROP COLUMN status_history;
@@ -0,0 +1,11 @@
+-- Append-only list of {status, at} entries, one per status the trade entered
+ALTER TABLE Trades ADD COLUMN status_history JSONB NOT NULL DEFAULT '[]'::jsonb;
+-- Trades from before the history start it with the status they are in, as of their last
+-- update. The backfill is no update of the trade, so it keeps its updated_at.
+ALTER TABLE Trades DISABLE TRIGGER set_updated_at;
+UPDATE Trades
+SET status_history = jsonb_build_array(jsonb_build_object(
+    'status', status,
+    'at', COALESCE(updated_at, created_at, CURRENT_TIMESTAMP)
+));
+ALTER TABLE Trades ENABLE TRIGGER set_updated_at;
@@ -1,46 +1,850 @@
-use std::{str::FromStr, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
-use anyhow::{Error, Result};
-use solana_client::nonblocking::rpc_client::RpcClient;
-use solana_sdk::{hash::Hash, pubkey::Pubkey};
+use log::warn;
+
+use crate::config::{Network, RpcConfig};
+use anyhow::{anyhow, bail, Result};
+use reqwest_rpc::header::{HeaderName, HeaderValue};
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    nonblocking::rpc_client::RpcClient,
+    rpc_client::RpcClientConfig,
+};
+use solana_rpc_client::http_sender::HttpSender;
+use solana_sdk::{
+    commitment_config::CommitmentConfig, hash::Hash, message::Message, pubkey::Pubkey,
+    signature::Signature, transaction::Transaction,
+};
 
 pub trait ChainContext {
-    fn get_latest_blockhash(&self) -> impl std::future::Future<Output = Result<Hash>> + std::marker::Send;
+    /// The blockhash with the last block height at which transactions using it are accepted.
+    fn get_latest_blockhash(
+        &self,
+    ) -> impl std::future::Future<Output = Result<(Hash, u64)>> + std::marker::Send;
     fn get_trade_with_me_program_id(&self) -> Pubkey;
+    /// Lamports the cluster would charge for the message, signature and priority fees included.
+    fn get_fee_for_message(
+        &self,
+        message: &Message,
+    ) -> impl std::future::Future<Output = Result<u64>> + std::marker::Send;
+    fn send_transaction(
+        &self,
+        tx: &Transaction,
+    ) -> impl std::future::Future<Output = Result<Signature>> + std::marker::Send;
+    /// `None` while the cluster hasn't seen the signature, otherwise the execution result.
+    fn get_signature_status(
+        &self,
+        signature: &Signature,
+    ) -> impl std::future::Future<Output = Result<Option<std::result::Result<(), String>>>>
+           + std::marker::Send;
+    fn is_blockhash_valid(
+        &self,
+        blockhash: &Hash,
+    ) -> impl std::future::Future<Output = Result<bool>> + std::marker::Send;
+    /// Height of the latest block, a blockhash expires once it passes its last valid block height.
+    fn get_block_height(
+        &self,
+    ) -> impl std::future::Future<Output = Result<u64>> + std::marker::Send;
+    /// Whether the signature reached `commitment`, false while it's unseen or below it.
+    fn confirm_transaction_with_commitment(
+        &self,
+        signature: &Signature,
+        commitment: CommitmentConfig,
+    ) -> impl std::future::Future<Output = Result<bool>> + std::marker::Send;
+    /// Lamports an account holding `data_len` bytes needs to be rent exempt.
+    fn get_minimum_balance_for_rent(
+        &self,
+        data_len: usize,
+    ) -> impl std::future::Future<Output = Result<u64>> + std::marker::Send;
+    fn get_balance(
+        &self,
+        address: &Pubkey,
+    ) -> impl std::future::Future<Output = Result<u64>> + std::marker::Send;
+    /// The given accounts that don't exist on chain yet.
+    fn get_missing_accounts(
+        &self,
+        addresses: &[Pubkey],
+    ) -> impl std::future::Future<Output = Result<Vec<Pubkey>>> + std::marker::Send;
+    /// Base units held by each of the token accounts, `None` for accounts that don't exist or
+    /// aren't token accounts. Fetched in a single RPC call.
+    fn get_token_account_balances(
+        &self,
+        accounts: &[Pubkey],
+    ) -> impl std::future::Future<Output = Result<Vec<Option<u64>>>> + std::marker::Send;
+    /// Compute unit prices, in micro-lamports, that landed transactions writing to all of the
+    /// accounts paid in each recent slot.
+    fn get_recent_prioritization_fees(
+        &self,
+        accounts: &[Pubkey],
+    ) -> impl std::future::Future<Output = Result<Vec<u64>>> + std::marker::Send;
 }
 
-pub struct MainnetChainContext {
-    pub rpc_client: Arc<RpcClient>,
+/// Owners of SPL token accounts: the Token and the Token-2022 program.
+const TOKEN_PROGRAM_IDS: [Pubkey; 2] = [
+    solana_sdk::pubkey!("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA"),
+    solana_sdk::pubkey!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb"),
+];
+
+/// Amount of a token account, the `u64` after its mint and owner in both token programs.
+fn token_account_amount(account: &solana_sdk::account::Account) -> Option<u64> {
+    if !TOKEN_PROGRAM_IDS.contains(&account.owner) {
+        return None;
+    }
+    let amount = account.data.get(64..72)?;
+    Some(u64::from_le_bytes(amount.try_into().ok()?))
 }
 
-impl MainnetChainContext {
+/// Program id of Trade With Me on mainnet, used when `program_id` isn't configured.
+pub const MAINNET_PROGRAM_ID: Pubkey =
+    solana_sdk::pubkey!("DMnLeeL2qJQdWHDDnXKTyRie7o1kNvKqg74UYEqzHqgq");
+
+/// Chain access through a list of RPC endpoints of one cluster. Requests go to the first
+/// endpoint and move on to the next one only on transport errors, the cluster's answers are
+/// the same on every endpoint.
+pub struct RpcChainContext {
+    rpc_clients: Vec<Arc<RpcClient>>,
+    program_id: Pubkey,
+}
+
+impl RpcChainContext {
+    /// Single endpoint talking to the mainnet program.
     pub fn new(rpc_client: Arc<RpcClient>) -> Self {
-        Self { rpc_client }
+        Self::with_endpoints(vec![rpc_client], MAINNET_PROGRAM_ID)
+    }
+
+    pub fn with_endpoints(rpc_clients: Vec<Arc<RpcClient>>, program_id: Pubkey) -> Self {
+        assert!(
+            !rpc_clients.is_empty(),
+            "at least one RPC endpoint is required"
+        );
+        Self {
+            rpc_clients,
+            program_id,
+        }
+    }
+
+    /// Runs `request` against each endpoint in order until one is reached, returning its
+    /// answer or the last transport error.
+    async fn request<R, F, Fut>(&self, request: F) -> Result<R>
+    where
+        F: Fn(Arc<RpcClient>) -> Fut,
+        Fut: std::future::Future<Output = std::result::Result<R, ClientError>>,
+    {
+        let mut last_error = None;
+        for rpc_client in &self.rpc_clients {
+            match request(Arc::clone(rpc_client)).await {
+                Err(e) if is_transport_error(&e) => {
+                    let e = rpc_error(e);
+                    warn!(
+                        "RPC endpoint {} failed: {}",
+                        redacted_rpc_url(&rpc_client.url()),
+                        e
+                    );
+                    last_error = Some(e);
+                }
+                result => return result.map_err(rpc_error),
+            }
+        }
+        Err(last_error.expect("at least one endpoint was tried"))
     }
 }
 
-impl ChainContext for MainnetChainContext {
-    async fn get_latest_blockhash(&self) -> Result<Hash> {
-        self.rpc_client
-            .get_latest_blockhash()
-            .await
-            .map_err(anyhow::Error::from)
+impl ChainContext for RpcChainContext {
+    async fn get_latest_blockhash(&self) -> Result<(Hash, u64)> {
+        self.request(|rpc_client| async move {
+            rpc_client
+                .get_latest_blockhash_with_commitment(rpc_client.commitment())
+                .await
+        })
+        .await
     }
 
     fn get_trade_with_me_program_id(&self) -> Pubkey {
-        Pubkey::from_str("DMnLeeL2qJQdWHDDnXKTyRie7o1kNvKqg74UYEqzHqgq").unwrap()
+        self.program_id
+    }
+
+    async fn get_fee_for_message(&self, message: &Message) -> Result<u64> {
+        self.request(|rpc_client| async move { rpc_client.get_fee_for_message(message).await })
+            .await
+    }
+
+    async fn send_transaction(&self, tx: &Transaction) -> Result<Signature> {
+        self.request(|rpc_client| async move { rpc_client.send_transaction(tx).await })
+            .await
+    }
+
+    async fn get_signature_status(
+        &self,
+        signature: &Signature,
+    ) -> Result<Option<std::result::Result<(), String>>> {
+        self.request(|rpc_client| async move {
+            rpc_client
+                .get_signature_status(signature)
+                .await
+                .map(|status| status.map(|result| result.map_err(|e| e.to_string())))
+        })
+        .await
+    }
+
+    async fn is_blockhash_valid(&self, blockhash: &Hash) -> Result<bool> {
+        self.request(|rpc_client| async move {
+            rpc_client
+                .is_blockhash_valid(blockhash, CommitmentConfig::processed())
+                .await
+        })
+        .await
+    }
+
+    async fn get_block_height(&self) -> Result<u64> {
+        self.request(|rpc_client| async move {
+            rpc_client
+                .get_block_height_with_commitment(CommitmentConfig::processed())
+                .await
+        })
+        .await
+    }
+
+    async fn confirm_transaction_with_commitment(
+        &self,
+        signature: &Signature,
+        commitment: CommitmentConfig,
+    ) -> Result<bool> {
+        self.request(|rpc_client| async move {
+            rpc_client
+                .confirm_transaction_with_commitment(signature, commitment)
+                .await
+                .map(|response| response.value)
+        })
+        .await
+    }
+
+    async fn get_minimum_balance_for_rent(&self, data_len: usize) -> Result<u64> {
+        self.request(|rpc_client| async move {
+            rpc_client
+                .get_minimum_balance_for_rent_exemption(data_len)
+                .await
+        })
+        .await
+    }
+
+    async fn get_balance(&self, address: &Pubkey) -> Result<u64> {
+        self.request(|rpc_client| async move { rpc_client.get_balance(address).await })
+            .await
+    }
+
+    async fn get_missing_accounts(&self, addresses: &[Pubkey]) -> Result<Vec<Pubkey>> {
+        let accounts = self
+            .request(|rpc_client| async move { rpc_client.get_multiple_accounts(addresses).await })
+            .await?;
+        Ok(addresses
+            .iter()
+            .zip(accounts)
+            .filter(|(_, account)| account.is_none())
+            .map(|(address, _)| *address)
+            .collect())
+    }
+
+    async fn get_token_account_balances(&self, accounts: &[Pubkey]) -> Result<Vec<Option<u64>>> {
+        let accounts = self
+            .request(|rpc_client| async move { rpc_client.get_multiple_accounts(accounts).await })
+            .await?;
+        Ok(accounts
+            .iter()
+            .map(|account| account.as_ref().and_then(token_account_amount))
+            .collect())
+    }
+
+    async fn get_recent_prioritization_fees(&self, accounts: &[Pubkey]) -> Result<Vec<u64>> {
+        let fees = self
+            .request(|rpc_client| async move {
+                rpc_client.get_recent_prioritization_fees(accounts).await
+            })
+            .await?;
+        Ok(fees.into_iter().map(|fee| fee.prioritization_fee).collect())
     }
 }
 
+/// Errors reaching the endpoint at all, as opposed to the cluster rejecting the request.
+pub fn is_transport_error(error: &ClientError) -> bool {
+    matches!(
+        error.kind,
+        ClientErrorKind::Reqwest(_) | ClientErrorKind::Io(_)
+    )
+}
+
+/// Timeouts get a message of their own, callers can retry them unlike rejected requests.
+/// Transport errors lose their url, which may carry the endpoint's API key.
+fn rpc_error(mut error: ClientError) -> anyhow::Error {
+    if let ClientErrorKind::Reqwest(e) = error.kind {
+        if e.is_timeout() {
+            return anyhow!("RPC request timed out, try again later");
+        }
+        error.kind = ClientErrorKind::Reqwest(e.without_url());
+    }
+    anyhow::Error::from(error)
+}
+
+/// `program_id` when configured, otherwise the program's mainnet id, which only exists on
+/// mainnet.
+pub fn resolve_program_id(network: Network, program_id: Option<&str>) -> Result<Pubkey> {
+    match program_id {
+        Some(program_id) => program_id
+            .parse()
+            .map_err(|e| anyhow!("Invalid program_id \"{}\": {}", program_id, e)),
+        None if network == Network::Mainnet => Ok(MAINNET_PROGRAM_ID),
+        None => bail!("program_id is required on {}", network),
+    }
+}
+
+/// Client for `rpc_url` sending `config.headers` along with the usual JSON-RPC headers.
+pub fn build_rpc_client(rpc_url: &str, config: &RpcConfig) -> Result<RpcClient> {
+    let mut headers = HttpSender::default_headers();
+    for (name, value) in &config.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| anyhow!("Invalid rpc header name \"{}\": {}", name, e))?;
+        let mut value = HeaderValue::from_str(value)
+            .map_err(|_| anyhow!("Invalid value of rpc header \"{}\"", name))?;
+        value.set_sensitive(true);
+        headers.insert(name, value);
+    }
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let client = reqwest_rpc::Client::builder()
+        .default_headers(headers)
+        .timeout(timeout)
+        .pool_idle_timeout(timeout)
+        .build()?;
+    Ok(RpcClient::new_sender(
+        HttpSender::new_with_client(rpc_url, client),
+        RpcClientConfig::with_commitment(CommitmentConfig::default()),
+    ))
+}
+
+/// `rpc_url` without path, query and user info, which is where providers put API keys, for
+/// logging.
+pub fn redacted_rpc_url(rpc_url: &str) -> String {
+    match reqwest::Url::parse(rpc_url) {
+        Ok(url) => {
+            let redacted = format!("{}://{}", url.scheme(), url.host_str().unwrap_or_default());
+            let redacted = match url.port() {
+                Some(port) => format!("{}:{}", redacted, port),
+                None => redacted,
+            };
+            if url.path() != "/" || url.query().is_some() || !url.username().is_empty() {
+                format!("{}/…", redacted)
+            } else {
+                redacted
+            }
+        }
+        Err(_) => "<invalid url>".to_string(),
+    }
+}
+
+/// Errors name the url as `redacted_rpc_url` does, it may hold an API key.
+pub fn validate_rpc_url(rpc_url: &str) -> Result<()> {
+    let url = reqwest::Url::parse(rpc_url)
+        .map_err(|e| anyhow!("Invalid rpc_url \"{}\": {}", redacted_rpc_url(rpc_url), e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!(
+            "Invalid rpc_url \"{}\": scheme must be http or https, got {}",
+            redacted_rpc_url(rpc_url),
+            url.scheme()
+        );
+    }
+    if url.host_str().is_none() {
+        bail!(
+            "Invalid rpc_url \"{}\": missing host",
+            redacted_rpc_url(rpc_url)
+        );
+    }
+    Ok(())
+}
+
+pub async fn probe_rpc_connection(rpc_client: &RpcClient) -> Result<()> {
+    rpc_client.get_health().await.map_err(|e| {
+        anyhow!(
+            "RPC endpoint {} is not healthy: {}",
+            redacted_rpc_url(&rpc_client.url()),
+            rpc_error(e)
+        )
+    })
+}
+
+/// Lands and finalizes every transaction, blockhashes never expire and token accounts hold
+/// unlimited amounts. `ScriptedChainContext` changes single answers.
 #[cfg(test)]
+#[derive(Debug, Clone, Default)]
 pub struct TestChainContext {}
 
 #[cfg(test)]
 impl ChainContext for TestChainContext {
-    async fn get_latest_blockhash(&self) -> Result<Hash> {
-        Ok(Hash::default())
+    async fn get_latest_blockhash(&self) -> Result<(Hash, u64)> {
+        Ok((Hash::default(), TEST_LAST_VALID_BLOCK_HEIGHT))
     }
     fn get_trade_with_me_program_id(&self) -> Pubkey {
-        Pubkey::from_str("DMnLeeL2qJQdWHDDnXKTyRie7o1kNvKqg74UYEqzHqgq").unwrap()
+        MAINNET_PROGRAM_ID
+    }
+    async fn get_fee_for_message(&self, message: &Message) -> Result<u64> {
+        use solana_sdk::compute_budget::{self, ComputeBudgetInstruction};
+
+        let mut compute_unit_limit = 0u64;
+        let mut compute_unit_price = 0u64;
+        for instruction in &message.instructions {
+            if message.account_keys[usize::from(instruction.program_id_index)]
+                != compute_budget::id()
+            {
+                continue;
+            }
+            match borsh::from_slice(&instruction.data)? {
+                ComputeBudgetInstruction::SetComputeUnitLimit(units) => {
+                    compute_unit_limit = u64::from(units)
+                }
+                ComputeBudgetInstruction::SetComputeUnitPrice(price) => compute_unit_price = price,
+                _ => {}
+            }
+        }
+        let signature_fee =
+            TEST_LAMPORTS_PER_SIGNATURE * u64::from(message.header.num_required_signatures);
+        Ok(signature_fee + (compute_unit_limit * compute_unit_price).div_ceil(1_000_000))
+    }
+    async fn send_transaction(&self, tx: &Transaction) -> Result<Signature> {
+        Ok(tx.signatures.first().copied().unwrap_or_default())
+    }
+    async fn get_signature_status(
+        &self,
+        _signature: &Signature,
+    ) -> Result<Option<std::result::Result<(), String>>> {
+        Ok(Some(Ok(())))
+    }
+    async fn is_blockhash_valid(&self, _blockhash: &Hash) -> Result<bool> {
+        Ok(true)
+    }
+    async fn get_block_height(&self) -> Result<u64> {
+        Ok(TEST_BLOCK_HEIGHT)
+    }
+    async fn confirm_transaction_with_commitment(
+        &self,
+        _signature: &Signature,
+        _commitment: CommitmentConfig,
+    ) -> Result<bool> {
+        Ok(true)
+    }
+    async fn get_minimum_balance_for_rent(&self, data_len: usize) -> Result<u64> {
+        // Default cluster rent: 3480 lamports per byte-year, exempt after two years
+        Ok((data_len as u64 + 128) * 3480 * 2)
+    }
+    async fn get_balance(&self, _address: &Pubkey) -> Result<u64> {
+        Ok(u64::MAX)
+    }
+    async fn get_missing_accounts(&self, _addresses: &[Pubkey]) -> Result<Vec<Pubkey>> {
+        Ok(vec![])
+    }
+    async fn get_token_account_balances(&self, accounts: &[Pubkey]) -> Result<Vec<Option<u64>>> {
+        Ok(vec![Some(u64::MAX); accounts.len()])
+    }
+    async fn get_recent_prioritization_fees(&self, _accounts: &[Pubkey]) -> Result<Vec<u64>> {
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+type ScriptedStatuses =
+    std::sync::Mutex<std::collections::VecDeque<Option<std::result::Result<(), String>>>>;
+
+/// Chain answering like `inner` except where an override is set, for tests that need a single
+/// call to behave differently. Every sent transaction and every message whose fee was asked for
+/// is kept, so flow tests can assert what would have reached the cluster.
+#[cfg(test)]
+#[derive(Default)]
+pub struct ScriptedChainContext<T = TestChainContext> {
+    pub inner: T,
+    /// Lamports every account holds.
+    pub balance: Option<u64>,
+    /// None of the asked for accounts exists yet.
+    pub accounts_missing: bool,
+    /// Signature statuses answered one per call, unseen once they ran out.
+    pub statuses: Option<ScriptedStatuses>,
+    /// Every call fails as if the RPC endpoint were down.
+    pub unreachable: bool,
+    /// Every blockhash expired.
+    pub blockhash_expired: bool,
+    /// Height of the latest block.
+    pub block_height: Option<u64>,
+    /// Highest commitment signatures reach.
+    pub highest_commitment: Option<solana_sdk::commitment_config::CommitmentLevel>,
+    /// Balances of the listed token accounts.
+    pub token_balances: std::collections::HashMap<Pubkey, u64>,
+    /// What recent slots paid for priority.
+    pub recent_prioritization_fees: Option<Vec<u64>>,
+    pub sent: std::sync::Mutex<Vec<Transaction>>,
+    pub fee_requests: std::sync::Mutex<Vec<Message>>,
+}
+
+#[cfg(test)]
+impl ScriptedChainContext {
+    /// Every account holds `balance` lamports and none of the asked for accounts exists yet.
+    pub fn underfunded(balance: u64) -> Self {
+        ScriptedChainContext {
+            balance: Some(balance),
+            accounts_missing: true,
+            ..ScriptedChainContext::default()
+        }
+    }
+
+    pub fn with_statuses(statuses: Vec<Option<std::result::Result<(), String>>>) -> Self {
+        ScriptedChainContext {
+            statuses: Some(std::sync::Mutex::new(statuses.into())),
+            ..ScriptedChainContext::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl<T> ScriptedChainContext<T> {
+    fn reachable(&self) -> Result<()> {
+        if self.unreachable {
+            bail!("RPC endpoint is unreachable");
+        }
+        Ok(())
+    }
+
+    pub fn sent(&self) -> Vec<Transaction> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl<T: ChainContext + Sync> ChainContext for ScriptedChainContext<T> {
+    async fn get_latest_blockhash(&self) -> Result<(Hash, u64)> {
+        self.reachable()?;
+        self.inner.get_latest_blockhash().await
+    }
+    fn get_trade_with_me_program_id(&self) -> Pubkey {
+        self.inner.get_trade_with_me_program_id()
+    }
+    async fn get_fee_for_message(&self, message: &Message) -> Result<u64> {
+        self.reachable()?;
+        self.fee_requests.lock().unwrap().push(message.clone());
+        self.inner.get_fee_for_message(message).await
+    }
+    async fn send_transaction(&self, tx: &Transaction) -> Result<Signature> {
+        self.reachable()?;
+        self.sent.lock().unwrap().push(tx.clone());
+        self.inner.send_transaction(tx).await
+    }
+    async fn get_signature_status(
+        &self,
+        signature: &Signature,
+    ) -> Result<Option<std::result::Result<(), String>>> {
+        self.reachable()?;
+        match &self.statuses {
+            Some(statuses) => Ok(statuses.lock().unwrap().pop_front().flatten()),
+            None => self.inner.get_signature_status(signature).await,
+        }
+    }
+    async fn is_blockhash_valid(&self, blockhash: &Hash) -> Result<bool> {
+        self.reachable()?;
+        if self.blockhash_expired {
+            return Ok(false);
+        }
+        self.inner.is_blockhash_valid(blockhash).await
+    }
+    async fn get_block_height(&self) -> Result<u64> {
+        self.reachable()?;
+        match self.block_height {
+            Some(block_height) => Ok(block_height),
+            None => self.inner.get_block_height().await,
+        }
+    }
+    async fn confirm_transaction_with_commitment(
+        &self,
+        signature: &Signature,
+        commitment: CommitmentConfig,
+    ) -> Result<bool> {
+        use solana_sdk::commitment_config::CommitmentLevel;
+
+        self.reachable()?;
+        let rank = |level: CommitmentLevel| match level {
+            CommitmentLevel::Processed => 0,
+            CommitmentLevel::Confirmed => 1,
+            CommitmentLevel::Finalized => 2,
+        };
+        match self.highest_commitment {
+            Some(highest) => Ok(rank(commitment.commitment) <= rank(highest)),
+            None => {
+                self.inner
+                    .confirm_transaction_with_commitment(signature, commitment)
+                    .await
+            }
+        }
+    }
+    async fn get_minimum_balance_for_rent(&self, data_len: usize) -> Result<u64> {
+        self.reachable()?;
+        self.inner.get_minimum_balance_for_rent(data_len).await
+    }
+    async fn get_balance(&self, address: &Pubkey) -> Result<u64> {
+        self.reachable()?;
+        match self.balance {
+            Some(balance) => Ok(balance),
+            None => self.inner.get_balance(address).await,
+        }
+    }
+    async fn get_missing_accounts(&self, addresses: &[Pubkey]) -> Result<Vec<Pubkey>> {
+        self.reachable()?;
+        if self.accounts_missing {
+            return Ok(addresses.to_vec());
+        }
+        self.inner.get_missing_accounts(addresses).await
+    }
+    async fn get_token_account_balances(&self, accounts: &[Pubkey]) -> Result<Vec<Option<u64>>> {
+        self.reachable()?;
+        let mut balances = self.inner.get_token_account_balances(accounts).await?;
+        for (balance, account) in balances.iter_mut().zip(accounts) {
+            if let Some(listed) = self.token_balances.get(account) {
+                *balance = Some(*listed);
+            }
+        }
+        Ok(balances)
+    }
+    async fn get_recent_prioritization_fees(&self, accounts: &[Pubkey]) -> Result<Vec<u64>> {
+        self.reachable()?;
+        match &self.recent_prioritization_fees {
+            Some(fees) => Ok(fees.clone()),
+            None => self.inner.get_recent_prioritization_fees(accounts).await,
+        }
+    }
+}
+
+#[cfg(test)]
+pub const TEST_LAMPORTS_PER_SIGNATURE: u64 = 5000;
+
+#[cfg(test)]
+pub const TEST_LAST_VALID_BLOCK_HEIGHT: u64 = 1000;
+
+#[cfg(test)]
+pub const TEST_BLOCK_HEIGHT: u64 = 850;
+
+#[cfg(test)]
+pub const TEST_MINT_DECIMALS: u8 = 6;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_accept_http_and_https_rpc_urls() {
+        assert!(validate_rpc_url("http://127.0.0.1:8899").is_ok());
+        assert!(validate_rpc_url("https://api.mainnet-beta.solana.com").is_ok());
+    }
+
+    #[test]
+    fn should_reject_malformed_rpc_urls() {
+        assert!(validate_rpc_url("").is_err());
+        assert!(validate_rpc_url("api.mainnet-beta.solana.com").is_err());
+        assert!(validate_rpc_url("htp//127.0.0.1:8899").is_err());
+        assert!(validate_rpc_url("ws://127.0.0.1:8900").is_err());
+    }
+
+    #[test]
+    fn rpc_url_errors_should_not_include_api_keys() {
+        let error = validate_rpc_url("ftp://rpc.example.com/api-key-secret").unwrap_err();
+        assert!(
+            error.to_string().contains("ftp://rpc.example.com/…"),
+            "{}",
+            error
+        );
+        let error = validate_rpc_url("https://user:secret@/").unwrap_err();
+        assert!(!error.to_string().contains("secret"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn slow_rpc_should_time_out_promptly() {
+        use std::time::{Duration, Instant};
+
+        // Accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        let chain_context = RpcChainContext::new(Arc::new(RpcClient::new_with_timeout(
+            url,
+            Duration::from_millis(200),
+        )));
+
+        let started = Instant::now();
+        let error = chain_context.get_latest_blockhash().await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(error.to_string(), "RPC request timed out, try again later");
+    }
+
+    #[tokio::test]
+    async fn configured_headers_should_be_sent_with_rpc_requests() {
+        use axum::{http::HeaderMap, routing::post, Json, Router};
+        use std::future::IntoFuture;
+        use std::sync::Mutex;
+
+        let api_keys = Arc::new(Mutex::new(vec![]));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let seen = Arc::clone(&api_keys);
+        let app = Router::new().route(
+            "/",
+            post(move |headers: HeaderMap| async move {
+                let api_key = headers
+                    .get("x-api-key")
+                    .map(|value| value.to_str().unwrap().to_string());
+                seen.lock().unwrap().push(api_key);
+                Json(serde_json::json!({ "jsonrpc": "2.0", "id": 1, "result": "ok" }))
+            }),
+        );
+        tokio::spawn(axum::serve(listener, app).into_future());
+        let config = RpcConfig {
+            headers: std::collections::HashMap::from([(
+                "x-api-key".to_string(),
+                "secret".to_string(),
+            )]),
+            ..RpcConfig::default()
+        };
+
+        let rpc_client = build_rpc_client(&format!("http://{}", address), &config).unwrap();
+        probe_rpc_connection(&rpc_client).await.unwrap();
+        assert_eq!(*api_keys.lock().unwrap(), vec![Some("secret".to_string())]);
+        assert!(!format!("{:?}", config).contains("secret"));
+    }
+
+    /// RPC endpoint answering every request with `result`, counting them in `requests`.
+    async fn serve_rpc(
+        result: serde_json::Value,
+        requests: Arc<std::sync::atomic::AtomicUsize>,
+    ) -> String {
+        use axum::{routing::post, Json, Router};
+        use std::future::IntoFuture;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/",
+            post(move |Json(request): Json<serde_json::Value>| async move {
+                requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Json(serde_json::json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+            }),
+        );
+        tokio::spawn(axum::serve(listener, app).into_future());
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn unreachable_primary_should_fall_over_to_secondary() {
+        // Nothing listens there
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let primary = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let blockhash = Hash::new_unique();
+        let requests = Arc::default();
+        let secondary = serve_rpc(
+            serde_json::json!({
+                "context": { "slot": 1 },
+                "value": { "blockhash": blockhash.to_string(), "lastValidBlockHeight": 150 }
+            }),
+            Arc::clone(&requests),
+        )
+        .await;
+        let chain_context = RpcChainContext::with_endpoints(
+            vec![
+                Arc::new(build_rpc_client(&primary, &RpcConfig::default()).unwrap()),
+                Arc::new(build_rpc_client(&secondary, &RpcConfig::default()).unwrap()),
+            ],
+            MAINNET_PROGRAM_ID,
+        );
+
+        assert_eq!(
+            chain_context.get_latest_blockhash().await.unwrap(),
+            (blockhash, 150)
+        );
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn rejected_request_should_not_be_retried_elsewhere() {
+        let primary_requests = Arc::default();
+        // Not a valid getBalance answer, the client reports it as a serde error
+        let primary = serve_rpc(
+            serde_json::json!("unexpected"),
+            Arc::clone(&primary_requests),
+        )
+        .await;
+        let secondary_requests = Arc::default();
+        let secondary = serve_rpc(
+            serde_json::json!({ "context": { "slot": 1 }, "value": 5 }),
+            Arc::clone(&secondary_requests),
+        )
+        .await;
+        let chain_context = RpcChainContext::with_endpoints(
+            vec![
+                Arc::new(build_rpc_client(&primary, &RpcConfig::default()).unwrap()),
+                Arc::new(build_rpc_client(&secondary, &RpcConfig::default()).unwrap()),
+            ],
+            MAINNET_PROGRAM_ID,
+        );
+
+        assert!(chain_context
+            .get_balance(&Pubkey::new_unique())
+            .await
+            .is_err());
+        assert_eq!(
+            primary_requests.load(std::sync::atomic::Ordering::SeqCst),
+            1
+        );
+        assert_eq!(
+            secondary_requests.load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+    }
+
+    #[test]
+    fn program_id_should_only_default_on_mainnet() {
+        let program_id = Pubkey::new_unique();
+        assert_eq!(
+            resolve_program_id(Network::Mainnet, None).unwrap(),
+            MAINNET_PROGRAM_ID
+        );
+        assert_eq!(
+            resolve_program_id(Network::Devnet, Some(&program_id.to_string())).unwrap(),
+            program_id
+        );
+        assert_eq!(
+            resolve_program_id(Network::Devnet, None)
+                .unwrap_err()
+                .to_string(),
+            "program_id is required on devnet"
+        );
+        assert!(resolve_program_id(Network::Mainnet, Some("nope")).is_err());
+    }
+
+    #[tokio::test]
+    async fn api_keys_should_stay_out_of_rpc_errors() {
+        assert_eq!(
+            redacted_rpc_url("https://rpc.example.com/v2/secret?api-key=secret"),
+            "https://rpc.example.com/…"
+        );
+        assert_eq!(
+            redacted_rpc_url("http://127.0.0.1:8899"),
+            "http://127.0.0.1:8899"
+        );
+
+        // Nothing listens there
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let rpc_client = build_rpc_client(
+            &format!("http://{}/?api-key=secret", address),
+            &RpcConfig::default(),
+        )
+        .unwrap();
+        let error = probe_rpc_connection(&rpc_client).await.unwrap_err();
+        assert!(!error.to_string().contains("secret"), "{}", error);
     }
 }
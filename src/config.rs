@@ -1,9 +1,87 @@
-use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use figment::{
+    providers::{Format, Yaml},
+    Figment,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use solana_sdk::commitment_config::CommitmentLevel;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Reads the configuration from the yaml file at `path`.
+pub fn load_config(path: &str) -> Result<Config, Box<figment::Error>> {
+    Ok(Figment::new().merge(Yaml::file(path)).extract()?)
+}
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub postgres: PostgresConfig,
-    pub rpc_url: String
+    /// Optional read replica serving read-only queries, the primary is used when absent.
+    pub postgres_replica: Option<PostgresConfig>,
+    pub rpc_url: String,
+    /// Endpoints of the same cluster tried in order when `rpc_url` can't be reached.
+    #[serde(default)]
+    pub rpc_fallback_urls: Vec<String>,
+    #[serde(default)]
+    pub network: Network,
+    /// Trade With Me program id, required off mainnet.
+    pub program_id: Option<String>,
+    #[serde(default)]
+    pub skip_rpc_health_check: bool,
+    #[serde(default)]
+    pub rpc: RpcConfig,
+    #[serde(default)]
+    pub metadata: MetadataConfig,
+    #[serde(default)]
+    pub tokens: TokensConfig,
+    #[serde(default)]
+    pub transaction: TransactionConfig,
+    #[serde(default)]
+    pub reconciliation: ReconciliationConfig,
+    #[serde(default)]
+    pub confirmation: ConfirmationConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub prices: PricesConfig,
+    #[serde(default)]
+    pub sessions: SessionsConfig,
+    #[serde(default)]
+    pub broadcasts: BroadcastConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+}
+
+impl Config {
+    /// `rpc_url` followed by `rpc_fallback_urls`.
+    pub fn rpc_urls(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.rpc_url.as_str())
+            .chain(self.rpc_fallback_urls.iter().map(String::as_str))
+    }
+}
+
+/// Solana cluster the RPC endpoints belong to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Devnet,
+    Testnet,
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Devnet => write!(f, "devnet"),
+            Network::Testnet => write!(f, "testnet"),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -12,5 +90,500 @@ pub struct PostgresConfig {
     pub port: u16,
     pub user: String,
     pub password: String,
-    pub database: String
+    pub database: String,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct RpcConfig {
+    /// Timeout of every request made to `rpc_url` and the fallback endpoints.
+    pub timeout_ms: u64,
+    /// Extra headers sent with every RPC request, e.g. the API key of a private endpoint.
+    pub headers: HashMap<String, String>,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        RpcConfig {
+            timeout_ms: 30000,
+            headers: HashMap::new(),
+        }
+    }
+}
+
+/// Header values are credentials, only their names are shown.
+impl fmt::Debug for RpcConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcConfig")
+            .field("timeout_ms", &self.timeout_ms)
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct MetadataConfig {
+    pub max_concurrent_fetches: usize,
+    /// Timeout of the HTTP requests following metadata uris to token images.
+    pub http_timeout_ms: u64,
+    /// Gateways tried in order for IPFS metadata uris and images, e.g. `https://ipfs.io/ipfs`.
+    pub ipfs_gateways: Vec<String>,
+    /// Url or file path of a token list used for mints without on-chain metadata.
+    pub token_list: Option<String>,
+    /// Hosts fetched even when they resolve to a private, loopback or link-local address.
+    pub allowed_hosts: Vec<String>,
+    /// Hosts whose metadata uris and images are never fetched.
+    pub denied_hosts: Vec<String>,
+    /// Number of recent DB lookups of stored metadata the fallback ratio is computed over.
+    pub db_fallback_window: usize,
+    /// Share of those lookups falling back to the RPC above which readiness reports degraded.
+    pub max_db_fallback_ratio: f64,
+    /// Attempts at each metadata RPC call and image request, only transient failures are retried.
+    pub retry_attempts: u32,
+    /// Delay before the first retry, doubled before each further one.
+    pub retry_base_delay_ms: u64,
+}
+
+impl Default for MetadataConfig {
+    fn default() -> Self {
+        MetadataConfig {
+            max_concurrent_fetches: 8,
+            http_timeout_ms: 10000,
+            ipfs_gateways: vec![
+                "https://ipfs.io/ipfs".to_string(),
+                "https://cloudflare-ipfs.com/ipfs".to_string(),
+            ],
+            token_list: None,
+            allowed_hosts: vec![],
+            denied_hosts: vec![],
+            db_fallback_window: 50,
+            max_db_fallback_ratio: 0.5,
+            retry_attempts: 3,
+            retry_base_delay_ms: 200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct TokensConfig {
+    /// Upper bound on token accounts returned for a wallet, protecting against spam accounts.
+    pub max_tokens_returned: usize,
+    /// Bytes of metadata json and images one request may download, tokens resolved after the
+    /// budget ran out are returned without images.
+    pub image_budget_bytes: u64,
+}
+
+impl Default for TokensConfig {
+    fn default() -> Self {
+        TokensConfig {
+            max_tokens_returned: 500,
+            image_budget_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct TransactionConfig {
+    /// Build trade transactions on chain, when off the server only hosts the negotiation and
+    /// settlement happens elsewhere.
+    pub trading_enabled: bool,
+    pub fee_payer: FeePayerPolicy,
+    /// Log the accounts of every built trade instruction at debug level.
+    pub log_details: bool,
+    /// Compute budget requested by trade transactions, no priority fee is paid when absent.
+    pub priority_fee: Option<PriorityFeeConfig>,
+    /// Encoding of the transaction to sign when the client doesn't ask for one.
+    pub encoding: TransactionEncoding,
+    /// Refuse to build transactions the fee payer can't fund, counting the rent of the
+    /// receiver token accounts that don't exist yet.
+    pub check_fee_payer_balance: bool,
+    /// Re-read the token accounts the offers are sent from before building, refusing offers
+    /// they no longer hold, e.g. after tokens were moved out during the negotiation.
+    pub check_token_balances: bool,
+    /// Add the participants' trade notes to the transaction as spl-memo instructions.
+    pub include_memos: bool,
+    /// Split trades with more net transfers than this into several transactions signed one
+    /// after the other, trades are always built as a single transaction when unset. Every
+    /// transaction settles on its own, so a split trade is not atomic.
+    pub max_transfers_per_transaction: Option<usize>,
+}
+
+impl Default for TransactionConfig {
+    fn default() -> Self {
+        TransactionConfig {
+            trading_enabled: true,
+            fee_payer: FeePayerPolicy::default(),
+            log_details: false,
+            priority_fee: None,
+            encoding: TransactionEncoding::default(),
+            check_fee_payer_balance: false,
+            check_token_balances: true,
+            include_memos: false,
+            max_transfers_per_transaction: None,
+        }
+    }
+}
+
+/// Wire encoding of a bincode serialized transaction, wallet libraries differ in what they expect.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionEncoding {
+    Base58,
+    #[default]
+    Base64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct PriorityFeeConfig {
+    pub compute_unit_limit: u32,
+    /// Paid per compute unit, the least paid when estimating from recent fees.
+    pub compute_unit_price_micro_lamports: u64,
+    /// Pay the median price recent slots paid to write the trade's accounts when that's more.
+    #[serde(default)]
+    pub estimate_from_recent_fees: bool,
+    /// Estimated prices are capped at this, uncapped when unset.
+    #[serde(default)]
+    pub max_compute_unit_price_micro_lamports: Option<u64>,
+}
+
+/// Who pays the network fee of a trade transaction. Solana transactions have exactly one fee
+/// payer, so fees cannot be split between the participants.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum FeePayerPolicy {
+    /// The participant who started the trade session pays.
+    #[default]
+    Initiator,
+    /// A server funded wallet pays and co-signs every trade transaction with its keypair, read
+    /// from the solana-keygen json file at `keypair_path`.
+    Server { keypair_path: String },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReconciliationConfig {
+    /// Seconds between runs, 0 is treated as 1.
+    pub interval_secs: u64,
+    /// `Created` trades without a live session are expired once untouched for this long.
+    pub stale_after_secs: u64,
+}
+
+impl Default for ReconciliationConfig {
+    fn default() -> Self {
+        ReconciliationConfig {
+            interval_secs: 300,
+            stale_after_secs: 1800,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConfirmationConfig {
+    pub poll_interval_ms: u64,
+    /// A submitted transaction whose signature is still unseen after this long is resubmitted.
+    pub resubmit_after_ms: u64,
+    pub max_resubmits: u32,
+    /// Commitment a landed transaction must reach before the trade counts as settled.
+    pub commitment: CommitmentLevel,
+}
+
+impl Default for ConfirmationConfig {
+    fn default() -> Self {
+        ConfirmationConfig {
+            poll_interval_ms: 2000,
+            resubmit_after_ms: 10000,
+            max_resubmits: 3,
+            commitment: CommitmentLevel::Confirmed,
+        }
+    }
+}
+
+/// Coalescing of session state broadcasts: changes are broadcast once no further change came
+/// in for `debounce_ms`, and at the latest `max_delay_ms` after the first unsent change.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BroadcastConfig {
+    /// Every change is broadcast right away when 0, the default.
+    pub debounce_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        BroadcastConfig {
+            debounce_ms: 0,
+            max_delay_ms: 250,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// How long background tasks get to finish their current work after the server stopped,
+    /// those still running afterwards are aborted.
+    pub drain_timeout_ms: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            drain_timeout_ms: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    /// Bearer token for the `/admin` endpoints, which are disabled when unset.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SessionsConfig {
+    /// Lamports the initiator wallet must hold to create a trade session, unchecked when unset.
+    pub min_initiator_lamports: Option<u64>,
+    /// What offering a zero or negative amount does.
+    pub non_positive_offers: NonPositiveAmounts,
+    /// What withdrawing a zero or negative amount does.
+    pub non_positive_withdrawals: NonPositiveAmounts,
+    /// What an offered or withdrawn amount finer than the mint's decimals becomes.
+    pub offer_rounding: OfferRounding,
+    /// Sessions without offers, accepts or keepalives for this long are closed unless their
+    /// transaction is being signed, never when unset.
+    pub idle_timeout_secs: Option<u64>,
+    /// Keepalives of a session sooner than this after the previous one are ignored.
+    pub keepalive_min_interval_secs: u64,
+    /// Whether both participants may offer the same mint, the offers are then netted out into
+    /// a single transfer. When false offering a mint the counterparty offers is refused.
+    pub allow_same_mint_both_sides: bool,
+    /// Websocket connections are pinged this often, never when 0.
+    pub ping_interval_ms: u64,
+    /// Connections sending nothing, pongs included, for this long are closed.
+    pub pong_timeout_ms: u64,
+    /// Distinct mints a participant may offer, which bounds the session and its transaction.
+    pub max_mints_per_user: usize,
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        SessionsConfig {
+            min_initiator_lamports: None,
+            non_positive_offers: NonPositiveAmounts::Reject,
+            non_positive_withdrawals: NonPositiveAmounts::Ignore,
+            offer_rounding: OfferRounding::Floor,
+            idle_timeout_secs: Some(1800),
+            keepalive_min_interval_secs: 10,
+            allow_same_mint_both_sides: true,
+            ping_interval_ms: 30000,
+            pong_timeout_ms: 60000,
+            max_mints_per_user: 20,
+        }
+    }
+}
+
+/// Handling of zero or negative offer and withdrawal amounts, which only buggy clients send.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NonPositiveAmounts {
+    /// The request succeeds without changing anything.
+    Ignore,
+    /// The request fails with a validation error.
+    Reject,
+}
+
+/// Handling of amounts with more decimal places than the mint has.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OfferRounding {
+    /// The request fails with a validation error.
+    Reject,
+    /// The extra digits are dropped, so never more than requested is offered.
+    Floor,
+    /// Rounded to the nearest base unit, halves up.
+    HalfUp,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct PricesConfig {
+    /// Jupiter compatible price API.
+    pub url: String,
+    pub ttl_secs: u64,
+    pub max_batch_size: usize,
+    /// Price requests each client address may make per minute, unlimited when 0. Clients behind
+    /// the same proxy share one address.
+    pub requests_per_minute: u32,
+}
+
+impl Default for PricesConfig {
+    fn default() -> Self {
+        PricesConfig {
+            url: "https://api.jup.ag/price/v2".to_string(),
+            ttl_secs: 30,
+            max_batch_size: 50,
+            requests_per_minute: 60,
+        }
+    }
+}
+
+/// Settings that take effect without a restart when the configuration is reloaded. Everything
+/// else is read once at startup and needs a restart: the database, RPC endpoints, network and
+/// program id, `rpc`, `metadata`, `prices`, `reconciliation`, `confirmation`, `admin` and
+/// `shutdown`.
+///
+/// Transactions already built keep the settings they were built with, a reload applies to the
+/// next one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TunableConfig {
+    pub tokens: TokensConfig,
+    pub sessions: SessionsConfig,
+    pub broadcasts: BroadcastConfig,
+    pub transaction: TransactionConfig,
+}
+
+impl TunableConfig {
+    pub fn from_config(config: &Config) -> Self {
+        TunableConfig {
+            tokens: config.tokens.clone(),
+            sessions: config.sessions.clone(),
+            broadcasts: config.broadcasts.clone(),
+            transaction: config.transaction.clone(),
+        }
+    }
+}
+
+/// The current `TunableConfig`, swapped as a whole on reload so readers never see a mix of old
+/// and new settings.
+#[derive(Debug, Default)]
+pub struct RuntimeConfig {
+    current: RwLock<Arc<TunableConfig>>,
+}
+
+impl RuntimeConfig {
+    pub fn new(config: TunableConfig) -> Self {
+        RuntimeConfig {
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<TunableConfig> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    /// Re-reads the yaml file at `path` and swaps in its tunable settings, returns whether any
+    /// changed. Changes to the other settings are ignored until a restart.
+    pub fn reload(&self, path: &str) -> Result<bool, Box<figment::Error>> {
+        let reloaded = TunableConfig::from_config(&load_config(path)?);
+        let mut current = self.current.write().unwrap();
+        if **current == reloaded {
+            return Ok(false);
+        }
+        *current = Arc::new(reloaded);
+        Ok(true)
+    }
+}
+
+/// Reloads the tunable settings from `path` whenever the process receives SIGHUP, until
+/// `shutdown` is cancelled.
+pub fn spawn_reload_on_sighup(
+    runtime_config: Arc<RuntimeConfig>,
+    path: &'static str,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut hangups =
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(e) => {
+                    warn!(
+                        "Unable to listen for SIGHUP, config reload is disabled: {}",
+                        e
+                    );
+                    return;
+                }
+            };
+        while let Some(Some(())) = shutdown.run_until_cancelled(hangups.recv()).await {
+            match runtime_config.reload(path) {
+                Ok(true) => info!("Reloaded tunable settings from {}", path),
+                Ok(false) => info!("Reloaded {}, no tunable setting changed", path),
+                Err(e) => warn!(
+                    "Unable to reload {}, keeping the current settings: {}",
+                    path, e
+                ),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(path: &std::path::Path, rpc_url: &str, max_tokens_returned: usize) {
+        std::fs::write(
+            path,
+            format!(
+                "postgres:\n  host: localhost\n  port: 5432\n  user: u\n  password: p\n  database: d\n\
+                 rpc_url: \"{}\"\ntokens:\n  max_tokens_returned: {}\n",
+                rpc_url, max_tokens_returned
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn reload_should_swap_tunable_settings_only() {
+        let path = std::env::temp_dir().join(format!("config-{}.yaml", uuid::Uuid::new_v4()));
+        write_config(&path, "http://127.0.0.1:8899", 500);
+        let path_str = path.to_str().unwrap();
+        let config = load_config(path_str).unwrap();
+        let runtime_config = RuntimeConfig::new(TunableConfig::from_config(&config));
+        let before = runtime_config.get();
+
+        assert!(!runtime_config.reload(path_str).unwrap());
+        write_config(&path, "http://10.0.0.1:8899", 20);
+        assert!(runtime_config.reload(path_str).unwrap());
+
+        assert_eq!(runtime_config.get().tokens.max_tokens_returned, 20);
+        // Readers holding the previous settings keep a consistent snapshot
+        assert_eq!(before.tokens.max_tokens_returned, 500);
+        std::fs::write(&path, "not: [valid").unwrap();
+        assert!(runtime_config.reload(path_str).is_err());
+        assert_eq!(runtime_config.get().tokens.max_tokens_returned, 20);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reload_should_reach_broadcast_and_transaction_settings() {
+        use crate::chain_context::TestChainContext;
+        use crate::transaction_service::TransactionService;
+
+        let path = std::env::temp_dir().join(format!("config-{}.yaml", uuid::Uuid::new_v4()));
+        write_config(&path, "http://127.0.0.1:8899", 500);
+        let path_str = path.to_str().unwrap();
+        let runtime_config = Arc::new(RuntimeConfig::new(TunableConfig::from_config(
+            &load_config(path_str).unwrap(),
+        )));
+        let transaction_service = TransactionService::with_runtime_config(
+            Arc::new(TestChainContext {}),
+            Arc::clone(&runtime_config),
+        );
+        assert!(transaction_service.trading_enabled());
+
+        let mut yaml = std::fs::read_to_string(&path).unwrap();
+        yaml.push_str("broadcasts:\n  debounce_ms: 40\ntransaction:\n  trading_enabled: false\n");
+        std::fs::write(&path, yaml).unwrap();
+        assert!(runtime_config.reload(path_str).unwrap());
+
+        assert_eq!(runtime_config.get().broadcasts.debounce_ms, 40);
+        assert!(!transaction_service.trading_enabled());
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{info, warn};
+use solana_sdk::{commitment_config::CommitmentConfig, transaction::Transaction};
+use tokio::time::Instant;
+
+use crate::{
+    chain_context::ChainContext, config::ConfirmationConfig, trade_repository::TradeStatus,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfirmationOutcome {
+    Confirmed,
+    /// The transaction landed but its execution failed.
+    Failed(String),
+    /// The blockhash expired before the transaction landed, it can never confirm now.
+    Expired,
+    /// Still unseen after every resubmit while the blockhash was valid.
+    Dropped,
+}
+
+impl ConfirmationOutcome {
+    /// Persisted status of the trade settled by the transaction.
+    pub fn trade_status(&self) -> TradeStatus {
+        match self {
+            ConfirmationOutcome::Confirmed => TradeStatus::Completed,
+            _ => TradeStatus::Failed,
+        }
+    }
+
+    pub fn failure_reason(&self) -> Option<String> {
+        match self {
+            ConfirmationOutcome::Confirmed => None,
+            ConfirmationOutcome::Failed(e) => Some(e.clone()),
+            ConfirmationOutcome::Expired => {
+                Some("Blockhash expired before the transaction landed".to_string())
+            }
+            ConfirmationOutcome::Dropped => Some("Transaction dropped by the cluster".to_string()),
+        }
+    }
+}
+
+/// Polls the signature of an already submitted transaction until it resolves. When the cluster
+/// hasn't seen it for `resubmit_after_ms` the same transaction is sent again, up to
+/// `max_resubmits` times, as long as its blockhash is still valid. A successful transaction only
+/// counts as confirmed once it reaches the configured `commitment`.
+pub async fn await_confirmation<T: ChainContext>(
+    chain_context: &T,
+    tx: &Transaction,
+    config: &ConfirmationConfig,
+) -> Result<ConfirmationOutcome> {
+    let signature = tx.signatures.first().copied().unwrap_or_default();
+    let poll_interval = Duration::from_millis(config.poll_interval_ms);
+    let resubmit_after = Duration::from_millis(config.resubmit_after_ms);
+    let mut resubmits = 0;
+    let mut last_submitted = Instant::now();
+    let commitment = CommitmentConfig {
+        commitment: config.commitment,
+    };
+
+    loop {
+        match chain_context.get_signature_status(&signature).await? {
+            Some(Ok(()))
+                if chain_context
+                    .confirm_transaction_with_commitment(&signature, commitment)
+                    .await? =>
+            {
+                return Ok(ConfirmationOutcome::Confirmed)
+            }
+            // Landed but below the configured commitment yet
+            Some(Ok(())) => {}
+            Some(Err(e)) => return Ok(ConfirmationOutcome::Failed(e)),
+            None if last_submitted.elapsed() >= resubmit_after => {
+                if !chain_context
+                    .is_blockhash_valid(&tx.message.recent_blockhash)
+                    .await?
+                {
+                    warn!("Blockhash of transaction {} expired", signature);
+                    return Ok(ConfirmationOutcome::Expired);
+                }
+                if resubmits == config.max_resubmits {
+                    warn!(
+                        "Transaction {} dropped after {} resubmits",
+                        signature, resubmits
+                    );
+                    return Ok(ConfirmationOutcome::Dropped);
+                }
+                resubmits += 1;
+                info!("Resubmitting transaction {} ({})", signature, resubmits);
+                chain_context.send_transaction(tx).await?;
+                last_submitted = Instant::now();
+            }
+            None => {}
+        }
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::commitment_config::CommitmentLevel;
+
+    use crate::chain_context::{ScriptedChainContext, TestChainContext};
+
+    use super::*;
+
+    fn config(max_resubmits: u32) -> ConfirmationConfig {
+        ConfirmationConfig {
+            poll_interval_ms: 1,
+            resubmit_after_ms: 0,
+            max_resubmits,
+            commitment: CommitmentLevel::Confirmed,
+        }
+    }
+
+    #[tokio::test]
+    async fn dropped_transaction_should_be_resubmitted_until_confirmed() {
+        let chain_context = ScriptedChainContext::with_statuses(vec![None, None, Some(Ok(()))]);
+
+        let outcome = await_confirmation(&chain_context, &Transaction::default(), &config(3))
+            .await
+            .unwrap();
+        assert_eq!(outcome, ConfirmationOutcome::Confirmed);
+        assert_eq!(chain_context.sent().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn should_give_up_after_max_resubmits() {
+        let chain_context = ScriptedChainContext::with_statuses(vec![]);
+
+        let outcome = await_confirmation(&chain_context, &Transaction::default(), &config(2))
+            .await
+            .unwrap();
+        assert_eq!(outcome, ConfirmationOutcome::Dropped);
+        assert_eq!(chain_context.sent().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn should_report_expiry_instead_of_resubmitting() {
+        let chain_context = ScriptedChainContext {
+            blockhash_expired: true,
+            ..ScriptedChainContext::with_statuses(vec![None])
+        };
+
+        let outcome = await_confirmation(&chain_context, &Transaction::default(), &config(3))
+            .await
+            .unwrap();
+        assert_eq!(outcome, ConfirmationOutcome::Expired);
+        assert_eq!(chain_context.sent().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn should_report_failed_execution() {
+        let chain_context =
+            ScriptedChainContext::with_statuses(vec![Some(Err("InsufficientFunds".to_string()))]);
+
+        let outcome = await_confirmation(&chain_context, &Transaction::default(), &config(3))
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            ConfirmationOutcome::Failed("InsufficientFunds".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn should_wait_for_configured_commitment() {
+        let chain_context: ScriptedChainContext = ScriptedChainContext {
+            highest_commitment: Some(CommitmentLevel::Confirmed),
+            ..ScriptedChainContext::default()
+        };
+        let finalized = ConfirmationConfig {
+            commitment: CommitmentLevel::Finalized,
+            ..config(3)
+        };
+
+        let pending = tokio::time::timeout(
+            Duration::from_millis(50),
+            await_confirmation(&chain_context, &Transaction::default(), &finalized),
+        )
+        .await;
+        assert!(pending.is_err(), "should still wait for finalization");
+
+        let outcome = await_confirmation(&chain_context, &Transaction::default(), &config(3))
+            .await
+            .unwrap();
+        assert_eq!(outcome, ConfirmationOutcome::Confirmed);
+        let finalized_chain = TestChainContext {};
+        let outcome = await_confirmation(&finalized_chain, &Transaction::default(), &finalized)
+            .await
+            .unwrap();
+        assert_eq!(outcome, ConfirmationOutcome::Confirmed);
+    }
+}
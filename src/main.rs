@@ -1,66 +1,208 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use chain_context::MainnetChainContext;
-use config::Config;
+use chain_context::{
+    build_rpc_client, probe_rpc_connection, redacted_rpc_url, resolve_program_id, validate_rpc_url,
+    RpcChainContext,
+};
+use config::{load_config, spawn_reload_on_sighup, Config, RuntimeConfig, TunableConfig};
 use db::PostgreSqlClient;
 use env_logger::Env;
-use figment::{
-    providers::{Format, Yaml},
-    Figment,
-};
-use log::info;
+use log::{info, warn};
 use metadata_cache::MetadataCache;
 use metadata_repository::MetadataRepository;
+use metrics::Metrics;
+use price_service::{JupiterPriceSource, PriceService};
+use reconciliation::spawn_reconciliation_task;
 use routes::{get_router, AppState};
-use solana_client::nonblocking::rpc_client::RpcClient;
+use shutdown::{drain, shutdown_signal};
 use token_amount_cache::TokenAmountCache;
+use token_list::TokenList;
 use token_service::TokenService;
+use tokio_util::sync::CancellationToken;
+use trade_event_repository::{AuditLog, TradeEventRepository};
 use trade_repository::TradeRepository;
 use trade_service::TradeService;
-use trade_session::SharedSessions;
+use trade_session::{
+    spawn_idle_session_reaper, spawn_session_writer, spawn_settlement_task, SharedSessions,
+};
 use transaction_service::TransactionService;
 
+pub mod chain_context;
 pub mod config;
+pub mod confirmation;
 pub mod db;
+pub mod error;
 pub mod metadata_cache;
 pub mod metadata_repository;
+pub mod metrics;
+pub mod price_service;
+pub mod reconciliation;
 pub mod routes;
 pub mod schema;
+pub mod shutdown;
+#[cfg(test)]
+mod test_logger;
+pub mod token_amount_cache;
+pub mod token_list;
 pub mod token_service;
+pub mod trade_event_repository;
 pub mod trade_repository;
 pub mod trade_service;
-pub mod trade_websocket;
 pub mod trade_session;
-pub mod token_amount_cache;
+pub mod trade_websocket;
 pub mod transaction_service;
-pub mod chain_context;
+pub mod url_policy;
+pub mod wallet_auth;
+
+const CONFIG_PATH: &str = "config.yaml";
 
 // example token holder address: 87UGBXfeuCaMyxNnCD3a9Wcbjc5C8c34hbKEBUfc2F86
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
-    let config: Config = Figment::new().merge(Yaml::file("config.yaml")).extract()?;
-    
-    let sqlite_db_client = Arc::new(PostgreSqlClient::init(&config.postgres)?);
-    let rpc_client = Arc::new(RpcClient::new(config.rpc_url));
+    let config: Config = load_config(CONFIG_PATH)?;
+    let runtime_config = Arc::new(RuntimeConfig::new(TunableConfig::from_config(&config)));
+    let shutdown = CancellationToken::new();
+    let mut background_tasks = vec![spawn_reload_on_sighup(
+        Arc::clone(&runtime_config),
+        CONFIG_PATH,
+        shutdown.clone(),
+    )];
 
+    let sqlite_db_client = Arc::new(PostgreSqlClient::init(
+        &config.postgres,
+        config.postgres_replica.as_ref(),
+    )?);
+    let mut rpc_clients = vec![];
+    for rpc_url in config.rpc_urls() {
+        validate_rpc_url(rpc_url)?;
+        rpc_clients.push(Arc::new(build_rpc_client(rpc_url, &config.rpc)?));
+    }
+    let rpc_client = Arc::clone(&rpc_clients[0]);
+    if config.skip_rpc_health_check {
+        info!("Skipping RPC health check");
+    } else {
+        probe_rpc_connection(&rpc_client).await?;
+        info!(
+            "RPC endpoint {} is healthy",
+            redacted_rpc_url(&rpc_client.url())
+        );
+        for fallback in &rpc_clients[1..] {
+            match probe_rpc_connection(fallback).await {
+                Ok(()) => info!(
+                    "Fallback RPC endpoint {} is healthy",
+                    redacted_rpc_url(&fallback.url())
+                ),
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+    let program_id = resolve_program_id(config.network, config.program_id.as_deref())?;
+    info!("Trading on {} with program {}", config.network, program_id);
+
+    let metrics = Arc::new(Metrics::new());
     let metadata_repository = MetadataRepository::new(Arc::clone(&sqlite_db_client));
-    let metadata_cache = MetadataCache::init(metadata_repository, Arc::clone(&rpc_client))?;
+    let token_list = match &config.metadata.token_list {
+        Some(source) => TokenList::load(source).await.unwrap_or_else(|e| {
+            warn!("Unable to load token list from {}: {}", source, e);
+            TokenList::default()
+        }),
+        None => TokenList::default(),
+    };
+    info!("Loaded {} token list entries", token_list.len());
+    let metadata_cache = MetadataCache::init(
+        metadata_repository,
+        Arc::clone(&rpc_client),
+        token_list,
+        &config.metadata,
+        Arc::clone(&metrics),
+    )?;
     let token_amount_cache = Arc::new(TokenAmountCache::init());
-    let token_service = TokenService::new(metadata_cache, Arc::clone(&rpc_client), Arc::clone(&token_amount_cache));
+    let token_service = TokenService::with_runtime_config(
+        metadata_cache,
+        Arc::clone(&rpc_client),
+        Arc::clone(&token_amount_cache),
+        Arc::clone(&metrics),
+        Arc::clone(&runtime_config),
+    );
     let trade_repository = TradeRepository::new(Arc::clone(&sqlite_db_client));
-    let trade_service = TradeService::new(trade_repository);
+    let trade_service =
+        TradeService::with_runtime_config(trade_repository, Arc::clone(&runtime_config));
     let app_state = AppState {
         token_service: Arc::new(token_service),
-        trade_service: Arc::new(trade_service)
+        trade_service: Arc::new(trade_service),
+        price_service: Arc::new(PriceService::new(
+            JupiterPriceSource::new(config.prices.url.clone()),
+            &config.prices,
+        )),
+        metrics,
+        admin_token: config.admin.token.clone(),
+        wallet_auth: Arc::default(),
     };
-    let transaction_service = Arc::new(TransactionService::new(Arc::new(MainnetChainContext::new(Arc::clone(&rpc_client)))));
-    let trade_sessions = Arc::new(SharedSessions::new(Arc::clone(&token_amount_cache), Arc::clone(&transaction_service)));
+    if !config.transaction.trading_enabled {
+        info!("Transaction building is disabled, only negotiation is served");
+    }
+    let transaction_service = Arc::new(TransactionService::with_runtime_config(
+        Arc::new(RpcChainContext::with_endpoints(rpc_clients, program_id)),
+        Arc::clone(&runtime_config),
+    ));
+    if let Some(fee_payer) = transaction_service.server_fee_payer()? {
+        info!("Network fees are paid by server wallet {}", fee_payer);
+    }
+    let (audit_log, audit_log_writer) = AuditLog::spawn(
+        Arc::new(TradeEventRepository::new(Arc::clone(&sqlite_db_client))),
+        shutdown.clone(),
+    );
+    background_tasks.push(audit_log_writer);
+    let trade_sessions = Arc::new(SharedSessions::with_config(
+        Arc::clone(&token_amount_cache),
+        Arc::clone(&transaction_service),
+        audit_log,
+        Some(Arc::new(TradeRepository::new(Arc::clone(
+            &sqlite_db_client,
+        )))),
+        Arc::clone(&runtime_config),
+    ));
+    match trade_sessions.restore_sessions() {
+        Ok(restored) => info!("Restored {} trade sessions", restored),
+        Err(e) => warn!("Unable to restore trade sessions: {}", e),
+    }
+    background_tasks.push(spawn_session_writer(
+        Arc::clone(&trade_sessions),
+        shutdown.clone(),
+    ));
+    background_tasks.push(spawn_idle_session_reaper(
+        Arc::clone(&trade_sessions),
+        shutdown.clone(),
+    ));
+    background_tasks.push(spawn_settlement_task(
+        Arc::clone(&trade_sessions),
+        config.confirmation,
+        shutdown.clone(),
+    ));
+    background_tasks.push(spawn_reconciliation_task(
+        Arc::clone(&trade_sessions),
+        Arc::new(TradeRepository::new(Arc::clone(&sqlite_db_client))),
+        config.reconciliation,
+        shutdown.clone(),
+    ));
     let router = get_router(Arc::new(app_state), trade_sessions);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
     info!("Server started on port 3000");
-    axum::serve(listener, router).await.unwrap();
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+
+    shutdown.cancel();
+    let drain_timeout = Duration::from_millis(config.shutdown.drain_timeout_ms);
+    if drain(background_tasks, drain_timeout).await {
+        info!("Background tasks stopped");
+    }
     Ok(())
 }
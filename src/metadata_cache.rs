@@ -1,78 +1,226 @@
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
 use std::io::Cursor;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use borsh::BorshDeserialize;
 use image::ImageFormat;
-use log::warn;
+use log::{debug, error, info, warn};
 use mpl_token_metadata::accounts::Metadata;
 use mpl_token_metadata::ID as TOKEN_METADATA_PROGRAM_ID;
-use reqwest::get;
 use serde_json::Value;
+use solana_account_decoder::{UiAccount, UiAccountEncoding};
 use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::rpc_config::RpcAccountInfoConfig;
+use solana_client::rpc_request::RpcRequest;
+use solana_client::rpc_response::Response as RpcResponse;
+use solana_sdk::account::Account;
 use solana_sdk::pubkey::Pubkey;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, Semaphore};
 
-use crate::metadata_repository::{MetadataEntity, MetadataRepository};
+use crate::chain_context::is_transport_error;
+use crate::config::MetadataConfig;
+use crate::metadata_repository::{MetadataEntity, MetadataStore};
+use crate::metrics::Metrics;
+use crate::token_list::TokenList;
+use crate::url_policy::UrlPolicy;
+
+/// The fields of a Metaplex metadata account this cache stores.
+#[derive(BorshDeserialize)]
+struct MetadataFields {
+    name: String,
+    symbol: String,
+    uri: String,
+}
+
+/// Start of the metadata account layout, unchanged across Metaplex versions.
+#[derive(BorshDeserialize)]
+struct MetadataPrefix {
+    _key: u8,
+    _update_authority: Pubkey,
+    _mint: Pubkey,
+    fields: MetadataFields,
+}
+
+/// Requests failing to connect, timing out or answered with 429 or a server error.
+fn is_transient_http_error(error: &reqwest::Error) -> bool {
+    error.is_connect()
+        || error.is_timeout()
+        || error.status().is_some_and(|status| {
+            status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        })
+}
+
+/// Native SOL wrapped as an SPL token, it's traded like any other mint of the Token program.
+pub const WRAPPED_SOL_MINT: &str = "So11111111111111111111111111111111111111112";
 
 pub struct MetadataCache {
     known_mint_addresses: RwLock<HashSet<String>>,
-    metadata_repository: MetadataRepository,
+    metadata_repository: Box<dyn MetadataStore>,
     rpc_client: Arc<RpcClient>,
+    http_client: reqwest::Client,
+    ipfs_gateways: Vec<String>,
+    url_policy: UrlPolicy,
+    token_list: TokenList,
+    fetch_limiter: FetchLimiter,
+    retry: RetryPolicy,
+    db_health: DbHealth,
+    metrics: Arc<Metrics>,
 }
 
 impl MetadataCache {
     pub fn init(
-        metadata_repository: MetadataRepository,
+        metadata_repository: impl MetadataStore + 'static,
         rpc_client: Arc<RpcClient>,
+        token_list: TokenList,
+        config: &MetadataConfig,
+        metrics: Arc<Metrics>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let known_mint_addresses = metadata_repository.get_all_saved_mint_addresses()?;
+        let url_policy = UrlPolicy::new(config);
+        let redirect_policy = url_policy.clone();
         Ok(MetadataCache {
             known_mint_addresses: RwLock::new(known_mint_addresses.into_iter().collect()),
-            metadata_repository,
+            metadata_repository: Box::new(metadata_repository),
             rpc_client,
+            http_client: reqwest::Client::builder()
+                .timeout(Duration::from_millis(config.http_timeout_ms))
+                .dns_resolver(Arc::new(url_policy.clone()))
+                .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                    if attempt.previous().len() >= 10 {
+                        attempt.error("too many redirects")
+                    } else if redirect_policy.permits_redirect(attempt.url()) {
+                        attempt.follow()
+                    } else {
+                        attempt.stop()
+                    }
+                }))
+                .build()?,
+            ipfs_gateways: config.ipfs_gateways.clone(),
+            url_policy,
+            token_list,
+            fetch_limiter: FetchLimiter::new(config.max_concurrent_fetches),
+            retry: RetryPolicy {
+                attempts: config.retry_attempts,
+                base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            },
+            db_health: DbHealth::new(config.db_fallback_window, config.max_db_fallback_ratio),
+            metrics,
         })
     }
+    /// Whether too many recent lookups of stored metadata failed in the DB and went to the RPC.
+    pub fn is_db_degraded(&self) -> bool {
+        self.db_health.is_degraded()
+    }
+
+    /// Whether metadata for the mint is already stored, i.e. resolvable without an RPC call.
+    pub async fn is_known(&self, mint_address: &str) -> bool {
+        self.known_mint_addresses
+            .read()
+            .await
+            .contains(mint_address)
+    }
+
     pub async fn get_token_metadata(&self, mint_address: &str) -> Result<MetadataEntity> {
+        self.get_token_metadata_within(mint_address, &ImageBudget::unlimited())
+            .await
+    }
+
+    /// Like `get_token_metadata`, downloading images only while `budget` lasts. Metadata whose
+    /// image was skipped for lack of budget isn't stored, so a later lookup can still get it.
+    pub async fn get_token_metadata_within(
+        &self,
+        mint_address: &str,
+        budget: &ImageBudget,
+    ) -> Result<MetadataEntity> {
         if self
             .known_mint_addresses
             .read()
             .await
             .contains(mint_address)
         {
+            self.metrics.metadata_cache_hits.inc();
             match self.metadata_repository.get_metadata(mint_address) {
-                Ok(result) => return Ok(result),
-                Err(_) => warn!("Unable to fetch metadata from DB"),
+                Ok(result) => {
+                    self.db_health.record(false);
+                    self.metrics.metadata_resolved_from_db.inc();
+                    return Ok(result);
+                }
+                Err(e) => {
+                    warn!("Unable to fetch metadata from DB: {}", e);
+                    self.metrics.metadata_db_fallbacks.inc();
+                    self.db_health.record(true);
+                }
             };
+        } else {
+            self.metrics.metadata_cache_misses.inc();
         }
 
-        let metaplex_metadata = self.fetch_token_metadata(mint_address).await?;
-        let resized_image = MetadataCache::follow_uri_to_get_image(&metaplex_metadata.uri)
+        let account_data = self
+            .fetch_limiter
+            .run(self.fetch_metadata_account(mint_address))
+            .await;
+        let fallback = || {
+            self.token_list_metadata(mint_address)
+                .or_else(|| MetadataCache::well_known_metadata(mint_address))
+        };
+        let metadata_fields = match account_data {
+            Ok(account_data) => MetadataCache::decode_metadata(mint_address, &account_data),
+            Err(e) => return fallback().ok_or(e),
+        };
+        let Some(metadata_fields) = metadata_fields else {
+            // Not persisted, a later lookup may understand the account
+            return Ok(fallback().unwrap_or_else(|| MetadataEntity {
+                mint_address: mint_address.to_string(),
+                symbol: None,
+                name: None,
+                uri: None,
+                image: None,
+            }));
+        };
+        self.metrics.metadata_resolved_from_rpc.inc();
+        let resized_image = self
+            .fetch_limiter
+            .run(self.follow_uri_to_get_image(&metadata_fields.uri, budget))
             .await
             .and_then(|image| MetadataCache::resize_image(&image));
+        if resized_image.is_some() {
+            self.metrics.images_processed.inc();
+        }
+        let over_budget = resized_image.is_none() && budget.is_exhausted();
 
         let new_metadata = MetadataEntity {
             mint_address: mint_address.to_string(),
             symbol: Some(
-                metaplex_metadata
+                metadata_fields
                     .symbol
                     .trim_end_matches(char::from(0))
                     .to_string(),
             ),
             name: Some(
-                metaplex_metadata
+                metadata_fields
                     .name
                     .trim_end_matches(char::from(0))
                     .to_string(),
             ),
             uri: Some(
-                metaplex_metadata
+                metadata_fields
                     .uri
                     .trim_end_matches(char::from(0))
                     .to_string(),
             ),
             image: resized_image,
         };
+        if over_budget {
+            debug!(
+                "Image budget exhausted, returning {} without its image",
+                mint_address
+            );
+            return Ok(new_metadata);
+        }
         self.known_mint_addresses
             .write()
             .await
@@ -81,12 +229,94 @@ impl MetadataCache {
         Ok(new_metadata)
     }
 
-    async fn fetch_token_metadata(&self, mint_address: &str) -> Result<Metadata> {
+    /// Not persisted, so the mint is still looked up on-chain next time in case it gains metadata.
+    fn token_list_metadata(&self, mint_address: &str) -> Option<MetadataEntity> {
+        let entry = self.token_list.get(mint_address)?;
+        self.metrics.metadata_resolved_from_token_list.inc();
+        Some(MetadataEntity {
+            mint_address: mint_address.to_string(),
+            symbol: Some(entry.symbol.clone()),
+            name: Some(entry.name.clone()),
+            uri: entry.logo_uri.clone(),
+            image: None,
+        })
+    }
+
+    fn well_known_metadata(mint_address: &str) -> Option<MetadataEntity> {
+        (mint_address == WRAPPED_SOL_MINT).then(|| MetadataEntity {
+            mint_address: mint_address.to_string(),
+            symbol: Some("SOL".to_string()),
+            name: Some("Wrapped SOL".to_string()),
+            uri: None,
+            image: None,
+        })
+    }
+
+    /// Reads the Metaplex account straight from the RPC, leaving the cache and database untouched.
+    pub async fn fetch_fresh_metadata(&self, mint_address: &str) -> Result<Metadata> {
+        let account_data = self
+            .fetch_limiter
+            .run(self.fetch_metadata_account(mint_address))
+            .await?;
+        Ok(Metadata::from_bytes(&account_data)?)
+    }
+
+    async fn fetch_metadata_account(&self, mint_address: &str) -> Result<Vec<u8>> {
         let mint_pubkey = Pubkey::try_from(mint_address)?;
         let metadata_pubkey = MetadataCache::derive_metadata_account(&mint_pubkey);
-        let account_data = self.rpc_client.get_account_data(&metadata_pubkey).await?;
-        let metadata: Metadata = Metadata::from_bytes(&account_data)?;
-        Ok(metadata)
+        // `get_account_data` reports every failure as a missing account, sending the request
+        // directly keeps transport errors apart so only those are retried
+        let rpc_client = &self.rpc_client;
+        let params = serde_json::json!([
+            metadata_pubkey.to_string(),
+            RpcAccountInfoConfig {
+                encoding: Some(UiAccountEncoding::Base64),
+                commitment: Some(rpc_client.commitment()),
+                ..RpcAccountInfoConfig::default()
+            }
+        ]);
+        let response: RpcResponse<Option<UiAccount>> = self
+            .retry
+            .run(
+                &format!("Fetching metadata account of {}", mint_address),
+                is_transport_error,
+                || rpc_client.send(RpcRequest::GetAccountInfo, params.clone()),
+            )
+            .await?;
+        let account: Account = response
+            .value
+            .and_then(|account| account.decode())
+            .ok_or_else(|| anyhow!("AccountNotFound: pubkey={}", metadata_pubkey))?;
+        Ok(account.data)
+    }
+
+    /// Accounts written with a layout `Metadata::from_bytes` doesn't know still share the leading
+    /// name, symbol and uri, `None` when even those can't be read.
+    fn decode_metadata(mint_address: &str, account_data: &[u8]) -> Option<MetadataFields> {
+        match Metadata::from_bytes(account_data) {
+            Ok(metadata) => Some(MetadataFields {
+                name: metadata.name,
+                symbol: metadata.symbol,
+                uri: metadata.uri,
+            }),
+            Err(e) => {
+                warn!(
+                    "Unable to decode metadata of mint {}, reading its leading fields only: {}",
+                    mint_address, e
+                );
+                let mut data = account_data;
+                match MetadataPrefix::deserialize(&mut data) {
+                    Ok(prefix) => Some(prefix.fields),
+                    Err(e) => {
+                        warn!(
+                            "Unable to decode any metadata of mint {}: {}",
+                            mint_address, e
+                        );
+                        None
+                    }
+                }
+            }
+        }
     }
 
     fn derive_metadata_account(mint_account: &Pubkey) -> Pubkey {
@@ -99,44 +329,136 @@ impl MetadataCache {
         metadata_pubkey
     }
 
-    async fn follow_uri_to_get_image(uri: &str) -> Option<Vec<u8>> {
+    async fn follow_uri_to_get_image(&self, uri: &str, budget: &ImageBudget) -> Option<Vec<u8>> {
+        if budget.is_exhausted() {
+            return None;
+        }
         //uri usually should contain json with "image": "image url" so it should be first way we do it
 
-        let uri_response = get(uri).await.ok();
-        if let Some(response) = uri_response {
-            if response
+        // A failing gateway moves on to the next one, only a successful non json answer ends the search
+        for url in self.candidate_urls(uri) {
+            let Some(response) = self.fetch(&url).await else {
+                continue;
+            };
+            if !response
                 .headers()
                 .get("content-type")
                 .and_then(|v| v.to_str().ok())
-                .map_or(false, |v| v.contains("application/json"))
+                .is_some_and(|v| v.contains("application/json"))
             {
-                let image_uri = response
-                    .text()
-                    .await
-                    .ok()
-                    .and_then(|text| serde_json::from_str::<Value>(&text).ok())
-                    .and_then(|json| json["image"].as_str().map(|r| r.to_string()));
-
-                if let Some(image_url) = image_uri {
-                    return MetadataCache::try_fetch_image(&image_url).await;
-                } else {
+                return None;
+            }
+            let image_uri = self
+                .read_body(response, budget)
+                .await
+                .and_then(|body| serde_json::from_slice::<Value>(&body).ok())
+                .and_then(|json| json["image"].as_str().map(|r| r.to_string()));
+
+            return match image_uri {
+                Some(image_url) => self.try_fetch_image(&image_url, budget).await,
+                None => None,
+            };
+        }
+        None
+    }
+
+    async fn try_fetch_image(&self, image_url: &str, budget: &ImageBudget) -> Option<Vec<u8>> {
+        for url in self.candidate_urls(image_url) {
+            if budget.is_exhausted() {
+                return None;
+            }
+            if let Some(response) = self.fetch(&url).await {
+                if let Some(bytes) = self.read_body(response, budget).await {
+                    return Some(bytes);
+                }
+            }
+        }
+        None
+    }
+
+    /// Reads the body chunk by chunk, giving up as soon as it no longer fits in `budget`.
+    async fn read_body(
+        &self,
+        mut response: reqwest::Response,
+        budget: &ImageBudget,
+    ) -> Option<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    self.metrics
+                        .metadata_bytes_downloaded
+                        .add(chunk.len() as u64);
+                    if !budget.take(chunk.len() as u64) {
+                        debug!("Image budget exhausted reading {}", response.url());
+                        return None;
+                    }
+                    body.extend_from_slice(&chunk);
+                }
+                Ok(None) => return Some(body),
+                Err(e) => {
+                    debug!("Reading {} failed: {}", response.url(), e);
                     return None;
                 }
             }
-        } else {
-            return None;
-        };
+        }
+    }
 
-        None
+    async fn fetch(&self, url: &str) -> Option<reqwest::Response> {
+        if !self.url_policy.permits(url).await {
+            warn!(
+                "Not fetching {} from token metadata, refused by the url policy",
+                url
+            );
+            return None;
+        }
+        let http_client = &self.http_client;
+        match self
+            .retry
+            .run(
+                &format!("Fetching {}", url),
+                is_transient_http_error,
+                || async move {
+                    http_client
+                        .get(url)
+                        .send()
+                        .await
+                        .and_then(|response| response.error_for_status())
+                },
+            )
+            .await
+        {
+            Ok(response) => Some(response),
+            Err(e) => {
+                debug!("Fetching {} failed: {}", url, e);
+                None
+            }
+        }
     }
 
-    async fn try_fetch_image(image_url: &str) -> Option<Vec<u8>> {
-        let image_response = get(image_url).await.ok();
-        if let Some(response) = image_response {
-            response.bytes().await.ok().map(|bytes| bytes.to_vec())
-        } else {
-            None
+    /// Urls to try in order for `uri`. IPFS content (`ipfs://<cid>` or `.../ipfs/<cid>`) is also
+    /// requested through each configured gateway, `ar://<id>` through arweave.net.
+    fn candidate_urls(&self, uri: &str) -> Vec<String> {
+        if let Some(arweave_id) = uri.strip_prefix("ar://") {
+            return vec![format!("https://arweave.net/{}", arweave_id)];
         }
+        let ipfs_path = uri
+            .strip_prefix("ipfs://")
+            .or_else(|| uri.split_once("/ipfs/").map(|(_, path)| path));
+        let Some(ipfs_path) = ipfs_path else {
+            return vec![uri.to_string()];
+        };
+        let mut urls = vec![];
+        if uri.starts_with("http") {
+            urls.push(uri.to_string());
+        }
+        for gateway in &self.ipfs_gateways {
+            let url = format!("{}/{}", gateway.trim_end_matches('/'), ipfs_path);
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+        urls
     }
 
     fn resize_image(image: &[u8]) -> Option<Vec<u8>> {
@@ -150,3 +472,719 @@ impl MetadataCache {
             .ok()
     }
 }
+
+/// Bytes of metadata json and images a single request may still download, shared by all the
+/// metadata lookups it makes.
+pub struct ImageBudget {
+    remaining: AtomicU64,
+}
+
+impl ImageBudget {
+    pub fn new(bytes: u64) -> Self {
+        ImageBudget {
+            remaining: AtomicU64::new(bytes),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        ImageBudget::new(u64::MAX)
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.remaining.load(Ordering::Relaxed) == 0
+    }
+
+    /// Takes `bytes` from the budget, returns false and exhausts it when they don't fit.
+    fn take(&self, bytes: u64) -> bool {
+        let taken = self
+            .remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+                remaining.checked_sub(bytes)
+            })
+            .is_ok();
+        if !taken {
+            self.remaining.store(0, Ordering::Relaxed);
+        }
+        taken
+    }
+}
+
+/// Share of the last `window` DB lookups that failed and fell back to the RPC. Above
+/// `max_fallback_ratio` the DB counts as degraded, which is logged once when it flips.
+struct DbHealth {
+    window: usize,
+    max_fallback_ratio: f64,
+    recent_fallbacks: Mutex<VecDeque<bool>>,
+    degraded: AtomicBool,
+}
+
+impl DbHealth {
+    fn new(window: usize, max_fallback_ratio: f64) -> Self {
+        DbHealth {
+            window: window.max(1),
+            max_fallback_ratio,
+            recent_fallbacks: Mutex::new(VecDeque::new()),
+            degraded: AtomicBool::new(false),
+        }
+    }
+
+    fn record(&self, fell_back: bool) {
+        let ratio = {
+            let mut recent = self.recent_fallbacks.lock().unwrap();
+            if recent.len() == self.window {
+                recent.pop_front();
+            }
+            recent.push_back(fell_back);
+            // Too few lookups yet to tell a failing DB from a single hiccup
+            if recent.len() < self.window {
+                return;
+            }
+            recent.iter().filter(|fell_back| **fell_back).count() as f64 / recent.len() as f64
+        };
+        let degraded = ratio > self.max_fallback_ratio;
+        if self.degraded.swap(degraded, Ordering::Relaxed) != degraded {
+            if degraded {
+                error!(
+                    "Metadata DB degraded: {:.0}% of the last {} lookups fell back to the RPC",
+                    ratio * 100.0,
+                    self.window
+                );
+            } else {
+                info!("Metadata DB recovered, lookups are served from the DB again");
+            }
+        }
+    }
+
+    fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+}
+
+/// Bounded retries of transient failures, the delay doubling after each attempt.
+struct RetryPolicy {
+    attempts: u32,
+    base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Runs `operation` until it succeeds, fails with an error `is_transient` rejects or
+    /// `attempts` run out, returning the last result.
+    async fn run<T, E, F, Fut>(
+        &self,
+        what: &str,
+        is_transient: impl Fn(&E) -> bool,
+        mut operation: F,
+    ) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(e) if attempt < self.attempts && is_transient(&e) => {
+                    let delay = self
+                        .base_delay
+                        .saturating_mul(2u32.saturating_pow(attempt - 1));
+                    debug!(
+                        "{} failed on attempt {}, retrying in {:?}: {}",
+                        what, attempt, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Caps the number of outbound metadata requests (RPC and HTTP) in flight at once,
+/// shared by every caller of the `MetadataCache`.
+pub struct FetchLimiter {
+    semaphore: Semaphore,
+}
+
+impl FetchLimiter {
+    pub fn new(max_concurrent_fetches: usize) -> Self {
+        FetchLimiter {
+            semaphore: Semaphore::new(max_concurrent_fetches.max(1)),
+        }
+    }
+
+    pub async fn run<F: Future>(&self, fetch: F) -> F::Output {
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("Fetch limiter semaphore is never closed");
+        fetch.await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use base64::{engine::general_purpose, Engine as _};
+    use std::collections::HashMap;
+
+    use crate::metadata_repository::InMemoryMetadataStore;
+    use crate::token_list::TokenListEntry;
+
+    use super::*;
+
+    fn metadata_entity(mint_address: &str) -> MetadataEntity {
+        MetadataEntity {
+            mint_address: mint_address.to_string(),
+            name: Some("Token".to_string()),
+            symbol: Some("TKN".to_string()),
+            uri: None,
+            image: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_count_cache_hits_and_misses() {
+        let known_mint = Pubkey::new_unique().to_string();
+        let unknown_mint = Pubkey::new_unique().to_string();
+        let metrics = Arc::new(Metrics::new());
+        let metadata_cache = MetadataCache::init(
+            InMemoryMetadataStore::with_entities(vec![metadata_entity(&known_mint)]),
+            Arc::new(RpcClient::new_mock("fails".to_string())),
+            TokenList::default(),
+            &MetadataConfig::default(),
+            Arc::clone(&metrics),
+        )
+        .unwrap();
+
+        let hit = metadata_cache.get_token_metadata(&known_mint).await;
+        assert!(hit.is_ok());
+        assert_eq!(metrics.metadata_cache_hits.get(), 1);
+        assert_eq!(metrics.metadata_cache_misses.get(), 0);
+        assert_eq!(metrics.metadata_resolved_from_db.get(), 1);
+
+        let miss = metadata_cache.get_token_metadata(&unknown_mint).await;
+        assert!(miss.is_err());
+        assert_eq!(metrics.metadata_cache_hits.get(), 1);
+        assert_eq!(metrics.metadata_cache_misses.get(), 1);
+        assert_eq!(metrics.metadata_resolved_from_rpc.get(), 0);
+    }
+
+    /// Knows which mints are stored but can't read them, like a DB that's failing or timing out.
+    struct UnreadableMetadataStore {
+        mint_addresses: Vec<String>,
+    }
+
+    impl MetadataStore for UnreadableMetadataStore {
+        fn insert_metadata(&self, _: &MetadataEntity) -> Result<(), Box<dyn std::error::Error>> {
+            Err("connection refused".into())
+        }
+
+        fn get_metadata(&self, _: &str) -> Result<MetadataEntity, Box<dyn std::error::Error>> {
+            Err("connection refused".into())
+        }
+
+        fn get_all_saved_mint_addresses(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+            Ok(self.mint_addresses.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_db_lookups_should_mark_db_degraded() {
+        let known_mint = WRAPPED_SOL_MINT.to_string();
+        let metrics = Arc::new(Metrics::new());
+        let metadata_cache = MetadataCache::init(
+            UnreadableMetadataStore {
+                mint_addresses: vec![known_mint.clone()],
+            },
+            Arc::new(RpcClient::new_mock("fails".to_string())),
+            TokenList::default(),
+            &MetadataConfig {
+                db_fallback_window: 4,
+                max_db_fallback_ratio: 0.5,
+                ..MetadataConfig::default()
+            },
+            Arc::clone(&metrics),
+        )
+        .unwrap();
+
+        for _ in 0..3 {
+            assert!(metadata_cache.get_token_metadata(&known_mint).await.is_ok());
+        }
+        // The window isn't full yet
+        assert!(!metadata_cache.is_db_degraded());
+
+        assert!(metadata_cache.get_token_metadata(&known_mint).await.is_ok());
+        assert!(metadata_cache.is_db_degraded());
+        assert_eq!(metrics.metadata_db_fallbacks.get(), 4);
+        assert_eq!(metrics.metadata_resolved_from_db.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn should_fall_back_to_token_list_without_onchain_metadata() {
+        let registry_mint = Pubkey::new_unique().to_string();
+        let unknown_mint = Pubkey::new_unique().to_string();
+        let metrics = Arc::new(Metrics::new());
+        let metadata_cache = MetadataCache::init(
+            InMemoryMetadataStore::with_entities(vec![]),
+            Arc::new(RpcClient::new_mock("fails".to_string())),
+            TokenList::from_entries(vec![TokenListEntry {
+                address: registry_mint.clone(),
+                name: "Registry Token".to_string(),
+                symbol: "REG".to_string(),
+                logo_uri: None,
+            }]),
+            &MetadataConfig::default(),
+            Arc::clone(&metrics),
+        )
+        .unwrap();
+
+        let metadata = metadata_cache
+            .get_token_metadata(&registry_mint)
+            .await
+            .unwrap();
+        assert_eq!(metadata.name.as_deref(), Some("Registry Token"));
+        assert_eq!(metadata.symbol.as_deref(), Some("REG"));
+        assert_eq!(metrics.metadata_resolved_from_token_list.get(), 1);
+
+        assert!(metadata_cache
+            .get_token_metadata(&unknown_mint)
+            .await
+            .is_err());
+        assert!(metadata_cache
+            .metadata_repository
+            .get_all_saved_mint_addresses()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_label_wrapped_sol_without_onchain_metadata() {
+        let metadata_cache = MetadataCache::init(
+            InMemoryMetadataStore::with_entities(vec![]),
+            Arc::new(RpcClient::new_mock("fails".to_string())),
+            TokenList::default(),
+            &MetadataConfig::default(),
+            Arc::new(Metrics::new()),
+        )
+        .unwrap();
+
+        let metadata = metadata_cache
+            .get_token_metadata(WRAPPED_SOL_MINT)
+            .await
+            .unwrap();
+        assert_eq!(metadata.symbol.as_deref(), Some("SOL"));
+        assert_eq!(metadata.name.as_deref(), Some("Wrapped SOL"));
+    }
+
+    fn metadata_account_mocks(mint: &Pubkey, name: &str, uri: &str) -> HashMap<RpcRequest, Value> {
+        let metadata_account = (
+            4u8,
+            Pubkey::new_unique().to_bytes(),
+            mint.to_bytes(),
+            format!("{:\0<32}", name),
+            "FRSH".to_string(),
+            uri.to_string(),
+            500u16,
+            [None::<u8>; 3],
+            (false, true),
+            [None::<u8>; 6],
+        );
+        account_data_mocks(&borsh::to_vec(&metadata_account).unwrap())
+    }
+
+    fn account_data_mocks(account_data: &[u8]) -> HashMap<RpcRequest, Value> {
+        let data = general_purpose::STANDARD.encode(account_data);
+        HashMap::from([(
+            RpcRequest::GetAccountInfo,
+            serde_json::json!({
+                "context": { "slot": 1 },
+                "value": {
+                    "data": [data, "base64"],
+                    "executable": false,
+                    "lamports": 1,
+                    "owner": TOKEN_METADATA_PROGRAM_ID.to_string(),
+                    "rentEpoch": 0,
+                }
+            }),
+        )])
+    }
+
+    #[tokio::test]
+    async fn fresh_metadata_should_bypass_and_not_populate_cache() {
+        let mint = Pubkey::new_unique();
+        let metadata_cache = MetadataCache::init(
+            InMemoryMetadataStore::with_entities(vec![]),
+            Arc::new(RpcClient::new_mock_with_mocks(
+                "succeeds".to_string(),
+                metadata_account_mocks(&mint, "Fresh Token", "https://example.com/fresh.json"),
+            )),
+            TokenList::default(),
+            &MetadataConfig::default(),
+            Arc::new(Metrics::new()),
+        )
+        .unwrap();
+
+        let metadata = metadata_cache
+            .fetch_fresh_metadata(&mint.to_string())
+            .await
+            .unwrap();
+        assert_eq!(metadata.mint, mint);
+        assert_eq!(metadata.name.trim_end_matches(char::from(0)), "Fresh Token");
+        assert_eq!(metadata.seller_fee_basis_points, 500);
+        assert!(!metadata_cache.is_known(&mint.to_string()).await);
+        assert!(metadata_cache
+            .metadata_repository
+            .get_all_saved_mint_addresses()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn transient_rpc_failures_should_be_retried() {
+        use axum::{http::StatusCode, response::IntoResponse, routing::post, Json, Router};
+        use std::future::IntoFuture;
+
+        let mint = Pubkey::new_unique();
+        let existing = MetadataCache::derive_metadata_account(&mint).to_string();
+        let account_info = account_data_mocks(
+            &borsh::to_vec(&(
+                4u8,
+                Pubkey::new_unique().to_bytes(),
+                mint.to_bytes(),
+                "Retried Token".to_string(),
+                "RTRY".to_string(),
+                String::new(),
+                0u16,
+                [None::<u8>; 3],
+                (false, true),
+                [None::<u8>; 6],
+            ))
+            .unwrap(),
+        )
+        .remove(&RpcRequest::GetAccountInfo)
+        .unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        // Fails the first two requests, then serves the metadata account of `mint` only
+        let rpc = Router::new().route(
+            "/",
+            post(move |Json(request): Json<Value>| async move {
+                if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                    return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                }
+                let result = if request["params"][0] == existing.as_str() {
+                    account_info
+                } else {
+                    serde_json::json!({ "context": { "slot": 1 }, "value": null })
+                };
+                Json(serde_json::json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+                    .into_response()
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(axum::serve(listener, rpc).into_future());
+        let metadata_cache = MetadataCache::init(
+            InMemoryMetadataStore::with_entities(vec![]),
+            Arc::new(RpcClient::new(rpc_url)),
+            TokenList::default(),
+            &MetadataConfig {
+                retry_base_delay_ms: 1,
+                ..MetadataConfig::default()
+            },
+            Arc::new(Metrics::new()),
+        )
+        .unwrap();
+
+        let metadata = metadata_cache
+            .get_token_metadata(&mint.to_string())
+            .await
+            .unwrap();
+        assert_eq!(metadata.name.as_deref(), Some("Retried Token"));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // A missing account isn't worth another attempt
+        assert!(metadata_cache
+            .get_token_metadata(&Pubkey::new_unique().to_string())
+            .await
+            .is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn undecodable_metadata_should_degrade_to_partial_metadata() {
+        let mint = Pubkey::new_unique();
+        // Leading fields only, the account ends where `Metadata` expects seller_fee_basis_points
+        let truncated = borsh::to_vec(&(
+            4u8,
+            Pubkey::new_unique().to_bytes(),
+            mint.to_bytes(),
+            format!("{:\0<32}", "Old Layout"),
+            "OLD".to_string(),
+            String::new(),
+        ))
+        .unwrap();
+        let cache_for = |account_data: &[u8]| {
+            MetadataCache::init(
+                InMemoryMetadataStore::with_entities(vec![]),
+                Arc::new(RpcClient::new_mock_with_mocks(
+                    "succeeds".to_string(),
+                    account_data_mocks(account_data),
+                )),
+                TokenList::default(),
+                &MetadataConfig::default(),
+                Arc::new(Metrics::new()),
+            )
+            .unwrap()
+        };
+
+        let metadata_cache = cache_for(&truncated);
+        let metadata = metadata_cache
+            .get_token_metadata(&mint.to_string())
+            .await
+            .unwrap();
+        assert_eq!(metadata.name.as_deref(), Some("Old Layout"));
+        assert_eq!(metadata.symbol.as_deref(), Some("OLD"));
+        assert!(metadata_cache
+            .fetch_fresh_metadata(&mint.to_string())
+            .await
+            .is_err());
+
+        let metadata_cache = cache_for(&[4, 1, 2]);
+        let metadata = metadata_cache
+            .get_token_metadata(&mint.to_string())
+            .await
+            .unwrap();
+        assert_eq!(metadata.mint_address, mint.to_string());
+        assert_eq!(metadata.name, None);
+        assert_eq!(metadata.symbol, None);
+        assert!(!metadata_cache.is_known(&mint.to_string()).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn fetch_limiter_should_never_exceed_bound() {
+        let max_concurrent = 3;
+        let limiter = Arc::new(FetchLimiter::new(max_concurrent));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_observed = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..50)
+            .map(|_| {
+                let limiter = Arc::clone(&limiter);
+                let in_flight = Arc::clone(&in_flight);
+                let max_observed = Arc::clone(&max_observed);
+                tokio::spawn(async move {
+                    limiter
+                        .run(async {
+                            let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                            max_observed.fetch_max(current, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(5)).await;
+                            in_flight.fetch_sub(1, Ordering::SeqCst);
+                        })
+                        .await
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert!(max_observed.load(Ordering::SeqCst) <= max_concurrent);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn should_refuse_images_on_internal_addresses() {
+        use axum::{http::header, routing::get, Router};
+        use std::future::IntoFuture;
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let counter = Arc::clone(&requests);
+        let app = Router::new().route(
+            "/meta.json",
+            get(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                (
+                    [(header::CONTENT_TYPE, "application/json")],
+                    r#"{"image": "http://169.254.169.254/latest/meta-data/iam"}"#,
+                )
+            }),
+        );
+        tokio::spawn(axum::serve(listener, app).into_future());
+        let uri = format!("http://{}/meta.json", address);
+        let metadata_cache = |allowed_hosts: Vec<String>| {
+            MetadataCache::init(
+                InMemoryMetadataStore::with_entities(vec![]),
+                Arc::new(RpcClient::new_mock("fails".to_string())),
+                TokenList::default(),
+                &MetadataConfig {
+                    allowed_hosts,
+                    ..MetadataConfig::default()
+                },
+                Arc::new(Metrics::new()),
+            )
+            .unwrap()
+        };
+
+        // The metadata itself sits on loopback, only fetched once allowed
+        assert_eq!(
+            metadata_cache(vec![])
+                .follow_uri_to_get_image(&uri, &ImageBudget::unlimited())
+                .await,
+            None
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+        assert_eq!(
+            metadata_cache(vec!["127.0.0.1".to_string()])
+                .follow_uri_to_get_image(&uri, &ImageBudget::unlimited())
+                .await,
+            None
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn should_fall_back_to_next_ipfs_gateway() {
+        use axum::{
+            http::{header, StatusCode},
+            routing::get,
+            Router,
+        };
+        use std::future::IntoFuture;
+
+        async fn serve(app: Router) -> String {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            tokio::spawn(axum::serve(listener, app).into_future());
+            format!("http://{}", address)
+        }
+        let failing_gateway =
+            serve(Router::new().fallback(|| async { StatusCode::BAD_GATEWAY })).await;
+        let working_gateway = serve(
+            Router::new()
+                .route(
+                    "/ipfs/meta/1.json",
+                    get(|| async {
+                        (
+                            [(header::CONTENT_TYPE, "application/json")],
+                            r#"{"image": "ipfs://image/1.png"}"#,
+                        )
+                    }),
+                )
+                .route("/ipfs/image/1.png", get(|| async { vec![1u8, 2, 3] })),
+        )
+        .await;
+        let metadata_cache = MetadataCache::init(
+            InMemoryMetadataStore::with_entities(vec![]),
+            Arc::new(RpcClient::new_mock("fails".to_string())),
+            TokenList::default(),
+            &MetadataConfig {
+                ipfs_gateways: vec![
+                    format!("{}/ipfs", failing_gateway),
+                    format!("{}/ipfs", working_gateway),
+                ],
+                allowed_hosts: vec!["127.0.0.1".to_string()],
+                ..MetadataConfig::default()
+            },
+            Arc::new(Metrics::new()),
+        )
+        .unwrap();
+
+        for uri in [
+            "ipfs://meta/1.json".to_string(),
+            format!("{}/ipfs/meta/1.json", failing_gateway),
+        ] {
+            assert_eq!(
+                metadata_cache
+                    .follow_uri_to_get_image(&uri, &ImageBudget::unlimited())
+                    .await,
+                Some(vec![1, 2, 3])
+            );
+        }
+        assert_eq!(
+            metadata_cache
+                .follow_uri_to_get_image(
+                    &format!("{}/meta/1.json", failing_gateway),
+                    &ImageBudget::unlimited()
+                )
+                .await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn images_past_the_budget_should_be_skipped_and_not_stored() {
+        use axum::{http::header, routing::get, Router};
+        use std::future::IntoFuture;
+
+        let mut png = Cursor::new(Vec::new());
+        image::DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let meta_json = format!(r#"{{"image": "http://{}/image.png"}}"#, address);
+        let served_json = meta_json.clone();
+        let served_png = png.clone();
+        let app =
+            Router::new()
+                .route(
+                    "/meta.json",
+                    get(move || async move {
+                        ([(header::CONTENT_TYPE, "application/json")], served_json)
+                    }),
+                )
+                .route("/image.png", get(move || async move { served_png }));
+        tokio::spawn(axum::serve(listener, app).into_future());
+        let metrics = Arc::new(Metrics::new());
+        // The mock RPC answers each request once, so every lookup gets its own cache
+        let metadata_cache = || {
+            MetadataCache::init(
+                InMemoryMetadataStore::with_entities(vec![]),
+                Arc::new(RpcClient::new_mock_with_mocks(
+                    "succeeds".to_string(),
+                    metadata_account_mocks(
+                        &Pubkey::new_unique(),
+                        "Budget Token",
+                        &format!("http://{}/meta.json", address),
+                    ),
+                )),
+                TokenList::default(),
+                &MetadataConfig {
+                    allowed_hosts: vec!["127.0.0.1".to_string()],
+                    ..MetadataConfig::default()
+                },
+                Arc::clone(&metrics),
+            )
+            .unwrap()
+        };
+
+        // Room for one metadata json and image only
+        let budget = ImageBudget::new((meta_json.len() + png.len() + 1) as u64);
+        let mut results = vec![];
+        for _ in 0..3 {
+            let mint = Pubkey::new_unique().to_string();
+            let metadata_cache = metadata_cache();
+            let metadata = metadata_cache
+                .get_token_metadata_within(&mint, &budget)
+                .await
+                .unwrap();
+            assert_eq!(metadata.name.as_deref(), Some("Budget Token"));
+            results.push((
+                metadata.image.is_some(),
+                metadata_cache.is_known(&mint).await,
+            ));
+        }
+
+        assert_eq!(results, vec![(true, true), (false, false), (false, false)]);
+        assert!(budget.is_exhausted());
+        assert_eq!(metrics.images_processed.get(), 1);
+        assert!(metrics.metadata_bytes_downloaded.get() >= (meta_json.len() + png.len()) as u64);
+    }
+}
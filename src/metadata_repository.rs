@@ -1,22 +1,37 @@
-
 use std::sync::Arc;
+#[cfg(test)]
+use std::{collections::HashMap, sync::Mutex};
 
-use diesel::prelude::*;
-use serde::{Deserialize, Serialize};
 use crate::db::PostgreSqlClient;
 use crate::schema::metadata;
 use crate::schema::metadata::dsl::metadata as metadata_table;
 use crate::schema::metadata::dsl::mint_address;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
 pub struct MetadataRepository {
     db: Arc<PostgreSqlClient>,
 }
 
+pub trait MetadataStore: Send + Sync {
+    fn insert_metadata(
+        &self,
+        metadata_entity: &MetadataEntity,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    fn get_metadata(&self, mint_addr: &str) -> Result<MetadataEntity, Box<dyn std::error::Error>>;
+    fn get_all_saved_mint_addresses(&self) -> Result<Vec<String>, Box<dyn std::error::Error>>;
+}
+
 impl MetadataRepository {
     pub fn new(db_client: Arc<PostgreSqlClient>) -> Self {
         MetadataRepository { db: db_client }
     }
+}
 
-    pub fn insert_metadata(&self, metadata_entity: &MetadataEntity) -> Result<(), Box<dyn std::error::Error>> {
+impl MetadataStore for MetadataRepository {
+    fn insert_metadata(
+        &self,
+        metadata_entity: &MetadataEntity,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         let mut conn = self.db.get_db_connection()?;
         diesel::insert_into(metadata_table)
             .values(metadata_entity)
@@ -24,23 +39,22 @@ impl MetadataRepository {
         Ok(())
     }
 
-    pub fn get_metadata(&self, mint_addr: &str) -> Result<MetadataEntity, Box<dyn std::error::Error>> {
-        let mut conn = self.db.get_db_connection()?;
+    fn get_metadata(&self, mint_addr: &str) -> Result<MetadataEntity, Box<dyn std::error::Error>> {
+        let mut conn = self.db.get_read_db_connection()?;
         Ok(metadata_table
-                    .filter(mint_address.eq(mint_addr))
-                    .first::<MetadataEntity>(&mut conn)?)
+            .filter(mint_address.eq(mint_addr))
+            .first::<MetadataEntity>(&mut conn)?)
     }
 
-    pub fn get_all_saved_mint_addresses(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-        let mut conn = self.db.get_db_connection()?;
+    fn get_all_saved_mint_addresses(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut conn = self.db.get_read_db_connection()?;
         Ok(metadata_table
             .select(mint_address)
             .load::<String>(&mut conn)?)
-
     }
 }
 
-#[derive(Debug, Queryable, Insertable, Serialize, Deserialize)]
+#[derive(Debug, Clone, Queryable, Insertable, Serialize, Deserialize)]
 #[diesel(table_name = metadata)]
 pub struct MetadataEntity {
     pub mint_address: String,
@@ -49,3 +63,91 @@ pub struct MetadataEntity {
     pub uri: Option<String>,
     pub image: Option<Vec<u8>>,
 }
+
+#[cfg(all(test, feature = "db-tests"))]
+mod db_tests {
+    use super::*;
+
+    fn metadata_entity(mint: &str) -> MetadataEntity {
+        MetadataEntity {
+            mint_address: mint.to_string(),
+            name: Some("Token".to_string()),
+            symbol: Some("TKN".to_string()),
+            uri: Some("https://example.com/token.json".to_string()),
+            image: Some(vec![1, 2, 3]),
+        }
+    }
+
+    #[test]
+    fn should_insert_get_and_list_metadata() {
+        let repository = MetadataRepository::new(Arc::new(PostgreSqlClient::init_test()));
+        let mint = uuid::Uuid::new_v4().to_string();
+
+        repository.insert_metadata(&metadata_entity(&mint)).unwrap();
+
+        let metadata = repository.get_metadata(&mint).unwrap();
+        assert_eq!(metadata.symbol.as_deref(), Some("TKN"));
+        assert_eq!(metadata.image, Some(vec![1, 2, 3]));
+        assert!(repository
+            .get_all_saved_mint_addresses()
+            .unwrap()
+            .contains(&mint));
+        assert!(repository.get_metadata("unknown mint").is_err());
+    }
+
+    #[test]
+    fn should_reject_duplicate_metadata() {
+        let repository = MetadataRepository::new(Arc::new(PostgreSqlClient::init_test()));
+        let mint = uuid::Uuid::new_v4().to_string();
+
+        repository.insert_metadata(&metadata_entity(&mint)).unwrap();
+        assert!(repository.insert_metadata(&metadata_entity(&mint)).is_err());
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct InMemoryMetadataStore {
+    pub entities: Mutex<HashMap<String, MetadataEntity>>,
+}
+
+#[cfg(test)]
+impl InMemoryMetadataStore {
+    pub fn with_entities(entities: Vec<MetadataEntity>) -> Self {
+        InMemoryMetadataStore {
+            entities: Mutex::new(
+                entities
+                    .into_iter()
+                    .map(|e| (e.mint_address.clone(), e))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+impl MetadataStore for InMemoryMetadataStore {
+    fn insert_metadata(
+        &self,
+        metadata_entity: &MetadataEntity,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.entities.lock().unwrap().insert(
+            metadata_entity.mint_address.clone(),
+            metadata_entity.clone(),
+        );
+        Ok(())
+    }
+
+    fn get_metadata(&self, mint_addr: &str) -> Result<MetadataEntity, Box<dyn std::error::Error>> {
+        self.entities
+            .lock()
+            .unwrap()
+            .get(mint_addr)
+            .cloned()
+            .ok_or_else(|| format!("Metadata for {} not found", mint_addr).into())
+    }
+
+    fn get_all_saved_mint_addresses(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(self.entities.lock().unwrap().keys().cloned().collect())
+    }
+}
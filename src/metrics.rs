@@ -0,0 +1,202 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+const FETCH_TOKENS_BUCKETS_SECONDS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub struct Metrics {
+    pub fetch_tokens_duration: Histogram,
+    pub metadata_cache_hits: Counter,
+    pub metadata_cache_misses: Counter,
+    pub metadata_resolved_from_db: Counter,
+    pub metadata_db_fallbacks: Counter,
+    pub metadata_resolved_from_rpc: Counter,
+    pub metadata_resolved_from_token_list: Counter,
+    pub token_accounts_unparsed: Counter,
+    pub metadata_bytes_downloaded: Counter,
+    pub images_processed: Counter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            fetch_tokens_duration: Histogram::new(FETCH_TOKENS_BUCKETS_SECONDS),
+            metadata_cache_hits: Counter::default(),
+            metadata_cache_misses: Counter::default(),
+            metadata_resolved_from_db: Counter::default(),
+            metadata_db_fallbacks: Counter::default(),
+            metadata_resolved_from_rpc: Counter::default(),
+            metadata_resolved_from_token_list: Counter::default(),
+            token_accounts_unparsed: Counter::default(),
+            metadata_bytes_downloaded: Counter::default(),
+            images_processed: Counter::default(),
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.fetch_tokens_duration.render(
+            &mut out,
+            "fetch_tokens_duration_seconds",
+            "Time spent fetching the token accounts of a wallet",
+        );
+        self.metadata_cache_hits.render(
+            &mut out,
+            "metadata_cache_hits_total",
+            "Metadata lookups for mints already known to the cache",
+        );
+        self.metadata_cache_misses.render(
+            &mut out,
+            "metadata_cache_misses_total",
+            "Metadata lookups for mints unknown to the cache",
+        );
+        self.metadata_resolved_from_db.render(
+            &mut out,
+            "metadata_resolved_from_db_total",
+            "Metadata lookups resolved from the database",
+        );
+        self.metadata_db_fallbacks.render(
+            &mut out,
+            "metadata_db_fallbacks_total",
+            "Lookups of stored metadata that failed in the database and fell back to the RPC",
+        );
+        self.metadata_resolved_from_rpc.render(
+            &mut out,
+            "metadata_resolved_from_rpc_total",
+            "Metadata lookups resolved from the RPC",
+        );
+        self.metadata_resolved_from_token_list.render(
+            &mut out,
+            "metadata_resolved_from_token_list_total",
+            "Metadata lookups resolved from the token list",
+        );
+        self.token_accounts_unparsed.render(
+            &mut out,
+            "token_accounts_unparsed_total",
+            "Token accounts the RPC returned without jsonParsed data",
+        );
+        self.metadata_bytes_downloaded.render(
+            &mut out,
+            "metadata_bytes_downloaded_total",
+            "Bytes of metadata json and images downloaded following metadata uris",
+        );
+        self.images_processed.render(
+            &mut out,
+            "images_processed_total",
+            "Token images decoded and resized",
+        );
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+#[derive(Default)]
+pub struct Counter {
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, self.get());
+    }
+}
+
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count());
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{}_count {}", name, self.count());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_should_count_observations_into_cumulative_buckets() {
+        let metrics = Metrics::new();
+        metrics
+            .fetch_tokens_duration
+            .observe(Duration::from_millis(80));
+        metrics
+            .fetch_tokens_duration
+            .observe(Duration::from_secs(3));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("fetch_tokens_duration_seconds_bucket{le=\"0.05\"} 0"));
+        assert!(rendered.contains("fetch_tokens_duration_seconds_bucket{le=\"0.1\"} 1"));
+        assert!(rendered.contains("fetch_tokens_duration_seconds_bucket{le=\"5\"} 2"));
+        assert!(rendered.contains("fetch_tokens_duration_seconds_count 2"));
+    }
+}
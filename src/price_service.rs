@@ -0,0 +1,469 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use futures::future::BoxFuture;
+use log::info;
+use rust_decimal::Decimal;
+use serde_json::Value;
+use tokio::sync::watch;
+
+use crate::config::PricesConfig;
+
+/// Upstream USD price API.
+pub trait PriceSource: Send + Sync {
+    /// Prices of the given mints, mints without a price are left out.
+    fn fetch_prices<'a>(
+        &'a self,
+        mints: &'a [String],
+    ) -> BoxFuture<'a, anyhow::Result<HashMap<String, Decimal>>>;
+}
+
+/// Jupiter price API v2 (`GET <url>?ids=<mint>,<mint>`).
+pub struct JupiterPriceSource {
+    http_client: reqwest::Client,
+    url: String,
+}
+
+impl JupiterPriceSource {
+    pub fn new(url: String) -> Self {
+        JupiterPriceSource {
+            http_client: reqwest::Client::new(),
+            url,
+        }
+    }
+}
+
+impl PriceSource for JupiterPriceSource {
+    fn fetch_prices<'a>(
+        &'a self,
+        mints: &'a [String],
+    ) -> BoxFuture<'a, anyhow::Result<HashMap<String, Decimal>>> {
+        Box::pin(async move {
+            let body = self
+                .http_client
+                .get(&self.url)
+                .query(&[("ids", mints.join(","))])
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            let response: Value = serde_json::from_str(&body)?;
+            let Some(data) = response["data"].as_object() else {
+                return Ok(HashMap::new());
+            };
+            Ok(data
+                .iter()
+                .filter_map(|(mint, entry)| {
+                    let price = Decimal::from_str(entry["price"].as_str()?).ok()?;
+                    Some((mint.clone(), price))
+                })
+                .collect())
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum PriceError {
+    BatchTooLarge { max_batch_size: usize },
+    RateLimited { retry_after: Duration },
+    Upstream(anyhow::Error),
+}
+
+impl fmt::Display for PriceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PriceError::BatchTooLarge { max_batch_size } => {
+                write!(f, "At most {} mints can be priced at once", max_batch_size)
+            }
+            PriceError::RateLimited { retry_after } => write!(
+                f,
+                "Too many price requests, retry in {} seconds",
+                retry_after.as_secs().max(1)
+            ),
+            PriceError::Upstream(e) => write!(f, "Price source unavailable: {}", e),
+        }
+    }
+}
+
+/// Outcome of fetching a mint's price, errors are kept as their message since every request
+/// waiting on the fetch reports them.
+type FetchOutcome = Result<Option<Decimal>, String>;
+
+/// Price of a mint being fetched, `None` until the fetch finished.
+type PendingPrice = watch::Receiver<Option<FetchOutcome>>;
+
+/// How long a client's request count is kept before it starts over.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Client request counts are pruned of finished windows once this many clients are tracked.
+const RATE_LIMIT_PRUNE_THRESHOLD: usize = 1024;
+
+/// USD prices served from a short lived cache, so clients valuing the same baskets cause at most
+/// one upstream request per mint and TTL. Mints the source has no price for are cached as well.
+/// Requests for a mint another request is already fetching wait for that fetch instead of
+/// starting their own.
+pub struct PriceService {
+    source: Box<dyn PriceSource>,
+    cache: Mutex<HashMap<String, (Option<Decimal>, Instant)>>,
+    in_flight: Mutex<HashMap<String, PendingPrice>>,
+    ttl: Duration,
+    max_batch_size: usize,
+    /// Start of the current window and the requests made in it, per client address.
+    client_requests: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+    requests_per_minute: u32,
+}
+
+/// Takes the mints a request fetches out of `in_flight` when the fetch finishes or the request
+/// is dropped half way, so later requests don't wait on a fetch nobody drives.
+struct InFlightGuard<'a> {
+    in_flight: &'a Mutex<HashMap<String, PendingPrice>>,
+    mints: &'a [String],
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        for mint in self.mints {
+            in_flight.remove(mint);
+        }
+    }
+}
+
+impl PriceService {
+    pub fn new(source: impl PriceSource + 'static, config: &PricesConfig) -> Self {
+        PriceService {
+            source: Box::new(source),
+            cache: Mutex::default(),
+            in_flight: Mutex::default(),
+            ttl: Duration::from_secs(config.ttl_secs),
+            max_batch_size: config.max_batch_size,
+            client_requests: Mutex::default(),
+            requests_per_minute: config.requests_per_minute,
+        }
+    }
+
+    /// Counts a price request of `client`, failing once it made `requests_per_minute` of them in
+    /// the current minute.
+    pub fn admit(&self, client: IpAddr) -> Result<(), PriceError> {
+        if self.requests_per_minute == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut client_requests = self.client_requests.lock().unwrap();
+        if client_requests.len() >= RATE_LIMIT_PRUNE_THRESHOLD {
+            client_requests.retain(|_, (window_start, _)| {
+                now.duration_since(*window_start) < RATE_LIMIT_WINDOW
+            });
+        }
+        let (window_start, requests) = client_requests.entry(client).or_insert((now, 0));
+        if now.duration_since(*window_start) >= RATE_LIMIT_WINDOW {
+            *window_start = now;
+            *requests = 0;
+        }
+        if *requests >= self.requests_per_minute {
+            return Err(PriceError::RateLimited {
+                retry_after: RATE_LIMIT_WINDOW - now.duration_since(*window_start),
+            });
+        }
+        *requests += 1;
+        Ok(())
+    }
+
+    pub async fn get_prices(
+        &self,
+        mints: &[String],
+    ) -> Result<HashMap<String, Decimal>, PriceError> {
+        let unique_mints: HashSet<&String> = mints.iter().collect();
+        if unique_mints.len() > self.max_batch_size {
+            return Err(PriceError::BatchTooLarge {
+                max_batch_size: self.max_batch_size,
+            });
+        }
+
+        let mut prices = HashMap::new();
+        let mut missing = vec![];
+        {
+            let cache = self.cache.lock().unwrap();
+            for mint in unique_mints {
+                match cache.get(mint) {
+                    Some((price, fetched_at)) if fetched_at.elapsed() < self.ttl => {
+                        if let Some(price) = price {
+                            prices.insert(mint.clone(), *price);
+                        }
+                    }
+                    _ => missing.push(mint.clone()),
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(prices);
+        }
+
+        let mut to_fetch = vec![];
+        let mut senders = vec![];
+        let mut waiting = vec![];
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            for mint in missing {
+                match in_flight.get(&mint) {
+                    Some(pending) => waiting.push((mint, pending.clone())),
+                    None => {
+                        let (sender, pending) = watch::channel(None);
+                        in_flight.insert(mint.clone(), pending);
+                        senders.push(sender);
+                        to_fetch.push(mint);
+                    }
+                }
+            }
+        }
+        let (fetched, waited) =
+            tokio::join!(self.fetch(&to_fetch, senders), Self::wait_for(waiting));
+        let (waited, abandoned) = waited?;
+        let fetched = fetched?;
+        // Mints whose fetch was dropped half way by its request are fetched here instead
+        let refetched = if abandoned.is_empty() {
+            HashMap::new()
+        } else {
+            self.fetch(&abandoned, vec![]).await?
+        };
+        prices.extend(
+            fetched
+                .into_iter()
+                .chain(waited)
+                .chain(refetched)
+                .filter_map(|(mint, price)| Some((mint, price?))),
+        );
+        Ok(prices)
+    }
+
+    /// Fetches `mints` from the source and caches them, telling the requests waiting on them
+    /// through `senders`, which are in the order of `mints`.
+    async fn fetch(
+        &self,
+        mints: &[String],
+        senders: Vec<watch::Sender<Option<FetchOutcome>>>,
+    ) -> Result<HashMap<String, Option<Decimal>>, PriceError> {
+        if mints.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let _guard = (!senders.is_empty()).then_some(InFlightGuard {
+            in_flight: &self.in_flight,
+            mints,
+        });
+        info!("Fetching prices of {} mints", mints.len());
+        let fetched = match self.source.fetch_prices(mints).await {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                for sender in senders {
+                    sender.send_replace(Some(Err(e.to_string())));
+                }
+                return Err(PriceError::Upstream(e));
+            }
+        };
+        let now = Instant::now();
+        let mut prices = HashMap::new();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|_, (_, fetched_at)| fetched_at.elapsed() < self.ttl);
+            for mint in mints {
+                let price = fetched.get(mint).copied();
+                cache.insert(mint.clone(), (price, now));
+                prices.insert(mint.clone(), price);
+            }
+        }
+        for (mint, sender) in mints.iter().zip(senders) {
+            sender.send_replace(Some(Ok(prices[mint])));
+        }
+        Ok(prices)
+    }
+
+    /// Prices of the mints other requests are fetching, along with the mints whose fetch was
+    /// dropped before it finished.
+    async fn wait_for(
+        waiting: Vec<(String, PendingPrice)>,
+    ) -> Result<(HashMap<String, Option<Decimal>>, Vec<String>), PriceError> {
+        let mut prices = HashMap::new();
+        let mut abandoned = vec![];
+        for (mint, mut pending) in waiting {
+            let outcome = match pending.wait_for(Option::is_some).await {
+                Ok(outcome) => outcome.clone(),
+                Err(_) => None,
+            };
+            match outcome {
+                Some(Ok(price)) => {
+                    prices.insert(mint, price);
+                }
+                Some(Err(e)) => return Err(PriceError::Upstream(anyhow!(e))),
+                None => abandoned.push(mint),
+            }
+        }
+        Ok((prices, abandoned))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    struct CountingPriceSource {
+        requested: Arc<Mutex<Vec<Vec<String>>>>,
+        delay: Duration,
+    }
+
+    impl PriceSource for CountingPriceSource {
+        fn fetch_prices<'a>(
+            &'a self,
+            mints: &'a [String],
+        ) -> BoxFuture<'a, anyhow::Result<HashMap<String, Decimal>>> {
+            let mut sorted = mints.to_vec();
+            sorted.sort();
+            self.requested.lock().unwrap().push(sorted);
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                Ok(mints
+                    .iter()
+                    .filter(|mint| mint.as_str() != "Unpriced")
+                    .map(|mint| (mint.clone(), dec!(1.5)))
+                    .collect())
+            })
+        }
+    }
+
+    fn price_service(max_batch_size: usize) -> (PriceService, Arc<Mutex<Vec<Vec<String>>>>) {
+        slow_price_service(max_batch_size, Duration::ZERO)
+    }
+
+    fn slow_price_service(
+        max_batch_size: usize,
+        delay: Duration,
+    ) -> (PriceService, Arc<Mutex<Vec<Vec<String>>>>) {
+        let requested = Arc::new(Mutex::new(vec![]));
+        let service = PriceService::new(
+            CountingPriceSource {
+                requested: Arc::clone(&requested),
+                delay,
+            },
+            &PricesConfig {
+                max_batch_size,
+                requests_per_minute: 2,
+                ..PricesConfig::default()
+            },
+        );
+        (service, requested)
+    }
+
+    #[tokio::test]
+    async fn should_serve_repeated_mints_from_cache() {
+        let (service, requested) = price_service(10);
+        let mints = vec![
+            "MintA".to_string(),
+            "MintA".to_string(),
+            "Unpriced".to_string(),
+        ];
+
+        let prices = service.get_prices(&mints).await.unwrap();
+        assert_eq!(prices, HashMap::from([("MintA".to_string(), dec!(1.5))]));
+
+        let prices = service
+            .get_prices(&[
+                "MintA".to_string(),
+                "MintB".to_string(),
+                "Unpriced".to_string(),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(prices.len(), 2);
+        assert_eq!(
+            *requested.lock().unwrap(),
+            vec![
+                vec!["MintA".to_string(), "Unpriced".to_string()],
+                vec!["MintB".to_string()]
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_batches_over_limit() {
+        let (service, requested) = price_service(2);
+
+        let duplicates = vec![
+            "MintA".to_string(),
+            "MintA".to_string(),
+            "MintB".to_string(),
+        ];
+        assert!(service.get_prices(&duplicates).await.is_ok());
+
+        let too_many = vec![
+            "MintA".to_string(),
+            "MintB".to_string(),
+            "MintC".to_string(),
+        ];
+        assert!(matches!(
+            service.get_prices(&too_many).await,
+            Err(PriceError::BatchTooLarge { max_batch_size: 2 })
+        ));
+        assert_eq!(requested.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_requests_should_share_in_flight_fetches() {
+        let (service, requested) = slow_price_service(10, Duration::from_millis(100));
+        let first = ["MintA".to_string(), "MintB".to_string()];
+        let second = ["MintB".to_string(), "MintC".to_string()];
+
+        let (first, second) = tokio::join!(service.get_prices(&first), service.get_prices(&second));
+
+        assert_eq!(first.unwrap().len(), 2);
+        assert_eq!(second.unwrap().len(), 2);
+        assert_eq!(
+            *requested.lock().unwrap(),
+            vec![
+                vec!["MintA".to_string(), "MintB".to_string()],
+                vec!["MintC".to_string()]
+            ]
+        );
+        assert!(service.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fetch_dropped_half_way_should_be_taken_over() {
+        let (service, requested) = slow_price_service(10, Duration::from_millis(100));
+        let mints = ["MintA".to_string()];
+
+        let abandoned = tokio::time::timeout(Duration::from_millis(10), service.get_prices(&mints));
+        let (abandoned, prices) = tokio::join!(abandoned, async {
+            tokio::task::yield_now().await;
+            service.get_prices(&mints).await
+        });
+
+        assert!(abandoned.is_err());
+        assert_eq!(prices.unwrap().len(), 1);
+        assert_eq!(requested.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn clients_should_be_limited_separately() {
+        let (service, _) = price_service(10);
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+
+        assert!(service.admit(client).is_ok());
+        assert!(service.admit(client).is_ok());
+        assert!(matches!(
+            service.admit(client),
+            Err(PriceError::RateLimited { retry_after }) if retry_after <= RATE_LIMIT_WINDOW
+        ));
+        assert!(service.admit("203.0.113.8".parse().unwrap()).is_ok());
+    }
+}
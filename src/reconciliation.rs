@@ -0,0 +1,204 @@
+use std::{collections::HashSet, error::Error, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    chain_context::ChainContext,
+    config::ReconciliationConfig,
+    trade_repository::{TradeStatus, TradeStore},
+    trade_session::{SessionId, SharedSessions},
+};
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ReconciliationReport {
+    /// Active trades that had no live session and were marked `Expired`.
+    pub expired_trades: Vec<Uuid>,
+    /// Live sessions without a trade row.
+    pub sessions_without_trade: Vec<SessionId>,
+}
+
+/// Compares the live sessions with the `TradeStatus::ACTIVE` trade rows: stale rows without a
+/// session are expired, sessions without a row are only reported since they may still be
+/// negotiated.
+pub fn reconcile<T: ChainContext>(
+    sessions: &SharedSessions<T>,
+    trade_store: &dyn TradeStore,
+    stale_after: Duration,
+    now: DateTime<Utc>,
+) -> Result<ReconciliationReport, Box<dyn Error>> {
+    let live_sessions: HashSet<SessionId> = sessions.session_ids().into_iter().collect();
+    let stale_before = now - chrono::Duration::from_std(stale_after)?;
+    let mut report = ReconciliationReport::default();
+
+    let mut active_trades = Vec::new();
+    for trade_status in &TradeStatus::ACTIVE {
+        active_trades.extend(trade_store.get_trades_by_status(trade_status)?);
+    }
+    for trade in active_trades {
+        let last_change = trade.updated_at.or(trade.created_at);
+        let is_stale = last_change.is_none_or(|at| at < stale_before);
+        if !live_sessions.contains(&trade.id) && is_stale {
+            trade_store.update_trade_status(&trade.id, &TradeStatus::Expired)?;
+            report.expired_trades.push(trade.id);
+        }
+    }
+
+    for session_id in live_sessions {
+        if trade_store.get_trade(&session_id)?.is_none() {
+            warn!("Session {} has no trade record", session_id);
+            report.sessions_without_trade.push(session_id);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Reconciles every `config.interval_secs`, at least every second, until `shutdown` is
+/// cancelled, a run in progress is finished first.
+pub fn spawn_reconciliation_task<T: ChainContext + Send + Sync + 'static>(
+    sessions: Arc<SharedSessions<T>>,
+    trade_store: Arc<dyn TradeStore>,
+    config: ReconciliationConfig,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+        while shutdown
+            .run_until_cancelled(interval.tick())
+            .await
+            .is_some()
+        {
+            let sessions = Arc::clone(&sessions);
+            let trade_store = Arc::clone(&trade_store);
+            let stale_after = Duration::from_secs(config.stale_after_secs);
+            let _ = tokio::task::spawn_blocking(move || {
+                match reconcile(&sessions, trade_store.as_ref(), stale_after, Utc::now()) {
+                    Ok(report) if !report.expired_trades.is_empty() => {
+                        info!("Expired {} abandoned trades", report.expired_trades.len())
+                    }
+                    Ok(_) => {}
+                    Err(e) => error!("Trade reconciliation failed: {}", e),
+                }
+            })
+            .await;
+        }
+        info!("Trade reconciliation stopped");
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::sync::mpsc;
+
+    use crate::{
+        chain_context::TestChainContext,
+        shutdown::drain,
+        token_amount_cache::TokenAmountCache,
+        trade_repository::{InMemoryTradeStore, TradeEntity},
+        transaction_service::TransactionService,
+    };
+
+    use super::*;
+
+    fn trade(trade_id: Uuid, updated_at: DateTime<Utc>) -> TradeEntity {
+        TradeEntity {
+            id: trade_id,
+            initiator: "Alice".to_string(),
+            counterparty: None,
+            status: TradeStatus::Created.as_str().to_string(),
+            status_details: None,
+            status_history: serde_json::json!([]),
+            created_at: Some(updated_at),
+            updated_at: Some(updated_at),
+        }
+    }
+
+    #[test]
+    fn should_expire_stale_trades_without_session_and_report_sessions_without_trade() {
+        let now = Utc::now();
+        let abandoned = Uuid::new_v4();
+        let fresh = Uuid::new_v4();
+        let live = Uuid::new_v4();
+        let unpersisted = Uuid::new_v4();
+        let trade_store = InMemoryTradeStore::with_trades(vec![
+            trade(abandoned, now - chrono::Duration::hours(2)),
+            trade(fresh, now - chrono::Duration::minutes(1)),
+            trade(live, now - chrono::Duration::hours(2)),
+        ]);
+        let sessions = SharedSessions::new(
+            Arc::new(TokenAmountCache::init()),
+            Arc::new(TransactionService::new(Arc::new(TestChainContext {}))),
+        );
+        for session_id in [live, unpersisted] {
+            let (tx, _rx) = mpsc::channel(10);
+            sessions.add_client(session_id, Uuid::new_v4(), tx);
+        }
+
+        let report = reconcile(&sessions, &trade_store, Duration::from_secs(1800), now).unwrap();
+
+        assert_eq!(report.expired_trades, vec![abandoned]);
+        assert_eq!(report.sessions_without_trade, vec![unpersisted]);
+        let statuses: HashMap<Uuid, String> = trade_store
+            .trades
+            .lock()
+            .unwrap()
+            .values()
+            .map(|t| (t.id, t.status.clone()))
+            .collect();
+        assert_eq!(statuses[&abandoned], TradeStatus::Expired.as_str());
+        assert_eq!(statuses[&fresh], TradeStatus::Created.as_str());
+        assert_eq!(statuses[&live], TradeStatus::Created.as_str());
+    }
+
+    #[tokio::test]
+    async fn reconciliation_task_should_exit_once_cancelled() {
+        let sessions = Arc::new(SharedSessions::new(
+            Arc::new(TokenAmountCache::init()),
+            Arc::new(TransactionService::new(Arc::new(TestChainContext {}))),
+        ));
+        let shutdown = CancellationToken::new();
+        let task = spawn_reconciliation_task(
+            sessions,
+            Arc::new(InMemoryTradeStore::with_trades(vec![])),
+            ReconciliationConfig {
+                interval_secs: 3600,
+                ..ReconciliationConfig::default()
+            },
+            shutdown.clone(),
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!task.is_finished());
+
+        shutdown.cancel();
+        assert!(drain(vec![task], Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test]
+    async fn zero_interval_should_not_stop_the_reconciliation_task() {
+        let sessions = Arc::new(SharedSessions::new(
+            Arc::new(TokenAmountCache::init()),
+            Arc::new(TransactionService::new(Arc::new(TestChainContext {}))),
+        ));
+        let shutdown = CancellationToken::new();
+        let task = spawn_reconciliation_task(
+            sessions,
+            Arc::new(InMemoryTradeStore::with_trades(vec![])),
+            ReconciliationConfig {
+                interval_secs: 0,
+                ..ReconciliationConfig::default()
+            },
+            shutdown.clone(),
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!task.is_finished());
+
+        shutdown.cancel();
+        assert!(drain(vec![task], Duration::from_secs(1)).await);
+    }
+}
@@ -1,27 +1,69 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, str::FromStr, sync::Arc};
 
 use axum::{
-    extract::{Path, State, WebSocketUpgrade},
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        ConnectInfo, Path, State, WebSocketUpgrade,
+    },
+    http::HeaderMap,
     response::IntoResponse,
     routing::{get, post},
     Extension, Json, Router,
 };
+use chrono::{DateTime, Utc};
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use subtle::ConstantTimeEq;
 use tower_http::cors::CorsLayer;
 use uuid::Uuid;
 
 use crate::{
-    chain_context::{ChainContext}, token_service::TokenService, trade_service::TradeService, trade_session::SharedSessions, trade_websocket::handle_socket
+    chain_context::ChainContext,
+    error::AppError,
+    metrics::Metrics,
+    price_service::{PriceError, PriceService},
+    token_service::{TokenPage, TokenService},
+    trade_repository::{StatusTransition, TradeEntity},
+    trade_service::{
+        BalanceUnavailableError, InsufficientBalanceError, InvalidAddressError, TradeService,
+    },
+    trade_session::SharedSessions,
+    trade_websocket::handle_socket,
+    wallet_auth::{WalletAuth, WalletAuthError, WALLET_TOKEN_TTL},
 };
 
-pub fn get_router<T: ChainContext + Sync + Send + 'static>(app_state: Arc<AppState>, sessions: Arc<SharedSessions<T>>) -> Router {
+pub fn get_router<T: ChainContext + Sync + Send + 'static>(
+    app_state: Arc<AppState>,
+    sessions: Arc<SharedSessions<T>>,
+) -> Router {
     Router::new()
         .route("/", get(root))
+        .route("/metrics", get(get_metrics))
+        .route("/ready", get(get_readiness))
         .route("/tokens", get(get_tokens))
         .route("/tokens/metadata", get(get_token_metadata))
-        .route("/trading_session", post(create_trade_session))
-        .route("/ws/trading_session/:session_id", get(websocket_handler::<T>))
+        .route("/prices", get(get_prices))
+        .route(
+            "/admin/tokens/metadata/fresh",
+            get(get_fresh_token_metadata),
+        )
+        .route("/auth/challenge", post(create_auth_challenge))
+        .route("/auth/token", post(create_wallet_token))
+        .route("/trading_session", post(create_trade_session::<T>))
+        .route(
+            "/trading_session/:session_id",
+            get(get_trade_session_state::<T>),
+        )
+        .route(
+            "/trading_session/:session_id/history",
+            get(get_trade_history),
+        )
+        .route("/trades/:session_id", get(get_trade))
+        .route(
+            "/ws/trading_session/:session_id",
+            get(websocket_handler::<T>),
+        )
         .with_state(app_state)
         .layer(Extension(sessions))
         .layer(CorsLayer::permissive())
@@ -31,58 +73,364 @@ async fn root() -> &'static str {
     "Hello, World!"
 }
 
+async fn get_metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )],
+        state.metrics.render(),
+    )
+}
+
+/// Reports `degraded` while metadata lookups fall back from the DB to the RPC. The server still
+/// answers then, so the status code stays 200 and only the body tells.
+async fn get_readiness(State(state): State<Arc<AppState>>) -> Json<Readiness> {
+    let metadata_db_degraded = state.token_service.is_metadata_db_degraded();
+    Json(Readiness {
+        status: if metadata_db_degraded {
+            "degraded"
+        } else {
+            "ready"
+        },
+        metadata_db_degraded,
+    })
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Readiness {
+    status: &'static str,
+    metadata_db_degraded: bool,
+}
+
 async fn get_token_metadata(
     State(state): State<Arc<AppState>>,
-    query_params: axum::extract::Query<GetTokenMetadataQuery>,
-) -> axum::http::Response<axum::body::Body> {
-    if let Some(metadata) = state
+    query_params: Result<axum::extract::Query<GetTokenMetadataQuery>, QueryRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let query_params = query_params?;
+    let metadata = state
         .token_service
         .get_token_metadata(&query_params.mint_address)
         .await
-    {
-        (StatusCode::CREATED, Json(metadata)).into_response()
-    } else {
-        (
-            StatusCode::NOT_FOUND,
-            format!(
+        .ok_or_else(|| {
+            AppError::not_found(format!(
                 "Metadata for token {} not found",
                 &query_params.mint_address
-            ),
-        )
-            .into_response()
+            ))
+        })?;
+    Ok((StatusCode::CREATED, Json(metadata)))
+}
+
+async fn get_prices(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    query_params: Result<axum::extract::Query<GetPricesQuery>, QueryRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let query_params = query_params?;
+    state
+        .price_service
+        .admit(client.ip())
+        .map_err(|e| AppError::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited", e.to_string()))?;
+    let mints: Vec<String> = query_params
+        .mints
+        .split(',')
+        .map(str::trim)
+        .filter(|mint| !mint.is_empty())
+        .map(String::from)
+        .collect();
+    let prices = state
+        .price_service
+        .get_prices(&mints)
+        .await
+        .map_err(|e| match e {
+            PriceError::BatchTooLarge { .. } => AppError::bad_request(e.to_string()),
+            PriceError::RateLimited { .. } => {
+                AppError::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited", e.to_string())
+            }
+            PriceError::Upstream(_) => AppError::bad_gateway(e.to_string()),
+        })?;
+    Ok(Json(serde_json::json!({ "prices": prices })))
+}
+
+async fn get_fresh_token_metadata(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    query_params: Result<axum::extract::Query<GetFreshTokenMetadataQuery>, QueryRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let query_params = query_params?;
+    authorize_admin(&state, &headers)?;
+    let metadata = state
+        .token_service
+        .get_fresh_token_metadata(&query_params.mint)
+        .await
+        .map_err(|e| {
+            AppError::not_found(format!(
+                "Metadata for token {} not found on chain: {}",
+                &query_params.mint, e
+            ))
+        })?;
+    Ok((StatusCode::OK, Json(metadata)))
+}
+
+/// Admin endpoints expect `Authorization: Bearer <admin token>` and don't exist without a token.
+fn authorize_admin(state: &AppState, headers: &HeaderMap) -> Result<(), AppError> {
+    let Some(admin_token) = &state.admin_token else {
+        return Err(AppError::not_found("Admin endpoints are disabled"));
+    };
+    if !bearer_token_matches(headers, admin_token) {
+        return Err(AppError::unauthorized("Invalid admin token"));
+    }
+    Ok(())
+}
+
+fn is_admin(state: &AppState, headers: &HeaderMap) -> bool {
+    state
+        .admin_token
+        .as_deref()
+        .is_some_and(|admin_token| bearer_token_matches(headers, admin_token))
+}
+
+/// Compares in constant time so response timing doesn't reveal how much of the token matched.
+fn bearer_token_matches(headers: &HeaderMap, token: &str) -> bool {
+    bearer_token(headers)
+        .is_some_and(|bearer| bool::from(bearer.as_bytes().ct_eq(token.as_bytes())))
+}
+
+/// Admins, and the participants of the trade when they send `Authorization: Bearer <wallet
+/// token>` with a token from `/auth/token`.
+fn authorize_participant(
+    state: &AppState,
+    headers: &HeaderMap,
+    trade_id: &Uuid,
+    initiator: &str,
+    counterparty: Option<&str>,
+) -> Result<(), AppError> {
+    if is_admin(state, headers) {
+        return Ok(());
+    }
+    let Some(wallet) = bearer_token(headers).and_then(|token| state.wallet_auth.wallet(token))
+    else {
+        return Err(AppError::unauthorized(
+            "Authenticate with a wallet token from /auth/token",
+        ));
+    };
+    if wallet != initiator && counterparty != Some(wallet.as_str()) {
+        return Err(AppError::forbidden(format!(
+            "Only participants may view trade {}",
+            trade_id
+        )));
     }
+    Ok(())
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
 }
 
 async fn websocket_handler<T: ChainContext + Sync + Send + 'static>(
     ws: WebSocketUpgrade,
-    Path(params): Path<SessionPathParam>,
+    State(state): State<Arc<AppState>>,
+    params: Result<Path<SessionPathParam>, PathRejection>,
     Extension(sessions): Extension<Arc<SharedSessions<T>>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, params.session_id, sessions))
+) -> Result<impl IntoResponse, AppError> {
+    let Path(params) = params?;
+    let token_service = Arc::clone(&state.token_service);
+    Ok(ws.on_upgrade(move |socket| {
+        handle_socket(socket, params.session_id, sessions, token_service)
+    }))
 }
 
 #[derive(Deserialize)]
 struct CreateTradeSession {
     #[serde(rename = "initiatorAddress")]
     initiator_address: String,
+    /// Only this address may join as the second participant, anyone may when omitted.
+    #[serde(rename = "counterpartyAddress", default)]
+    counterparty_address: Option<String>,
 }
 
-async fn create_trade_session(
+async fn create_trade_session<T: ChainContext + Sync + Send + 'static>(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<CreateTradeSession>,
-) -> axum::http::Response<axum::body::Body> {
-    match state
+    Extension(sessions): Extension<Arc<SharedSessions<T>>>,
+    payload: Result<Json<CreateTradeSession>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let Json(payload) = payload?;
+    let id = state
         .trade_service
-        .create_trade_session(&payload.initiator_address)
-    {
-        Ok(id) => (
-            StatusCode::CREATED,
-            Json(CreateTradeSessionResponse {
-                uuid: id.to_string(),
-            }),
+        .create_trade_session(
+            sessions.chain_context(),
+            &payload.initiator_address,
+            payload.counterparty_address.as_deref(),
         )
-            .into_response(),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        .await
+        .map_err(|e| {
+            if e.is::<InsufficientBalanceError>() || e.is::<InvalidAddressError>() {
+                AppError::bad_request(e.to_string())
+            } else if e.is::<BalanceUnavailableError>() {
+                AppError::bad_gateway(e.to_string())
+            } else {
+                AppError::internal(e.to_string())
+            }
+        })?;
+    if let Some(counterparty_address) = payload.counterparty_address {
+        sessions.invite(id, payload.initiator_address, counterparty_address);
+    }
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateTradeSessionResponse {
+            uuid: id.to_string(),
+        }),
+    ))
+}
+
+/// Current state of the session, the same `TradeStateUpdate` a websocket client gets on
+/// connect, for clients reloading the page. Visible to the participants of the trade and to
+/// admins like `/trades/:session_id`.
+async fn get_trade_session_state<T: ChainContext + Sync + Send + 'static>(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    params: Result<Path<SessionPathParam>, PathRejection>,
+    Extension(sessions): Extension<Arc<SharedSessions<T>>>,
+) -> Result<impl IntoResponse, AppError> {
+    let Path(params) = params?;
+    let trade = state
+        .trade_service
+        .get_trade(&params.session_id)
+        .map_err(|e| AppError::internal(e.to_string()))?
+        .ok_or_else(|| AppError::not_found(format!("Session {} not found", params.session_id)))?;
+    authorize_participant(
+        &state,
+        &headers,
+        &trade.id,
+        &trade.initiator,
+        trade.counterparty.as_deref(),
+    )?;
+    let state = sessions
+        .session_state(&params.session_id)
+        .map_err(|e| AppError::internal(e.to_string()))?
+        .ok_or_else(|| AppError::not_found(format!("Session {} not found", params.session_id)))?;
+    Ok(Json(state))
+}
+
+/// Statuses the trade went through, oldest first, visible to its participants and to admins,
+/// see `authorize_participant`.
+async fn get_trade_history(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    params: Result<Path<SessionPathParam>, PathRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let Path(params) = params?;
+    let trade = state
+        .trade_service
+        .get_trade(&params.session_id)
+        .map_err(|e| AppError::internal(e.to_string()))?
+        .ok_or_else(|| AppError::not_found(format!("Trade {} not found", params.session_id)))?;
+    authorize_participant(
+        &state,
+        &headers,
+        &trade.id,
+        &trade.initiator,
+        trade.counterparty.as_deref(),
+    )?;
+    Ok(Json(
+        serde_json::json!({ "statusHistory": trade.status_transitions() }),
+    ))
+}
+
+/// Full record of a trade, visible to its participants and to admins, see
+/// `authorize_participant`.
+async fn get_trade(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    params: Result<Path<SessionPathParam>, PathRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let Path(params) = params?;
+    let trade = state
+        .trade_service
+        .get_trade(&params.session_id)
+        .map_err(|e| AppError::internal(e.to_string()))?
+        .ok_or_else(|| AppError::not_found(format!("Trade {} not found", params.session_id)))?;
+    authorize_participant(
+        &state,
+        &headers,
+        &trade.id,
+        &trade.initiator,
+        trade.counterparty.as_deref(),
+    )?;
+    Ok(Json(TradeDetails::from(trade)))
+}
+
+/// A nonce for the wallet to sign and send back to `/auth/token`.
+async fn create_auth_challenge(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(serde_json::json!({ "nonce": state.wallet_auth.challenge() }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateWalletToken {
+    user_address: String,
+    nonce: String,
+    /// Base58 encoded ed25519 signature of the utf-8 bytes of the nonce.
+    signature: String,
+}
+
+/// Bearer token proving ownership of the wallet that signed the challenge's nonce.
+async fn create_wallet_token(
+    State(state): State<Arc<AppState>>,
+    payload: Result<Json<CreateWalletToken>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let Json(payload) = payload?;
+    let token = state
+        .wallet_auth
+        .issue_token(&payload.nonce, &payload.user_address, &payload.signature)
+        .map_err(|e| match e {
+            WalletAuthError::Rejected(crate::trade_session::SessionError::InvalidAddress {
+                ..
+            }) => AppError::bad_request(e.to_string()),
+            _ => AppError::unauthorized(e.to_string()),
+        })?;
+    Ok(Json(serde_json::json!({
+        "token": token,
+        "expiresIn": WALLET_TOKEN_TTL.as_secs(),
+    })))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TradeDetails {
+    id: Uuid,
+    initiator: String,
+    counterparty: Option<String>,
+    status: String,
+    status_details: Option<serde_json::Value>,
+    status_history: Vec<StatusTransition>,
+    created_at: Option<DateTime<Utc>>,
+    updated_at: Option<DateTime<Utc>>,
+    /// Signature of the trade transaction, once it was submitted.
+    signature: Option<String>,
+}
+
+impl From<TradeEntity> for TradeDetails {
+    fn from(trade: TradeEntity) -> Self {
+        TradeDetails {
+            status_history: trade.status_transitions(),
+            signature: trade
+                .status_details
+                .as_ref()
+                .and_then(|details| details["signature"].as_str())
+                .map(String::from),
+            id: trade.id,
+            initiator: trade.initiator,
+            counterparty: trade.counterparty,
+            status: trade.status,
+            status_details: trade.status_details,
+            created_at: trade.created_at,
+            updated_at: trade.updated_at,
+        }
     }
 }
 
@@ -94,6 +442,10 @@ pub struct CreateTradeSessionResponse {
 #[derive(Deserialize)]
 pub struct GetTokensQuery {
     address: String,
+    /// Accounts to skip, 0 when missing.
+    offset: Option<usize>,
+    /// Page size, every remaining account when missing.
+    limit: Option<usize>,
 }
 
 #[derive(Deserialize)]
@@ -101,17 +453,42 @@ pub struct GetTokenMetadataQuery {
     mint_address: String,
 }
 
+#[derive(Deserialize)]
+pub struct GetPricesQuery {
+    /// Comma separated mint addresses.
+    mints: String,
+}
+
+#[derive(Deserialize)]
+pub struct GetFreshTokenMetadataQuery {
+    mint: String,
+}
+
 async fn get_tokens(
     State(state): State<Arc<AppState>>,
-    query_params: axum::extract::Query<GetTokensQuery>,
-) -> axum::response::Json<serde_json::Value> {
+    query_params: Result<axum::extract::Query<GetTokensQuery>, QueryRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let query_params = query_params?;
     let wallet_address = &query_params.address;
-    let tokens = state
+    Pubkey::from_str(wallet_address)
+        .map_err(|_| AppError::bad_request(format!("Invalid wallet address {}", wallet_address)))?;
+    let wallet_tokens = state
         .token_service
-        .fetch_tokens(wallet_address)
+        .fetch_tokens(
+            wallet_address,
+            TokenPage {
+                offset: query_params.offset.unwrap_or_default(),
+                limit: query_params.limit,
+            },
+        )
         .await
-        .unwrap_or_default();
-    axum::response::Json(serde_json::json!({ "tokens": tokens }))
+        .map_err(|e| AppError::bad_gateway(format!("Failed to fetch tokens: {}", e)))?;
+    Ok(Json(serde_json::json!({
+        "tokens": wallet_tokens.tokens,
+        "truncated": wallet_tokens.truncated,
+        "has_more": wallet_tokens.has_more,
+        "unparsed_accounts": wallet_tokens.unparsed_accounts
+    })))
 }
 
 #[derive(Deserialize)]
@@ -123,4 +500,557 @@ struct SessionPathParam {
 pub struct AppState {
     pub token_service: Arc<TokenService>,
     pub trade_service: Arc<TradeService>,
+    pub price_service: Arc<PriceService>,
+    pub metrics: Arc<Metrics>,
+    pub admin_token: Option<String>,
+    pub wallet_auth: Arc<WalletAuth>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::IntoFuture;
+
+    use solana_client::nonblocking::rpc_client::RpcClient;
+    use tokio::net::TcpListener;
+
+    use crate::{
+        chain_context::TestChainContext,
+        config::{MetadataConfig, PricesConfig, TokensConfig},
+        metadata_cache::MetadataCache,
+        metadata_repository::InMemoryMetadataStore,
+        price_service::JupiterPriceSource,
+        token_amount_cache::TokenAmountCache,
+        token_list::TokenList,
+        trade_repository::{InMemoryTradeStore, TradeEntity},
+        transaction_service::TransactionService,
+    };
+
+    use super::*;
+
+    async fn serve(admin_token: Option<String>) -> String {
+        serve_with_trades(admin_token, vec![]).await
+    }
+
+    async fn serve_with_trades(admin_token: Option<String>, trades: Vec<TradeEntity>) -> String {
+        serve_with_sessions(admin_token, trades, Arc::new(TokenAmountCache::init()))
+            .await
+            .0
+    }
+
+    async fn serve_with_sessions(
+        admin_token: Option<String>,
+        trades: Vec<TradeEntity>,
+        token_amount_cache: Arc<TokenAmountCache>,
+    ) -> (String, Arc<SharedSessions<TestChainContext>>) {
+        let rpc_client = Arc::new(RpcClient::new_mock("fails".to_string()));
+        serve_with_rpc(admin_token, trades, token_amount_cache, rpc_client).await
+    }
+
+    async fn serve_with_rpc(
+        admin_token: Option<String>,
+        trades: Vec<TradeEntity>,
+        token_amount_cache: Arc<TokenAmountCache>,
+        rpc_client: Arc<RpcClient>,
+    ) -> (String, Arc<SharedSessions<TestChainContext>>) {
+        let metrics = Arc::new(Metrics::new());
+        let metadata_cache = MetadataCache::init(
+            InMemoryMetadataStore::with_entities(vec![]),
+            Arc::clone(&rpc_client),
+            TokenList::default(),
+            &MetadataConfig::default(),
+            Arc::clone(&metrics),
+        )
+        .unwrap();
+        let app_state = AppState {
+            token_service: Arc::new(TokenService::new(
+                metadata_cache,
+                rpc_client,
+                Arc::clone(&token_amount_cache),
+                Arc::clone(&metrics),
+                &TokensConfig::default(),
+            )),
+            trade_service: Arc::new(TradeService::new(InMemoryTradeStore::with_trades(trades))),
+            price_service: Arc::new(PriceService::new(
+                JupiterPriceSource::new("http://127.0.0.1:1".to_string()),
+                &PricesConfig {
+                    max_batch_size: 2,
+                    ..PricesConfig::default()
+                },
+            )),
+            metrics,
+            admin_token,
+            wallet_auth: Arc::default(),
+        };
+        let sessions = Arc::new(SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::new(Arc::new(TestChainContext {}))),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::serve(
+                listener,
+                get_router(Arc::new(app_state), Arc::clone(&sessions))
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .into_future(),
+        );
+        (format!("http://{}", address), sessions)
+    }
+
+    async fn error_body(response: reqwest::Response) -> serde_json::Value {
+        let body = response.text().await.unwrap();
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()["error"].clone()
+    }
+
+    /// Local RPC answering getTokenAccountsByOwner with `accounts` for the Token program only,
+    /// every other request finds nothing.
+    async fn serve_token_accounts_rpc(accounts: serde_json::Value) -> Arc<RpcClient> {
+        let rpc = Router::new().route(
+            "/",
+            post(move |Json(request): Json<serde_json::Value>| async move {
+                let value = match request["method"].as_str() {
+                    Some("getTokenAccountsByOwner")
+                        if request["params"][1]["programId"]
+                            == "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA" =>
+                    {
+                        accounts
+                    }
+                    Some("getTokenAccountsByOwner") => serde_json::json!([]),
+                    _ => serde_json::Value::Null,
+                };
+                Json(serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "result": { "context": { "slot": 1 }, "value": value }
+                }))
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(axum::serve(listener, rpc).into_future());
+        Arc::new(RpcClient::new(rpc_url))
+    }
+
+    #[tokio::test]
+    async fn token_amounts_should_be_sent_as_exact_decimal_strings() {
+        let mint = solana_sdk::pubkey::Pubkey::new_unique().to_string();
+        let rpc_client = serve_token_accounts_rpc(serde_json::json!([{
+            "pubkey": solana_sdk::pubkey::Pubkey::new_unique().to_string(),
+            "account": {
+                "lamports": 2039280,
+                "data": {
+                    "program": "spl-token",
+                    "parsed": {
+                        "type": "account",
+                        "info": {
+                            "mint": mint,
+                            "tokenAmount": {
+                                "amount": "12345678901234567891",
+                                "decimals": 18,
+                                "uiAmount": 12.345678901234567,
+                                "uiAmountString": "12.345678901234567891"
+                            }
+                        }
+                    },
+                    "space": 165
+                },
+                "owner": "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA",
+                "executable": false,
+                "rentEpoch": 0,
+                "space": 165
+            }
+        }]))
+        .await;
+        let (base_url, _) =
+            serve_with_rpc(None, vec![], Arc::new(TokenAmountCache::init()), rpc_client).await;
+        let wallet = solana_sdk::pubkey::Pubkey::new_unique();
+
+        let response = reqwest::get(format!("{}/tokens?address={}", base_url, wallet))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(body["tokens"][0]["mint"], mint);
+        assert_eq!(body["tokens"][0]["amount"], "12.345678901234567891");
+    }
+
+    #[tokio::test]
+    async fn missing_metadata_should_return_json_error() {
+        let base_url = serve(None).await;
+
+        let response = reqwest::get(format!("{}/tokens/metadata?mint_address=Unknown", base_url))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let error = error_body(response).await;
+        assert_eq!(error["code"], "not_found");
+        assert_eq!(error["message"], "Metadata for token Unknown not found");
+    }
+
+    #[tokio::test]
+    async fn admin_endpoint_should_return_json_errors() {
+        let base_url = serve(Some("secret".to_string())).await;
+        let client = reqwest::Client::new();
+        let url = format!("{}/admin/tokens/metadata/fresh?mint=Unknown", base_url);
+
+        let response = client.get(&url).bearer_auth("wrong").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error_body(response).await["code"], "unauthorized");
+
+        let response = client.get(&url).bearer_auth("secret").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let error = error_body(response).await;
+        assert_eq!(error["code"], "not_found");
+        assert!(error["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Metadata for token Unknown not found on chain"));
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_should_return_json_errors() {
+        let base_url = serve(None).await;
+        let client = reqwest::Client::new();
+
+        let response = reqwest::get(format!("{}/tokens", base_url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_body(response).await["code"], "bad_request");
+
+        let response = reqwest::get(format!("{}/trades/not-a-uuid", base_url))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_body(response).await["code"], "bad_request");
+
+        let response = client
+            .post(format!("{}/trading_session", base_url))
+            .header(axum::http::header::CONTENT_TYPE, "application/json")
+            .body("{")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_body(response).await["code"], "bad_request");
+    }
+
+    #[tokio::test]
+    async fn tokens_should_tell_bad_addresses_from_rpc_failures() {
+        let base_url = serve(None).await;
+
+        let response = reqwest::get(format!("{}/tokens?address=not-a-wallet", base_url))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            error_body(response).await["message"],
+            "Invalid wallet address not-a-wallet"
+        );
+
+        let response = reqwest::get(format!(
+            "{}/tokens?address={}",
+            base_url,
+            solana_sdk::pubkey::Pubkey::new_unique()
+        ))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(error_body(response).await["code"], "bad_gateway");
+    }
+
+    #[tokio::test]
+    async fn readiness_should_report_ready_with_healthy_db() {
+        let base_url = serve(None).await;
+
+        let response = reqwest::get(format!("{}/ready", base_url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["metadataDbDegraded"], false);
+    }
+
+    #[tokio::test]
+    async fn prices_should_reject_oversized_batches() {
+        let base_url = serve(None).await;
+
+        let response = reqwest::get(format!("{}/prices?mints=A,B,C", base_url))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_body(response).await["code"], "bad_request");
+    }
+
+    #[tokio::test]
+    async fn trade_history_should_list_transitions_in_order_to_participants() {
+        use solana_sdk::signature::{Keypair, Signer};
+
+        let alice = Keypair::new();
+        let trade_id = Uuid::new_v4();
+        let history = serde_json::json!([
+            {"status": "Created", "at": "2025-03-01T10:00:00Z"},
+            {"status": "Completed", "at": "2025-03-01T10:05:00Z"},
+        ]);
+        let base_url = serve_with_trades(
+            Some("secret".to_string()),
+            vec![TradeEntity {
+                id: trade_id,
+                initiator: alice.pubkey().to_string(),
+                counterparty: None,
+                status: "Completed".to_string(),
+                status_details: None,
+                created_at: None,
+                updated_at: None,
+                status_history: history.clone(),
+            }],
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let url = format!("{}/trading_session/{}/history", base_url, trade_id);
+
+        let alice_token = wallet_token(&base_url, &alice).await;
+        for token in [alice_token.as_str(), "secret"] {
+            let response = client.get(&url).bearer_auth(token).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: serde_json::Value =
+                serde_json::from_str(&response.text().await.unwrap()).unwrap();
+            assert_eq!(body["statusHistory"], history);
+        }
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client
+            .get(&url)
+            .bearer_auth(wallet_token(&base_url, &Keypair::new()).await)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = client
+            .get(format!(
+                "{}/trading_session/{}/history",
+                base_url,
+                Uuid::new_v4()
+            ))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// Bearer token for the wallet of `keypair`, obtained like a client would.
+    async fn wallet_token(base_url: &str, keypair: &solana_sdk::signature::Keypair) -> String {
+        use solana_sdk::signature::Signer;
+
+        let client = reqwest::Client::new();
+        let response = client
+            .post(format!("{}/auth/challenge", base_url))
+            .send()
+            .await
+            .unwrap();
+        let challenge: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        let nonce = challenge["nonce"].as_str().unwrap();
+        let payload = serde_json::json!({
+            "userAddress": keypair.pubkey().to_string(),
+            "nonce": nonce,
+            "signature": keypair.sign_message(nonce.as_bytes()).to_string(),
+        });
+        let response = client
+            .post(format!("{}/auth/token", base_url))
+            .header(axum::http::header::CONTENT_TYPE, "application/json")
+            .body(payload.to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        body["token"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn trade_should_only_be_shown_to_participants_and_admins() {
+        use solana_sdk::signature::{Keypair, Signer};
+
+        let (alice, bob, mallory) = (Keypair::new(), Keypair::new(), Keypair::new());
+        let trade_id = Uuid::new_v4();
+        let base_url = serve_with_trades(
+            Some("secret".to_string()),
+            vec![TradeEntity {
+                id: trade_id,
+                initiator: alice.pubkey().to_string(),
+                counterparty: Some(bob.pubkey().to_string()),
+                status: "Completed".to_string(),
+                status_details: Some(serde_json::json!({"signature": "5ig"})),
+                created_at: None,
+                updated_at: None,
+                status_history: serde_json::json!([
+                    {"status": "Created", "at": "2025-03-01T10:00:00Z"},
+                ]),
+            }],
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let url = format!("{}/trades/{}", base_url, trade_id);
+        let bob_token = wallet_token(&base_url, &bob).await;
+        let mallory_token = wallet_token(&base_url, &mallory).await;
+
+        for request in [
+            client.get(&url).bearer_auth(&bob_token),
+            client.get(&url).bearer_auth("secret"),
+        ] {
+            let response = request.send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: serde_json::Value =
+                serde_json::from_str(&response.text().await.unwrap()).unwrap();
+            assert_eq!(body["id"], trade_id.to_string());
+            assert_eq!(body["counterparty"], bob.pubkey().to_string());
+            assert_eq!(body["signature"], "5ig");
+            assert_eq!(body["statusHistory"][0]["status"], "Created");
+        }
+
+        let response = client
+            .get(&url)
+            .bearer_auth(&mallory_token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_body(response).await["code"], "forbidden");
+        // Naming a participant's wallet proves nothing
+        for request in [
+            client
+                .get(&url)
+                .query(&[("address", bob.pubkey().to_string())]),
+            client.get(&url).bearer_auth("wrong"),
+            client.get(&url),
+        ] {
+            let response = request.send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(error_body(response).await["code"], "unauthorized");
+        }
+
+        let response = client
+            .get(format!("{}/trades/{}", base_url, Uuid::new_v4()))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn wallet_token_should_require_the_wallet_signature_of_an_issued_nonce() {
+        use solana_sdk::signature::{Keypair, Signer};
+
+        let base_url = serve(None).await;
+        let client = reqwest::Client::new();
+        let alice = Keypair::new();
+        let request_token = |nonce: String, signature: String| {
+            let payload = serde_json::json!({
+                "userAddress": alice.pubkey().to_string(),
+                "nonce": nonce,
+                "signature": signature,
+            });
+            client
+                .post(format!("{}/auth/token", base_url))
+                .header(axum::http::header::CONTENT_TYPE, "application/json")
+                .body(payload.to_string())
+        };
+
+        let response = request_token(
+            "never-issued".to_string(),
+            alice.sign_message(b"never-issued").to_string(),
+        )
+        .send()
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = client
+            .post(format!("{}/auth/challenge", base_url))
+            .send()
+            .await
+            .unwrap();
+        let challenge: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        let nonce = challenge["nonce"].as_str().unwrap().to_string();
+        let forged = Keypair::new().sign_message(nonce.as_bytes()).to_string();
+        let response = request_token(nonce, forged).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error_body(response).await["code"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn session_state_should_show_offers_of_live_sessions_to_participants() {
+        use solana_sdk::signature::{Keypair, Signer};
+
+        let alice = Keypair::new();
+        let alice_address = alice.pubkey().to_string();
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice_address.clone(),
+            std::collections::HashMap::from([(
+                "TokenA".to_string(),
+                rust_decimal_macros::dec!(10),
+            )]),
+            crate::chain_context::TEST_MINT_DECIMALS,
+        );
+        let session_id = Uuid::new_v4();
+        let trade = TradeEntity {
+            id: session_id,
+            initiator: alice_address.clone(),
+            counterparty: None,
+            status: "Created".to_string(),
+            status_details: None,
+            status_history: serde_json::json!([]),
+            created_at: None,
+            updated_at: None,
+        };
+        let (base_url, sessions) = serve_with_sessions(None, vec![trade], token_amount_cache).await;
+        sessions.add_client(session_id, Uuid::new_v4(), tokio::sync::mpsc::channel(10).0);
+        sessions
+            .add_tokens_offer(
+                &session_id,
+                &alice_address,
+                "TokenA".to_string(),
+                rust_decimal_macros::dec!(2.5),
+            )
+            .unwrap();
+        let client = reqwest::Client::new();
+        let url = format!("{}/trading_session/{}", base_url, session_id);
+
+        let response = client
+            .get(&url)
+            .bearer_auth(wallet_token(&base_url, &alice).await)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let state: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(state["offers"][&alice_address]["TokenA"], "2.5");
+        assert_eq!(state["userActed"], serde_json::Value::Null);
+        assert_eq!(state["status"], "Trading");
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client
+            .get(&url)
+            .bearer_auth(wallet_token(&base_url, &Keypair::new()).await)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = reqwest::get(format!("{}/trading_session/{}", base_url, Uuid::new_v4()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(error_body(response).await["code"], "not_found");
+    }
 }
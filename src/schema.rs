@@ -10,6 +10,17 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    trade_events (id) {
+        id -> Int8,
+        session_id -> Uuid,
+        actor -> Text,
+        action -> Text,
+        payload -> Nullable<Jsonb>,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     trades (id) {
         id -> Uuid,
@@ -19,10 +30,8 @@ diesel::table! {
         status_details -> Nullable<Jsonb>,
         created_at -> Nullable<Timestamptz>,
         updated_at -> Nullable<Timestamptz>,
+        status_history -> Jsonb,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(
-    metadata,
-    trades,
-);
+diesel::allow_tables_to_appear_in_same_query!(metadata, trade_events, trades,);
@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::task::JoinHandle;
+
+/// Resolves once the process is asked to stop, by Ctrl+C or SIGTERM.
+pub async fn shutdown_signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Unable to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    info!("Shutting down");
+}
+
+/// Waits up to `timeout` for background tasks that were told to stop, aborting the ones still
+/// running afterwards. Returns whether every task finished on its own without panicking.
+pub async fn drain(tasks: Vec<JoinHandle<()>>, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut clean = true;
+    for mut task in tasks {
+        match tokio::time::timeout_at(deadline, &mut task).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                warn!("Background task failed during shutdown: {}", e);
+                clean = false;
+            }
+            Err(_) => {
+                warn!(
+                    "Background task still running after {:?}, aborting it",
+                    timeout
+                );
+                task.abort();
+                clean = false;
+            }
+        }
+    }
+    clean
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_should_wait_for_cancelled_tasks_and_abort_stuck_ones() {
+        let shutdown = CancellationToken::new();
+        let finished_work = Arc::new(AtomicBool::new(false));
+        let cooperative = tokio::spawn({
+            let shutdown = shutdown.clone();
+            let finished_work = Arc::clone(&finished_work);
+            async move {
+                shutdown.cancelled().await;
+                // Work in progress when cancelled is still finished
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                finished_work.store(true, Ordering::SeqCst);
+            }
+        });
+        shutdown.cancel();
+        assert!(drain(vec![cooperative], Duration::from_secs(1)).await);
+        assert!(finished_work.load(Ordering::SeqCst));
+
+        let stuck = tokio::spawn(std::future::pending::<()>());
+        let panicking = tokio::spawn(async { panic!("background task panicked") });
+        assert!(!drain(vec![stuck, panicking], Duration::from_millis(50)).await);
+    }
+}
@@ -0,0 +1,48 @@
+use std::{
+    io::Write,
+    sync::{Mutex, Once},
+};
+
+use log::LevelFilter;
+
+/// Everything logged by any test so far. `log` takes one logger per process, so tests that
+/// look at log output and tests that just want it printed share this one.
+static LOGS: Mutex<String> = Mutex::new(String::new());
+static INIT: Once = Once::new();
+
+struct Capture;
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let line = String::from_utf8_lossy(buf);
+        // print! goes to the test harness, which only shows it for failing tests
+        print!("{}", line);
+        LOGS.lock().unwrap().push_str(&line);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Logs at debug level for the rest of the test run.
+pub fn init() {
+    INIT.call_once(|| {
+        env_logger::Builder::new()
+            .filter(None, LevelFilter::Debug)
+            .target(env_logger::Target::Pipe(Box::new(Capture)))
+            .init();
+    });
+}
+
+/// Logged lines containing `needle`, logging at debug level from now on.
+pub fn lines_containing(needle: &str) -> Vec<String> {
+    init();
+    LOGS.lock()
+        .unwrap()
+        .lines()
+        .filter(|line| line.contains(needle))
+        .map(String::from)
+        .collect()
+}
@@ -1,25 +1,244 @@
-use std::{collections::HashMap, sync::Mutex, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
 use lru_time_cache::LruCache;
 use rust_decimal::Decimal;
 
+use crate::transaction_service::MAX_MINT_DECIMALS;
+
+/// A token account listed by a wallet fetch, with the balance it held then.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeldTokenAccount {
+    pub account: String,
+    pub mint: String,
+    pub amount: Decimal,
+}
+
+struct CachedAmounts {
+    amounts: HashMap<String, Decimal>,
+    /// The token accounts `amounts` were summed from.
+    accounts: Vec<HeldTokenAccount>,
+    /// When the fetch producing `amounts` started.
+    fetched_at: Instant,
+}
+
 pub struct TokenAmountCache {
-    cache: Mutex<LruCache::<String, HashMap<String, Decimal>>>
+    cache: Mutex<LruCache<String, CachedAmounts>>,
+    /// Wallets whose amounts expired since they were last fetched, remembered for another expiry
+    /// period, so an expired balance can be told apart from one never fetched.
+    expired: Mutex<LruCache<String, ()>>,
+    /// Decimals of the mints seen in fetched wallets, they never change so they don't expire.
+    mint_decimals: Mutex<HashMap<String, u8>>,
 }
 
 impl TokenAmountCache {
     pub fn init() -> Self {
+        TokenAmountCache::with_expiry(Duration::from_secs(600))
+    }
+
+    pub fn with_expiry(expiry: Duration) -> Self {
         TokenAmountCache {
-            cache: Mutex::new(LruCache::<String, HashMap<String, Decimal>>::with_expiry_duration(Duration::from_secs(600)))
+            cache: Mutex::new(LruCache::<String, CachedAmounts>::with_expiry_duration(
+                expiry,
+            )),
+            expired: Mutex::new(LruCache::with_expiry_duration(expiry)),
+            mint_decimals: Mutex::new(HashMap::new()),
         }
     }
 
     pub fn get_token_amounts(&self, user_address: &str) -> Option<HashMap<String, Decimal>> {
-        self.cache.lock().unwrap().get(user_address).cloned()
+        self.cached(user_address, |cached| cached.amounts.clone())
+    }
+
+    /// Token accounts of the wallet as its last fetch listed them.
+    pub fn get_token_accounts(&self, user_address: &str) -> Option<Vec<HeldTokenAccount>> {
+        self.cached(user_address, |cached| cached.accounts.clone())
     }
 
-    pub fn insert_token_amounts(&self, user_address: String, token_amounts: HashMap<String, Decimal>) {      
-        self.cache.lock().unwrap().insert(user_address, token_amounts);
+    /// Whether the wallet was fetched before but its amounts expired since, unlike a wallet
+    /// never fetched.
+    pub fn has_expired(&self, user_address: &str) -> bool {
+        // Expired amounts are only dropped when the cache is accessed
+        let _ = self.get_token_amounts(user_address);
+        self.expired.lock().unwrap().peek(user_address).is_some()
     }
 
-}
\ No newline at end of file
+    fn cached<R>(&self, user_address: &str, read: impl FnOnce(&CachedAmounts) -> R) -> Option<R> {
+        let mut cache = self.cache.lock().unwrap();
+        let (cached, expired) = cache.notify_get(user_address);
+        let value = cached.map(read);
+        drop(cache);
+        self.remember_expired(expired);
+        value
+    }
+
+    fn remember_expired(&self, expired: Vec<(String, CachedAmounts)>) {
+        if expired.is_empty() {
+            return;
+        }
+        let mut remembered = self.expired.lock().unwrap();
+        for (user_address, _) in expired {
+            remembered.insert(user_address, ());
+        }
+    }
+
+    pub fn insert_token_amounts(
+        &self,
+        user_address: String,
+        token_amounts: HashMap<String, Decimal>,
+    ) {
+        self.insert_token_amounts_fetched_at(user_address, token_amounts, vec![], Instant::now());
+    }
+
+    /// Stores the amounts of a fetch started at `fetched_at`, unless the cache already holds the
+    /// result of a fetch started later. A slow fetch finishing after a newer one would otherwise
+    /// replace fresher balances. Returns whether the amounts were stored.
+    pub fn insert_token_amounts_fetched_at(
+        &self,
+        user_address: String,
+        token_amounts: HashMap<String, Decimal>,
+        accounts: Vec<HeldTokenAccount>,
+        fetched_at: Instant,
+    ) -> bool {
+        let mut cache = self.cache.lock().unwrap();
+        if cache
+            .peek(&user_address)
+            .is_some_and(|cached| cached.fetched_at > fetched_at)
+        {
+            return false;
+        }
+        self.expired.lock().unwrap().remove(&user_address);
+        let (_, expired) = cache.notify_insert(
+            user_address,
+            CachedAmounts {
+                amounts: token_amounts,
+                accounts,
+                fetched_at,
+            },
+        );
+        drop(cache);
+        self.remember_expired(expired);
+        true
+    }
+
+    pub fn get_mint_decimals(&self, mint: &str) -> Option<u8> {
+        self.mint_decimals.lock().unwrap().get(mint).copied()
+    }
+
+    /// Refuses mints with more than `MAX_MINT_DECIMALS`, returning false, so they can't be
+    /// offered.
+    pub fn insert_mint_decimals(&self, mint: String, decimals: u8) -> bool {
+        if decimals > MAX_MINT_DECIMALS {
+            return false;
+        }
+        self.mint_decimals.lock().unwrap().insert(mint, decimals);
+        true
+    }
+
+    /// Stores `token_amounts` like a wallet fetch would, along with `decimals` for each mint.
+    #[cfg(test)]
+    pub fn insert_token_amounts_with_decimals(
+        &self,
+        user_address: String,
+        token_amounts: HashMap<String, Decimal>,
+        decimals: u8,
+    ) {
+        for mint in token_amounts.keys() {
+            assert!(self.insert_mint_decimals(mint.clone(), decimals));
+        }
+        self.insert_token_amounts(user_address, token_amounts);
+    }
+
+    /// Stores `accounts` like a wallet fetch would, summed per mint, along with `decimals` for
+    /// each mint.
+    #[cfg(test)]
+    pub fn insert_token_accounts_with_decimals(
+        &self,
+        user_address: String,
+        accounts: Vec<HeldTokenAccount>,
+        decimals: u8,
+    ) {
+        let mut token_amounts: HashMap<String, Decimal> = HashMap::new();
+        for held in &accounts {
+            assert!(self.insert_mint_decimals(held.mint.clone(), decimals));
+            *token_amounts.entry(held.mint.clone()).or_default() += held.amount;
+        }
+        self.insert_token_amounts_fetched_at(user_address, token_amounts, accounts, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn older_fetch_should_not_overwrite_newer_amounts() {
+        let cache = TokenAmountCache::init();
+        let older_fetch = Instant::now();
+        let newer_fetch = older_fetch + Duration::from_millis(10);
+
+        assert!(cache.insert_token_amounts_fetched_at(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(5))]),
+            vec![],
+            newer_fetch,
+        ));
+        assert!(!cache.insert_token_amounts_fetched_at(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(9))]),
+            vec![],
+            older_fetch,
+        ));
+        assert_eq!(
+            cache.get_token_amounts("Alice"),
+            Some(HashMap::from([("TokenA".to_string(), dec!(5))]))
+        );
+
+        assert!(cache.insert_token_amounts_fetched_at(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(3))]),
+            vec![],
+            newer_fetch + Duration::from_millis(10),
+        ));
+        assert_eq!(
+            cache.get_token_amounts("Alice"),
+            Some(HashMap::from([("TokenA".to_string(), dec!(3))]))
+        );
+    }
+
+    #[test]
+    fn expired_amounts_should_be_told_apart_from_never_fetched_ones() {
+        let cache = TokenAmountCache::with_expiry(Duration::from_millis(50));
+        cache.insert_token_amounts(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(5))]),
+        );
+        assert!(!cache.has_expired("Alice"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.has_expired("Alice"));
+        assert!(!cache.has_expired("Bob"));
+        assert_eq!(cache.get_token_amounts("Alice"), None);
+
+        cache.insert_token_amounts(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(5))]),
+        );
+        assert!(!cache.has_expired("Alice"));
+    }
+
+    #[test]
+    fn mints_with_too_many_decimals_should_be_refused() {
+        let cache = TokenAmountCache::init();
+
+        assert!(cache.insert_mint_decimals("TokenA".to_string(), MAX_MINT_DECIMALS));
+        assert!(!cache.insert_mint_decimals("TokenB".to_string(), MAX_MINT_DECIMALS + 1));
+        assert_eq!(cache.get_mint_decimals("TokenA"), Some(MAX_MINT_DECIMALS));
+        assert_eq!(cache.get_mint_decimals("TokenB"), None);
+    }
+}
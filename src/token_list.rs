@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+/// Names and symbols of tokens from an off-chain token list, consulted for mints without
+/// on-chain Metaplex metadata. Accepts both the Solana token list format (`{"tokens": [...]}`)
+/// and the plain array served by Jupiter.
+#[derive(Debug, Default)]
+pub struct TokenList {
+    entries: HashMap<String, TokenListEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TokenListEntry {
+    pub address: String,
+    pub name: String,
+    pub symbol: String,
+    #[serde(rename = "logoURI")]
+    pub logo_uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TokenListDocument {
+    Registry { tokens: Vec<TokenListEntry> },
+    Entries(Vec<TokenListEntry>),
+}
+
+impl TokenList {
+    /// Loads the list once from an http(s) url or a local file path.
+    pub async fn load(source: &str) -> Result<Self> {
+        let json = if source.starts_with("http://") || source.starts_with("https://") {
+            reqwest::get(source)
+                .await?
+                .error_for_status()?
+                .text()
+                .await?
+        } else {
+            tokio::fs::read_to_string(source).await?
+        };
+        TokenList::parse(&json)
+    }
+
+    pub fn parse(json: &str) -> Result<Self> {
+        let tokens = match serde_json::from_str::<TokenListDocument>(json)? {
+            TokenListDocument::Registry { tokens } => tokens,
+            TokenListDocument::Entries(tokens) => tokens,
+        };
+        Ok(TokenList::from_entries(tokens))
+    }
+
+    pub fn from_entries(tokens: Vec<TokenListEntry>) -> Self {
+        TokenList {
+            entries: tokens
+                .into_iter()
+                .map(|token| (token.address.clone(), token))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, mint_address: &str) -> Option<&TokenListEntry> {
+        self.entries.get(mint_address)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_registry_and_jupiter_formats() {
+        let registry = r#"{"name": "Solana Token List", "tokens": [
+            {"chainId": 101, "address": "MintA", "name": "Token A", "symbol": "TKA", "decimals": 6}
+        ]}"#;
+        let jupiter = r#"[
+            {"address": "MintB", "name": "Token B", "symbol": "TKB", "logoURI": "https://b.png", "tags": []}
+        ]"#;
+
+        let registry_list = TokenList::parse(registry).unwrap();
+        assert_eq!(registry_list.len(), 1);
+        assert_eq!(registry_list.get("MintA").unwrap().symbol, "TKA");
+
+        let jupiter_list = TokenList::parse(jupiter).unwrap();
+        let token_b = jupiter_list.get("MintB").unwrap();
+        assert_eq!(token_b.name, "Token B");
+        assert_eq!(token_b.logo_uri.as_deref(), Some("https://b.png"));
+        assert!(jupiter_list.get("MintA").is_none());
+    }
+}
@@ -1,20 +1,34 @@
 use base64::{engine::general_purpose, Engine as _};
+use log::{debug, warn};
 use rust_decimal::prelude::*;
-use rust_decimal_macros::dec;
 use serde::{Deserialize, Serialize};
-use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::{
+    nonblocking::rpc_client::RpcClient,
+    rpc_config::{RpcAccountInfoConfig, RpcTokenAccountsFilter},
+    rpc_request::RpcRequest,
+    rpc_response::{Response, RpcKeyedAccount},
+};
 use solana_sdk::pubkey::Pubkey;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Instant,
+};
 
 use crate::{
-    metadata_cache::MetadataCache, metadata_repository::MetadataEntity,
-    token_amount_cache::TokenAmountCache,
+    config::{RuntimeConfig, TokensConfig, TunableConfig},
+    metadata_cache::{ImageBudget, MetadataCache},
+    metrics::Metrics,
+    token_amount_cache::{HeldTokenAccount, TokenAmountCache},
 };
 
 pub struct TokenService {
     metadata_cache: MetadataCache,
     rpc_client: Arc<RpcClient>,
     token_amount_cache: Arc<TokenAmountCache>,
+    metrics: Arc<Metrics>,
+    runtime_config: Arc<RuntimeConfig>,
 }
 
 impl TokenService {
@@ -22,23 +36,53 @@ impl TokenService {
         metadata_cache: MetadataCache,
         rpc_client: Arc<RpcClient>,
         token_amount_cache: Arc<TokenAmountCache>,
+        metrics: Arc<Metrics>,
+        config: &TokensConfig,
+    ) -> Self {
+        let runtime_config = RuntimeConfig::new(TunableConfig {
+            tokens: config.clone(),
+            ..TunableConfig::default()
+        });
+        TokenService::with_runtime_config(
+            metadata_cache,
+            rpc_client,
+            token_amount_cache,
+            metrics,
+            Arc::new(runtime_config),
+        )
+    }
+
+    /// Like `new`, but reads `tokens` settings from `runtime_config` on every request so they
+    /// follow config reloads.
+    pub fn with_runtime_config(
+        metadata_cache: MetadataCache,
+        rpc_client: Arc<RpcClient>,
+        token_amount_cache: Arc<TokenAmountCache>,
+        metrics: Arc<Metrics>,
+        runtime_config: Arc<RuntimeConfig>,
     ) -> Self {
         TokenService {
             metadata_cache,
             rpc_client,
             token_amount_cache,
+            metrics,
+            runtime_config,
         }
     }
 
+    pub fn is_metadata_db_degraded(&self) -> bool {
+        self.metadata_cache.is_db_degraded()
+    }
+
     pub async fn get_token_metadata(&self, mint_address: &str) -> Option<MetadataView> {
         let metadata = self
             .metadata_cache
             .get_token_metadata(mint_address)
             .await
             .ok();
-        if metadata.is_some() {
+        if let Some(entity) = &metadata {
             let metadata_view = MetadataView {
-                mint: metadata.as_ref().unwrap().mint_address.clone(),
+                mint: entity.mint_address.clone(),
                 symbol: metadata.as_ref().and_then(|m| {
                     m.symbol
                         .as_ref()
@@ -69,95 +113,287 @@ impl TokenService {
         }
     }
 
+    pub async fn get_fresh_token_metadata(
+        &self,
+        mint_address: &str,
+    ) -> anyhow::Result<RawMetadataView> {
+        let metadata = self
+            .metadata_cache
+            .fetch_fresh_metadata(mint_address)
+            .await?;
+        Ok(RawMetadataView {
+            mint: metadata.mint.to_string(),
+            update_authority: metadata.update_authority.to_string(),
+            name: metadata.name,
+            symbol: metadata.symbol,
+            uri: metadata.uri,
+            seller_fee_basis_points: metadata.seller_fee_basis_points,
+            primary_sale_happened: metadata.primary_sale_happened,
+            is_mutable: metadata.is_mutable,
+        })
+    }
+
+    /// Mints the wallet can offer right now with their amounts, fetched from chain when the
+    /// wallet's balances aren't cached.
+    pub async fn get_available_amounts(
+        &self,
+        wallet_address: &str,
+    ) -> Result<HashMap<String, Decimal>, Box<dyn std::error::Error>> {
+        let token_amounts = match self.token_amount_cache.get_token_amounts(wallet_address) {
+            Some(token_amounts) => token_amounts,
+            None => {
+                self.fetch_tokens(wallet_address, TokenPage::default())
+                    .await?;
+                self.token_amount_cache
+                    .get_token_amounts(wallet_address)
+                    .unwrap_or_default()
+            }
+        };
+        Ok(token_amounts
+            .into_iter()
+            .filter(|(_, amount)| *amount > Decimal::ZERO)
+            .collect())
+    }
+
+    /// Token accounts of the wallet within `page`. The full set of balances is cached either
+    /// way, so offers can be checked against tokens on pages not fetched yet.
     pub async fn fetch_tokens(
         &self,
         wallet_address: &str,
-    ) -> Result<Vec<TokenAccount>, Box<dyn std::error::Error>> {
+        page: TokenPage,
+    ) -> Result<WalletTokens, Box<dyn std::error::Error>> {
+        let started = Instant::now();
+        let result = self.load_tokens(wallet_address, page).await;
+        self.metrics
+            .fetch_tokens_duration
+            .observe(started.elapsed());
+        result
+    }
+
+    async fn load_tokens(
+        &self,
+        wallet_address: &str,
+        page: TokenPage,
+    ) -> Result<WalletTokens, Box<dyn std::error::Error>> {
+        let fetch_started = Instant::now();
         let wallet_pubkey = Pubkey::try_from(wallet_address)?;
 
         let mut token_accounts = self
-            .rpc_client
-            .get_token_accounts_by_owner(
+            .get_parsed_token_accounts(
                 &wallet_pubkey,
-                solana_client::rpc_request::TokenAccountsFilter::ProgramId(Pubkey::try_from(
-                    "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA",
-                )?),
+                "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA",
             )
             .await?;
-
         let token_2022_accounts = self
-            .rpc_client
-            .get_token_accounts_by_owner(
+            .get_parsed_token_accounts(
                 &wallet_pubkey,
-                solana_client::rpc_request::TokenAccountsFilter::ProgramId(Pubkey::try_from(
-                    "TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb",
-                )?),
+                "TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb",
             )
             .await?;
         token_accounts.extend(token_2022_accounts);
 
         let mut balances: Vec<TokenAccount> = Vec::new();
+        let mut unparsed_accounts = Vec::new();
 
         for keyed_account in token_accounts {
-            if let solana_account_decoder::UiAccountData::Json(parsed_account) =
+            let solana_account_decoder::UiAccountData::Json(parsed_account) =
                 keyed_account.account.data
-            {
-                if let serde_json::Value::Object(info) = parsed_account.parsed["info"].clone() {
-                    let mint = info["mint"].as_str().unwrap_or_default().to_string();
-                    let token_amount = &info["tokenAmount"];
-
-                    let balance = token_amount["uiAmount"].as_f64().unwrap_or(0.0);
-
-                    let is_nft = TokenService::is_nft(token_amount);
-
-                    if balance > 0.0 {
-                        let metadata = self.metadata_cache.get_token_metadata(&mint).await.ok();
-                        balances.push(TokenAccount {
-                            token_account: keyed_account.pubkey.to_string(),
-                            mint,
-                            amount: balance,
-                            is_nft,
-                            symbol: metadata.as_ref().and_then(|m| {
-                                m.symbol
-                                    .as_ref()
-                                    .map(|s| s.trim_end_matches(char::from(0)).to_string())
-                                    .clone()
-                            }),
-                            name: metadata.as_ref().and_then(|m| {
-                                m.name
-                                    .as_ref()
-                                    .map(|n| n.trim_end_matches(char::from(0)).to_string())
-                                    .clone()
-                            }),
-                            uri: metadata.as_ref().and_then(|m| {
-                                m.uri
-                                    .as_ref()
-                                    .map(|u| u.trim_end_matches(char::from(0)).to_string())
-                                    .clone()
-                            }),
-                            image: metadata.as_ref().and_then(|m| {
-                                m.image
-                                    .as_ref()
-                                    .map(|i| TokenService::encode_image_to_data_url(i))
-                            }),
-                        });
+            else {
+                warn!(
+                    "Token account {} of {} was not returned as jsonParsed data, leaving it out",
+                    keyed_account.pubkey, wallet_address
+                );
+                self.metrics.token_accounts_unparsed.inc();
+                unparsed_accounts.push(keyed_account.pubkey);
+                continue;
+            };
+            if let serde_json::Value::Object(info) = parsed_account.parsed["info"].clone() {
+                let mint = info["mint"].as_str().unwrap_or_default().to_string();
+                let token_amount = &info["tokenAmount"];
+
+                let balance = TokenService::token_balance(token_amount);
+
+                let is_nft = TokenService::is_nft(token_amount);
+                if let Some(decimals) = token_amount["decimals"]
+                    .as_u64()
+                    .and_then(|decimals| u8::try_from(decimals).ok())
+                {
+                    if !self
+                        .token_amount_cache
+                        .insert_mint_decimals(mint.clone(), decimals)
+                    {
+                        warn!(
+                            "Mint {} has {} decimals, more than supported, it can't be offered",
+                            mint, decimals
+                        );
                     }
                 }
+
+                if balance > Decimal::ZERO {
+                    balances.push(TokenAccount {
+                        token_account: keyed_account.pubkey.to_string(),
+                        mint,
+                        amount: balance,
+                        is_nft,
+                        symbol: None,
+                        name: None,
+                        uri: None,
+                        image: None,
+                    });
+                }
             }
         }
 
-        let token_amounts: HashMap<String, Decimal> = balances
+        // Every account counts towards the amounts available to offer, even the ones
+        // truncated from the response
+        let held_accounts = balances
             .iter()
-            .map(|b| {
-                (
-                    b.mint.clone(),
-                    Decimal::from_f64(b.amount).unwrap_or_default(),
-                )
+            .map(|balance| HeldTokenAccount {
+                account: balance.token_account.clone(),
+                mint: balance.mint.clone(),
+                amount: balance.amount,
             })
             .collect();
-        self.token_amount_cache
-            .insert_token_amounts(wallet_address.to_owned(), token_amounts);
-        Ok(balances)
+        if !self.token_amount_cache.insert_token_amounts_fetched_at(
+            wallet_address.to_owned(),
+            TokenService::available_amounts(&balances),
+            held_accounts,
+            fetch_started,
+        ) {
+            debug!(
+                "A newer fetch already cached the balances of {}, keeping those",
+                wallet_address
+            );
+        }
+
+        // Same order on every fetch so consecutive pages don't overlap
+        balances.sort_by(|a, b| a.token_account.cmp(&b.token_account));
+        let mut known_mints = HashSet::new();
+        for balance in &balances {
+            if self.metadata_cache.is_known(&balance.mint).await {
+                known_mints.insert(balance.mint.clone());
+            }
+        }
+        let max_tokens_returned = self.runtime_config.get().tokens.max_tokens_returned;
+        let (tokens, truncated) =
+            TokenService::truncate_tokens(balances, max_tokens_returned, &known_mints);
+        let (mut tokens, has_more) = TokenService::page_tokens(tokens, &page);
+
+        let image_budget = ImageBudget::new(self.runtime_config.get().tokens.image_budget_bytes);
+        for token in tokens.iter_mut() {
+            let metadata = self
+                .metadata_cache
+                .get_token_metadata_within(&token.mint, &image_budget)
+                .await
+                .ok();
+            token.symbol = metadata.as_ref().and_then(|m| {
+                m.symbol
+                    .as_ref()
+                    .map(|s| s.trim_end_matches(char::from(0)).to_string())
+            });
+            token.name = metadata.as_ref().and_then(|m| {
+                m.name
+                    .as_ref()
+                    .map(|n| n.trim_end_matches(char::from(0)).to_string())
+            });
+            token.uri = metadata.as_ref().and_then(|m| {
+                m.uri
+                    .as_ref()
+                    .map(|u| u.trim_end_matches(char::from(0)).to_string())
+            });
+            token.image = metadata.as_ref().and_then(|m| {
+                m.image
+                    .as_ref()
+                    .map(|i| TokenService::encode_image_to_data_url(i))
+            });
+        }
+        Ok(WalletTokens {
+            tokens,
+            truncated,
+            has_more,
+            unparsed_accounts,
+        })
+    }
+
+    /// Token accounts of `owner` under `program_id`, requested as `jsonParsed` data. The RPC
+    /// still falls back to binary data for accounts it cannot parse.
+    async fn get_parsed_token_accounts(
+        &self,
+        owner: &Pubkey,
+        program_id: &str,
+    ) -> Result<Vec<RpcKeyedAccount>, Box<dyn std::error::Error>> {
+        let config = RpcAccountInfoConfig {
+            encoding: Some(UiAccountEncoding::JsonParsed),
+            commitment: Some(self.rpc_client.commitment()),
+            data_slice: None,
+            min_context_slot: None,
+        };
+        let response: Response<Vec<RpcKeyedAccount>> = self
+            .rpc_client
+            .send(
+                RpcRequest::GetTokenAccountsByOwner,
+                serde_json::json!([
+                    owner.to_string(),
+                    RpcTokenAccountsFilter::ProgramId(program_id.to_string()),
+                    config
+                ]),
+            )
+            .await?;
+        Ok(response.value)
+    }
+
+    /// Keeps at most `max_tokens` accounts, preferring mints with known metadata, and reports
+    /// whether any were dropped. Truncation happens before metadata is resolved, so spam
+    /// accounts past the cap cost no metadata fetches.
+    fn truncate_tokens(
+        mut balances: Vec<TokenAccount>,
+        max_tokens: usize,
+        known_mints: &HashSet<String>,
+    ) -> (Vec<TokenAccount>, bool) {
+        if balances.len() <= max_tokens {
+            return (balances, false);
+        }
+        balances.sort_by_key(|balance| !known_mints.contains(&balance.mint));
+        balances.truncate(max_tokens);
+        (balances, true)
+    }
+
+    /// Accounts of `page`, and whether any come after it. Paging happens after truncation and
+    /// before metadata is resolved, so only the returned page costs metadata fetches.
+    fn page_tokens(tokens: Vec<TokenAccount>, page: &TokenPage) -> (Vec<TokenAccount>, bool) {
+        let remaining = tokens.len().saturating_sub(page.offset);
+        let limit = page.limit.unwrap_or(remaining);
+        let has_more = remaining > limit;
+        (
+            tokens.into_iter().skip(page.offset).take(limit).collect(),
+            has_more,
+        )
+    }
+
+    /// Amount available to offer per mint, summed over all of the wallet's token accounts.
+    fn available_amounts(balances: &[TokenAccount]) -> HashMap<String, Decimal> {
+        let mut token_amounts: HashMap<String, Decimal> = HashMap::new();
+        for balance in balances {
+            *token_amounts.entry(balance.mint.clone()).or_default() += balance.amount;
+        }
+        token_amounts
+    }
+
+    /// Exact balance of a `jsonParsed` token amount, its raw `amount` scaled by its `decimals`.
+    /// Zero when either is missing or the decimals exceed what `Decimal` can represent.
+    fn token_balance(token_amount: &serde_json::Value) -> Decimal {
+        let raw = token_amount["amount"]
+            .as_str()
+            .and_then(|amount| amount.parse::<u64>().ok());
+        let decimals = token_amount["decimals"]
+            .as_u64()
+            .and_then(|decimals| u32::try_from(decimals).ok());
+        let (Some(raw), Some(decimals)) = (raw, decimals) else {
+            return Decimal::ZERO;
+        };
+        Decimal::try_from_i128_with_scale(i128::from(raw), decimals)
+            .map(|balance| balance.normalize())
+            .unwrap_or_default()
     }
 
     fn is_nft(token_amount: &serde_json::Value) -> bool {
@@ -180,11 +416,31 @@ impl TokenService {
     }
 }
 
+#[derive(Debug, Default, Serialize)]
+pub struct WalletTokens {
+    pub tokens: Vec<TokenAccount>,
+    /// Set when the wallet holds more accounts than `max_tokens_returned`.
+    pub truncated: bool,
+    /// Set when accounts within `max_tokens_returned` come after the requested page.
+    pub has_more: bool,
+    /// Token accounts the RPC didn't return as `jsonParsed` data, left out of `tokens`.
+    pub unparsed_accounts: Vec<String>,
+}
+
+/// Window of a wallet's token accounts, all of them by default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokenPage {
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TokenAccount {
     pub token_account: String,
     pub mint: String,
-    pub amount: f64,
+    /// Sent as a decimal string, a JSON number would lose the digits past f64 precision.
+    #[serde(with = "rust_decimal::serde::str")]
+    pub amount: Decimal,
     pub is_nft: bool,
     pub name: Option<String>,
     pub symbol: Option<String>,
@@ -192,7 +448,20 @@ pub struct TokenAccount {
     pub image: Option<String>,
 }
 
+/// Metaplex fields exactly as stored on chain, padding included.
 #[derive(Debug, Serialize, Deserialize)]
+pub struct RawMetadataView {
+    pub mint: String,
+    pub update_authority: String,
+    pub name: String,
+    pub symbol: String,
+    pub uri: String,
+    pub seller_fee_basis_points: u16,
+    pub primary_sale_happened: bool,
+    pub is_mutable: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MetadataView {
     pub mint: String,
     pub name: Option<String>,
@@ -200,3 +469,354 @@ pub struct MetadataView {
     pub uri: Option<String>,
     pub image: Option<String>,
 }
+
+#[cfg(test)]
+impl TokenService {
+    /// Service resolving metadata of the given entities only, with every RPC call failing.
+    pub fn with_metadata(
+        entities: Vec<crate::metadata_repository::MetadataEntity>,
+        token_amount_cache: Arc<TokenAmountCache>,
+    ) -> Self {
+        let rpc_client = Arc::new(RpcClient::new_mock("fails".to_string()));
+        let metrics = Arc::new(Metrics::new());
+        let metadata_cache = MetadataCache::init(
+            crate::metadata_repository::InMemoryMetadataStore::with_entities(entities),
+            Arc::clone(&rpc_client),
+            crate::token_list::TokenList::default(),
+            &crate::config::MetadataConfig::default(),
+            Arc::clone(&metrics),
+        )
+        .unwrap();
+        TokenService::new(
+            metadata_cache,
+            rpc_client,
+            token_amount_cache,
+            metrics,
+            &TokensConfig::default(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn token_account(token_account: &str, mint: &str, amount: Decimal) -> TokenAccount {
+        TokenAccount {
+            token_account: token_account.to_string(),
+            mint: mint.to_string(),
+            amount,
+            is_nft: false,
+            name: None,
+            symbol: None,
+            uri: None,
+            image: None,
+        }
+    }
+
+    #[test]
+    fn should_truncate_wallet_over_cap_preferring_known_mints() {
+        let balances = (0..5)
+            .map(|i| token_account(&format!("Account{}", i), &format!("Mint{}", i), dec!(1)))
+            .collect();
+        let known_mints = HashSet::from(["Mint3".to_string(), "Mint4".to_string()]);
+
+        let (tokens, truncated) = TokenService::truncate_tokens(balances, 3, &known_mints);
+        assert!(truncated);
+        let mints: Vec<&str> = tokens.iter().map(|t| t.mint.as_str()).collect();
+        assert_eq!(mints, vec!["Mint3", "Mint4", "Mint0"]);
+
+        let balances = vec![token_account("Account0", "Mint0", dec!(1))];
+        let (tokens, truncated) = TokenService::truncate_tokens(balances, 3, &known_mints);
+        assert!(!truncated);
+        assert_eq!(tokens.len(), 1);
+    }
+
+    #[test]
+    fn pages_should_stop_at_the_end_of_the_accounts() {
+        let tokens = || {
+            (0..5)
+                .map(|i| token_account(&format!("Account{}", i), &format!("Mint{}", i), dec!(1)))
+                .collect::<Vec<_>>()
+        };
+        let page = |offset, limit| {
+            let (tokens, has_more) =
+                TokenService::page_tokens(tokens(), &TokenPage { offset, limit });
+            let accounts: Vec<String> = tokens.into_iter().map(|t| t.token_account).collect();
+            (accounts, has_more)
+        };
+
+        assert_eq!(
+            page(0, None),
+            (
+                tokens().into_iter().map(|t| t.token_account).collect(),
+                false
+            )
+        );
+        assert_eq!(
+            page(0, Some(2)),
+            (vec!["Account0".to_string(), "Account1".to_string()], true)
+        );
+        assert_eq!(
+            page(3, Some(2)),
+            (vec!["Account3".to_string(), "Account4".to_string()], false)
+        );
+        assert_eq!(page(4, Some(2)), (vec!["Account4".to_string()], false));
+        assert_eq!(page(4, None), (vec!["Account4".to_string()], false));
+        assert_eq!(page(5, Some(2)), (vec![], false));
+        assert_eq!(page(9, Some(2)), (vec![], false));
+        assert_eq!(page(0, Some(0)), (vec![], true));
+    }
+
+    #[test]
+    fn available_amounts_should_sum_accounts_of_same_mint() {
+        let balances = vec![
+            token_account("Account1", "TokenA", dec!(1.5)),
+            token_account("Account2", "TokenA", dec!(2.25)),
+            token_account("Account3", "TokenB", dec!(4)),
+        ];
+
+        let amounts = TokenService::available_amounts(&balances);
+        assert_eq!(amounts.len(), 2);
+        assert_eq!(amounts["TokenA"], dec!(3.75));
+        assert_eq!(amounts["TokenB"], dec!(4));
+    }
+
+    /// `jsonParsed` Token program account holding 1.5 of `mint`.
+    fn parsed_token_account(pubkey: &str, mint: &str) -> serde_json::Value {
+        parsed_token_account_with_amount(pubkey, mint, "1500000", 6, 1.5)
+    }
+
+    fn parsed_token_account_with_amount(
+        pubkey: &str,
+        mint: &str,
+        amount: &str,
+        decimals: u8,
+        ui_amount: f64,
+    ) -> serde_json::Value {
+        serde_json::json!({
+            "pubkey": pubkey,
+            "account": {
+                "lamports": 2039280,
+                "data": {
+                    "program": "spl-token",
+                    "parsed": {
+                        "type": "account",
+                        "info": {
+                            "mint": mint,
+                            "tokenAmount": {
+                                "amount": amount,
+                                "decimals": decimals,
+                                "uiAmount": ui_amount,
+                                "uiAmountString": ui_amount.to_string()
+                            }
+                        }
+                    },
+                    "space": 165
+                },
+                "owner": "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA",
+                "executable": false,
+                "rentEpoch": 0,
+                "space": 165
+            }
+        })
+    }
+
+    /// Token service over a local RPC answering getTokenAccountsByOwner with `accounts` for the
+    /// Token program only, counting the requests in `requests`.
+    async fn serve_token_accounts(
+        accounts: serde_json::Value,
+        requests: Arc<AtomicUsize>,
+        metrics: Arc<Metrics>,
+    ) -> TokenService {
+        use axum::{routing::post, Json, Router};
+        use std::future::IntoFuture;
+
+        let rpc = Router::new().route(
+            "/",
+            post(move |Json(request): Json<serde_json::Value>| async move {
+                requests.fetch_add(1, Ordering::SeqCst);
+                assert_eq!(request["params"][2]["encoding"], "jsonParsed");
+                let value = if request["params"][1]["programId"]
+                    == "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA"
+                {
+                    accounts
+                } else {
+                    serde_json::json!([])
+                };
+                Json(serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "result": { "context": { "slot": 1 }, "value": value }
+                }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(axum::serve(listener, rpc).into_future());
+
+        let metadata_cache = MetadataCache::init(
+            crate::metadata_repository::InMemoryMetadataStore::with_entities(vec![]),
+            Arc::new(RpcClient::new_mock("fails".to_string())),
+            crate::token_list::TokenList::default(),
+            &crate::config::MetadataConfig::default(),
+            Arc::clone(&metrics),
+        )
+        .unwrap();
+        TokenService::new(
+            metadata_cache,
+            Arc::new(RpcClient::new(rpc_url)),
+            Arc::new(TokenAmountCache::init()),
+            metrics,
+            &TokensConfig::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn accounts_without_parsed_data_should_be_reported() {
+        let parsed_account = Pubkey::new_unique().to_string();
+        let binary_account = Pubkey::new_unique().to_string();
+        let accounts = serde_json::json!([
+            parsed_token_account(&parsed_account, &Pubkey::new_unique().to_string()),
+            {
+                "pubkey": binary_account,
+                "account": {
+                    "lamports": 2039280,
+                    "data": ["AAAA", "base64"],
+                    "owner": "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA",
+                    "executable": false,
+                    "rentEpoch": 0,
+                    "space": 165
+                }
+            }
+        ]);
+        let metrics = Arc::new(Metrics::new());
+        let token_service =
+            serve_token_accounts(accounts, Arc::default(), Arc::clone(&metrics)).await;
+
+        let wallet_tokens = token_service
+            .fetch_tokens(&Pubkey::new_unique().to_string(), TokenPage::default())
+            .await
+            .unwrap();
+        assert_eq!(wallet_tokens.tokens.len(), 1);
+        assert_eq!(wallet_tokens.tokens[0].token_account, parsed_account);
+        assert_eq!(wallet_tokens.unparsed_accounts, vec![binary_account]);
+        assert_eq!(metrics.token_accounts_unparsed.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn available_amounts_should_be_fetched_once_when_not_cached() {
+        let mint = Pubkey::new_unique().to_string();
+        let accounts = serde_json::json!([parsed_token_account(
+            &Pubkey::new_unique().to_string(),
+            &mint
+        )]);
+        let requests = Arc::new(AtomicUsize::new(0));
+        let token_service =
+            serve_token_accounts(accounts, Arc::clone(&requests), Arc::new(Metrics::new())).await;
+        let wallet = Pubkey::new_unique().to_string();
+
+        for _ in 0..2 {
+            let available = token_service.get_available_amounts(&wallet).await.unwrap();
+            assert_eq!(available, HashMap::from([(mint.clone(), dec!(1.5))]));
+        }
+        // One fetch covers both token programs
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn paged_fetch_should_cache_amounts_of_every_account() {
+        let mints: Vec<String> = (0..3).map(|_| Pubkey::new_unique().to_string()).collect();
+        let accounts = serde_json::Value::Array(
+            mints
+                .iter()
+                .map(|mint| parsed_token_account(&Pubkey::new_unique().to_string(), mint))
+                .collect(),
+        );
+        let token_service =
+            serve_token_accounts(accounts, Arc::default(), Arc::new(Metrics::new())).await;
+        let wallet = Pubkey::new_unique().to_string();
+
+        let first = token_service
+            .fetch_tokens(
+                &wallet,
+                TokenPage {
+                    offset: 0,
+                    limit: Some(2),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(first.tokens.len(), 2);
+        assert!(first.has_more);
+        let rest = token_service
+            .fetch_tokens(
+                &wallet,
+                TokenPage {
+                    offset: 2,
+                    limit: Some(2),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(rest.tokens.len(), 1);
+        assert!(!rest.has_more);
+
+        let mut paged: Vec<String> = first
+            .tokens
+            .into_iter()
+            .chain(rest.tokens)
+            .map(|t| t.mint)
+            .collect();
+        paged.sort();
+        let mut expected = mints.clone();
+        expected.sort();
+        assert_eq!(paged, expected);
+        assert_eq!(
+            token_service
+                .token_amount_cache
+                .get_token_amounts(&wallet)
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn high_decimal_balance_should_be_exact() {
+        let mint = Pubkey::new_unique().to_string();
+        let raw = 12_345_678_901_234_567_891u64;
+        let ui_amount = raw as f64 / 1e18;
+        let accounts = serde_json::json!([parsed_token_account_with_amount(
+            &Pubkey::new_unique().to_string(),
+            &mint,
+            &raw.to_string(),
+            18,
+            ui_amount
+        )]);
+        let token_service =
+            serve_token_accounts(accounts, Arc::default(), Arc::new(Metrics::new())).await;
+        let wallet = Pubkey::new_unique().to_string();
+
+        let exact = dec!(12.345678901234567891);
+        // What reading `uiAmount` used to yield
+        assert_ne!(Decimal::from_f64(ui_amount).unwrap(), exact);
+
+        let wallet_tokens = token_service
+            .fetch_tokens(&wallet, TokenPage::default())
+            .await
+            .unwrap();
+        assert_eq!(wallet_tokens.tokens[0].amount, exact);
+        assert_eq!(
+            token_service
+                .token_amount_cache
+                .get_token_amounts(&wallet)
+                .unwrap(),
+            HashMap::from([(mint, exact)])
+        );
+    }
+}
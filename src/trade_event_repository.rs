@@ -0,0 +1,285 @@
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::db::PostgreSqlClient;
+use crate::schema::trade_events;
+use crate::schema::trade_events::dsl::trade_events as trade_events_table;
+use crate::schema::trade_events::{id, session_id};
+
+pub trait TradeEventStore: Send + Sync {
+    fn insert_event(&self, new_event: NewTradeEvent) -> Result<(), Box<dyn std::error::Error>>;
+    /// Events of the session, oldest first.
+    fn get_events_by_session(
+        &self,
+        trade_session_id: &Uuid,
+    ) -> Result<Vec<TradeEventEntity>, Box<dyn std::error::Error>>;
+}
+
+pub struct TradeEventRepository {
+    db_client: Arc<PostgreSqlClient>,
+}
+
+impl TradeEventRepository {
+    pub fn new(db_client: Arc<PostgreSqlClient>) -> Self {
+        TradeEventRepository { db_client }
+    }
+}
+
+impl TradeEventStore for TradeEventRepository {
+    fn insert_event(&self, new_event: NewTradeEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.db_client.get_db_connection()?;
+        diesel::insert_into(trade_events_table)
+            .values(&new_event)
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    fn get_events_by_session(
+        &self,
+        trade_session_id: &Uuid,
+    ) -> Result<Vec<TradeEventEntity>, Box<dyn std::error::Error>> {
+        let mut conn = self.db_client.get_read_db_connection()?;
+        Ok(trade_events_table
+            .filter(session_id.eq(trade_session_id))
+            .order(id.asc())
+            .load::<TradeEventEntity>(&mut conn)?)
+    }
+}
+
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone)]
+pub struct TradeEventEntity {
+    pub id: i64,
+    pub session_id: Uuid,
+    pub actor: String,
+    pub action: String,
+    pub payload: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = trade_events)]
+pub struct NewTradeEvent {
+    pub session_id: Uuid,
+    pub actor: String,
+    pub action: String,
+    pub payload: Option<serde_json::Value>,
+}
+
+/// Queues audit events and writes them from a background task, so recording an action never
+/// waits on the database.
+#[derive(Clone)]
+pub struct AuditLog {
+    sender: Option<mpsc::UnboundedSender<NewTradeEvent>>,
+}
+
+impl AuditLog {
+    /// Audit log dropping every event.
+    pub fn disabled() -> Self {
+        AuditLog { sender: None }
+    }
+
+    /// Writes events in the background until `shutdown`, then writes the ones still queued
+    /// before the returned task ends, so awaiting it loses no event.
+    pub fn spawn(
+        store: Arc<dyn TradeEventStore>,
+        shutdown: CancellationToken,
+    ) -> (Self, JoinHandle<()>) {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<NewTradeEvent>();
+        let writer = tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    event = receiver.recv() => event,
+                    _ = shutdown.cancelled() => {
+                        receiver.close();
+                        receiver.recv().await
+                    }
+                };
+                let Some(event) = event else {
+                    break;
+                };
+                let store = Arc::clone(&store);
+                let result = tokio::task::spawn_blocking(move || {
+                    store.insert_event(event).map_err(|e| e.to_string())
+                })
+                .await;
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("Unable to write trade event: {}", e),
+                    Err(e) => warn!("Trade event writer failed: {}", e),
+                }
+            }
+        });
+        (
+            AuditLog {
+                sender: Some(sender),
+            },
+            writer,
+        )
+    }
+
+    pub fn record(&self, event: NewTradeEvent) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(event);
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct InMemoryTradeEventStore {
+    pub events: Mutex<Vec<TradeEventEntity>>,
+}
+
+#[cfg(test)]
+impl TradeEventStore for InMemoryTradeEventStore {
+    fn insert_event(&self, new_event: NewTradeEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let mut events = self.events.lock().unwrap();
+        let event_id = events.len() as i64 + 1;
+        events.push(TradeEventEntity {
+            id: event_id,
+            session_id: new_event.session_id,
+            actor: new_event.actor,
+            action: new_event.action,
+            payload: new_event.payload,
+            created_at: Utc::now(),
+        });
+        Ok(())
+    }
+
+    fn get_events_by_session(
+        &self,
+        trade_session_id: &Uuid,
+    ) -> Result<Vec<TradeEventEntity>, Box<dyn std::error::Error>> {
+        Ok(self
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.session_id == *trade_session_id)
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn audit_log_should_write_events_in_background() {
+        let store = Arc::new(InMemoryTradeEventStore::default());
+        let (audit_log, _) = AuditLog::spawn(
+            Arc::clone(&store) as Arc<dyn TradeEventStore>,
+            CancellationToken::new(),
+        );
+        let trade_session_id = Uuid::new_v4();
+
+        for action in ["TokensOffered", "TradeAccepted"] {
+            audit_log.record(NewTradeEvent {
+                session_id: trade_session_id,
+                actor: "Alice".to_string(),
+                action: action.to_string(),
+                payload: None,
+            });
+        }
+
+        for _ in 0..100 {
+            if store.events.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        let actions: Vec<String> = store
+            .get_events_by_session(&trade_session_id)
+            .unwrap()
+            .into_iter()
+            .map(|event| event.action)
+            .collect();
+        assert_eq!(actions, vec!["TokensOffered", "TradeAccepted"]);
+    }
+
+    #[tokio::test]
+    async fn audit_log_should_write_queued_events_on_shutdown() {
+        let store = Arc::new(InMemoryTradeEventStore::default());
+        let shutdown = CancellationToken::new();
+        let (audit_log, writer) = AuditLog::spawn(
+            Arc::clone(&store) as Arc<dyn TradeEventStore>,
+            shutdown.clone(),
+        );
+        let trade_session_id = Uuid::new_v4();
+
+        for _ in 0..50 {
+            audit_log.record(NewTradeEvent {
+                session_id: trade_session_id,
+                actor: "Alice".to_string(),
+                action: "TokensOffered".to_string(),
+                payload: None,
+            });
+        }
+        shutdown.cancel();
+        writer.await.unwrap();
+
+        assert_eq!(
+            store
+                .get_events_by_session(&trade_session_id)
+                .unwrap()
+                .len(),
+            50
+        );
+    }
+}
+
+#[cfg(all(test, feature = "db-tests"))]
+mod db_tests {
+    use super::*;
+
+    #[test]
+    fn should_insert_and_query_events_by_session() {
+        let repository = TradeEventRepository::new(Arc::new(PostgreSqlClient::init_test()));
+        let trade_session_id = Uuid::new_v4();
+        let other_session_id = Uuid::new_v4();
+
+        for (event_session_id, actor, action) in [
+            (trade_session_id, "Alice", "TokensOffered"),
+            (other_session_id, "Carol", "TokensOffered"),
+            (trade_session_id, "Bob", "TradeAccepted"),
+        ] {
+            repository
+                .insert_event(NewTradeEvent {
+                    session_id: event_session_id,
+                    actor: actor.to_string(),
+                    action: action.to_string(),
+                    payload: Some(serde_json::json!({ "tokenMint": "TokenA" })),
+                })
+                .unwrap();
+        }
+
+        let events = repository.get_events_by_session(&trade_session_id).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            (events[0].actor.as_str(), events[0].action.as_str()),
+            ("Alice", "TokensOffered")
+        );
+        assert_eq!(
+            (events[1].actor.as_str(), events[1].action.as_str()),
+            ("Bob", "TradeAccepted")
+        );
+        assert_eq!(
+            events[0].payload,
+            Some(serde_json::json!({ "tokenMint": "TokenA" }))
+        );
+        assert!(repository
+            .get_events_by_session(&Uuid::new_v4())
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -1,40 +1,227 @@
+use crate::schema::trades::dsl::trades as trades_table;
+use crate::schema::trades::{id, status, status_history};
+use crate::{db::PostgreSqlClient, schema::trades};
 use chrono::{DateTime, Utc};
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
-use crate::schema::trades::dsl::trades as trades_table;
-use crate::schema::trades::id;
-use crate::{db::PostgreSqlClient, schema::trades};
 use std::str::FromStr;
 use std::sync::Arc;
+#[cfg(test)]
+use std::{collections::HashMap, sync::Mutex};
+use uuid::Uuid;
 pub struct TradeRepository {
-    db_client: Arc<PostgreSqlClient>
+    db_client: Arc<PostgreSqlClient>,
+}
+
+pub trait TradeStore: Send + Sync {
+    fn insert_trade(&self, new_trade: NewTrade) -> Result<Uuid, Box<dyn std::error::Error>>;
+    fn get_trade(&self, trade_id: &Uuid)
+        -> Result<Option<TradeEntity>, Box<dyn std::error::Error>>;
+    fn get_trades_by_status(
+        &self,
+        trade_status: &TradeStatus,
+    ) -> Result<Vec<TradeEntity>, Box<dyn std::error::Error>>;
+    fn update_trade_status(
+        &self,
+        trade_id: &Uuid,
+        trade_status: &TradeStatus,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    /// Moves the trade to the terminal `trade_status` unless it already is in one of
+    /// `TradeStatus::TERMINAL`, returns whether it was moved.
+    fn finalize_trade(
+        &self,
+        trade_id: &Uuid,
+        trade_status: &TradeStatus,
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+    /// Sets the top level keys of `details` in the trade's `status_details`, keeping the others.
+    fn merge_status_details(
+        &self,
+        trade_id: &Uuid,
+        details: &serde_json::Value,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    /// Stores the live session snapshot as `status_details.session` and its notes as
+    /// `status_details.memos`, its status and the counterparty once known, unless a snapshot of
+    /// a later revision is stored already. A trade in one of `TradeStatus::ENDED` keeps its
+    /// status.
+    fn update_trade(
+        &self,
+        trade_id: &Uuid,
+        update: &TradeUpdate,
+    ) -> Result<(), Box<dyn std::error::Error>>;
 }
 
 impl TradeRepository {
     pub fn new(db_client: Arc<PostgreSqlClient>) -> Self {
         TradeRepository { db_client }
     }
+}
 
-    pub fn insert_trade(&self, new_trade: NewTrade) -> Result<Uuid, Box<dyn std::error::Error>> {
+impl TradeStore for TradeRepository {
+    fn insert_trade(&self, new_trade: NewTrade) -> Result<Uuid, Box<dyn std::error::Error>> {
         let mut conn = self.db_client.get_db_connection()?;
+        let history = status_history_entry(&new_trade.status);
         let inserted_id = diesel::insert_into(trades_table)
-            .values(&new_trade)
+            .values((&new_trade, status_history.eq(history)))
             .returning(id)
             .get_result(&mut conn)?;
         Ok(inserted_id)
     }
+
+    fn get_trade(
+        &self,
+        trade_id: &Uuid,
+    ) -> Result<Option<TradeEntity>, Box<dyn std::error::Error>> {
+        let mut conn = self.db_client.get_read_db_connection()?;
+        Ok(trades_table
+            .find(trade_id)
+            .first::<TradeEntity>(&mut conn)
+            .optional()?)
+    }
+
+    fn get_trades_by_status(
+        &self,
+        trade_status: &TradeStatus,
+    ) -> Result<Vec<TradeEntity>, Box<dyn std::error::Error>> {
+        let mut conn = self.db_client.get_read_db_connection()?;
+        Ok(trades_table
+            .filter(status.eq(trade_status.as_str()))
+            .load::<TradeEntity>(&mut conn)?)
+    }
+
+    fn update_trade_status(
+        &self,
+        trade_id: &Uuid,
+        trade_status: &TradeStatus,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.db_client.get_db_connection()?;
+        diesel::update(trades_table.find(trade_id))
+            .set((
+                status.eq(trade_status.as_str()),
+                status_history
+                    .eq(status_history.concat(status_history_entry(trade_status.as_str()))),
+            ))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    fn finalize_trade(
+        &self,
+        trade_id: &Uuid,
+        trade_status: &TradeStatus,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut conn = self.db_client.get_db_connection()?;
+        let updated = diesel::update(
+            trades_table.find(trade_id).filter(
+                status.ne_all(
+                    TradeStatus::TERMINAL
+                        .iter()
+                        .map(TradeStatus::as_str)
+                        .collect::<Vec<_>>(),
+                ),
+            ),
+        )
+        .set((
+            status.eq(trade_status.as_str()),
+            status_history.eq(status_history.concat(status_history_entry(trade_status.as_str()))),
+        ))
+        .execute(&mut conn)?;
+        Ok(updated > 0)
+    }
+
+    fn merge_status_details(
+        &self,
+        trade_id: &Uuid,
+        details: &serde_json::Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.db_client.get_db_connection()?;
+        diesel::sql_query(
+            "UPDATE trades SET status_details = COALESCE(status_details, '{}'::jsonb) || $1 WHERE id = $2",
+        )
+        .bind::<diesel::sql_types::Jsonb, _>(details)
+        .bind::<diesel::sql_types::Uuid, _>(trade_id)
+        .execute(&mut conn)?;
+        Ok(())
+    }
+
+    fn update_trade(
+        &self,
+        trade_id: &Uuid,
+        update: &TradeUpdate,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.db_client.get_db_connection()?;
+        // Snapshots are written from background tasks, a late older one must not win, nor may
+        // one written after the trade ended
+        diesel::sql_query(
+            "UPDATE trades SET counterparty = COALESCE($1, counterparty), \
+             status_details = COALESCE(status_details, '{}'::jsonb) || jsonb_build_object('session', $2, 'memos', $5), \
+             status_history = CASE WHEN status = ANY($8) OR status = $6 THEN status_history ELSE status_history || $7 END, \
+             status = CASE WHEN status = ANY($8) THEN status ELSE $6 END \
+             WHERE id = $3 AND COALESCE((status_details->'session'->>'revision')::bigint, -1) < $4",
+        )
+        .bind::<diesel::sql_types::Nullable<diesel::sql_types::Text>, _>(&update.counterparty)
+        .bind::<diesel::sql_types::Jsonb, _>(&update.session)
+        .bind::<diesel::sql_types::Uuid, _>(trade_id)
+        .bind::<diesel::sql_types::BigInt, _>(update.revision as i64)
+        .bind::<diesel::sql_types::Jsonb, _>(&update.memos)
+        .bind::<diesel::sql_types::Text, _>(update.status.as_str())
+        .bind::<diesel::sql_types::Jsonb, _>(status_history_entry(update.status.as_str()))
+        .bind::<diesel::sql_types::Array<diesel::sql_types::Text>, _>(
+            TradeStatus::ENDED.iter().map(TradeStatus::as_str).collect::<Vec<_>>(),
+        )
+        .execute(&mut conn)?;
+        Ok(())
+    }
 }
 
-#[derive(Queryable, Serialize, Deserialize, Debug)]
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone)]
 pub struct TradeEntity {
     pub id: Uuid,
     pub initiator: String,
     pub counterparty: Option<String>,
-    pub status: String, 
+    pub status: String,
     pub status_details: Option<serde_json::Value>,
-    pub created_at: DateTime<Utc>,
-    pub updated_at: DateTime<Utc>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    /// `StatusTransition`s in the order they happened.
+    pub status_history: serde_json::Value,
+}
+
+impl TradeEntity {
+    pub fn status_transitions(&self) -> Vec<StatusTransition> {
+        serde_json::from_value(self.status_history.clone()).unwrap_or_default()
+    }
+}
+
+/// A status the trade entered and when.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StatusTransition {
+    pub status: String,
+    pub at: DateTime<Utc>,
+}
+
+/// `status_history` entry for a status entered now, as a one element array so it can be
+/// appended with jsonb `||` without rewriting the existing entries.
+fn status_history_entry(trade_status: &str) -> serde_json::Value {
+    serde_json::json!([StatusTransition {
+        status: trade_status.to_string(),
+        at: Utc::now(),
+    }])
+}
+
+/// Snapshot of a live trade session, see `TradeStore::update_trade`.
+#[derive(Debug, Clone)]
+pub struct TradeUpdate {
+    /// Set once the second participant joined, `None` keeps the stored counterparty.
+    pub counterparty: Option<String>,
+    /// Increases with every snapshot of the session.
+    pub revision: u64,
+    /// Status of the session, stored in the `status` column.
+    pub status: TradeStatus,
+    /// The snapshot, carrying `revision` as its `revision` key.
+    pub session: serde_json::Value,
+    /// Trade notes of the participants, stored as `status_details.memos` along with the snapshot
+    /// so an older revision can't overwrite newer notes either.
+    pub memos: serde_json::Value,
 }
 
 #[derive(Insertable, Serialize, Deserialize, Debug)]
@@ -46,18 +233,68 @@ pub struct NewTrade {
     pub status_details: Option<serde_json::Value>,
 }
 
-
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TradeStatus {
     Created,
+    /// The participants are negotiating, no one accepted yet or an offer changed since.
+    Trading,
+    OneUserAccepted,
+    Accepted,
+    TransactionCreated,
+    OneUserSigned,
+    FullySigned,
+    TransactionSent,
     Expired,
+    /// The trade transaction confirmed on chain.
+    Completed,
+    /// The trade transaction failed, expired or was dropped.
+    Failed,
+    /// A participant backed out before the transaction was sent.
+    Cancelled,
 }
 
 impl TradeStatus {
-    pub fn as_str(&self) -> &str {
+    /// Statuses a trade never leaves.
+    pub const TERMINAL: [TradeStatus; 3] = [
+        TradeStatus::Completed,
+        TradeStatus::Failed,
+        TradeStatus::Cancelled,
+    ];
+
+    /// Statuses of a trade that is over, the `TERMINAL` ones and `Expired`.
+    pub const ENDED: [TradeStatus; 4] = [
+        TradeStatus::Completed,
+        TradeStatus::Failed,
+        TradeStatus::Cancelled,
+        TradeStatus::Expired,
+    ];
+
+    /// Statuses of a trade that may still be negotiated or settled.
+    pub const ACTIVE: [TradeStatus; 8] = [
+        TradeStatus::Created,
+        TradeStatus::Trading,
+        TradeStatus::OneUserAccepted,
+        TradeStatus::Accepted,
+        TradeStatus::TransactionCreated,
+        TradeStatus::OneUserSigned,
+        TradeStatus::FullySigned,
+        TradeStatus::TransactionSent,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
         match self {
             TradeStatus::Created => "Created",
+            TradeStatus::Trading => "Trading",
+            TradeStatus::OneUserAccepted => "OneUserAccepted",
+            TradeStatus::Accepted => "Accepted",
+            TradeStatus::TransactionCreated => "TransactionCreated",
+            TradeStatus::OneUserSigned => "OneUserSigned",
+            TradeStatus::FullySigned => "FullySigned",
+            TradeStatus::TransactionSent => "TransactionSent",
             TradeStatus::Expired => "Expired",
+            TradeStatus::Completed => "Completed",
+            TradeStatus::Failed => "Failed",
+            TradeStatus::Cancelled => "Cancelled",
         }
     }
 }
@@ -68,7 +305,17 @@ impl FromStr for TradeStatus {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "Created" => Ok(TradeStatus::Created),
+            "Trading" => Ok(TradeStatus::Trading),
+            "OneUserAccepted" => Ok(TradeStatus::OneUserAccepted),
+            "Accepted" => Ok(TradeStatus::Accepted),
+            "TransactionCreated" => Ok(TradeStatus::TransactionCreated),
+            "OneUserSigned" => Ok(TradeStatus::OneUserSigned),
+            "FullySigned" => Ok(TradeStatus::FullySigned),
+            "TransactionSent" => Ok(TradeStatus::TransactionSent),
             "Expired" => Ok(TradeStatus::Expired),
+            "Completed" => Ok(TradeStatus::Completed),
+            "Failed" => Ok(TradeStatus::Failed),
+            "Cancelled" => Ok(TradeStatus::Cancelled),
             _ => Err(format!("Invalid trade status: {}", s)),
         }
     }
@@ -79,3 +326,351 @@ impl AsRef<str> for TradeStatus {
         self.as_str()
     }
 }
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct InMemoryTradeStore {
+    pub trades: Mutex<HashMap<Uuid, TradeEntity>>,
+    /// `finalize_trade` fails this many more times, as if the database were unreachable.
+    pub failing_finalizations: Mutex<usize>,
+}
+
+#[cfg(test)]
+impl InMemoryTradeStore {
+    pub fn with_trades(trades: Vec<TradeEntity>) -> Self {
+        InMemoryTradeStore {
+            trades: Mutex::new(trades.into_iter().map(|t| (t.id, t)).collect()),
+            failing_finalizations: Mutex::new(0),
+        }
+    }
+}
+
+#[cfg(test)]
+impl TradeStore for InMemoryTradeStore {
+    fn insert_trade(&self, new_trade: NewTrade) -> Result<Uuid, Box<dyn std::error::Error>> {
+        let trade_id = Uuid::new_v4();
+        let now = Utc::now();
+        self.trades.lock().unwrap().insert(
+            trade_id,
+            TradeEntity {
+                id: trade_id,
+                initiator: new_trade.initiator,
+                counterparty: new_trade.counterparty,
+                status_history: status_history_entry(&new_trade.status),
+                status: new_trade.status,
+                status_details: new_trade.status_details,
+                created_at: Some(now),
+                updated_at: Some(now),
+            },
+        );
+        Ok(trade_id)
+    }
+
+    fn get_trade(
+        &self,
+        trade_id: &Uuid,
+    ) -> Result<Option<TradeEntity>, Box<dyn std::error::Error>> {
+        Ok(self.trades.lock().unwrap().get(trade_id).cloned())
+    }
+
+    fn get_trades_by_status(
+        &self,
+        trade_status: &TradeStatus,
+    ) -> Result<Vec<TradeEntity>, Box<dyn std::error::Error>> {
+        Ok(self
+            .trades
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|t| t.status == trade_status.as_str())
+            .cloned()
+            .collect())
+    }
+
+    fn update_trade_status(
+        &self,
+        trade_id: &Uuid,
+        trade_status: &TradeStatus,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(trade) = self.trades.lock().unwrap().get_mut(trade_id) {
+            trade.status = trade_status.as_str().to_string();
+            append_status_history(trade, trade_status);
+            trade.updated_at = Some(Utc::now());
+        }
+        Ok(())
+    }
+
+    fn finalize_trade(
+        &self,
+        trade_id: &Uuid,
+        trade_status: &TradeStatus,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut failing_finalizations = self.failing_finalizations.lock().unwrap();
+        if *failing_finalizations > 0 {
+            *failing_finalizations -= 1;
+            return Err("database is unreachable".into());
+        }
+        match self.trades.lock().unwrap().get_mut(trade_id) {
+            Some(trade)
+                if !TradeStatus::TERMINAL
+                    .iter()
+                    .any(|s| trade.status == s.as_str()) =>
+            {
+                trade.status = trade_status.as_str().to_string();
+                append_status_history(trade, trade_status);
+                trade.updated_at = Some(Utc::now());
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn merge_status_details(
+        &self,
+        trade_id: &Uuid,
+        details: &serde_json::Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(trade) = self.trades.lock().unwrap().get_mut(trade_id) {
+            let merged = trade
+                .status_details
+                .get_or_insert_with(|| serde_json::json!({}));
+            if let (Some(merged), Some(details)) = (merged.as_object_mut(), details.as_object()) {
+                merged.extend(details.clone());
+            }
+            trade.updated_at = Some(Utc::now());
+        }
+        Ok(())
+    }
+
+    fn update_trade(
+        &self,
+        trade_id: &Uuid,
+        update: &TradeUpdate,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(trade) = self.trades.lock().unwrap().get_mut(trade_id) {
+            let details = trade
+                .status_details
+                .get_or_insert_with(|| serde_json::json!({}));
+            let stored_revision = details["session"]["revision"].as_u64();
+            if stored_revision.is_some_and(|revision| revision >= update.revision) {
+                return Ok(());
+            }
+            if let Some(details) = details.as_object_mut() {
+                details.insert("session".to_string(), update.session.clone());
+                details.insert("memos".to_string(), update.memos.clone());
+            }
+            if update.counterparty.is_some() {
+                trade.counterparty = update.counterparty.clone();
+            }
+            let ended = TradeStatus::ENDED
+                .iter()
+                .any(|s| trade.status == s.as_str());
+            if !ended && trade.status != update.status.as_str() {
+                trade.status = update.status.as_str().to_string();
+                append_status_history(trade, &update.status);
+            }
+            trade.updated_at = Some(Utc::now());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn append_status_history(trade: &mut TradeEntity, trade_status: &TradeStatus) {
+    if let (Some(history), serde_json::Value::Array(entry)) = (
+        trade.status_history.as_array_mut(),
+        status_history_entry(trade_status.as_str()),
+    ) {
+        history.extend(entry);
+    }
+}
+
+#[cfg(all(test, feature = "db-tests"))]
+mod db_tests {
+    use super::*;
+
+    fn new_trade(initiator: &str) -> NewTrade {
+        NewTrade {
+            initiator: initiator.to_string(),
+            counterparty: None,
+            status: TradeStatus::Created.as_str().to_string(),
+            status_details: None,
+        }
+    }
+
+    #[test]
+    fn should_insert_and_get_trade() {
+        let repository = TradeRepository::new(Arc::new(PostgreSqlClient::init_test()));
+
+        let trade_id = repository.insert_trade(new_trade("Alice")).unwrap();
+        let trade = repository
+            .get_trade(&trade_id)
+            .unwrap()
+            .expect("Trade not found");
+
+        assert_eq!(trade.id, trade_id);
+        assert_eq!(trade.initiator, "Alice");
+        assert_eq!(trade.status, TradeStatus::Created.as_str());
+        assert!(trade.created_at.is_some());
+        assert!(repository.get_trade(&Uuid::new_v4()).unwrap().is_none());
+    }
+
+    #[test]
+    fn should_list_and_update_trades_by_status() {
+        let repository = TradeRepository::new(Arc::new(PostgreSqlClient::init_test()));
+        let first = repository.insert_trade(new_trade("Alice")).unwrap();
+        let second = repository.insert_trade(new_trade("Bob")).unwrap();
+
+        repository
+            .update_trade_status(&first, &TradeStatus::Expired)
+            .unwrap();
+
+        let created: Vec<Uuid> = repository
+            .get_trades_by_status(&TradeStatus::Created)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.id)
+            .collect();
+        let expired: Vec<Uuid> = repository
+            .get_trades_by_status(&TradeStatus::Expired)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.id)
+            .collect();
+        assert!(created.contains(&second));
+        assert!(!created.contains(&first));
+        assert!(expired.contains(&first));
+    }
+
+    #[test]
+    fn should_finalize_trade_once() {
+        let repository = TradeRepository::new(Arc::new(PostgreSqlClient::init_test()));
+        let trade_id = repository.insert_trade(new_trade("Alice")).unwrap();
+
+        assert!(repository
+            .finalize_trade(&trade_id, &TradeStatus::Completed)
+            .unwrap());
+        assert!(!repository
+            .finalize_trade(&trade_id, &TradeStatus::Failed)
+            .unwrap());
+
+        let trade = repository.get_trade(&trade_id).unwrap().unwrap();
+        assert_eq!(trade.status, TradeStatus::Completed.as_str());
+    }
+
+    #[test]
+    fn transitions_should_be_recorded_in_order() {
+        let repository = TradeRepository::new(Arc::new(PostgreSqlClient::init_test()));
+        let trade_id = repository.insert_trade(new_trade("Alice")).unwrap();
+
+        repository
+            .update_trade_status(&trade_id, &TradeStatus::Expired)
+            .unwrap();
+        repository
+            .finalize_trade(&trade_id, &TradeStatus::Failed)
+            .unwrap();
+        // Already terminal, must not be recorded
+        repository
+            .finalize_trade(&trade_id, &TradeStatus::Completed)
+            .unwrap();
+
+        let transitions = repository
+            .get_trade(&trade_id)
+            .unwrap()
+            .unwrap()
+            .status_transitions();
+        let statuses: Vec<&str> = transitions.iter().map(|t| t.status.as_str()).collect();
+        assert_eq!(statuses, vec!["Created", "Expired", "Failed"]);
+        assert!(transitions.windows(2).all(|pair| pair[0].at <= pair[1].at));
+    }
+
+    #[test]
+    fn should_merge_status_details() {
+        let repository = TradeRepository::new(Arc::new(PostgreSqlClient::init_test()));
+        let trade_id = repository.insert_trade(new_trade("Alice")).unwrap();
+
+        repository
+            .merge_status_details(
+                &trade_id,
+                &serde_json::json!({"memos": {"Alice": "hi"}, "other": 1}),
+            )
+            .unwrap();
+        repository
+            .merge_status_details(
+                &trade_id,
+                &serde_json::json!({"memos": {"Alice": "thanks"}}),
+            )
+            .unwrap();
+
+        let trade = repository.get_trade(&trade_id).unwrap().unwrap();
+        assert_eq!(
+            trade.status_details,
+            Some(serde_json::json!({"memos": {"Alice": "thanks"}, "other": 1}))
+        );
+    }
+
+    #[test]
+    fn should_keep_latest_session_snapshot() {
+        let repository = TradeRepository::new(Arc::new(PostgreSqlClient::init_test()));
+        let trade_id = repository.insert_trade(new_trade("Alice")).unwrap();
+        repository
+            .merge_status_details(&trade_id, &serde_json::json!({"other": 1}))
+            .unwrap();
+        let update = |revision: u64, counterparty: Option<&str>, memo: &str| TradeUpdate {
+            counterparty: counterparty.map(String::from),
+            revision,
+            status: if revision == 1 {
+                TradeStatus::Trading
+            } else {
+                TradeStatus::Accepted
+            },
+            session: serde_json::json!({"revision": revision}),
+            memos: serde_json::json!({"Alice": memo}),
+        };
+
+        repository
+            .update_trade(&trade_id, &update(2, Some("Bob"), "thanks"))
+            .unwrap();
+        repository
+            .update_trade(&trade_id, &update(1, None, "hi"))
+            .unwrap();
+
+        let trade = repository.get_trade(&trade_id).unwrap().unwrap();
+        assert_eq!(trade.counterparty.as_deref(), Some("Bob"));
+        assert_eq!(trade.status, TradeStatus::Accepted.as_str());
+        assert_eq!(
+            trade.status_details,
+            Some(serde_json::json!({
+                "other": 1,
+                "memos": {"Alice": "thanks"},
+                "session": {"revision": 2}
+            }))
+        );
+    }
+
+    #[test]
+    fn snapshot_should_not_change_status_of_ended_trade() {
+        let repository = TradeRepository::new(Arc::new(PostgreSqlClient::init_test()));
+        let trade_id = repository.insert_trade(new_trade("Alice")).unwrap();
+        repository
+            .finalize_trade(&trade_id, &TradeStatus::Cancelled)
+            .unwrap();
+
+        repository
+            .update_trade(
+                &trade_id,
+                &TradeUpdate {
+                    counterparty: None,
+                    revision: 1,
+                    status: TradeStatus::Trading,
+                    session: serde_json::json!({"revision": 1}),
+                    memos: serde_json::json!({}),
+                },
+            )
+            .unwrap();
+
+        let trade = repository.get_trade(&trade_id).unwrap().unwrap();
+        assert_eq!(trade.status, TradeStatus::Cancelled.as_str());
+        assert_eq!(trade.status_transitions().len(), 2);
+    }
+}
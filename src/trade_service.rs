@@ -1,26 +1,220 @@
-use std::error::Error;
+use std::{error::Error, fmt, str::FromStr, sync::Arc};
 
+use solana_sdk::pubkey::Pubkey;
 use uuid::Uuid;
 
-use crate::trade_repository::{NewTrade, TradeRepository, TradeStatus};
+use crate::{
+    chain_context::ChainContext,
+    config::{RuntimeConfig, SessionsConfig, TunableConfig},
+    trade_repository::{NewTrade, TradeEntity, TradeStatus, TradeStore},
+};
 
 pub struct TradeService {
-    trade_repository: TradeRepository
+    trade_repository: Box<dyn TradeStore>,
+    runtime_config: Arc<RuntimeConfig>,
 }
 
+/// The initiator wallet holds less SOL than `sessions.min_initiator_lamports`.
+#[derive(Debug, PartialEq)]
+pub struct InsufficientBalanceError {
+    pub address: String,
+    pub balance: u64,
+    pub required: u64,
+}
+
+impl fmt::Display for InsufficientBalanceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Wallet {} holds {} lamports, at least {} are required to create a trade",
+            self.address, self.balance, self.required
+        )
+    }
+}
+
+impl Error for InsufficientBalanceError {}
+
+/// The initiator address is not a valid public key, so its balance can't be checked.
+#[derive(Debug, PartialEq)]
+pub struct InvalidAddressError {
+    pub address: String,
+}
+
+impl fmt::Display for InvalidAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not a valid wallet address", self.address)
+    }
+}
+
+impl Error for InvalidAddressError {}
+
+/// The RPC failed to return the initiator's balance.
+#[derive(Debug)]
+pub struct BalanceUnavailableError {
+    pub address: String,
+    pub reason: String,
+}
+
+impl fmt::Display for BalanceUnavailableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Could not read the balance of {}: {}",
+            self.address, self.reason
+        )
+    }
+}
+
+impl Error for BalanceUnavailableError {}
+
 impl TradeService {
-    pub fn new(trade_repository: TradeRepository) -> Self {
+    pub fn new(trade_repository: impl TradeStore + 'static) -> Self {
+        TradeService::with_config(trade_repository, &SessionsConfig::default())
+    }
+
+    pub fn with_config(
+        trade_repository: impl TradeStore + 'static,
+        config: &SessionsConfig,
+    ) -> Self {
+        let runtime_config = RuntimeConfig::new(TunableConfig {
+            sessions: config.clone(),
+            ..TunableConfig::default()
+        });
+        TradeService::with_runtime_config(trade_repository, Arc::new(runtime_config))
+    }
+
+    /// Reads `sessions` settings from `runtime_config` on every call so they follow config reloads.
+    pub fn with_runtime_config(
+        trade_repository: impl TradeStore + 'static,
+        runtime_config: Arc<RuntimeConfig>,
+    ) -> Self {
         TradeService {
-            trade_repository
+            trade_repository: Box::new(trade_repository),
+            runtime_config,
         }
     }
 
-    pub fn create_trade_session(&self, initiator_address: &str) -> Result<Uuid, Box<dyn Error>> {
+    pub fn get_trade(&self, trade_id: &Uuid) -> Result<Option<TradeEntity>, Box<dyn Error>> {
+        self.trade_repository.get_trade(trade_id)
+    }
+
+    /// Creates a trade, targeted at `counterparty_address` when given or open to anyone otherwise.
+    pub async fn create_trade_session<T: ChainContext>(
+        &self,
+        chain_context: &T,
+        initiator_address: &str,
+        counterparty_address: Option<&str>,
+    ) -> Result<Uuid, Box<dyn Error>> {
+        if let Some(required) = self.runtime_config.get().sessions.min_initiator_lamports {
+            let initiator =
+                Pubkey::from_str(initiator_address).map_err(|_| InvalidAddressError {
+                    address: initiator_address.to_string(),
+                })?;
+            let balance = chain_context.get_balance(&initiator).await.map_err(|e| {
+                BalanceUnavailableError {
+                    address: initiator_address.to_string(),
+                    reason: e.to_string(),
+                }
+            })?;
+            if balance < required {
+                return Err(Box::new(InsufficientBalanceError {
+                    address: initiator_address.to_string(),
+                    balance,
+                    required,
+                }));
+            }
+        }
         self.trade_repository.insert_trade(NewTrade {
             initiator: initiator_address.to_string(),
-            counterparty: None,
+            counterparty: counterparty_address.map(str::to_string),
             status: TradeStatus::Created.as_str().to_string(),
-            status_details: None
+            status_details: None,
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{chain_context::ScriptedChainContext, trade_repository::InMemoryTradeStore};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn initiator_below_min_balance_should_be_refused() {
+        let initiator = Pubkey::new_unique().to_string();
+        let trade_service = TradeService::with_config(
+            InMemoryTradeStore::with_trades(vec![]),
+            &SessionsConfig {
+                min_initiator_lamports: Some(1_000_000),
+                ..SessionsConfig::default()
+            },
+        );
+
+        let error = trade_service
+            .create_trade_session(
+                &ScriptedChainContext::underfunded(999_999),
+                &initiator,
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<InsufficientBalanceError>(),
+            Some(&InsufficientBalanceError {
+                address: initiator.clone(),
+                balance: 999_999,
+                required: 1_000_000,
+            })
+        );
+
+        assert!(trade_service
+            .create_trade_session(
+                &ScriptedChainContext::underfunded(1_000_000),
+                &initiator,
+                None
+            )
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn balance_gate_should_tell_bad_addresses_from_rpc_failures() {
+        let trade_service = TradeService::with_config(
+            InMemoryTradeStore::with_trades(vec![]),
+            &SessionsConfig {
+                min_initiator_lamports: Some(1_000_000),
+                ..SessionsConfig::default()
+            },
+        );
+
+        let error = trade_service
+            .create_trade_session(
+                &ScriptedChainContext::underfunded(u64::MAX),
+                "not-a-wallet",
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<InvalidAddressError>(),
+            Some(&InvalidAddressError {
+                address: "not-a-wallet".to_string()
+            })
+        );
+
+        let unreachable: ScriptedChainContext = ScriptedChainContext {
+            unreachable: true,
+            ..Default::default()
+        };
+        let initiator = Pubkey::new_unique().to_string();
+        let error = trade_service
+            .create_trade_session(&unreachable, &initiator, None)
+            .await
+            .unwrap_err();
+        assert!(
+            error.downcast_ref::<BalanceUnavailableError>().is_some(),
+            "{}",
+            error
+        );
+    }
+}
@@ -1,702 +1,6723 @@
 use crate::chain_context::ChainContext;
+use crate::config::{
+    ConfirmationConfig, NonPositiveAmounts, OfferRounding, RuntimeConfig, TransactionEncoding,
+};
+use crate::confirmation::{await_confirmation, ConfirmationOutcome};
 use crate::token_amount_cache::TokenAmountCache;
+use crate::trade_event_repository::{AuditLog, NewTradeEvent};
+use crate::trade_repository::{self, TradeStore, TradeUpdate};
 use crate::trade_websocket::WebsocketMessage;
-use crate::transaction_service::{self, TransactionService};
+use crate::transaction_service::{
+    encode_transaction, from_base_units, to_base_units, BalanceChangedError, BaseUnitOffers,
+    HeldAccounts, Memos, SourceAccounts, TransactionService, UpToOffers,
+    TRANSACTION_BUILDING_DISABLED,
+};
 use anyhow::*;
+use dashmap::{mapref::one::RefMut, DashMap};
+use log::{info, warn};
 use rust_decimal::prelude::*;
 use rust_decimal_macros::dec;
-use solana_sdk::transaction::Transaction;
+use solana_sdk::{
+    instruction::AccountMeta, pubkey::Pubkey, signature::Signature, transaction::Transaction,
+};
 use std::cmp;
 use std::result::Result::Ok;
+use std::str::FromStr;
+use std::time::Duration;
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 use strum_macros::Display;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 pub type SessionId = Uuid;
 pub type ConnectionId = Uuid;
 
+/// How long a participant who disconnected during signing has to come back before the
+/// session returns to editing.
+pub const OFFLINE_PARTICIPANT_REVERT_AFTER: Duration = Duration::from_secs(60);
+
+/// How often idle sessions are looked for.
+pub const IDLE_SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long settlement waits before polling a sent transaction again after the RPC failed, and
+/// before settling a session again after an attempt failed.
+pub const CONFIRMATION_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest trade note in characters, longer ones are cut off.
+pub const MAX_MEMO_LEN: usize = 140;
+
+/// Number of recent events kept per session for clients joining mid-negotiation.
+pub const SESSION_EVENT_LOG_SIZE: usize = 50;
+
+/// Lifecycle events buffered per subscriber, one lagging further behind misses the oldest.
+pub const LIFECYCLE_EVENT_CAPACITY: usize = 256;
+
+/// Session failures clients can tell apart by `code`.
+#[derive(Debug, PartialEq)]
+pub enum SessionError {
+    /// No live session has the id.
+    NotFound { session_id: SessionId },
+    /// The action isn't possible in the session's current `status`.
+    InvalidState { status: TradeStatus },
+    /// A wallet or mint address that isn't a valid public key.
+    InvalidAddress { address: String },
+    /// The wallet's balances were never fetched or expired, unlike a wallet holding none of
+    /// the offered mint.
+    BalancesNotCached { user_address: String },
+    /// The participant offers `sessions.max_mints_per_user` mints already.
+    TooManyMints {
+        user_address: String,
+        max_mints: usize,
+    },
+    /// Both participant slots are taken by the given addresses.
+    SessionFull { participants: Vec<String> },
+    /// The session's transaction was signed by everyone or the trade ended, see
+    /// `TradeStatus::is_closed`.
+    SessionClosed { status: TradeStatus },
+    /// A websocket frame that isn't any `WebsocketMessage`.
+    InvalidMessage,
+    /// A message on behalf of a wallet the connection didn't prove to own.
+    NotAuthenticated { user_address: String },
+    /// The signature of the connection's challenge isn't one by the claimed wallet.
+    AuthenticationFailed { user_address: String },
+    /// The participant's role doesn't allow the action yet, see `TradeSession::check_permission`.
+    Forbidden {
+        user_address: String,
+        role: ParticipantRole,
+        action: SessionAction,
+    },
+}
+
+impl SessionError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            SessionError::NotFound { .. } => "session_not_found",
+            SessionError::InvalidState { .. } => "invalid_state",
+            SessionError::InvalidAddress { .. } => "invalid_address",
+            SessionError::BalancesNotCached { .. } => "balances_not_cached",
+            SessionError::TooManyMints { .. } => "too_many_mints",
+            SessionError::SessionFull { .. } => "session_full",
+            SessionError::SessionClosed { .. } => "session_closed",
+            SessionError::InvalidMessage => "invalid_message",
+            SessionError::NotAuthenticated { .. } => "not_authenticated",
+            SessionError::AuthenticationFailed { .. } => "authentication_failed",
+            SessionError::Forbidden { .. } => "forbidden",
+        }
+    }
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::NotFound { session_id } => {
+                write!(f, "Session {} not found", session_id)
+            }
+            SessionError::InvalidState { .. } => {
+                write!(f, "Invalid action for current trade session state")
+            }
+            SessionError::InvalidAddress { address } => {
+                write!(f, "{} is not a valid Solana address", address)
+            }
+            SessionError::BalancesNotCached { user_address } => write!(
+                f,
+                "Balances of {} aren't known, fetch the wallet's tokens before offering",
+                user_address
+            ),
+            SessionError::TooManyMints {
+                user_address,
+                max_mints,
+            } => write!(
+                f,
+                "{} already offers {} different mints, withdraw one before offering another",
+                user_address, max_mints
+            ),
+            SessionError::SessionFull { participants } => write!(
+                f,
+                "There are already 2 users involved in this trade: {}",
+                participants.join(", ")
+            ),
+            SessionError::SessionClosed { status } => write!(
+                f,
+                "Trade session is closed with status {}, it can no longer be changed",
+                status
+            ),
+            SessionError::InvalidMessage => write!(f, "Invalid message"),
+            SessionError::NotAuthenticated { user_address } => write!(
+                f,
+                "Connection is not authenticated as {}, answer the AuthChallenge first",
+                user_address
+            ),
+            SessionError::AuthenticationFailed { user_address } => {
+                write!(f, "Challenge was not signed by {}", user_address)
+            }
+            SessionError::Forbidden {
+                user_address,
+                role,
+                action,
+            } => {
+                let reason = match action {
+                    SessionAction::Cancel => "the counterparty didn't join yet",
+                    SessionAction::PassOnFees => "the counterparty has to volunteer",
+                };
+                write!(
+                    f,
+                    "{} can't {} as the {}, {}",
+                    user_address, action, role, reason
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+/// Code sent to clients in `WebsocketMessage::Error`, `rejected` for failures without a
+/// `SessionError`, e.g. an offer exceeding the balance.
+pub fn error_code(error: &Error) -> &'static str {
+    error
+        .downcast_ref::<SessionError>()
+        .map_or("rejected", SessionError::code)
+}
+
 pub struct SharedSessions<T: ChainContext> {
-    internal: Mutex<HashMap<SessionId, TradeSession>>,
+    internal: Arc<DashMap<SessionId, TradeSession>>,
     token_amount_cache: Arc<TokenAmountCache>,
     transaction_service: Arc<TransactionService<T>>,
+    audit_log: AuditLog,
+    /// Receives the trade notes and session snapshots, which are then kept in the trade's
+    /// `status_details`.
+    trade_store: Option<Arc<dyn TradeStore>>,
+    runtime_config: Arc<RuntimeConfig>,
+    lifecycle: broadcast::Sender<LifecycleEvent>,
+    /// Writes handed to the session writer so far.
+    queued_writes: AtomicU64,
+    /// Writes the session writer is done with, notifying `write_done` for each.
+    finished_writes: AtomicU64,
+    write_done: Notify,
 }
 impl<T: ChainContext> SharedSessions<T> {
     pub fn new(
         token_amount_cache: Arc<TokenAmountCache>,
         transaction_service: Arc<TransactionService<T>>,
+    ) -> Self {
+        SharedSessions::with_audit_log(
+            token_amount_cache,
+            transaction_service,
+            AuditLog::disabled(),
+        )
+    }
+
+    /// Sessions appending every offer, withdrawal, accept and signature to `audit_log`.
+    pub fn with_audit_log(
+        token_amount_cache: Arc<TokenAmountCache>,
+        transaction_service: Arc<TransactionService<T>>,
+        audit_log: AuditLog,
+    ) -> Self {
+        SharedSessions::with_stores(token_amount_cache, transaction_service, audit_log, None)
+    }
+
+    /// Like `with_audit_log`, also persisting trade notes and the sessions to `trade_store`.
+    pub fn with_stores(
+        token_amount_cache: Arc<TokenAmountCache>,
+        transaction_service: Arc<TransactionService<T>>,
+        audit_log: AuditLog,
+        trade_store: Option<Arc<dyn TradeStore>>,
+    ) -> Self {
+        SharedSessions::with_config(
+            token_amount_cache,
+            transaction_service,
+            audit_log,
+            trade_store,
+            Arc::default(),
+        )
+    }
+
+    /// Like `with_stores`, coalescing the broadcasts of `broadcast_current_state` as configured
+    /// instead of sending every change right away. `sessions` and `broadcasts` settings are read
+    /// from `runtime_config` on every call so they follow config reloads.
+    pub fn with_config(
+        token_amount_cache: Arc<TokenAmountCache>,
+        transaction_service: Arc<TransactionService<T>>,
+        audit_log: AuditLog,
+        trade_store: Option<Arc<dyn TradeStore>>,
+        runtime_config: Arc<RuntimeConfig>,
     ) -> Self {
         SharedSessions {
-            internal: Mutex::default(),
+            internal: Arc::default(),
             token_amount_cache,
             transaction_service,
+            audit_log,
+            trade_store,
+            runtime_config,
+            lifecycle: broadcast::channel(LIFECYCLE_EVENT_CAPACITY).0,
+            queued_writes: AtomicU64::new(0),
+            finished_writes: AtomicU64::new(0),
+            write_done: Notify::new(),
+        }
+    }
+
+    /// Ping interval and pong timeout of websocket connections, `None` when pings are off.
+    pub fn heartbeat(&self) -> Option<(Duration, Duration)> {
+        let runtime_config = self.runtime_config.get();
+        let sessions_config = &runtime_config.sessions;
+        (sessions_config.ping_interval_ms > 0).then(|| {
+            (
+                Duration::from_millis(sessions_config.ping_interval_ms),
+                Duration::from_millis(sessions_config.pong_timeout_ms),
+            )
+        })
+    }
+
+    /// Receives the lifecycle transitions of every session from now on, for side effects that
+    /// shouldn't be wired into the session methods themselves.
+    pub fn subscribe(&self) -> broadcast::Receiver<LifecycleEvent> {
+        self.lifecycle.subscribe()
+    }
+
+    /// Brings back the sessions of the trades still in one of `TradeStatus::ACTIVE` in
+    /// `trade_store`, as last persisted, e.g. after a restart. Targeted trades nobody offered in
+    /// yet get their invite back. Returns the number of restored sessions.
+    pub fn restore_sessions(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let Some(trade_store) = &self.trade_store else {
+            return Ok(0);
+        };
+        let mut trades = Vec::new();
+        for trade_status in &trade_repository::TradeStatus::ACTIVE {
+            trades.extend(trade_store.get_trades_by_status(trade_status)?);
+        }
+        let now = Instant::now();
+        let mut restored = 0;
+        for trade in trades {
+            let mut trade_session = match PersistedSession::of_trade(&trade) {
+                Some(Ok(snapshot)) => TradeSession {
+                    state: snapshot.state,
+                    initiator: snapshot.initiator,
+                    revision: snapshot.revision,
+                    persisted_revision: snapshot.revision,
+                    ..TradeSession::default()
+                },
+                Some(Err(e)) => {
+                    warn!("Unable to restore session {}: {}", trade.id, e);
+                    continue;
+                }
+                None if trade.counterparty.is_some() => TradeSession {
+                    initiator: Some(trade.initiator.clone()),
+                    ..TradeSession::default()
+                },
+                None => continue,
+            };
+            if let Some(counterparty) = trade.counterparty {
+                trade_session.invite = Some(TradeInvite {
+                    initiator: trade.initiator,
+                    counterparty,
+                });
+            }
+            trade_session.touch(now);
+            self.internal.insert(trade.id, trade_session);
+            restored += 1;
+        }
+        Ok(restored)
+    }
+
+    /// The `TradeStateUpdate` a client connecting now would get. Sessions no longer in memory
+    /// are rebuilt from the snapshot in `trade_store`, with the status of finished trades taken
+    /// from the trade record. `None` when neither knows the session.
+    pub fn session_state(
+        &self,
+        session_id: &SessionId,
+    ) -> Result<Option<WebsocketMessage>, Box<dyn std::error::Error>> {
+        if let Some(trade_session) = self.internal.get(session_id) {
+            return Ok(Some(trade_session.snapshot()));
+        }
+        let Some(trade) = self
+            .trade_store
+            .as_ref()
+            .map(|trade_store| trade_store.get_trade(session_id))
+            .transpose()?
+            .flatten()
+        else {
+            return Ok(None);
+        };
+        let mut trade_session = match PersistedSession::of_trade(&trade).transpose()? {
+            Some(snapshot) => TradeSession {
+                state: snapshot.state,
+                initiator: snapshot.initiator,
+                ..TradeSession::default()
+            },
+            None => TradeSession {
+                initiator: Some(trade.initiator.clone()),
+                ..TradeSession::default()
+            },
+        };
+        match trade.status.parse() {
+            Ok(trade_repository::TradeStatus::Completed) => {
+                trade_session.state.status = TradeStatus::Completed
+            }
+            Ok(trade_repository::TradeStatus::Failed) => {
+                trade_session.state.status = TradeStatus::Failed
+            }
+            Ok(trade_repository::TradeStatus::Cancelled) => {
+                trade_session.state.status = TradeStatus::Cancelled
+            }
+            Ok(trade_repository::TradeStatus::Expired) => {
+                trade_session.state.status = TradeStatus::Expired
+            }
+            _ => {}
         }
+        Ok(Some(trade_session.snapshot()))
     }
 
+    /// Registers the sender of a connection, returns false without touching the session when
+    /// `connection_id` is already taken by another connection.
     pub fn add_client(
         &self,
         session_id: SessionId,
         connection_id: ConnectionId,
         tx: mpsc::Sender<WebsocketMessage>,
-    ) {
-        let mut sessions = self.internal.lock().unwrap();
-        sessions
-            .entry(session_id)
-            .or_default()
-            .ws_clients
-            .insert(connection_id, tx);
+    ) -> bool {
+        let mut trade_session = self.session_entry(session_id);
+        match trade_session.ws_clients.entry(connection_id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(tx);
+                trade_session.touch(Instant::now());
+                true
+            }
+        }
+    }
+
+    /// Restricts the session to `initiator` and the invited `counterparty`.
+    pub fn invite(&self, session_id: SessionId, initiator: String, counterparty: String) {
+        let mut trade_session = self.session_entry(session_id);
+        // Whoever offers first, the creator of the trade stays its initiator
+        trade_session.initiator = Some(initiator.clone());
+        trade_session.invite = Some(TradeInvite {
+            initiator,
+            counterparty,
+        });
+        trade_session.touch(Instant::now());
     }
 
-    pub fn remove_client(&self, session_id: &SessionId, connection_id: &ConnectionId) {
-        let mut sessions = self.internal.lock().unwrap();
-        if let Some(trade_session) = sessions.get_mut(session_id) {
-            trade_session.ws_clients.remove(connection_id);
+    /// Counts as activity like an offer would, so participants still deciding keep the session
+    /// from being reaped. Returns false when the session is unknown or the keepalive came sooner
+    /// than `sessions.keepalive_min_interval_secs` after the last one and was ignored.
+    pub fn keep_alive(&self, session_id: &SessionId, now: Instant) -> bool {
+        let min_interval = Duration::from_secs(
+            self.runtime_config
+                .get()
+                .sessions
+                .keepalive_min_interval_secs,
+        );
+        let Some(mut trade_session) = self.internal.get_mut(session_id) else {
+            return false;
+        };
+        if trade_session
+            .last_keepalive
+            .is_some_and(|at| now.saturating_duration_since(at) < min_interval)
+        {
+            return false;
         }
+        trade_session.last_keepalive = Some(now);
+        trade_session.touch(now);
+        true
     }
 
-    pub fn broadcast_current_state(&self, session_id: &SessionId) {
-        let sessions = self.internal.lock().unwrap();
-        if let Some(trade_session) = sessions.get(session_id) {
-            for tx in trade_session.ws_clients.values() {
-                let _ = tx.try_send(WebsocketMessage::TradeStateUpdate {
-                    offers: Arc::clone(&trade_session.state.items),
-                    user_acted: trade_session.state.user_acted.clone(),
-                    status: trade_session.state.status.to_string(),
-                    tx: trade_session.state.tx.clone(),
-                });
+    /// Closes the sessions without activity for `sessions.idle_timeout_secs` whose transaction
+    /// isn't being signed, telling their clients with `SessionExpired`. Their trades are marked
+    /// `Expired` in `trade_store`. Returns the closed sessions.
+    pub fn reap_idle_sessions(&self, now: Instant) -> Vec<SessionId> {
+        let Some(idle_timeout) = self.runtime_config.get().sessions.idle_timeout_secs else {
+            return Vec::new();
+        };
+        let idle_timeout = Duration::from_secs(idle_timeout);
+        let candidates: Vec<SessionId> = self
+            .internal
+            .iter()
+            .filter(|entry| entry.value().is_idle(now, idle_timeout))
+            .map(|entry| *entry.key())
+            .collect();
+        let reason = format!(
+            "Session closed after {} seconds without activity",
+            idle_timeout.as_secs()
+        );
+        let mut idle = Vec::with_capacity(candidates.len());
+        for session_id in candidates {
+            // Checked again, the session may have seen activity since it was listed
+            if let Some((_, trade_session)) =
+                self.internal.remove_if(&session_id, |_, trade_session| {
+                    trade_session.is_idle(now, idle_timeout)
+                })
+            {
+                for tx in trade_session.ws_clients.values() {
+                    let _ = tx.try_send(WebsocketMessage::SessionExpired {
+                        reason: reason.clone(),
+                    });
+                }
+                idle.push(session_id);
             }
         }
+        for session_id in &idle {
+            self.queue_write(LifecycleEvent::Completed {
+                session_id: *session_id,
+                status: TradeStatus::Expired,
+            });
+        }
+        idle
     }
 
-    pub fn add_tokens_offer(
+    pub fn chain_context(&self) -> &T {
+        &self.transaction_service.chain_context
+    }
+
+    pub fn session_ids(&self) -> Vec<SessionId> {
+        self.internal.iter().map(|entry| *entry.key()).collect()
+    }
+
+    /// Remembers which participant a connection authenticated as, so their presence can be tracked.
+    pub fn identify_client(
         &self,
         session_id: &SessionId,
+        connection_id: &ConnectionId,
         user_address: &str,
-        token_mint: String,
-        token_amount: Decimal,
-    ) -> Result<()> {
-        if token_amount <= dec!(0) {
-            return Ok(());
+    ) {
+        if let Some(mut trade_session) = self.internal.get_mut(session_id) {
+            if trade_session.ws_clients.contains_key(connection_id) {
+                trade_session
+                    .connection_users
+                    .insert(*connection_id, user_address.to_string());
+            }
         }
+    }
 
-        let mut sessions = self.internal.lock().unwrap();
-        if let Some(trade_session) = sessions.get_mut(session_id) {
-            if !matches!(
-                trade_session.state.status,
-                TradeStatus::Trading | TradeStatus::OneUserAccepted
-            ) {
-                return Err(Error::msg(format!(
-                    "Invalid action for current trade session state"
-                )));
+    /// Participant the connection authenticated as, `None` until the connection authenticated.
+    pub fn connection_user(
+        &self,
+        session_id: &SessionId,
+        connection_id: &ConnectionId,
+    ) -> Option<String> {
+        self.internal
+            .get(session_id)?
+            .connection_users
+            .get(connection_id)
+            .cloned()
+    }
+
+    /// Removes the connection. When that was the last connection of a participant while the
+    /// transaction is being signed, the remaining clients are warned and the participant who
+    /// went offline is returned.
+    pub fn remove_client(
+        &self,
+        session_id: &SessionId,
+        connection_id: &ConnectionId,
+    ) -> Option<String> {
+        let mut trade_session = self.internal.get_mut(session_id)?;
+        trade_session.ws_clients.remove(connection_id);
+        let user_address = trade_session.connection_users.remove(connection_id);
+        if trade_session.is_abandoned() {
+            drop(trade_session);
+            // Checked again, a client may have joined since the session was unlocked
+            self.internal
+                .remove_if(session_id, |_, trade_session| trade_session.is_abandoned());
+            return None;
+        }
+        let user_address = user_address?;
+        if !matches!(
+            trade_session.state.status,
+            TradeStatus::TransactionCreated | TradeStatus::OneUserSigned
+        ) || trade_session.is_online(&user_address)
+        {
+            return None;
+        }
+        trade_session.warn_clients(format!(
+            "Counterparty {} went offline before signing, the trade returns to editing if they are not back within {} seconds",
+            user_address,
+            OFFLINE_PARTICIPANT_REVERT_AFTER.as_secs()
+        ));
+        Some(user_address)
+    }
+
+    /// Returns the session to `Trading` when `user_address` is still offline during signing.
+    pub fn revert_if_offline(&self, session_id: &SessionId, user_address: &str) -> bool {
+        let Some(mut trade_session) = self.internal.get_mut(session_id) else {
+            return false;
+        };
+        if !matches!(
+            trade_session.state.status,
+            TradeStatus::TransactionCreated | TradeStatus::OneUserSigned
+        ) || trade_session.is_online(user_address)
+            || trade_session.state.settled_txs > 0
+        {
+            return false;
+        }
+        trade_session.revert_to_trading();
+        self.persist_session(session_id, &mut trade_session);
+        true
+    }
+
+    /// Sends the recent session events to a single, typically just connected, client.
+    pub fn send_event_log(&self, session_id: &SessionId, connection_id: &ConnectionId) {
+        if let Some(trade_session) = self.internal.get(session_id) {
+            if let Some(tx) = trade_session.ws_clients.get(connection_id) {
+                if !trade_session.events.is_empty() {
+                    let _ = tx.try_send(WebsocketMessage::SessionEvents {
+                        events: trade_session.events.iter().cloned().collect(),
+                    });
+                }
             }
-            let token_amounts = self.token_amount_cache.get_token_amounts(user_address);
-            let available_tokens = token_amounts.map_or_else(
-                || dec!(0),
-                |amounts| {
-                    amounts
-                        .get(&token_mint)
-                        .map_or_else(|| dec!(0), |amount| amount.to_owned())
-                },
-            );
+        }
+    }
 
-            let mut new_state_items = (*trade_session.state.items).clone();
-            if let Some(trade_items) = new_state_items.get_mut(user_address) {
-                trade_items
-                    .entry(token_mint)
-                    .and_modify(|amount| {
-                        *amount = cmp::min(*amount + token_amount, available_tokens)
-                    })
-                    .or_insert(cmp::min(token_amount, available_tokens));
-                trade_session.state = TradeState {
-                    items: Arc::new(new_state_items),
-                    user_acted: None,
-                    status: TradeStatus::Trading,
-                    tx: None,
-                };
-            } else if trade_session.state.items.len() == 2 {
-                return Err(Error::msg(
-                    "There are already 2 users involved in this trade",
-                ));
-            } else {
-                new_state_items.insert(
-                    String::from(user_address),
-                    HashMap::from([(token_mint, cmp::min(token_amount, available_tokens))]),
-                );
-                trade_session.state = TradeState {
-                    items: Arc::new(new_state_items),
-                    user_acted: None,
-                    status: TradeStatus::Trading,
-                    tx: None,
+    /// Brings every client up to date with the session state. Clients get a `TradeStateDelta`
+    /// against the last broadcast state when only offered amounts changed, a full
+    /// `TradeStateUpdate` otherwise, and nothing when the state didn't change.
+    ///
+    /// With a broadcast debounce configured the broadcast is sent from a background task once
+    /// the session saw no further call for the debounce window, so a burst of changes costs a
+    /// single broadcast.
+    pub fn broadcast_current_state(&self, session_id: &SessionId) {
+        let Some(mut trade_session) = self.internal.get_mut(session_id) else {
+            return;
+        };
+        let broadcasts = self.runtime_config.get().broadcasts.clone();
+        let debounce = Duration::from_millis(broadcasts.debounce_ms);
+        let max_delay = Duration::from_millis(broadcasts.max_delay_ms);
+        if debounce.is_zero() {
+            trade_session.broadcast_state(None);
+            return;
+        }
+        let now = Instant::now();
+        if let Some(pending) = &mut trade_session.pending_broadcast {
+            pending.last_change = now;
+            return;
+        }
+        trade_session.pending_broadcast = Some(PendingBroadcast {
+            first_change: now,
+            last_change: now,
+        });
+
+        let internal = Arc::clone(&self.internal);
+        let session_id = *session_id;
+        tokio::spawn(async move {
+            loop {
+                let due = {
+                    let Some(mut trade_session) = internal.get_mut(&session_id) else {
+                        return;
+                    };
+                    let Some(pending) = &trade_session.pending_broadcast else {
+                        return;
+                    };
+                    let due = pending.due(debounce, max_delay);
+                    if due <= Instant::now() {
+                        trade_session.pending_broadcast = None;
+                        trade_session.broadcast_state(None);
+                        return;
+                    }
+                    due
                 };
+                tokio::time::sleep_until(due).await;
             }
-        } else {
-            return Err(Error::msg(format!("Session {} not found", session_id)));
+        });
+    }
+
+    /// Tells the connection whose request failed why, the other clients aren't bothered.
+    pub fn send_error(&self, session_id: &SessionId, connection_id: &ConnectionId, error: &Error) {
+        let Some(trade_session) = self.internal.get(session_id) else {
+            return;
+        };
+        if let Some(tx) = trade_session.ws_clients.get(connection_id) {
+            let _ = tx.try_send(WebsocketMessage::Error {
+                code: error_code(error).to_string(),
+                message: error.to_string(),
+            });
         }
-        Ok(())
     }
 
-    pub fn withdraw_tokens(
+    /// Sends a full snapshot to one client, on connect or when it asks to resync.
+    pub fn send_current_state(&self, session_id: &SessionId, connection_id: &ConnectionId) {
+        if let Some(mut trade_session) = self.internal.get_mut(session_id) {
+            trade_session.broadcast_state(Some(connection_id));
+        }
+    }
+
+    pub fn add_tokens_offer(
+        &self,
+        session_id: &SessionId,
+        user_address: &str,
+        token_mint: String,
+        token_amount: Decimal,
+    ) -> Result<()> {
+        self.add_tokens_offer_from(
+            session_id,
+            user_address,
+            token_mint,
+            token_amount,
+            None,
+            OfferMode::Exact,
+        )
+    }
+
+    /// Offers tokens sent from `token_account` instead of the user's associated token account,
+    /// for tokens held in a non-ATA account. `None` keeps the previously chosen source.
+    /// `mode` applies to the whole offered amount of the mint.
+    pub fn add_tokens_offer_from(
         &self,
         session_id: &SessionId,
         user_address: &str,
         token_mint: String,
         token_amount: Decimal,
+        token_account: Option<String>,
+        mode: OfferMode,
     ) -> Result<()> {
         if token_amount <= dec!(0) {
-            return Ok(());
+            return self.non_positive_amount(
+                self.runtime_config.get().sessions.non_positive_offers,
+                "Offered",
+                token_amount,
+            );
         }
-        let mut sessions = self.internal.lock().unwrap();
-        if let Some(trade_session) = sessions.get_mut(session_id) {
-            if !matches!(
-                trade_session.state.status,
-                TradeStatus::Trading | TradeStatus::OneUserAccepted
+        if let Some(token_account) = &token_account {
+            Pubkey::from_str(token_account)
+                .map_err(|_| anyhow!("Invalid token account {}", token_account))?;
+        }
+
+        if let Some(mut trade_session) = self.internal.get_mut(session_id) {
+            let offer = match self.offered_amount_after(
+                &trade_session,
+                user_address,
+                &token_mint,
+                token_amount,
             ) {
-                return Err(Error::msg(format!(
-                    "Invalid action for current trade session state"
-                )));
-            }
-            let mut new_state_items = (*trade_session.state.items).clone();
-            if let Some(trade_items) = new_state_items.get_mut(user_address) {
-                trade_items.entry(token_mint.clone()).and_modify(|amount| {
-                    *amount = if token_amount >= *amount {
-                        dec!(0)
-                    } else {
-                        *amount - token_amount
-                    }
-                });
-                if let Some(a) = trade_items.get(&token_mint) {
-                    if *a == dec!(0) {
-                        trade_items.remove(&token_mint);
+                Ok(offer) => offer,
+                Err(e) => {
+                    // Only a stale balance is worth telling everyone, not one never fetched
+                    let balances_missing = matches!(
+                        e.downcast_ref::<SessionError>(),
+                        Some(SessionError::BalancesNotCached { .. })
+                    );
+                    if balances_missing && self.token_amount_cache.has_expired(user_address) {
+                        trade_session.warn_clients(balance_expired_message(user_address));
                     }
+                    return Err(e);
                 }
+            };
+            if let Some(token_account) = &token_account {
+                self.verify_source_account(user_address, &token_mint, token_account)?;
+            }
 
-                trade_session.state = TradeState {
-                    items: Arc::new(new_state_items),
-                    user_acted: None,
-                    status: TradeStatus::Trading,
-                    tx: None,
-                };
-            } else {
-                return Err(Error::msg(format!(
-                    "There are no tokens {} in session state",
-                    token_mint
-                )));
+            if trade_session.initiator.is_none() {
+                trade_session.initiator = Some(String::from(user_address));
+            }
+            if trade_session.state.status == TradeStatus::OneUserAccepted {
+                trade_session.notify_offer_changed(user_address);
             }
+            let mut source_accounts = trade_session.state.source_accounts.clone();
+            if let Some(token_account) = token_account {
+                source_accounts
+                    .entry(String::from(user_address))
+                    .or_default()
+                    .insert(token_mint.clone(), token_account);
+            }
+            let mut up_to_offers = trade_session.state.up_to_offers.clone();
+            let user_up_to = up_to_offers.entry(String::from(user_address)).or_default();
+            match mode {
+                OfferMode::Exact => user_up_to.remove(&token_mint),
+                OfferMode::UpTo => user_up_to.insert(token_mint.clone()),
+            };
+            up_to_offers.retain(|_, mints| !mints.is_empty());
+            self.record_event(
+                session_id,
+                &mut trade_session,
+                SessionEvent::TokensOffered {
+                    user_address: String::from(user_address),
+                    token_mint: token_mint.clone(),
+                    amount: offer.added,
+                },
+            );
+            let last_rounding =
+                AppliedRounding::between(user_address, &token_mint, token_amount, offer.applied);
+            // Copied only while a broadcast or a transaction build still holds the offers
+            Arc::make_mut(&mut trade_session.state.items)
+                .entry(String::from(user_address))
+                .or_default()
+                .insert(token_mint.clone(), offer.offered);
+            let mut mint_decimals = trade_session.state.mint_decimals.clone();
+            mint_decimals.insert(token_mint, offer.decimals);
+            trade_session.state = TradeState {
+                items: Arc::clone(&trade_session.state.items),
+                mint_decimals,
+                source_accounts,
+                memos: trade_session.state.memos.clone(),
+                fee_payer: trade_session.state.fee_payer.clone(),
+                up_to_offers,
+                user_acted: None,
+                status: TradeStatus::Trading,
+                tx: None,
+                last_valid_block_height: None,
+                queued_txs: Vec::new(),
+                settled_txs: 0,
+                last_rounding,
+            };
+            self.publish(LifecycleEvent::OfferChanged {
+                session_id: *session_id,
+                user_address: String::from(user_address),
+            });
+            self.persist_session(session_id, &mut trade_session);
+        } else {
+            return Err(Error::from(SessionError::NotFound {
+                session_id: *session_id,
+            }));
         }
         Ok(())
     }
 
-    pub fn accept_trade(&self, session_id: &SessionId, user_address: &str) -> Result<()> {
-        let mut sessions = self.internal.lock().unwrap();
-        if let Some(trade_session) = sessions.get_mut(session_id) {
-            if !matches!(
-                trade_session.state.status,
-                TradeStatus::Trading | TradeStatus::OneUserAccepted
-            ) {
-                return Err(Error::msg(format!(
-                    "Invalid action for current trade session state"
-                )));
-            }
-            if let Some(user_accepted) = &trade_session.state.user_acted {
-                if *user_accepted != user_address {
-                    trade_session.state.user_acted = None;
-                    trade_session.state.status = TradeStatus::Accepted;
-                }
-            } else {
-                trade_session.state.user_acted = Some(String::from(user_address));
-                trade_session.state.status = TradeStatus::OneUserAccepted;
-            }
+    /// The `mint -> amount` offers of a participant of the session.
+    pub fn get_offers(
+        &self,
+        session_id: &SessionId,
+        user_address: &str,
+    ) -> Result<HashMap<String, Decimal>> {
+        let trade_session = self.internal.get(session_id).ok_or_else(|| {
+            Error::from(SessionError::NotFound {
+                session_id: *session_id,
+            })
+        })?;
+        trade_session
+            .state
+            .ui_items()
+            .remove(user_address)
+            .ok_or_else(|| anyhow!("{} is not a participant of this session", user_address))
+    }
+
+    /// Sets the trade note of a participant, cut off at `MAX_MEMO_LEN` characters, an empty
+    /// memo removes it. Changing the note reverts an accept like changing the offers does.
+    pub fn set_memo(&self, session_id: &SessionId, user_address: &str, memo: &str) -> Result<()> {
+        let mut trade_session = self.internal.get_mut(session_id).ok_or_else(|| {
+            Error::from(SessionError::NotFound {
+                session_id: *session_id,
+            })
+        })?;
+        trade_session.ensure_open()?;
+        if !matches!(
+            trade_session.state.status,
+            TradeStatus::Trading | TradeStatus::OneUserAccepted
+        ) {
+            return Err(trade_session.invalid_state());
+        }
+        if !trade_session.state.items.contains_key(user_address) {
+            return Err(anyhow!(
+                "{} is not a participant of this session",
+                user_address
+            ));
+        }
+        let memo: String = memo.chars().take(MAX_MEMO_LEN).collect();
+        if trade_session
+            .state
+            .memos
+            .get(user_address)
+            .map(String::as_str)
+            .unwrap_or("")
+            == memo
+        {
+            return Ok(());
+        }
+        if trade_session.state.status == TradeStatus::OneUserAccepted {
+            trade_session.notify_offer_changed(user_address);
+            trade_session.state.status = TradeStatus::Trading;
+            trade_session.state.user_acted = None;
+        }
+        if memo.is_empty() {
+            trade_session.state.memos.remove(user_address);
         } else {
-            return Err(Error::msg(format!("Session {} not found", session_id)));
+            trade_session
+                .state
+                .memos
+                .insert(String::from(user_address), memo);
         }
+        self.persist_session(session_id, &mut trade_session);
         Ok(())
     }
 
-    // The reason the locking in this transaction is done twice is because we use Mutex without Send
-    // and so we cannot use .await during lock
-    // First we lock and check conditions for creating transaction
-    // If needed, we create transaction
-    // we lock again and save the transaction to session trade state
-    pub async fn get_transaction_to_sign(
+    /// Lets a participant choose who pays the network fees, themselves or, for the counterparty,
+    /// the initiator who pays by default. Like changing the offers it reverts an accept, so both
+    /// participants agree to the payer by accepting the trade afterwards. Refused when the server
+    /// wallet pays.
+    pub fn set_fee_payer(
         &self,
         session_id: &SessionId,
         user_address: &str,
+        fee_payer: &str,
     ) -> Result<()> {
-        let (need_create_tx, items_to_process) = {
-            let sessions = self.internal.lock().unwrap();
-            let trade_session = sessions
-                .get(session_id)
-                .ok_or_else(|| Error::msg("No session found with given session_id"))?;
-            if !matches!(
-                trade_session.state.status,
-                TradeStatus::Accepted | TradeStatus::TransactionCreated
-            ) {
-                return Err(Error::msg(format!(
-                    "Invalid action for current trade session state"
-                )));
+        if self.transaction_service.server_pays_fees() {
+            return Err(anyhow!(
+                "Network fees are paid by the server, the fee payer can't be chosen"
+            ));
+        }
+        let mut trade_session = self.internal.get_mut(session_id).ok_or_else(|| {
+            Error::from(SessionError::NotFound {
+                session_id: *session_id,
+            })
+        })?;
+        trade_session.ensure_open()?;
+        if !matches!(
+            trade_session.state.status,
+            TradeStatus::Trading | TradeStatus::OneUserAccepted
+        ) {
+            return Err(trade_session.invalid_state());
+        }
+        for address in [user_address, fee_payer] {
+            if !trade_session.state.items.contains_key(address) {
+                return Err(anyhow!("{} is not a participant of this session", address));
             }
+        }
+        if fee_payer != user_address {
+            trade_session.check_permission(user_address, SessionAction::PassOnFees)?;
+        }
+        if trade_session.fee_paying_participant().ok().as_deref() == Some(fee_payer) {
+            return Ok(());
+        }
+        if trade_session.state.status == TradeStatus::OneUserAccepted {
+            trade_session.notify_offer_changed(user_address);
+            trade_session.state.status = TradeStatus::Trading;
+            trade_session.state.user_acted = None;
+        }
+        trade_session.state.fee_payer = Some(String::from(fee_payer));
+        self.persist_session(session_id, &mut trade_session);
+        Ok(())
+    }
 
-            let need_create = trade_session.state.user_acted.is_none();
-            let items_clone = Arc::clone(&trade_session.state.items);
-            (need_create, items_clone)
-        };
-
-        let tx_created = if need_create_tx {
-            Some(
-                self.transaction_service
-                    .create_transaction(items_to_process)
-                    .await?,
-            )
-        } else {
-            None
-        };
-
-        if let Some(tx) = tx_created {
-            let mut sessions = self.internal.lock().unwrap();
-            let trade_session = sessions
-                .get_mut(session_id)
-                .ok_or_else(|| Error::msg("Session disappeared unexpectedly"))?;
+    /// Dry run of `add_tokens_offer`: runs the same checks and returns the amount of `token_mint`
+    /// the user would end up offering, without touching the session state.
+    pub fn validate_offer(
+        &self,
+        session_id: &SessionId,
+        user_address: &str,
+        token_mint: &str,
+        token_amount: Decimal,
+    ) -> Result<Decimal> {
+        let trade_session = self.internal.get(session_id).ok_or_else(|| {
+            Error::from(SessionError::NotFound {
+                session_id: *session_id,
+            })
+        })?;
+        self.offered_amount_after(&trade_session, user_address, token_mint, token_amount)
+            .and_then(|offer| from_base_units(offer.offered, offer.decimals))
+    }
 
-            if trade_session.state.user_acted.is_none() {
-                trade_session.state.tx = Some(tx);
-                trade_session.state.user_acted = Some(user_address.to_string());
-                trade_session.state.status = TradeStatus::TransactionCreated;
+    /// Base-unit balances of the token accounts the participants hold their offered mints in,
+    /// going by their last wallet fetch.
+    fn held_accounts(&self, trade_session: &TradeSession) -> HeldAccounts {
+        let mut held_accounts = HeldAccounts::new();
+        for (user_address, offers) in trade_session.state.items.iter() {
+            let Some(accounts) = self.token_amount_cache.get_token_accounts(user_address) else {
+                continue;
+            };
+            for held in accounts {
+                let Some(decimals) = trade_session.state.mint_decimals.get(&held.mint) else {
+                    continue;
+                };
+                let Ok(balance) = to_base_units(held.amount, *decimals) else {
+                    continue;
+                };
+                if offers.contains_key(&held.mint) {
+                    held_accounts
+                        .entry(user_address.clone())
+                        .or_default()
+                        .entry(held.mint)
+                        .or_default()
+                        .push((held.account, balance));
+                }
             }
         }
-
-        Ok(())
-    }
-    pub fn sign_transaction(&self, session_id: &SessionId, signature: String) -> Result<()> {
-        Ok(())
+        held_accounts
     }
-}
+
+    /// Source accounts must be token accounts of the wallet holding `token_mint`, going by its
+    /// last fetch, so a client can't have the trade draw on an account it doesn't own.
+    fn verify_source_account(
+        &self,
+        user_address: &str,
+        token_mint: &str,
+        token_account: &str,
+    ) -> Result<()> {
+        let held_accounts = self
+            .token_amount_cache
+            .get_token_accounts(user_address)
+            .unwrap_or_default();
+        if held_accounts
+            .iter()
+            .any(|held| held.account == token_account && held.mint == token_mint)
+        {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "{} is not a token account of {} holding {}",
+            token_account,
+            user_address,
+            token_mint
+        ))
+    }
+
+    /// Amount of `token_mint` in base units the user would offer after adding `token_amount`,
+    /// rounded to the mint's decimals as `sessions.offer_rounding` says.
+    fn offered_amount_after(
+        &self,
+        trade_session: &TradeSession,
+        user_address: &str,
+        token_mint: &str,
+        token_amount: Decimal,
+    ) -> Result<NormalizedOffer> {
+        trade_session.ensure_open()?;
+        if !matches!(
+            trade_session.state.status,
+            TradeStatus::Trading | TradeStatus::OneUserAccepted
+        ) {
+            return Err(trade_session.invalid_state());
+        }
+        let current_offer = trade_session.state.items.get(user_address);
+        if current_offer.is_none() && trade_session.state.items.len() == 2 {
+            let mut participants: Vec<String> = trade_session.state.items.keys().cloned().collect();
+            participants.sort();
+            return Err(SessionError::SessionFull { participants }.into());
+        }
+        if let Some(invite) = &trade_session.invite {
+            if current_offer.is_none() && !invite.admits(user_address) {
+                return Err(Error::msg(
+                    "This trade is reserved for an invited counterparty",
+                ));
+            }
+        }
+        // The same wallet spelled differently would otherwise take the counterparty slot and
+        // only fail once the transaction is built
+        if current_offer.is_none()
+            && trade_session
+                .state
+                .items
+                .keys()
+                .any(|participant| same_wallet(participant, user_address))
+        {
+            return Err(Error::msg("A wallet cannot trade with itself"));
+        }
+        let runtime_config = self.runtime_config.get();
+        let sessions_config = &runtime_config.sessions;
+        let counterparty_offers_mint = trade_session
+            .state
+            .items
+            .iter()
+            .filter(|(participant, _)| participant.as_str() != user_address)
+            .any(|(_, offers)| offers.get(token_mint).is_some_and(|amount| *amount > 0));
+        if counterparty_offers_mint && !sessions_config.allow_same_mint_both_sides {
+            return Err(anyhow!(
+                "{} is already offered by the counterparty, the same mint can't be traded both ways",
+                token_mint
+            ));
+        }
+        let already_offered = current_offer
+            .and_then(|offers| offers.get(token_mint))
+            .copied()
+            .unwrap_or(0);
+        let token_amounts = self.token_amount_cache.get_token_amounts(user_address);
+        let decimals = trade_session
+            .state
+            .mint_decimals
+            .get(token_mint)
+            .copied()
+            .or_else(|| self.token_amount_cache.get_mint_decimals(token_mint));
+        if token_amount <= dec!(0) {
+            return self
+                .non_positive_amount(sessions_config.non_positive_offers, "Offered", token_amount)
+                .map(|()| NormalizedOffer {
+                    offered: already_offered,
+                    decimals: decimals.unwrap_or_default(),
+                    applied: token_amount,
+                    added: dec!(0),
+                });
+        }
+        let offered_mints = current_offer.map_or(0, HashMap::len);
+        let new_mint = !current_offer.is_some_and(|offers| offers.contains_key(token_mint));
+        if new_mint && offered_mints >= sessions_config.max_mints_per_user {
+            return Err(SessionError::TooManyMints {
+                user_address: String::from(user_address),
+                max_mints: sessions_config.max_mints_per_user,
+            }
+            .into());
+        }
+        let token_amounts = token_amounts.ok_or_else(|| {
+            Error::from(SessionError::BalancesNotCached {
+                user_address: String::from(user_address),
+            })
+        })?;
+        let decimals = decimals.ok_or_else(|| {
+            anyhow!(
+                "Decimals of {} are unknown, fetch the wallet's tokens again",
+                token_mint
+            )
+        })?;
+        let token_amount = mint_base_units(
+            token_mint,
+            token_amount,
+            decimals,
+            sessions_config.offer_rounding,
+        )?;
+
+        // Balances are exact since they are read from raw amounts, this only guards against a
+        // cached amount finer than the mint allows
+        let available_tokens = match token_amounts.get(token_mint) {
+            Some(amount) => to_base_units(
+                amount.round_dp_with_strategy(u32::from(decimals), RoundingStrategy::ToZero),
+                decimals,
+            )?,
+            None => 0,
+        };
+        let offered = cmp::min(
+            already_offered.saturating_add(token_amount),
+            available_tokens,
+        );
+        Ok(NormalizedOffer {
+            offered,
+            decimals,
+            applied: from_base_units(token_amount, decimals)?,
+            added: from_base_units(offered.saturating_sub(already_offered), decimals)?,
+        })
+    }
+
+    pub fn withdraw_tokens(
+        &self,
+        session_id: &SessionId,
+        user_address: &str,
+        token_mint: String,
+        token_amount: Decimal,
+    ) -> Result<()> {
+        if token_amount <= dec!(0) {
+            return self.non_positive_amount(
+                self.runtime_config.get().sessions.non_positive_withdrawals,
+                "Withdrawn",
+                token_amount,
+            );
+        }
+        if let Some(mut trade_session) = self.internal.get_mut(session_id) {
+            trade_session.ensure_open()?;
+            if !matches!(
+                trade_session.state.status,
+                TradeStatus::Trading | TradeStatus::OneUserAccepted
+            ) {
+                return Err(trade_session.invalid_state());
+            }
+            let Some(user_offers) = trade_session.state.items.get(user_address) else {
+                return Err(Error::msg(format!(
+                    "There are no tokens {} in session state",
+                    token_mint
+                )));
+            };
+            let Some(offered) = user_offers.get(&token_mint).copied() else {
+                // Nothing of this mint is offered, so the offer and the other side's accept stand
+                return Ok(());
+            };
+            let decimals = trade_session
+                .state
+                .mint_decimals
+                .get(&token_mint)
+                .copied()
+                .unwrap_or_default();
+            let withdrawn = mint_base_units(
+                &token_mint,
+                token_amount,
+                decimals,
+                self.runtime_config.get().sessions.offer_rounding,
+            )?;
+            let withdrawn_amount = from_base_units(withdrawn, decimals)?;
+            let remaining = offered.saturating_sub(withdrawn);
+            // Withdrawing more than offered removes only what was offered
+            let removed_amount = from_base_units(offered - remaining, decimals)?;
+            let user_offers = Arc::make_mut(&mut trade_session.state.items)
+                .entry(String::from(user_address))
+                .or_default();
+            let emptied = remaining == 0;
+            if emptied {
+                user_offers.remove(&token_mint);
+            } else {
+                user_offers.insert(token_mint.clone(), remaining);
+            }
+            if trade_session.state.status == TradeStatus::OneUserAccepted {
+                trade_session.notify_offer_changed(user_address);
+            }
+            self.record_event(
+                session_id,
+                &mut trade_session,
+                SessionEvent::TokensWithdrawn {
+                    user_address: String::from(user_address),
+                    token_mint: token_mint.clone(),
+                    amount: removed_amount,
+                },
+            );
+            let last_rounding =
+                AppliedRounding::between(user_address, &token_mint, token_amount, withdrawn_amount);
+            let mut source_accounts = trade_session.state.source_accounts.clone();
+            let mut up_to_offers = trade_session.state.up_to_offers.clone();
+            let mut mint_decimals = trade_session.state.mint_decimals.clone();
+            if emptied {
+                if let Some(user_sources) = source_accounts.get_mut(user_address) {
+                    user_sources.remove(&token_mint);
+                }
+                if let Some(user_up_to) = up_to_offers.get_mut(user_address) {
+                    user_up_to.remove(&token_mint);
+                }
+                // Decimals are only kept for mints still offered, so they can't pile up
+                if !trade_session
+                    .state
+                    .items
+                    .values()
+                    .any(|offers| offers.contains_key(&token_mint))
+                {
+                    mint_decimals.remove(&token_mint);
+                }
+            }
+
+            trade_session.state = TradeState {
+                items: Arc::clone(&trade_session.state.items),
+                mint_decimals,
+                source_accounts,
+                memos: trade_session.state.memos.clone(),
+                fee_payer: trade_session.state.fee_payer.clone(),
+                up_to_offers,
+                user_acted: None,
+                status: TradeStatus::Trading,
+                tx: None,
+                last_valid_block_height: None,
+                queued_txs: Vec::new(),
+                settled_txs: 0,
+                last_rounding,
+            };
+            self.publish(LifecycleEvent::OfferChanged {
+                session_id: *session_id,
+                user_address: String::from(user_address),
+            });
+            self.persist_session(session_id, &mut trade_session);
+        } else {
+            return Err(Error::from(SessionError::NotFound {
+                session_id: *session_id,
+            }));
+        }
+        Ok(())
+    }
+
+    pub fn accept_trade(&self, session_id: &SessionId, user_address: &str) -> Result<()> {
+        if let Some(mut trade_session) = self.internal.get_mut(session_id) {
+            trade_session.ensure_open()?;
+            if !matches!(
+                trade_session.state.status,
+                TradeStatus::Trading | TradeStatus::OneUserAccepted
+            ) {
+                return Err(trade_session.invalid_state());
+            }
+            if let Some(user_accepted) = &trade_session.state.user_acted {
+                if *user_accepted != user_address {
+                    trade_session.state.user_acted = None;
+                    trade_session.state.status = TradeStatus::Accepted;
+                    self.record_event(
+                        session_id,
+                        &mut trade_session,
+                        SessionEvent::TradeAccepted {
+                            user_address: String::from(user_address),
+                        },
+                    );
+                    self.publish(LifecycleEvent::Accepted {
+                        session_id: *session_id,
+                    });
+                }
+            } else {
+                trade_session.state.user_acted = Some(String::from(user_address));
+                trade_session.state.status = TradeStatus::OneUserAccepted;
+                self.record_event(
+                    session_id,
+                    &mut trade_session,
+                    SessionEvent::TradeAccepted {
+                        user_address: String::from(user_address),
+                    },
+                );
+            }
+            self.persist_session(session_id, &mut trade_session);
+        } else {
+            return Err(Error::from(SessionError::NotFound {
+                session_id: *session_id,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Ends the session as `Cancelled` on behalf of one of its participants, from any status
+    /// before the transaction was sent. An invited counterparty can only cancel once they
+    /// joined. The trade is finalized as `Cancelled` in `trade_store` and the session refuses
+    /// changes from then on.
+    pub fn cancel_trade(&self, session_id: &SessionId, user_address: &str) -> Result<()> {
+        let mut trade_session = self.internal.get_mut(session_id).ok_or_else(|| {
+            Error::from(SessionError::NotFound {
+                session_id: *session_id,
+            })
+        })?;
+        trade_session.ensure_open()?;
+        trade_session.check_permission(user_address, SessionAction::Cancel)?;
+        let state = &mut trade_session.state;
+        state.status = TradeStatus::Cancelled;
+        state.user_acted = Some(String::from(user_address));
+        state.tx = None;
+        state.last_valid_block_height = None;
+        state.queued_txs.clear();
+        self.record_event(
+            session_id,
+            &mut trade_session,
+            SessionEvent::TradeCancelled {
+                user_address: String::from(user_address),
+            },
+        );
+        self.persist_session(session_id, &mut trade_session);
+        self.queue_write(LifecycleEvent::Completed {
+            session_id: *session_id,
+            status: TradeStatus::Cancelled,
+        });
+        Ok(())
+    }
+
+    // The session is looked up twice because a DashMap guard locks its whole shard and must not
+    // be held across .await
+    // First we lock and check conditions for creating transaction
+    // If needed, we create transaction
+    // we lock again and save the transaction to session trade state
+    //
+    // Returns true when this call built the transaction, which is then sent to every client as
+    // `TransactionToSign` in the default encoding. Later calls keep the built transaction.
+    pub async fn get_transaction_to_sign(
+        &self,
+        session_id: &SessionId,
+        user_address: &str,
+    ) -> Result<bool> {
+        if !self.transaction_service.trading_enabled() {
+            return Err(Error::msg(TRANSACTION_BUILDING_DISABLED));
+        }
+        let (
+            need_create_tx,
+            items_to_process,
+            source_accounts,
+            held_accounts,
+            memos,
+            up_to_offers,
+            initiator,
+        ) = {
+            let trade_session = self.internal.get(session_id).ok_or_else(|| {
+                Error::from(SessionError::NotFound {
+                    session_id: *session_id,
+                })
+            })?;
+            trade_session.ensure_open()?;
+            if !matches!(
+                trade_session.state.status,
+                TradeStatus::Accepted
+                    | TradeStatus::TransactionCreated
+                    | TradeStatus::OneUserSigned
+            ) {
+                return Err(trade_session.invalid_state());
+            }
+
+            let need_create = trade_session.state.user_acted.is_none();
+            let items_clone = Arc::clone(&trade_session.state.items);
+            let initiator = trade_session.fee_paying_participant()?;
+            (
+                need_create,
+                items_clone,
+                trade_session.state.source_accounts.clone(),
+                self.held_accounts(&trade_session),
+                trade_session.state.memos.clone(),
+                trade_session.state.up_to_offers.clone(),
+                initiator,
+            )
+        };
+
+        let tx_created = if need_create_tx {
+            let created = self
+                .transaction_service
+                .create_transactions_with_sources(
+                    items_to_process,
+                    &source_accounts,
+                    &held_accounts,
+                    &memos,
+                    &up_to_offers,
+                    &initiator,
+                    0,
+                )
+                .await;
+            match created {
+                Ok(created) => Some(created),
+                Err(e) => {
+                    if let Some(balance_changed) = e.downcast_ref::<BalanceChangedError>() {
+                        return Err(self.lower_unbacked_offers(session_id, balance_changed));
+                    }
+                    return Err(e);
+                }
+            }
+        } else {
+            None
+        };
+
+        if let Some((mut txs, last_valid_block_height)) = tx_created {
+            let mut trade_session = self
+                .internal
+                .get_mut(session_id)
+                .ok_or_else(|| Error::msg("Session disappeared unexpectedly"))?;
+
+            // Built concurrently by the other participant, whose transaction is kept
+            if trade_session.state.user_acted.is_some() {
+                return Ok(false);
+            }
+            let tx = txs.remove(0);
+            let encoding = self.transaction_service.default_encoding();
+            let transaction = encode_transaction(&tx, encoding)?;
+            trade_session.state.tx = Some(tx);
+            trade_session.state.queued_txs = txs;
+            trade_session.state.last_valid_block_height = Some(last_valid_block_height);
+            trade_session.state.user_acted = Some(user_address.to_string());
+            trade_session.state.status = TradeStatus::TransactionCreated;
+            self.persist_session(session_id, &mut trade_session);
+            let batch = trade_session.state.batch();
+            for client in trade_session.ws_clients.values() {
+                let _ = client.try_send(WebsocketMessage::TransactionToSign {
+                    encoding,
+                    transaction: transaction.clone(),
+                    batch,
+                });
+            }
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Lowers the offers the wallets no longer hold by what is missing and returns the session
+    /// to `Trading`, so the participants accept the basket they can actually trade.
+    fn lower_unbacked_offers(
+        &self,
+        session_id: &SessionId,
+        balance_changed: &BalanceChangedError,
+    ) -> Error {
+        let Some(mut trade_session) = self.internal.get_mut(session_id) else {
+            return Error::from(SessionError::NotFound {
+                session_id: *session_id,
+            });
+        };
+        let trade_session = &mut *trade_session;
+        let mut lowered = vec![];
+        for shortfall in &balance_changed.shortfalls {
+            let sender = shortfall.sender.to_string();
+            let mint = shortfall.mint.to_string();
+            let Some(offer) = Arc::make_mut(&mut trade_session.state.items)
+                .get_mut(&sender)
+                .and_then(|offers| offers.get_mut(&mint))
+            else {
+                continue;
+            };
+            *offer = offer.saturating_sub(shortfall.needed - shortfall.held);
+            let offer = *offer;
+            lowered.push(format!(
+                "{} now offers {} of {}",
+                sender,
+                trade_session.state.ui_amount(&mint, offer),
+                mint
+            ));
+            trade_session.notify_offer_changed(&sender);
+            self.publish(LifecycleEvent::OfferChanged {
+                session_id: *session_id,
+                user_address: sender,
+            });
+        }
+        for offers in Arc::make_mut(&mut trade_session.state.items).values_mut() {
+            offers.retain(|_, amount| *amount > 0);
+        }
+        let state = &mut trade_session.state;
+        state
+            .mint_decimals
+            .retain(|mint, _| state.items.values().any(|offers| offers.contains_key(mint)));
+        trade_session.revert_to_trading();
+        self.persist_session(session_id, trade_session);
+        anyhow!(
+            "Balance changed since the offers were made, accept again: {}",
+            lowered.join(", ")
+        )
+    }
+
+    /// The session's built transaction serialized for signing, in the requested encoding or the
+    /// configured default one.
+    pub fn encoded_transaction(
+        &self,
+        session_id: &SessionId,
+        encoding: Option<TransactionEncoding>,
+    ) -> Result<(TransactionEncoding, String)> {
+        let trade_session = self.internal.get(session_id).ok_or_else(|| {
+            Error::from(SessionError::NotFound {
+                session_id: *session_id,
+            })
+        })?;
+        let tx = trade_session
+            .state
+            .tx
+            .as_ref()
+            .ok_or_else(|| Error::msg("No transaction has been created for this trade"))?;
+        let encoding = encoding.unwrap_or_else(|| self.transaction_service.default_encoding());
+        Ok((encoding, encode_transaction(tx, encoding)?))
+    }
+
+    /// Position of the transaction to sign when the trade was split into several transactions,
+    /// `None` for a single transaction trade.
+    pub fn transaction_batch(&self, session_id: &SessionId) -> Option<TransactionBatch> {
+        self.internal.get(session_id)?.state.batch()
+    }
+
+    /// Moves a split trade on to its next transaction once the current one was sent and
+    /// confirmed, which the participants then sign like the first. The next transaction gets a
+    /// fresh blockhash, the one it was built with may be long gone by now. Returns false when the
+    /// landed transaction was the last one.
+    pub async fn next_transaction(&self, session_id: &SessionId) -> Result<bool> {
+        let landed_tx = {
+            let trade_session = self.internal.get(session_id).ok_or_else(|| {
+                Error::from(SessionError::NotFound {
+                    session_id: *session_id,
+                })
+            })?;
+            // Split trades move on from `TransactionSent`, only an ended trade is final here
+            if matches!(
+                trade_session.state.status,
+                TradeStatus::Completed | TradeStatus::Failed
+            ) {
+                return Err(SessionError::SessionClosed {
+                    status: trade_session.state.status.clone(),
+                }
+                .into());
+            }
+            if trade_session.state.status != TradeStatus::TransactionSent {
+                return Err(Error::msg("The current transaction has not been sent yet"));
+            }
+            if trade_session.state.queued_txs.is_empty() {
+                return Ok(false);
+            }
+            trade_session
+                .state
+                .tx
+                .clone()
+                .ok_or_else(|| Error::msg("No transaction has been created for this trade"))?
+        };
+        let (blockhash, last_valid_block_height) =
+            self.chain_context().get_latest_blockhash().await?;
+
+        let mut trade_session = self
+            .internal
+            .get_mut(session_id)
+            .ok_or_else(|| Error::msg("Session disappeared unexpectedly"))?;
+        // Moved on by another call in the meantime
+        if trade_session.state.tx.as_ref() != Some(&landed_tx) {
+            return Ok(true);
+        }
+        let state = &mut trade_session.state;
+        let mut tx = state.queued_txs.remove(0);
+        tx.message.recent_blockhash = blockhash;
+        state.settled_txs += 1;
+        state.tx = Some(tx);
+        state.last_valid_block_height = Some(last_valid_block_height);
+        state.user_acted = None;
+        state.status = TradeStatus::TransactionCreated;
+        let reason = format!(
+            "Transaction {} of {} landed, sign the next one",
+            state.settled_txs,
+            state.settled_txs + 1 + state.queued_txs.len()
+        );
+        trade_session.settling = false;
+        self.persist_session(session_id, &mut trade_session);
+        for client in trade_session.ws_clients.values() {
+            let _ = client.try_send(WebsocketMessage::ResignRequired {
+                reason: reason.clone(),
+            });
+        }
+        trade_session.broadcast_state(None);
+        Ok(true)
+    }
+
+    /// Accounts the transaction of the current offers would reference, see
+    /// `TransactionService::preview_accounts`.
+    pub async fn preview_accounts(&self, session_id: &SessionId) -> Result<Vec<AccountMeta>> {
+        let (items, source_accounts, held_accounts, memos, up_to_offers, initiator) = {
+            let trade_session = self.internal.get(session_id).ok_or_else(|| {
+                Error::from(SessionError::NotFound {
+                    session_id: *session_id,
+                })
+            })?;
+            let initiator = trade_session.fee_paying_participant()?;
+            (
+                Arc::clone(&trade_session.state.items),
+                trade_session.state.source_accounts.clone(),
+                self.held_accounts(&trade_session),
+                trade_session.state.memos.clone(),
+                trade_session.state.up_to_offers.clone(),
+                initiator,
+            )
+        };
+        self.transaction_service
+            .preview_accounts(
+                items,
+                &source_accounts,
+                &held_accounts,
+                &memos,
+                &up_to_offers,
+                &initiator,
+            )
+            .await
+    }
+
+    /// Network fee of the session's built transaction in lamports.
+    pub async fn get_fee_estimate(&self, session_id: &SessionId) -> Result<u64> {
+        let tx = {
+            let trade_session = self.internal.get(session_id).ok_or_else(|| {
+                Error::from(SessionError::NotFound {
+                    session_id: *session_id,
+                })
+            })?;
+            trade_session
+                .state
+                .tx
+                .clone()
+                .ok_or_else(|| Error::msg("No transaction has been created for this trade"))?
+        };
+        self.transaction_service.estimate_fee(&tx).await
+    }
+
+    /// Discards the built transaction, together with any signatures collected for it, and
+    /// returns the session to `Trading` so the offers can be edited again.
+    pub fn reject_transaction(&self, session_id: &SessionId, user_address: &str) -> Result<()> {
+        let mut trade_session = self.internal.get_mut(session_id).ok_or_else(|| {
+            Error::from(SessionError::NotFound {
+                session_id: *session_id,
+            })
+        })?;
+        trade_session.ensure_open()?;
+        if !matches!(
+            trade_session.state.status,
+            TradeStatus::TransactionCreated | TradeStatus::OneUserSigned
+        ) {
+            return Err(trade_session.invalid_state());
+        }
+        if !trade_session.state.items.contains_key(user_address) {
+            return Err(Error::msg(format!(
+                "User {} is not part of this trade",
+                user_address
+            )));
+        }
+        if trade_session.state.settled_txs > 0 {
+            return Err(Error::msg(
+                "Part of the trade already settled, only the remaining transactions can be signed",
+            ));
+        }
+        trade_session.revert_to_trading();
+        self.persist_session(session_id, &mut trade_session);
+        Ok(())
+    }
+
+    /// Rebuilds the session's transaction with a fresh blockhash when the chain moved past the
+    /// last block height its blockhash is valid for, and asks the clients to sign again.
+    /// `settle` checks this right before submitting, signatures collected for the old
+    /// transaction are discarded with it. Returns whether it was rebuilt.
+    pub async fn refresh_stale_blockhash(&self, session_id: &SessionId) -> Result<bool> {
+        let (
+            stale_tx,
+            last_valid_block_height,
+            settled,
+            items,
+            source_accounts,
+            held_accounts,
+            memos,
+            up_to_offers,
+            initiator,
+        ) = {
+            let trade_session = self.internal.get(session_id).ok_or_else(|| {
+                Error::from(SessionError::NotFound {
+                    session_id: *session_id,
+                })
+            })?;
+            let Some(tx) = trade_session.state.tx.clone() else {
+                return Ok(false);
+            };
+            // A sent transaction must keep its signatures, whatever its blockhash
+            if !matches!(
+                trade_session.state.status,
+                TradeStatus::TransactionCreated
+                    | TradeStatus::OneUserSigned
+                    | TradeStatus::FullySigned
+            ) {
+                return Ok(false);
+            }
+            let initiator = trade_session.fee_paying_participant()?;
+            (
+                tx,
+                trade_session.state.last_valid_block_height,
+                trade_session.state.settled_txs,
+                Arc::clone(&trade_session.state.items),
+                trade_session.state.source_accounts.clone(),
+                self.held_accounts(&trade_session),
+                trade_session.state.memos.clone(),
+                trade_session.state.up_to_offers.clone(),
+                initiator,
+            )
+        };
+        let expired = match last_valid_block_height {
+            Some(last_valid_block_height) => {
+                self.chain_context().get_block_height().await? > last_valid_block_height
+            }
+            // Snapshots from before the height was kept, only the cluster can tell
+            None => {
+                !self
+                    .chain_context()
+                    .is_blockhash_valid(&stale_tx.message.recent_blockhash)
+                    .await?
+            }
+        };
+        if !expired {
+            return Ok(false);
+        }
+        let (mut txs, last_valid_block_height) = self
+            .transaction_service
+            .create_transactions_with_sources(
+                items,
+                &source_accounts,
+                &held_accounts,
+                &memos,
+                &up_to_offers,
+                &initiator,
+                settled,
+            )
+            .await?;
+
+        let mut trade_session = self
+            .internal
+            .get_mut(session_id)
+            .ok_or_else(|| Error::msg("Session disappeared unexpectedly"))?;
+        // Rejected or replaced in the meantime
+        if trade_session.state.tx.as_ref() != Some(&stale_tx) {
+            return Ok(false);
+        }
+        trade_session.state.tx = Some(txs.remove(0));
+        trade_session.state.queued_txs = txs;
+        trade_session.state.last_valid_block_height = Some(last_valid_block_height);
+        if matches!(
+            trade_session.state.status,
+            TradeStatus::OneUserSigned | TradeStatus::FullySigned
+        ) {
+            trade_session.state.status = TradeStatus::TransactionCreated;
+        }
+        trade_session.settling = false;
+        self.persist_session(session_id, &mut trade_session);
+        for client in trade_session.ws_clients.values() {
+            let _ = client.try_send(WebsocketMessage::ResignRequired {
+                reason: "Transaction blockhash expired, sign the rebuilt transaction".to_string(),
+            });
+        }
+        trade_session.broadcast_state(None);
+        Ok(true)
+    }
+
+    /// Attaches `user_address`'s signature to the session's transaction. Refused unless the
+    /// user is one of its signers and the signature is theirs over exactly this transaction's
+    /// message, so a signature of another transaction can't be slipped in.
+    ///
+    /// The first signature moves the session to `OneUserSigned`, the last one to `FullySigned`.
+    /// When the server pays the fees its signature is added along with the participants', so
+    /// their signatures are all that is missing. Submitting a signature that is already attached
+    /// changes nothing.
+    pub fn sign_transaction(
+        &self,
+        session_id: &SessionId,
+        user_address: &str,
+        signature: String,
+    ) -> Result<()> {
+        let mut trade_session = self.internal.get_mut(session_id).ok_or_else(|| {
+            Error::from(SessionError::NotFound {
+                session_id: *session_id,
+            })
+        })?;
+        trade_session.ensure_open()?;
+        if !matches!(
+            trade_session.state.status,
+            TradeStatus::TransactionCreated | TradeStatus::OneUserSigned
+        ) {
+            return Err(trade_session.invalid_state());
+        }
+        let tx = trade_session
+            .state
+            .tx
+            .as_mut()
+            .ok_or_else(|| Error::msg("No transaction has been created for this trade"))?;
+        let signer = Pubkey::from_str(user_address)?;
+        let parsed = Signature::from_str(&signature)
+            .map_err(|e| anyhow!("Invalid signature {}: {}", signature, e))?;
+        let position = tx
+            .message
+            .account_keys
+            .iter()
+            .take(usize::from(tx.message.header.num_required_signatures))
+            .position(|key| *key == signer)
+            .ok_or_else(|| anyhow!("{} is not a signer of the trade transaction", user_address))?;
+        if !parsed.verify(signer.as_ref(), &tx.message_data()) {
+            return Err(anyhow!(
+                "Signature of {} does not match the trade transaction",
+                user_address
+            ));
+        }
+        if tx.signatures[position] == parsed {
+            return Ok(());
+        }
+        self.transaction_service.sign_as_fee_payer(tx)?;
+        tx.signatures[position] = parsed;
+        let fully_signed = !tx.signatures.contains(&Signature::default());
+        trade_session.state.status = if fully_signed {
+            TradeStatus::FullySigned
+        } else {
+            TradeStatus::OneUserSigned
+        };
+        self.persist_session(session_id, &mut trade_session);
+        self.record_event(
+            session_id,
+            &mut trade_session,
+            SessionEvent::TransactionSigned {
+                user_address: String::from(user_address),
+                signature,
+            },
+        );
+        if fully_signed {
+            self.publish(LifecycleEvent::FullySigned {
+                session_id: *session_id,
+            });
+        }
+        Ok(())
+    }
+
+    /// Ends the session with the outcome of its transaction: the trade is persisted as
+    /// `Completed` or `Failed`, along with the transaction signature, and the clients are told.
+    /// The session is dropped once its last snapshot is written, or when its last client leaves
+    /// if any are still connected. `session_state` serves it from the trade record from then on.
+    /// Repeated calls for a trade that already ended change nothing and return false.
+    pub async fn finish_trade(
+        &self,
+        session_id: &SessionId,
+        outcome: &ConfirmationOutcome,
+    ) -> Result<bool> {
+        if let Some(trade_store) = &self.trade_store {
+            let trade_store = Arc::clone(trade_store);
+            let trade_id = *session_id;
+            let trade_status = outcome.trade_status();
+            let failure_reason = outcome.failure_reason();
+            let signature = self.transaction_signature(session_id);
+            let finalized = tokio::task::spawn_blocking(move || {
+                let finalized = trade_store
+                    .finalize_trade(&trade_id, &trade_status)
+                    .map_err(|e| anyhow!("Unable to finalize trade {}: {}", trade_id, e))?;
+                let mut details = serde_json::Map::new();
+                if let Some(signature) = signature {
+                    details.insert("signature".to_string(), signature.to_string().into());
+                }
+                if let Some(reason) = failure_reason {
+                    details.insert("failure_reason".to_string(), reason.into());
+                }
+                if finalized && !details.is_empty() {
+                    let details = serde_json::Value::Object(details);
+                    if let Err(e) = trade_store.merge_status_details(&trade_id, &details) {
+                        warn!("Unable to persist outcome of trade {}: {}", trade_id, e);
+                    }
+                }
+                Ok::<bool, Error>(finalized)
+            })
+            .await??;
+            if !finalized {
+                return Ok(false);
+            }
+        }
+        let Some(mut trade_session) = self.internal.get_mut(session_id) else {
+            return Ok(true);
+        };
+        if matches!(
+            trade_session.state.status,
+            TradeStatus::Completed | TradeStatus::Failed
+        ) {
+            return Ok(false);
+        }
+        trade_session.state.status = match outcome {
+            ConfirmationOutcome::Confirmed => TradeStatus::Completed,
+            _ => TradeStatus::Failed,
+        };
+        self.persist_session(session_id, &mut trade_session);
+        trade_session.broadcast_state(None);
+        self.publish(LifecycleEvent::Completed {
+            session_id: *session_id,
+            status: trade_session.state.status.clone(),
+        });
+        drop(trade_session);
+        // Written here, the session writer only finds sessions still in memory
+        self.write_session(session_id).await;
+        self.internal
+            .remove_if(session_id, |_, trade_session| trade_session.is_abandoned());
+        Ok(true)
+    }
+
+    /// Sends the session's fully signed transaction and ends the trade with its outcome, or
+    /// moves a split trade on to its next transaction, see `spawn_settlement_task`. A session
+    /// already `TransactionSent`, e.g. restored after a restart, is only awaited. Sessions in
+    /// neither status, or being settled already, are left alone. A failed attempt leaves the
+    /// session to be settled again.
+    pub async fn settle(&self, session_id: &SessionId, config: &ConfirmationConfig) -> Result<()> {
+        let Some((tx, sent)) = self.claim_settlement(session_id) else {
+            return Ok(());
+        };
+        let settled = self.settle_claimed(session_id, tx, sent, config).await;
+        if settled.is_err() {
+            // Released for the next attempt
+            if let Some(mut trade_session) = self.internal.get_mut(session_id) {
+                trade_session.settling = false;
+            }
+        }
+        settled
+    }
+
+    /// `settle` once the session is claimed.
+    async fn settle_claimed(
+        &self,
+        session_id: &SessionId,
+        tx: Transaction,
+        sent: bool,
+        config: &ConfirmationConfig,
+    ) -> Result<()> {
+        if !sent {
+            match self.refresh_stale_blockhash(session_id).await {
+                // Settled once everyone signed the rebuilt transaction
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => warn!("Unable to check blockhash of session {}: {}", session_id, e),
+            }
+            if let Err(e) = self.chain_context().send_transaction(&tx).await {
+                let outcome =
+                    ConfirmationOutcome::Failed(format!("Unable to send the transaction: {}", e));
+                self.finish_trade(session_id, &outcome).await?;
+                return Ok(());
+            }
+            self.mark_sent(session_id);
+        }
+        let mut outcome = loop {
+            match await_confirmation(self.chain_context(), &tx, config).await {
+                Ok(outcome) => break outcome,
+                // The transaction may land all the same, only the chain can tell
+                Err(e) => {
+                    warn!(
+                        "Unable to confirm transaction of session {}: {}",
+                        session_id, e
+                    );
+                    tokio::time::sleep(CONFIRMATION_RETRY_INTERVAL).await;
+                }
+            }
+        };
+        if outcome == ConfirmationOutcome::Confirmed {
+            match self.next_transaction(session_id).await {
+                // Settled once everyone signed the next transaction of a split trade
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => {
+                    outcome = ConfirmationOutcome::Failed(format!(
+                        "Unable to move on to the next transaction: {}",
+                        e
+                    ))
+                }
+            }
+        }
+        self.finish_trade(session_id, &outcome).await?;
+        Ok(())
+    }
+
+    /// The transaction to settle and whether it was sent already, claiming the session so no
+    /// other `settle` call sends it too.
+    fn claim_settlement(&self, session_id: &SessionId) -> Option<(Transaction, bool)> {
+        let mut trade_session = self.internal.get_mut(session_id)?;
+        let sent = match trade_session.state.status {
+            TradeStatus::FullySigned => false,
+            TradeStatus::TransactionSent => true,
+            _ => return None,
+        };
+        if trade_session.settling {
+            return None;
+        }
+        let tx = trade_session.state.tx.clone()?;
+        trade_session.settling = true;
+        Some((tx, sent))
+    }
+
+    /// Moves the session on to `TransactionSent` once the cluster accepted its transaction.
+    fn mark_sent(&self, session_id: &SessionId) {
+        let Some(mut trade_session) = self.internal.get_mut(session_id) else {
+            return;
+        };
+        if trade_session.state.status != TradeStatus::FullySigned {
+            return;
+        }
+        trade_session.state.status = TradeStatus::TransactionSent;
+        self.persist_session(session_id, &mut trade_session);
+        trade_session.broadcast_state(None);
+        self.publish(LifecycleEvent::TransactionSent {
+            session_id: *session_id,
+        });
+    }
+
+    /// Sessions with a signed transaction that isn't settled yet and that nothing is settling.
+    fn unsettled_sessions(&self) -> Vec<SessionId> {
+        self.internal
+            .iter()
+            .filter(|entry| {
+                matches!(
+                    entry.state.status,
+                    TradeStatus::FullySigned | TradeStatus::TransactionSent
+                ) && !entry.settling
+            })
+            .map(|entry| *entry.key())
+            .collect()
+    }
+
+    /// Signature identifying the session's transaction once the fee payer signed it.
+    fn transaction_signature(&self, session_id: &SessionId) -> Option<Signature> {
+        let trade_session = self.internal.get(session_id)?;
+        let tx = trade_session.state.tx.as_ref()?;
+        tx.signatures
+            .first()
+            .copied()
+            .filter(|signature| *signature != Signature::default())
+    }
+
+    /// Outcome of a request for a zero or negative amount, which changes nothing either way.
+    fn non_positive_amount(
+        &self,
+        handling: NonPositiveAmounts,
+        action: &str,
+        token_amount: Decimal,
+    ) -> Result<()> {
+        match handling {
+            NonPositiveAmounts::Ignore => Ok(()),
+            NonPositiveAmounts::Reject => Err(anyhow!(
+                "{} amount must be positive, got {}",
+                action,
+                token_amount
+            )),
+        }
+    }
+
+    /// Records a change of the session for the session writer to store in the trade record, for
+    /// `restore_sessions` to bring it back from. See `spawn_session_writer`.
+    fn persist_session(&self, session_id: &SessionId, trade_session: &mut TradeSession) {
+        if self.trade_store.is_none() {
+            return;
+        }
+        trade_session.revision += 1;
+        self.queue_write(LifecycleEvent::Updated {
+            session_id: *session_id,
+        });
+    }
+
+    /// Publishes an event the session writer stores in the trade record, counted for `flushed`.
+    fn queue_write(&self, event: LifecycleEvent) {
+        if self.trade_store.is_some() {
+            self.queued_writes.fetch_add(1, Ordering::SeqCst);
+        }
+        self.publish(event);
+    }
+
+    /// Stores what `event` changed in the trade record, see `spawn_session_writer`.
+    async fn write_event(&self, event: LifecycleEvent) {
+        match event {
+            LifecycleEvent::Updated { session_id } => self.write_session(&session_id).await,
+            LifecycleEvent::Completed {
+                session_id,
+                status: status @ (TradeStatus::Cancelled | TradeStatus::Expired),
+            } => self.write_status(&session_id, status).await,
+            _ => return,
+        }
+        self.finished_writes.fetch_add(1, Ordering::SeqCst);
+        self.write_done.notify_waiters();
+    }
+
+    /// Stores the latest snapshot of the session in the trade record, unless it's there already.
+    async fn write_session(&self, session_id: &SessionId) {
+        let Some(trade_store) = &self.trade_store else {
+            return;
+        };
+        let Some(update) = self
+            .internal
+            .get(session_id)
+            .and_then(|trade_session| trade_session.unwritten_update(session_id))
+        else {
+            return;
+        };
+        let revision = update.revision;
+        let trade_store = Arc::clone(trade_store);
+        let trade_id = *session_id;
+        let written = tokio::task::spawn_blocking(move || {
+            trade_store
+                .update_trade(&trade_id, &update)
+                .map_err(|e| e.to_string())
+        })
+        .await;
+        match written {
+            Ok(Ok(())) => {
+                if let Some(mut trade_session) = self.internal.get_mut(session_id) {
+                    trade_session.persisted_revision =
+                        trade_session.persisted_revision.max(revision);
+                }
+            }
+            Ok(Err(e)) => warn!("Unable to persist session {}: {}", session_id, e),
+            Err(e) => warn!("Unable to persist session {}: {}", session_id, e),
+        }
+    }
+
+    /// Ends the trade record of a cancelled or expired session. Cancelled trades are finalized,
+    /// a trade that ended otherwise in the meantime keeps its status.
+    async fn write_status(&self, session_id: &SessionId, status: TradeStatus) {
+        let Some(trade_store) = &self.trade_store else {
+            return;
+        };
+        let trade_store = Arc::clone(trade_store);
+        let trade_id = *session_id;
+        let written = tokio::task::spawn_blocking(move || {
+            match status {
+                TradeStatus::Cancelled => trade_store
+                    .finalize_trade(&trade_id, &trade_repository::TradeStatus::Cancelled)
+                    .map(|_| ()),
+                _ => trade_store
+                    .update_trade_status(&trade_id, &trade_repository::TradeStatus::Expired),
+            }
+            .map_err(|e| e.to_string())
+        })
+        .await;
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Unable to end trade {}: {}", session_id, e),
+            Err(e) => warn!("Unable to end trade {}: {}", session_id, e),
+        }
+    }
+
+    /// Waits until the session writer handled every write queued so far, whether it succeeded
+    /// or not.
+    #[cfg(test)]
+    pub async fn flushed(&self) {
+        let queued = self.queued_writes.load(Ordering::SeqCst);
+        loop {
+            let written = self.write_done.notified();
+            tokio::pin!(written);
+            // Registered before checking, a write finishing in between isn't missed
+            written.as_mut().enable();
+            if self.finished_writes.load(Ordering::SeqCst) >= queued {
+                return;
+            }
+            written.await;
+        }
+    }
+
+    /// Writes the sessions with changes not yet stored in the trade record, after the session
+    /// writer missed their events. Missed writes count as finished for `flushed`.
+    async fn write_unwritten_sessions(&self) {
+        let unwritten: Vec<SessionId> = self
+            .internal
+            .iter()
+            .filter(|entry| entry.revision > entry.persisted_revision)
+            .map(|entry| *entry.key())
+            .collect();
+        for session_id in unwritten {
+            self.write_session(&session_id).await;
+        }
+        self.finished_writes
+            .fetch_max(self.queued_writes.load(Ordering::SeqCst), Ordering::SeqCst);
+        self.write_done.notify_waiters();
+    }
+
+    /// The session, created and announced as `LifecycleEvent::Created` when it's new.
+    fn session_entry(&self, session_id: SessionId) -> RefMut<'_, SessionId, TradeSession> {
+        match self.internal.entry(session_id) {
+            dashmap::mapref::entry::Entry::Occupied(entry) => entry.into_ref(),
+            dashmap::mapref::entry::Entry::Vacant(entry) => {
+                self.publish(LifecycleEvent::Created { session_id });
+                entry.insert(TradeSession::default())
+            }
+        }
+    }
+
+    /// Sending only fails without subscribers, nobody is interested then.
+    fn publish(&self, event: LifecycleEvent) {
+        let _ = self.lifecycle.send(event);
+    }
+
+    /// Keeps the event for clients joining later and appends it to the audit trail.
+    fn record_event(
+        &self,
+        session_id: &SessionId,
+        trade_session: &mut TradeSession,
+        event: SessionEvent,
+    ) {
+        self.audit_log.record(event.audit_entry(*session_id));
+        trade_session.record_event(event);
+        trade_session.touch(Instant::now());
+    }
+}
+
+/// Reaps idle sessions every `IDLE_SESSION_SWEEP_INTERVAL` until `shutdown` is cancelled.
+pub fn spawn_idle_session_reaper<T: ChainContext + Send + Sync + 'static>(
+    sessions: Arc<SharedSessions<T>>,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(IDLE_SESSION_SWEEP_INTERVAL);
+        while shutdown
+            .run_until_cancelled(interval.tick())
+            .await
+            .is_some()
+        {
+            let reaped = sessions.reap_idle_sessions(Instant::now());
+            if !reaped.is_empty() {
+                info!("Closed {} idle sessions", reaped.len());
+            }
+        }
+        info!("Idle session reaper stopped");
+    })
+}
+
+/// Settles every session whose last signature lands, until `shutdown` is cancelled: the
+/// transaction is sent, awaited as `confirmation` says and the trade finished with the outcome.
+/// Sessions signed while nobody listened, before a restart or while events were missed, are
+/// picked up too. Failed attempts are retried, settlements still running on shutdown resume
+/// after the restart.
+pub fn spawn_settlement_task<T: ChainContext + Send + Sync + 'static>(
+    sessions: Arc<SharedSessions<T>>,
+    confirmation: ConfirmationConfig,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    // Subscribed before the first scan, nothing signed in between is missed
+    let mut events = sessions.subscribe();
+    tokio::spawn(async move {
+        let mut settlements = JoinSet::new();
+        let settle = |settlements: &mut JoinSet<()>, session_id: SessionId| {
+            let sessions = Arc::clone(&sessions);
+            let confirmation = confirmation.clone();
+            settlements.spawn(async move {
+                while let Err(e) = sessions.settle(&session_id, &confirmation).await {
+                    warn!("Unable to settle session {}, retrying: {}", session_id, e);
+                    tokio::time::sleep(CONFIRMATION_RETRY_INTERVAL).await;
+                }
+            });
+        };
+        for session_id in sessions.unsettled_sessions() {
+            settle(&mut settlements, session_id);
+        }
+        loop {
+            let event = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                Some(_) = settlements.join_next() => continue,
+                event = events.recv() => event,
+            };
+            match event {
+                Ok(LifecycleEvent::FullySigned { session_id }) => {
+                    settle(&mut settlements, session_id)
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Settlement missed {} lifecycle events", missed);
+                    for session_id in sessions.unsettled_sessions() {
+                        settle(&mut settlements, session_id);
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+        info!("Settlement stopped");
+    })
+}
+
+/// Stores the changes of every session in its trade record until `shutdown` is cancelled, one
+/// write at a time so they land in order. Changes not written by then are written before the
+/// writer stops.
+pub fn spawn_session_writer<T: ChainContext + Send + Sync + 'static>(
+    sessions: Arc<SharedSessions<T>>,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    let mut events = sessions.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                event = events.recv() => event,
+            };
+            match event {
+                Ok(event) => sessions.write_event(event).await,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Session writer missed {} lifecycle events", missed);
+                    sessions.write_unwritten_sessions().await;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+        loop {
+            match events.try_recv() {
+                Ok(event) => sessions.write_event(event).await,
+                Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+        sessions.write_unwritten_sessions().await;
+        info!("Session writer stopped");
+    })
+}
+
+/// Whether both addresses name the same wallet, compared as public keys when both parse as one.
+fn same_wallet(address: &str, other: &str) -> bool {
+    match (
+        Pubkey::from_str(address.trim()),
+        Pubkey::from_str(other.trim()),
+    ) {
+        (Ok(address), Ok(other)) => address == other,
+        _ => address.trim() == other.trim(),
+    }
+}
+
+/// Cached balances expire, the session is told to refresh them so the next offer isn't
+/// checked against nothing.
+fn balance_expired_message(user_address: &str) -> String {
+    format!(
+        "Cached balance of {} expired, refresh tokens before offering",
+        user_address
+    )
+}
+
+/// `amount` of `token_mint` in base units, amounts finer than the mint's decimals are refused or
+/// rounded as `rounding` says. NFTs have no decimals, so this also keeps anyone from offering
+/// half of one.
+fn mint_base_units(
+    token_mint: &str,
+    amount: Decimal,
+    decimals: u8,
+    rounding: OfferRounding,
+) -> Result<u64> {
+    let whole_units_only = || anyhow!("{} can only be offered in whole units", token_mint);
+    let decimal_places = u32::from(decimals);
+    if amount.normalize().scale() <= decimal_places {
+        return to_base_units(amount, decimals);
+    }
+    let rounded = match rounding {
+        OfferRounding::Reject if decimals == 0 => return Err(whole_units_only()),
+        OfferRounding::Reject => {
+            return Err(anyhow!("{} has at most {} decimals", token_mint, decimals))
+        }
+        OfferRounding::Floor => {
+            amount.round_dp_with_strategy(decimal_places, RoundingStrategy::ToZero)
+        }
+        OfferRounding::HalfUp => {
+            amount.round_dp_with_strategy(decimal_places, RoundingStrategy::MidpointAwayFromZero)
+        }
+    };
+    if rounded.is_zero() {
+        return Err(if decimals == 0 {
+            whole_units_only()
+        } else {
+            anyhow!(
+                "{} of {} rounds to 0 at {} decimals",
+                amount,
+                token_mint,
+                decimals
+            )
+        });
+    }
+    to_base_units(rounded, decimals)
+}
+
+/// Result of `offered_amount_after`.
+struct NormalizedOffer {
+    /// Total offered after the change, in base units.
+    offered: u64,
+    decimals: u8,
+    /// The requested amount after rounding it to the mint's decimals.
+    applied: Decimal,
+    /// What the offer actually grows by, `applied` clamped to the available balance.
+    added: Decimal,
+}
 
 #[derive(Default)]
 pub struct TradeSession {
     pub state: TradeState,
+    /// The creator of a targeted trade, otherwise the first participant to offer.
+    pub initiator: Option<String>,
     pub ws_clients: HashMap<ConnectionId, mpsc::Sender<WebsocketMessage>>,
+    /// Participant each identified connection acts for.
+    pub connection_users: HashMap<ConnectionId, String>,
+    /// The last `SESSION_EVENT_LOG_SIZE` events, oldest first.
+    pub events: VecDeque<SessionEvent>,
+    /// Set for targeted trades, open trades admit any second participant.
+    pub invite: Option<TradeInvite>,
+    /// State the clients were last brought up to date with, deltas are computed against it.
+    pub last_broadcast: Option<TradeState>,
+    /// Number of state broadcasts so far, sent as their `seq`.
+    pub broadcast_seq: u64,
+    /// Set while a coalesced broadcast is waiting to be sent.
+    pub pending_broadcast: Option<PendingBroadcast>,
+    /// Last connection, offer change or keepalive, idle sessions are reaped.
+    pub last_activity: Option<Instant>,
+    /// Last keepalive that counted, keepalives are rate limited.
+    pub last_keepalive: Option<Instant>,
+    /// Revision of the session's state, bumped by every change that is persisted.
+    pub revision: u64,
+    /// Revision stored in the trade record, behind `revision` while a write is due.
+    pub persisted_revision: u64,
+    /// Set while `SharedSessions::settle` sends or awaits the transaction.
+    pub settling: bool,
+}
+
+/// What `SharedSessions::write_session` stores of a session.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PersistedSession {
+    revision: u64,
+    initiator: Option<String>,
+    state: TradeState,
+}
+
+impl PersistedSession {
+    /// The snapshot stored in the trade record, if any.
+    fn of_trade(
+        trade: &trade_repository::TradeEntity,
+    ) -> Option<std::result::Result<PersistedSession, serde_json::Error>> {
+        trade
+            .status_details
+            .as_ref()
+            .and_then(|details| details.get("session"))
+            .map(|session| serde_json::from_value(session.clone()))
+    }
+}
+
+pub struct PendingBroadcast {
+    pub first_change: Instant,
+    pub last_change: Instant,
+}
+
+impl PendingBroadcast {
+    fn due(&self, debounce: Duration, max_delay: Duration) -> Instant {
+        cmp::min(self.last_change + debounce, self.first_change + max_delay)
+    }
+}
+
+pub struct TradeInvite {
+    pub initiator: String,
+    pub counterparty: String,
+}
+
+impl TradeInvite {
+    fn admits(&self, user_address: &str) -> bool {
+        user_address == self.initiator || user_address == self.counterparty
+    }
+}
+
+impl TradeSession {
+    fn warn_clients(&self, message: String) {
+        for tx in self.ws_clients.values() {
+            let _ = tx.try_send(WebsocketMessage::Warning {
+                message: message.clone(),
+            });
+        }
+    }
+
+    /// What to store in the trade record when it lags behind the session.
+    fn unwritten_update(&self, session_id: &SessionId) -> Option<TradeUpdate> {
+        if self.revision <= self.persisted_revision {
+            return None;
+        }
+        let snapshot = PersistedSession {
+            revision: self.revision,
+            initiator: self.initiator.clone(),
+            state: self.state.clone(),
+        };
+        let session = match serde_json::to_value(&snapshot) {
+            Ok(session) => session,
+            Err(e) => {
+                warn!("Unable to serialize session {}: {}", session_id, e);
+                return None;
+            }
+        };
+        Some(TradeUpdate {
+            counterparty: self
+                .state
+                .items
+                .keys()
+                .find(|participant| self.initiator.as_ref() != Some(*participant))
+                .cloned(),
+            revision: snapshot.revision,
+            status: self.state.status.persisted(),
+            session,
+            memos: serde_json::json!(self.state.memos),
+        })
+    }
+
+    /// Full `TradeStateUpdate` of the current state.
+    fn snapshot(&self) -> WebsocketMessage {
+        WebsocketMessage::TradeStateUpdate {
+            offers: Arc::new(self.state.ui_items()),
+            user_acted: self.state.user_acted.clone(),
+            status: self.state.status.clone(),
+            tx: self.state.tx.clone(),
+            memos: self.state.memos.clone(),
+            fee_payer: self.state.fee_payer.clone(),
+            rounding: self.state.last_rounding.clone().map(Box::new),
+            roles: self.roles(&self.state),
+            read_only: self.state.status.is_closed(),
+            up_to: self.state.up_to_offers.clone(),
+            seq: self.broadcast_seq,
+        }
+    }
+
+    /// Broadcasts the state change since the last broadcast, `resync` gets a full snapshot
+    /// instead of the delta.
+    fn broadcast_state(&mut self, resync: Option<&ConnectionId>) {
+        let delta = match &self.last_broadcast {
+            Some(previous)
+                if previous.status == self.state.status
+                    && previous.user_acted == self.state.user_acted
+                    && previous.tx == self.state.tx
+                    && previous.memos == self.state.memos
+                    && previous.fee_payer == self.state.fee_payer
+                    && previous.last_rounding == self.state.last_rounding
+                    && self.roles(previous) == self.roles(&self.state) =>
+            {
+                let (changed, removed) = self.state.items_delta(previous);
+                let up_to = (previous.up_to_offers != self.state.up_to_offers)
+                    .then(|| self.state.up_to_offers.clone());
+                Some((changed, removed, up_to))
+            }
+            _ => None,
+        };
+        let unchanged = matches!(
+            &delta,
+            Some((changed, removed, None)) if changed.is_empty() && removed.is_empty()
+        );
+        if !unchanged {
+            self.broadcast_seq += 1;
+        }
+        let snapshot = self.snapshot();
+        let update = match delta {
+            _ if unchanged => None,
+            Some((changed, removed, up_to)) => Some(WebsocketMessage::TradeStateDelta {
+                seq: self.broadcast_seq,
+                changed,
+                removed,
+                up_to,
+            }),
+            None => Some(snapshot.clone()),
+        };
+        for (connection_id, tx) in &self.ws_clients {
+            let message = if Some(connection_id) == resync {
+                Some(snapshot.clone())
+            } else {
+                update.clone()
+            };
+            if let Some(message) = message {
+                let _ = tx.try_send(message);
+            }
+        }
+        self.last_broadcast = Some(self.state.clone());
+    }
+
+    fn record_event(&mut self, event: SessionEvent) {
+        if self.events.len() == SESSION_EVENT_LOG_SIZE {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Tells the clients that `user_address` changed the basket, reverting an accept.
+    fn notify_offer_changed(&self, user_address: &str) {
+        for tx in self.ws_clients.values() {
+            let _ = tx.try_send(WebsocketMessage::OfferChanged {
+                by: String::from(user_address),
+            });
+        }
+    }
+
+    /// Role of every participant of `state`, invited participants count before their first
+    /// offer.
+    fn roles(&self, state: &TradeState) -> HashMap<String, ParticipantRole> {
+        let invited = self
+            .invite
+            .iter()
+            .flat_map(|invite| [&invite.initiator, &invite.counterparty]);
+        state
+            .items
+            .keys()
+            .chain(invited)
+            .map(|participant| {
+                let role = if self.initiator.as_ref() == Some(participant) {
+                    ParticipantRole::Initiator
+                } else {
+                    ParticipantRole::Counterparty
+                };
+                (participant.clone(), role)
+            })
+            .collect()
+    }
+
+    /// Refuses actions the participant's role doesn't allow, on top of the status checks of each
+    /// action:
+    ///
+    /// | action                 | initiator | counterparty |
+    /// |------------------------|-----------|--------------|
+    /// | cancel                 | always    | once joined  |
+    /// | make the other one pay | never     | always       |
+    ///
+    /// The counterparty joins with their first offer, an invited counterparty who didn't offer
+    /// yet declines by not joining. The initiator pays the fees by default, so only the
+    /// counterparty can take them over.
+    fn check_permission(&self, user_address: &str, action: SessionAction) -> Result<()> {
+        let Some(role) = self.roles(&self.state).get(user_address).copied() else {
+            return Err(anyhow!(
+                "{} is not a participant of this session",
+                user_address
+            ));
+        };
+        let allowed = match (action, role) {
+            (SessionAction::Cancel, ParticipantRole::Initiator) => true,
+            (SessionAction::Cancel, ParticipantRole::Counterparty) => {
+                self.state.items.contains_key(user_address)
+            }
+            (SessionAction::PassOnFees, ParticipantRole::Initiator) => false,
+            (SessionAction::PassOnFees, ParticipantRole::Counterparty) => true,
+        };
+        if !allowed {
+            return Err(SessionError::Forbidden {
+                user_address: String::from(user_address),
+                role,
+                action,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn invalid_state(&self) -> Error {
+        SessionError::InvalidState {
+            status: self.state.status.clone(),
+        }
+        .into()
+    }
+
+    fn ensure_open(&self) -> Result<()> {
+        if self.state.status.is_closed() {
+            return Err(SessionError::SessionClosed {
+                status: self.state.status.clone(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn touch(&mut self, now: Instant) {
+        self.last_activity = Some(self.last_activity.map_or(now, |at| at.max(now)));
+    }
+
+    /// Nothing happened for `idle_timeout` and no transaction is being signed.
+    fn is_idle(&self, now: Instant, idle_timeout: Duration) -> bool {
+        matches!(
+            self.state.status,
+            TradeStatus::Trading | TradeStatus::OneUserAccepted | TradeStatus::Accepted
+        ) && self
+            .last_activity
+            .is_some_and(|at| now.saturating_duration_since(at) >= idle_timeout)
+    }
+
+    /// Nobody is connected and there is nothing worth keeping: the trade ended, or there are no
+    /// offers, no invite and no trade in flight. Other clientless sessions are left to the
+    /// reaper.
+    fn is_abandoned(&self) -> bool {
+        self.ws_clients.is_empty()
+            && (matches!(
+                self.state.status,
+                TradeStatus::Cancelled | TradeStatus::Completed | TradeStatus::Failed
+            ) || self.state.items.values().all(HashMap::is_empty)
+                && self.invite.is_none()
+                && self.state.status == TradeStatus::Trading)
+    }
+
+    /// Participant passed as the initiator when building the transaction, which makes them
+    /// the fee payer under `FeePayerPolicy::Initiator`.
+    fn fee_paying_participant(&self) -> Result<String> {
+        self.state
+            .fee_payer
+            .clone()
+            .or_else(|| self.initiator.clone())
+            .ok_or_else(|| Error::msg("Trade session has no initiator"))
+    }
+
+    fn is_online(&self, user_address: &str) -> bool {
+        self.connection_users
+            .values()
+            .any(|connected_user| connected_user == user_address)
+    }
+
+    /// Drops the built transaction and any signatures collected for it, keeping the offers.
+    fn revert_to_trading(&mut self) {
+        self.state = TradeState {
+            items: Arc::clone(&self.state.items),
+            mint_decimals: self.state.mint_decimals.clone(),
+            source_accounts: self.state.source_accounts.clone(),
+            memos: self.state.memos.clone(),
+            fee_payer: self.state.fee_payer.clone(),
+            up_to_offers: self.state.up_to_offers.clone(),
+            user_acted: None,
+            status: TradeStatus::Trading,
+            tx: None,
+            last_valid_block_height: None,
+            queued_txs: Vec::new(),
+            settled_txs: 0,
+            last_rounding: self.state.last_rounding.clone(),
+        };
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type")]
+pub enum SessionEvent {
+    TokensOffered {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+        #[serde(rename = "tokenMint")]
+        token_mint: String,
+        amount: Decimal,
+    },
+    TokensWithdrawn {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+        #[serde(rename = "tokenMint")]
+        token_mint: String,
+        amount: Decimal,
+    },
+    TradeAccepted {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+    },
+    TransactionSigned {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+        signature: String,
+    },
+    TradeCancelled {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+    },
+}
+
+impl SessionEvent {
+    pub fn audit_entry(&self, session_id: SessionId) -> NewTradeEvent {
+        let (actor, action) = match self {
+            SessionEvent::TokensOffered { user_address, .. } => (user_address, "TokensOffered"),
+            SessionEvent::TokensWithdrawn { user_address, .. } => (user_address, "TokensWithdrawn"),
+            SessionEvent::TradeAccepted { user_address } => (user_address, "TradeAccepted"),
+            SessionEvent::TransactionSigned { user_address, .. } => {
+                (user_address, "TransactionSigned")
+            }
+            SessionEvent::TradeCancelled { user_address } => (user_address, "TradeCancelled"),
+        };
+        NewTradeEvent {
+            session_id,
+            actor: actor.clone(),
+            action: action.to_string(),
+            payload: serde_json::to_value(self).ok(),
+        }
+    }
+}
+
+/// Transition of a session, published to the receivers of `SharedSessions::subscribe`. Unlike
+/// `SessionEvent` these are never sent to clients.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LifecycleEvent {
+    /// First connection or invite of the session.
+    Created { session_id: SessionId },
+    /// `user_address` offered or withdrew tokens, or their offer was lowered to their balance.
+    OfferChanged {
+        session_id: SessionId,
+        user_address: String,
+    },
+    /// Both participants accepted the trade.
+    Accepted { session_id: SessionId },
+    /// The last missing signature was attached, the transaction can be sent.
+    FullySigned { session_id: SessionId },
+    /// The cluster accepted the transaction, its confirmation is awaited.
+    TransactionSent { session_id: SessionId },
+    /// The session changed in a way that is persisted, see `spawn_session_writer`.
+    Updated { session_id: SessionId },
+    /// The trade ended as `Completed`, `Failed` or `Cancelled`, or its idle session was reaped
+    /// as `Expired`.
+    Completed {
+        session_id: SessionId,
+        status: TradeStatus,
+    },
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TradeState {
+    /// Offered amounts in base units, converted to ui amounts only when sent to clients.
+    pub items: Arc<BaseUnitOffers>,
+    /// Decimals of every offered mint, to convert its amounts to and from ui amounts.
+    #[serde(default)]
+    pub mint_decimals: HashMap<String, u8>,
+    /// Token accounts to send offered mints from where they differ from the ATA.
+    #[serde(default)]
+    pub source_accounts: SourceAccounts,
+    /// Trade note of each participant who wrote one.
+    #[serde(default)]
+    pub memos: Memos,
+    /// Offers lowered to the counterparty's offer of the same mint when the transaction is built.
+    #[serde(default)]
+    pub up_to_offers: UpToOffers,
+    /// Participant paying the network fees, the initiator when unset.
+    #[serde(default)]
+    pub fee_payer: Option<String>,
+    pub user_acted: Option<String>,
+    pub status: TradeStatus,
+    pub tx: Option<Transaction>,
+    /// Last block height the blockhash of `tx` is valid at.
+    #[serde(default)]
+    pub last_valid_block_height: Option<u64>,
+    /// Transactions of a split trade to sign after `tx` landed, in order.
+    #[serde(default)]
+    pub queued_txs: Vec<Transaction>,
+    /// Transactions of a split trade that already landed.
+    #[serde(default)]
+    pub settled_txs: usize,
+    /// Rounding of the latest offer or withdrawal, `None` when it was taken as requested.
+    #[serde(default)]
+    pub last_rounding: Option<AppliedRounding>,
+}
+
+/// An offered or withdrawn amount rounded to the decimals of its mint.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppliedRounding {
+    pub user_address: String,
+    pub token_mint: String,
+    pub requested: Decimal,
+    pub applied: Decimal,
+}
+
+impl AppliedRounding {
+    fn between(
+        user_address: &str,
+        token_mint: &str,
+        requested: Decimal,
+        applied: Decimal,
+    ) -> Option<Self> {
+        (requested != applied).then(|| AppliedRounding {
+            user_address: String::from(user_address),
+            token_mint: String::from(token_mint),
+            requested,
+            applied,
+        })
+    }
+}
+
+/// Which side of the trade a participant is on, matching `initiator` and `counterparty` of the
+/// trade record.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ParticipantRole {
+    Initiator,
+    Counterparty,
+}
+
+impl std::fmt::Display for ParticipantRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParticipantRole::Initiator => write!(f, "initiator"),
+            ParticipantRole::Counterparty => write!(f, "counterparty"),
+        }
+    }
+}
+
+/// Actions `TradeSession::check_permission` restricts by role.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionAction {
+    Cancel,
+    /// Choosing the other participant as the fee payer.
+    PassOnFees,
+}
+
+impl std::fmt::Display for SessionAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionAction::Cancel => write!(f, "cancel"),
+            SessionAction::PassOnFees => write!(f, "make the other participant pay the fees"),
+        }
+    }
+}
+
+/// Position of the transaction to sign within a trade split into several transactions.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TransactionBatch {
+    /// Zero based.
+    pub index: usize,
+    pub total: usize,
+}
+
+/// How an offered amount is settled against the counterparty's offer of the same mint.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OfferMode {
+    /// The whole amount is offered, only the difference to the counterparty's offer moves.
+    #[default]
+    Exact,
+    /// At most the amount is offered, matched down to the counterparty's offer.
+    UpTo,
+}
+
+/// Offered ui amounts per user and mint.
+pub type OfferAmounts = HashMap<String, HashMap<String, Decimal>>;
+
+impl TradeState {
+    /// Offers as ui amounts, as shown to clients.
+    pub fn ui_items(&self) -> OfferAmounts {
+        self.items
+            .iter()
+            .map(|(user_address, offers)| {
+                let offers = offers
+                    .iter()
+                    .map(|(mint, amount)| (mint.clone(), self.ui_amount(mint, *amount)))
+                    .collect();
+                (user_address.clone(), offers)
+            })
+            .collect()
+    }
+
+    /// See `SharedSessions::transaction_batch`.
+    fn batch(&self) -> Option<TransactionBatch> {
+        let total = self.settled_txs + 1 + self.queued_txs.len();
+        (total > 1).then_some(TransactionBatch {
+            index: self.settled_txs,
+            total,
+        })
+    }
+
+    /// Decimals only get here after `to_base_units` accepted them for the offer, so the
+    /// conversion back can't fail.
+    fn ui_amount(&self, mint: &str, amount: u64) -> Decimal {
+        from_base_units(
+            amount,
+            self.mint_decimals.get(mint).copied().unwrap_or_default(),
+        )
+        .unwrap_or_default()
+    }
+
+    /// Amounts that are new or differ from `previous`, and mints no longer offered, per user.
+    pub fn items_delta(
+        &self,
+        previous: &TradeState,
+    ) -> (OfferAmounts, HashMap<String, Vec<String>>) {
+        let mut changed = OfferAmounts::new();
+        for (user_address, offers) in self.items.iter() {
+            let previous_offers = previous.items.get(user_address);
+            for (mint, amount) in offers {
+                if previous_offers.and_then(|offers| offers.get(mint)) != Some(amount) {
+                    changed
+                        .entry(user_address.clone())
+                        .or_default()
+                        .insert(mint.clone(), self.ui_amount(mint, *amount));
+                }
+            }
+        }
+        let mut removed: HashMap<String, Vec<String>> = HashMap::new();
+        for (user_address, previous_offers) in previous.items.iter() {
+            let offers = self.items.get(user_address);
+            for mint in previous_offers.keys() {
+                if !offers.is_some_and(|offers| offers.contains_key(mint)) {
+                    removed
+                        .entry(user_address.clone())
+                        .or_default()
+                        .push(mint.clone());
+                }
+            }
+        }
+        (changed, removed)
+    }
+}
+
+/// Status of a live session, sent to clients as the variant name (`"Trading"`,
+/// `"OneUserAccepted"`, ...). Part of the websocket protocol, so variants are never renamed;
+/// `Completed`, `Failed`, `Cancelled` and `Expired` are spelled like the persisted
+/// `trade_repository::TradeStatus`.
+#[derive(Clone, Debug, Display, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum TradeStatus {
+    #[default]
+    Trading,
+    OneUserAccepted,
+    Accepted,
+    TransactionCreated,
+    OneUserSigned,
+    /// Everyone signed, the transaction is about to be sent.
+    FullySigned,
+    TransactionSent,
+    /// The transaction confirmed, the tokens changed hands.
+    Completed,
+    /// The transaction failed or never landed.
+    Failed,
+    /// A participant backed out before the transaction was sent.
+    Cancelled,
+    /// Reaped after idling, only ever read back from the trade record.
+    Expired,
+}
+
+impl TradeStatus {
+    /// The transaction was signed by everyone or the trade ended, participants may still look at
+    /// the session but no longer change it.
+    pub fn is_closed(&self) -> bool {
+        matches!(
+            self,
+            TradeStatus::FullySigned
+                | TradeStatus::TransactionSent
+                | TradeStatus::Completed
+                | TradeStatus::Failed
+                | TradeStatus::Cancelled
+                | TradeStatus::Expired
+        )
+    }
+
+    /// The status of the trade record while the session is in this status.
+    pub fn persisted(&self) -> trade_repository::TradeStatus {
+        match self {
+            TradeStatus::Trading => trade_repository::TradeStatus::Trading,
+            TradeStatus::OneUserAccepted => trade_repository::TradeStatus::OneUserAccepted,
+            TradeStatus::Accepted => trade_repository::TradeStatus::Accepted,
+            TradeStatus::TransactionCreated => trade_repository::TradeStatus::TransactionCreated,
+            TradeStatus::OneUserSigned => trade_repository::TradeStatus::OneUserSigned,
+            TradeStatus::FullySigned => trade_repository::TradeStatus::FullySigned,
+            TradeStatus::TransactionSent => trade_repository::TradeStatus::TransactionSent,
+            TradeStatus::Completed => trade_repository::TradeStatus::Completed,
+            TradeStatus::Failed => trade_repository::TradeStatus::Failed,
+            TradeStatus::Cancelled => trade_repository::TradeStatus::Cancelled,
+            TradeStatus::Expired => trade_repository::TradeStatus::Expired,
+        }
+    }
 }
 
-#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
-pub struct TradeState {
-    pub items: Arc<HashMap<String, HashMap<String, Decimal>>>,
-    pub user_acted: Option<String>,
-    pub status: TradeStatus,
-    pub tx: Option<Transaction>,
-}
+#[cfg(test)]
+mod tests {
+    use crate::chain_context::{ScriptedChainContext, TestChainContext, TEST_MINT_DECIMALS};
+    use crate::config::{FeePayerPolicy, TransactionConfig};
+    use crate::trade_repository::{InMemoryTradeStore, TradeEntity};
+    use crate::transaction_service::server_keypair_file;
+
+    use super::*;
+    use solana_sdk::pubkey::Pubkey;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn offer_no_longer_held_at_build_time_should_be_lowered() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let alice = Pubkey::new_unique();
+        let bob = Pubkey::new_unique();
+        let token_a = Pubkey::new_unique();
+        let token_b = Pubkey::new_unique();
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice.to_string(),
+            HashMap::from([(token_a.to_string(), dec!(1))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            bob.to_string(),
+            HashMap::from([(token_b.to_string(), dec!(1))]),
+            TEST_MINT_DECIMALS,
+        );
+        // Alice moved most of her tokens out after offering them
+        let alice_ata =
+            spl_associated_token_account::get_associated_token_address(&alice, &token_a);
+        let chain_context: ScriptedChainContext = ScriptedChainContext {
+            token_balances: HashMap::from([(alice_ata, 300_000)]),
+            ..ScriptedChainContext::default()
+        };
+        let transaction_service = Arc::new(TransactionService::new(Arc::new(chain_context)));
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+        shared
+            .add_tokens_offer(
+                &session_id,
+                &alice.to_string(),
+                token_a.to_string(),
+                dec!(0.8),
+            )
+            .unwrap();
+        shared
+            .add_tokens_offer(
+                &session_id,
+                &bob.to_string(),
+                token_b.to_string(),
+                dec!(0.5),
+            )
+            .unwrap();
+        shared
+            .accept_trade(&session_id, &alice.to_string())
+            .unwrap();
+        shared.accept_trade(&session_id, &bob.to_string()).unwrap();
+
+        let error = shared
+            .get_transaction_to_sign(&session_id, &alice.to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Balance changed since the offers were made, accept again: {} now offers 0.3 of {}",
+                alice, token_a
+            )
+        );
+        {
+            let sessions = &shared.internal;
+            let session = sessions.get(&session_id).unwrap();
+            assert_eq!(session.state.status, TradeStatus::Trading);
+            assert!(session.state.tx.is_none());
+        }
+        assert_eq!(
+            shared.get_offers(&session_id, &alice.to_string()).unwrap(),
+            HashMap::from([(token_a.to_string(), dec!(0.3))])
+        );
+        match rx.try_recv() {
+            Ok(WebsocketMessage::OfferChanged { by }) => assert_eq!(by, alice.to_string()),
+            other => panic!("Expected OfferChanged, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_transaction_to_sign_possible_in_accepted_state() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let user_address1 = String::from("DuiJXfXdZdcJQko3LugHAAWR9RgQPNXVXk79y691rpHg");
+        let user_address2 = String::from("2qkf9i5rEjDJ53izfccdEmUhW1LkgMzgCDz1SG3zYYym");
+        let token_a = String::from("FKqe4pSujn57nL8JD62mYfwsnJ6bE9HCr5wr6C7nBzGM");
+        let token_b = String::from("HBc27s2MjdMK8Bg46KzKBuZAk1EvTioTKVaxxcnn1hJW");
+
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+
+        token_amount_cache.insert_token_amounts_with_decimals(
+            user_address1.clone(),
+            HashMap::from([(token_a.clone(), dec!(0.6))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            user_address2.clone(),
+            HashMap::from([(token_b.clone(), dec!(2.0))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let connection_id = Uuid::new_v4();
+
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, connection_id, tx);
+
+        // Add tokens for user "Alice"
+        let result =
+            shared.add_tokens_offer(&session_id, &user_address1, token_a.clone(), dec!(0.1001));
+        assert!(result.is_ok());
+
+        let result =
+            shared.add_tokens_offer(&session_id, &user_address2, token_b.clone(), dec!(0.5));
+        assert!(result.is_ok());
+
+        let _ = shared.accept_trade(&session_id, &user_address1);
+        let _ = shared.accept_trade(&session_id, &user_address2);
+
+        {
+            let sessions = &shared.internal;
+            let session = sessions.get(&session_id).expect("Session not found");
+            assert_eq!(session.state.user_acted, None);
+            assert_eq!(session.state.status, TradeStatus::Accepted);
+        }
+
+        let result = shared
+            .get_transaction_to_sign(&session_id, &user_address1)
+            .await;
+
+        if let Err(e) = result {
+            println!("Error: {:#?}", e.backtrace());
+        }
+        {
+            let sessions = &shared.internal;
+            let session = sessions.get(&session_id).expect("Session not found");
+            assert_eq!(
+                session.state.user_acted,
+                Some(user_address1.clone()),
+                "User acted missing after transaction created"
+            );
+            assert_eq!(
+                session.state.status,
+                TradeStatus::TransactionCreated,
+                "Transaction should be in TransactionCreated state"
+            );
+            let tx = session.state.tx.clone().unwrap();
+            assert_eq!(tx.message().account_keys.len(), 9);
+            assert!(tx
+                .message()
+                .account_keys
+                .contains(&Pubkey::from_str(&user_address1).unwrap()));
+            assert!(tx
+                .message()
+                .account_keys
+                .contains(&Pubkey::from_str(&user_address2).unwrap()));
+            assert!(tx
+                .message()
+                .account_keys
+                .contains(&Pubkey::from_str(&token_a).unwrap()));
+            assert!(tx
+                .message()
+                .account_keys
+                .contains(&Pubkey::from_str(&token_b).unwrap()));
+
+            println!("Tx message: {:#?}", tx.message());
+        }
+    }
+
+    #[tokio::test]
+    async fn transaction_to_sign_should_be_built_once_and_sent_to_every_client() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let alice = Pubkey::new_unique().to_string();
+        let bob = Pubkey::new_unique().to_string();
+        let token_a = Pubkey::new_unique().to_string();
+        let token_b = Pubkey::new_unique().to_string();
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice.clone(),
+            HashMap::from([(token_a.clone(), dec!(1))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            bob.clone(),
+            HashMap::from([(token_b.clone(), dec!(1))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+        );
+        let session_id = Uuid::new_v4();
+        let (alice_tx, mut alice_rx) = mpsc::channel(10);
+        let (bob_tx, mut bob_rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), alice_tx);
+        shared.add_client(session_id, Uuid::new_v4(), bob_tx);
+        assert!(shared
+            .get_transaction_to_sign(&session_id, &alice)
+            .await
+            .is_err());
+        shared
+            .add_tokens_offer(&session_id, &alice, token_a, dec!(0.5))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, &bob, token_b, dec!(0.5))
+            .unwrap();
+        shared.accept_trade(&session_id, &alice).unwrap();
+        shared.accept_trade(&session_id, &bob).unwrap();
+        while alice_rx.try_recv().is_ok() {}
+        while bob_rx.try_recv().is_ok() {}
+
+        assert!(shared
+            .get_transaction_to_sign(&session_id, &alice)
+            .await
+            .unwrap());
+        let (_, built) = shared.encoded_transaction(&session_id, None).unwrap();
+        for rx in [&mut alice_rx, &mut bob_rx] {
+            match rx.try_recv() {
+                Ok(WebsocketMessage::TransactionToSign {
+                    encoding,
+                    transaction,
+                    batch,
+                }) => {
+                    assert_eq!(encoding, TransactionEncoding::Base64);
+                    assert_eq!(transaction, built);
+                    assert_eq!(batch, None);
+                }
+                other => panic!("Expected TransactionToSign, got {:?}", other),
+            }
+        }
+
+        // The counterparty asking as well gets the same transaction, nothing is rebuilt
+        assert!(!shared
+            .get_transaction_to_sign(&session_id, &bob)
+            .await
+            .unwrap());
+        assert_eq!(
+            shared.encoded_transaction(&session_id, None).unwrap().1,
+            built
+        );
+        assert!(alice_rx.try_recv().is_err());
+        assert!(bob_rx.try_recv().is_err());
+        let sessions = &shared.internal;
+        let session = sessions.get(&session_id).unwrap();
+        assert_eq!(session.state.status, TradeStatus::TransactionCreated);
+        assert_eq!(session.state.user_acted, Some(alice));
+    }
+
+    #[tokio::test]
+    async fn connection_user_should_be_known_only_after_identification() {
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let shared = SharedSessions::new(Arc::new(TokenAmountCache::init()), transaction_service);
+        let session_id = Uuid::new_v4();
+        let connection_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, connection_id, tx);
+
+        assert_eq!(shared.connection_user(&session_id, &connection_id), None);
+
+        shared.identify_client(&session_id, &connection_id, "Alice");
+        assert_eq!(
+            shared.connection_user(&session_id, &connection_id),
+            Some("Alice".to_string())
+        );
+        assert_eq!(shared.connection_user(&session_id, &Uuid::new_v4()), None);
+
+        shared.remove_client(&session_id, &connection_id);
+        assert_eq!(shared.connection_user(&session_id, &connection_id), None);
+    }
+
+    #[tokio::test]
+    async fn test_accept_trade_only_possible_in_trading_or_oneuseraccepted_status() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let user_address1 = String::from("Alice");
+
+        token_amount_cache.insert_token_amounts_with_decimals(
+            user_address1.clone(),
+            HashMap::from([("TokenA".to_string(), dec!(0.6))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let connection_id = Uuid::new_v4();
+
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, connection_id, tx);
+
+        // Add tokens for user "Alice"
+        let result = shared.add_tokens_offer(
+            &session_id,
+            &user_address1,
+            "TokenA".to_string(),
+            dec!(0.1001),
+        );
+        assert!(result.is_ok());
+
+        let _ = shared.accept_trade(&session_id, &user_address1);
+
+        // states that should not allow changing token offers
+        for trade_status in [
+            TradeStatus::Accepted,
+            TradeStatus::TransactionCreated,
+            TradeStatus::OneUserSigned,
+            TradeStatus::TransactionSent,
+        ] {
+            //change trade status
+            {
+                let sessions = &shared.internal;
+                let mut session = sessions.get_mut(&session_id).expect("Session not found");
+                session.state.status = trade_status;
+            }
+
+            let result = shared.accept_trade(&session_id, &user_address1);
+            assert!(result.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trade_must_be_mutable_only_in_trading_or_oneuseraccepted_status() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let user_address1 = String::from("Alice");
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+
+        token_amount_cache.insert_token_amounts_with_decimals(
+            user_address1.clone(),
+            HashMap::from([("TokenA".to_string(), dec!(0.6))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let connection_id = Uuid::new_v4();
+
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, connection_id, tx);
+
+        // Add tokens for user "Alice"
+        let result = shared.add_tokens_offer(
+            &session_id,
+            &user_address1,
+            "TokenA".to_string(),
+            dec!(0.1001),
+        );
+        assert!(result.is_ok());
+
+        // states that allow mutability
+        for trade_status in [TradeStatus::Trading, TradeStatus::OneUserAccepted] {
+            //change trade status
+            {
+                let sessions = &shared.internal;
+                let mut session = sessions.get_mut(&session_id).expect("Session not found");
+                session.state.status = trade_status;
+            }
+
+            let result = shared.add_tokens_offer(
+                &session_id,
+                &user_address1,
+                "TokenA".to_string(),
+                dec!(0.1001),
+            );
+            assert!(result.is_ok());
+
+            let result = shared.withdraw_tokens(
+                &session_id,
+                &user_address1,
+                "TokenA".to_string(),
+                dec!(0.0501),
+            );
+            assert!(result.is_ok());
+        }
+
+        // states that should not allow changing token offers
+        for trade_status in [
+            TradeStatus::Accepted,
+            TradeStatus::TransactionCreated,
+            TradeStatus::OneUserSigned,
+            TradeStatus::TransactionSent,
+        ] {
+            //change trade status
+            {
+                let sessions = &shared.internal;
+                let mut session = sessions.get_mut(&session_id).expect("Session not found");
+                session.state.status = trade_status;
+            }
+
+            let result = shared.add_tokens_offer(
+                &session_id,
+                &user_address1,
+                "TokenA".to_string(),
+                dec!(0.1001),
+            );
+            assert!(result.is_err());
+
+            let result = shared.withdraw_tokens(
+                &session_id,
+                &user_address1,
+                "TokenA".to_string(),
+                dec!(0.0801),
+            );
+            assert!(result.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_second_user_accepts() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let user_address1 = String::from("Alice");
+        let user_address2 = String::from("Bob");
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+
+        token_amount_cache.insert_token_amounts_with_decimals(
+            user_address1.clone(),
+            HashMap::from([("TokenA".to_string(), dec!(0.6))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let connection_id = Uuid::new_v4();
+
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, connection_id, tx);
+
+        // Add tokens for user "Alice"
+        let result = shared.add_tokens_offer(
+            &session_id,
+            &user_address1,
+            "TokenA".to_string(),
+            dec!(0.1001),
+        );
+        assert!(result.is_ok());
+
+        let _ = shared.accept_trade(&session_id, &user_address1);
+        let _ = shared.accept_trade(&session_id, &user_address2);
+
+        {
+            let sessions = &shared.internal;
+            let session = sessions.get(&session_id).expect("Session not found");
+            let alice_tokens = session
+                .state
+                .ui_items()
+                .remove("Alice")
+                .expect("Alice not found in state");
+
+            assert_eq!(session.state.user_acted, None);
+            assert_eq!(session.state.status, TradeStatus::Accepted);
+            assert_eq!(
+                *alice_tokens.get("TokenA").expect("TokenA not found"),
+                dec!(0.1001)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejecting_signed_transaction_should_return_to_editable_state() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let alice = Pubkey::new_unique().to_string();
+        let bob = Pubkey::new_unique().to_string();
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice.clone(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            bob.clone(),
+            HashMap::from([("TokenB".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+
+        shared
+            .add_tokens_offer(&session_id, &alice, "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, &bob, "TokenB".to_string(), dec!(1))
+            .unwrap();
+        {
+            let sessions = &shared.internal;
+            let mut session = sessions.get_mut(&session_id).unwrap();
+            session.state.status = TradeStatus::OneUserSigned;
+            session.state.user_acted = Some(alice.clone());
+            session.state.tx = Some(Transaction::default());
+        }
+
+        assert!(shared.reject_transaction(&session_id, "Mallory").is_err());
+        shared.reject_transaction(&session_id, &bob).unwrap();
+        {
+            let sessions = &shared.internal;
+            let session = sessions.get(&session_id).unwrap();
+            assert_eq!(session.state.status, TradeStatus::Trading);
+            assert_eq!(session.state.user_acted, None);
+            assert!(session.state.tx.is_none());
+            assert_eq!(session.state.items.len(), 2);
+        }
+
+        shared
+            .add_tokens_offer(&session_id, &alice, "TokenA".to_string(), dec!(1))
+            .unwrap();
+        assert!(shared.reject_transaction(&session_id, &alice).is_err());
+        let sessions = &shared.internal;
+        let alice_tokens = sessions.get(&session_id).unwrap().state.ui_items()[&alice].clone();
+        assert_eq!(alice_tokens["TokenA"], dec!(2));
+    }
+
+    #[tokio::test]
+    async fn split_trade_should_move_on_to_next_transaction_once_sent() {
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let shared = SharedSessions::new(Arc::new(TokenAmountCache::init()), transaction_service);
+        let session_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+        let unsigned = |payer: &Pubkey| Transaction::new_with_payer(&[], Some(payer));
+        let payer = Pubkey::new_unique();
+        {
+            let sessions = &shared.internal;
+            let state = &mut sessions.get_mut(&session_id).unwrap().state;
+            state.items = Arc::new(HashMap::from([("Alice".to_string(), HashMap::new())]));
+            state.status = TradeStatus::OneUserSigned;
+            state.tx = Some(unsigned(&payer));
+            state.queued_txs = vec![unsigned(&payer)];
+        }
+        assert_eq!(
+            shared.transaction_batch(&session_id),
+            Some(TransactionBatch { index: 0, total: 2 })
+        );
+        assert!(shared.next_transaction(&session_id).await.is_err());
+
+        let send_current = || {
+            let sessions = &shared.internal;
+            let state = &mut sessions.get_mut(&session_id).unwrap().state;
+            state.tx.as_mut().unwrap().signatures = vec![Signature::new_unique()];
+            state.status = TradeStatus::TransactionSent;
+        };
+        send_current();
+        assert!(shared.next_transaction(&session_id).await.unwrap());
+        assert_eq!(
+            shared.transaction_batch(&session_id),
+            Some(TransactionBatch { index: 1, total: 2 })
+        );
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(WebsocketMessage::ResignRequired { reason }) if reason == "Transaction 1 of 2 landed, sign the next one"
+        ));
+        {
+            let sessions = &shared.internal;
+            let session = sessions.get(&session_id).unwrap();
+            let state = &session.state;
+            assert_eq!(state.status, TradeStatus::TransactionCreated);
+            assert_eq!(state.tx, Some(unsigned(&payer)));
+            assert_eq!(
+                state.last_valid_block_height,
+                Some(crate::chain_context::TEST_LAST_VALID_BLOCK_HEIGHT)
+            );
+        }
+        // Half of the trade landed, going back to editing the offers is no longer possible
+        assert!(shared.reject_transaction(&session_id, "Alice").is_err());
+
+        send_current();
+        assert!(!shared.next_transaction(&session_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_blockhash_should_force_rebuild_before_submitting() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let alice = Pubkey::new_unique().to_string();
+        let bob = Pubkey::new_unique().to_string();
+        let token_a = Pubkey::new_unique().to_string();
+        let token_b = Pubkey::new_unique().to_string();
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice.clone(),
+            HashMap::from([(token_a.clone(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            bob.clone(),
+            HashMap::from([(token_b.clone(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared_with = |block_height: u64| {
+            SharedSessions::new(
+                Arc::clone(&token_amount_cache),
+                Arc::new(TransactionService::<ScriptedChainContext>::new(Arc::new(
+                    ScriptedChainContext {
+                        block_height: Some(block_height),
+                        ..ScriptedChainContext::default()
+                    },
+                ))),
+            )
+        };
+        let stale_blockhash = solana_sdk::hash::Hash::new_unique();
+        let signing_session = |shared: &SharedSessions<ScriptedChainContext>| {
+            let session_id = Uuid::new_v4();
+            let (tx, rx) = mpsc::channel(10);
+            shared.add_client(session_id, Uuid::new_v4(), tx);
+            shared
+                .add_tokens_offer(&session_id, &alice, token_a.clone(), dec!(1))
+                .unwrap();
+            shared
+                .add_tokens_offer(&session_id, &bob, token_b.clone(), dec!(1))
+                .unwrap();
+            let sessions = &shared.internal;
+            let mut session = sessions.get_mut(&session_id).unwrap();
+            let mut stale_tx = Transaction::default();
+            stale_tx.message.recent_blockhash = stale_blockhash;
+            session.state.status = TradeStatus::FullySigned;
+            session.state.user_acted = Some(alice.clone());
+            session.state.tx = Some(stale_tx);
+            session.state.last_valid_block_height = Some(900);
+            (session_id, rx)
+        };
+
+        let shared = shared_with(900);
+        let (session_id, _rx) = signing_session(&shared);
+        assert!(!shared.refresh_stale_blockhash(&session_id).await.unwrap());
+
+        let shared = shared_with(901);
+        let (session_id, mut rx) = signing_session(&shared);
+        while rx.try_recv().is_ok() {}
+        assert!(shared.refresh_stale_blockhash(&session_id).await.unwrap());
+        {
+            let sessions = &shared.internal;
+            let session = sessions.get(&session_id).unwrap();
+            let tx = session.state.tx.as_ref().unwrap();
+            assert_ne!(tx.message.recent_blockhash, stale_blockhash);
+            assert!(tx
+                .message
+                .account_keys
+                .contains(&Pubkey::from_str(&alice).unwrap()));
+            assert_eq!(
+                session.state.last_valid_block_height,
+                Some(crate::chain_context::TEST_LAST_VALID_BLOCK_HEIGHT)
+            );
+            assert_eq!(session.state.status, TradeStatus::TransactionCreated);
+            assert_eq!(session.state.user_acted, Some(alice.clone()));
+        }
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(WebsocketMessage::ResignRequired { .. })
+        ));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(WebsocketMessage::TradeStateUpdate { tx: Some(_), .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn expired_split_trade_should_be_rebuilt_from_its_unsettled_transactions() {
+        use spl_associated_token_account::get_associated_token_address;
+
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let alice = Pubkey::new_unique();
+        let bob = Pubkey::new_unique();
+        let token_a = Pubkey::new_unique();
+        let token_b = Pubkey::new_unique();
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice.to_string(),
+            HashMap::from([(token_a.to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            bob.to_string(),
+            HashMap::from([(token_b.to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let transaction_config = TransactionConfig {
+            check_token_balances: true,
+            max_transfers_per_transaction: Some(1),
+            ..TransactionConfig::default()
+        };
+        let offers = Arc::new(HashMap::from([
+            (
+                alice.to_string(),
+                HashMap::from([(token_a.to_string(), 1_000_000)]),
+            ),
+            (
+                bob.to_string(),
+                HashMap::from([(token_b.to_string(), 1_000_000)]),
+            ),
+        ]));
+        let (txs, _) = TransactionService::with_config(
+            Arc::new(TestChainContext {}),
+            transaction_config.clone(),
+        )
+        .create_transactions_with_sources(
+            Arc::clone(&offers),
+            &SourceAccounts::new(),
+            &HeldAccounts::new(),
+            &Memos::new(),
+            &UpToOffers::new(),
+            &alice.to_string(),
+            0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(txs.len(), 2);
+        let alice_ata = get_associated_token_address(&alice, &token_a);
+        let bob_ata = get_associated_token_address(&bob, &token_b);
+        let (landed_sender, pending_sender) = if txs[0].message.account_keys.contains(&alice_ata) {
+            (alice_ata, bob_ata)
+        } else {
+            (bob_ata, alice_ata)
+        };
+        // The landed transaction emptied its sender's account
+        let chain_context: ScriptedChainContext = ScriptedChainContext {
+            block_height: Some(901),
+            token_balances: HashMap::from([(landed_sender, 0)]),
+            ..ScriptedChainContext::default()
+        };
+        let shared = SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::with_config(
+                Arc::new(chain_context),
+                transaction_config,
+            )),
+        );
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        {
+            let sessions = &shared.internal;
+            let mut session = sessions.get_mut(&session_id).unwrap();
+            session.initiator = Some(alice.to_string());
+            session.state.items = offers;
+            session.state.mint_decimals = HashMap::from([
+                (token_a.to_string(), TEST_MINT_DECIMALS),
+                (token_b.to_string(), TEST_MINT_DECIMALS),
+            ]);
+            session.state.status = TradeStatus::OneUserSigned;
+            session.state.tx = Some(txs[1].clone());
+            session.state.settled_txs = 1;
+            session.state.last_valid_block_height = Some(900);
+        }
+
+        assert!(shared.refresh_stale_blockhash(&session_id).await.unwrap());
+        let sessions = &shared.internal;
+        let state = &sessions.get(&session_id).unwrap().state;
+        assert_eq!(state.status, TradeStatus::TransactionCreated);
+        assert!(state.queued_txs.is_empty());
+        let account_keys = &state.tx.as_ref().unwrap().message.account_keys;
+        assert!(account_keys.contains(&pending_sender));
+        assert!(!account_keys.contains(&landed_sender));
+    }
+
+    #[test]
+    fn status_should_serialize_to_stable_names() {
+        use crate::trade_repository::TradeStatus as PersistedStatus;
+
+        for (status, name) in [
+            (TradeStatus::Trading, "Trading"),
+            (TradeStatus::OneUserAccepted, "OneUserAccepted"),
+            (TradeStatus::Accepted, "Accepted"),
+            (TradeStatus::TransactionCreated, "TransactionCreated"),
+            (TradeStatus::OneUserSigned, "OneUserSigned"),
+            (TradeStatus::FullySigned, "FullySigned"),
+            (TradeStatus::TransactionSent, "TransactionSent"),
+            (TradeStatus::Completed, PersistedStatus::Completed.as_str()),
+            (TradeStatus::Failed, PersistedStatus::Failed.as_str()),
+            (TradeStatus::Cancelled, PersistedStatus::Cancelled.as_str()),
+            (TradeStatus::Expired, PersistedStatus::Expired.as_str()),
+        ] {
+            assert_eq!(
+                serde_json::to_value(&status).unwrap(),
+                serde_json::json!(name)
+            );
+            assert_eq!(status.to_string(), name);
+            assert_eq!(
+                serde_json::from_value::<TradeStatus>(serde_json::json!(name)).unwrap(),
+                status
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_of_offers_should_be_broadcast_coalesced() {
+        use crate::config::{BroadcastConfig, TunableConfig};
+
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let alice = Pubkey::new_unique().to_string();
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice.clone(),
+            HashMap::from([("TokenA".to_string(), dec!(100))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::with_config(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+            AuditLog::disabled(),
+            None,
+            Arc::new(RuntimeConfig::new(TunableConfig {
+                broadcasts: BroadcastConfig {
+                    debounce_ms: 30,
+                    max_delay_ms: 200,
+                },
+                ..TunableConfig::default()
+            })),
+        );
+        let session_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(100);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+
+        for _ in 0..50 {
+            shared
+                .add_tokens_offer(&session_id, &alice, "TokenA".to_string(), dec!(1))
+                .unwrap();
+            shared.broadcast_current_state(&session_id);
+        }
+        assert!(rx.try_recv().is_err());
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        let mut broadcasts = vec![];
+        while let Ok(message) = rx.try_recv() {
+            broadcasts.push(message);
+        }
+        assert_eq!(
+            broadcasts.len(),
+            1,
+            "{} broadcasts for 50 offers",
+            broadcasts.len()
+        );
+        match broadcasts.last() {
+            Some(WebsocketMessage::TradeStateUpdate { offers, .. }) => {
+                assert_eq!(offers[&alice]["TokenA"], dec!(50))
+            }
+            other => panic!("Expected a state update, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn rapid_offers_should_change_offers_in_place_unless_shared() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let alice = Pubkey::new_unique().to_string();
+        let mints: Vec<String> = (0..20).map(|i| format!("Token{}", i)).collect();
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice.clone(),
+            mints.iter().map(|mint| (mint.clone(), dec!(100))).collect(),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+        );
+        let session_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(100);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+        let items = || Arc::clone(&shared.internal.get(&session_id).unwrap().state.items);
+
+        shared
+            .add_tokens_offer(&session_id, &alice, mints[0].clone(), dec!(1))
+            .unwrap();
+        let offers_ptr = Arc::as_ptr(&items());
+        for _ in 0..10 {
+            for mint in &mints {
+                shared
+                    .add_tokens_offer(&session_id, &alice, mint.clone(), dec!(3))
+                    .unwrap();
+                shared
+                    .withdraw_tokens(&session_id, &alice, mint.clone(), dec!(1))
+                    .unwrap();
+            }
+        }
+        let offers = items();
+        assert_eq!(Arc::as_ptr(&offers), offers_ptr);
+        assert_eq!(offers[&alice].len(), mints.len());
+        assert_eq!(
+            offers[&alice][&mints[0]],
+            21 * 10u64.pow(TEST_MINT_DECIMALS as u32)
+        );
+        for mint in &mints[1..] {
+            assert_eq!(
+                offers[&alice][mint],
+                20 * 10u64.pow(TEST_MINT_DECIMALS as u32)
+            );
+        }
+
+        shared
+            .add_tokens_offer(&session_id, &alice, mints[1].clone(), dec!(5))
+            .unwrap();
+        assert_eq!(
+            offers[&alice][&mints[1]],
+            20 * 10u64.pow(TEST_MINT_DECIMALS as u32)
+        );
+        assert_ne!(Arc::as_ptr(&items()), offers_ptr);
+        while rx.try_recv().is_ok() {}
+        shared.broadcast_current_state(&session_id);
+        match rx.try_recv() {
+            Ok(WebsocketMessage::TradeStateUpdate { offers, .. }) => {
+                assert_eq!(offers[&alice][&mints[0]], dec!(21));
+                assert_eq!(offers[&alice][&mints[1]], dec!(25));
+            }
+            other => panic!("Expected a state update, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn same_wallet_should_not_become_counterparty() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let alice = Pubkey::new_unique().to_string();
+        let alice_padded = format!("{} ", alice);
+        for address in [&alice, &alice_padded] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                address.clone(),
+                HashMap::from([("TokenA".to_string(), dec!(10))]),
+                TEST_MINT_DECIMALS,
+            );
+        }
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+
+        shared
+            .add_tokens_offer(&session_id, &alice, "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, &alice, "TokenA".to_string(), dec!(1))
+            .unwrap();
+        let result =
+            shared.add_tokens_offer(&session_id, &alice_padded, "TokenA".to_string(), dec!(1));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "A wallet cannot trade with itself"
+        );
+
+        let sessions = &shared.internal;
+        let items = sessions.get(&session_id).unwrap().state.ui_items();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[&alice]["TokenA"], dec!(2));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_offers_should_keep_session_invariants() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let users: Vec<String> = (0..4).map(|i| format!("User{}", i)).collect();
+        for user in &users {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                user.clone(),
+                HashMap::from([
+                    ("TokenA".to_string(), dec!(5)),
+                    ("TokenB".to_string(), dec!(3)),
+                ]),
+                TEST_MINT_DECIMALS,
+            );
+        }
+        let shared = Arc::new(SharedSessions::new(token_amount_cache, transaction_service));
+        let session_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+
+        let mut tasks = vec![];
+        for task in 0..32 {
+            let shared = Arc::clone(&shared);
+            let user = users[task % users.len()].clone();
+            tasks.push(tokio::spawn(async move {
+                for step in 0..50 {
+                    let mint = if step % 2 == 0 { "TokenA" } else { "TokenB" };
+                    match (task + step) % 3 {
+                        0 => {
+                            let _ = shared.add_tokens_offer(
+                                &session_id,
+                                &user,
+                                mint.to_string(),
+                                dec!(0.7),
+                            );
+                        }
+                        1 => {
+                            let _ = shared.withdraw_tokens(
+                                &session_id,
+                                &user,
+                                mint.to_string(),
+                                dec!(0.3),
+                            );
+                        }
+                        _ => {
+                            let _ = shared.accept_trade(&session_id, &user);
+                        }
+                    }
+                    let sessions = &shared.internal;
+                    let items = sessions.get(&session_id).unwrap().state.ui_items();
+                    assert!(items.len() <= 2);
+                    for offers in items.values() {
+                        assert!(offers.get("TokenA").is_none_or(|amount| *amount <= dec!(5)));
+                        assert!(offers.get("TokenB").is_none_or(|amount| *amount <= dec!(3)));
+                    }
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.expect("task panicked");
+        }
+
+        let sessions = &shared.internal;
+        let session = sessions.get(&session_id).unwrap();
+        assert!(session.state.items.len() <= 2);
+        assert!(session
+            .state
+            .items
+            .values()
+            .flat_map(|offers| offers.values())
+            .all(|amount| *amount > 0));
+    }
+
+    #[tokio::test]
+    async fn disconnect_during_signing_should_notify_remaining_participant() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let (alice_connection, bob_connection) = (Uuid::new_v4(), Uuid::new_v4());
+        let (alice_tx, _alice_rx) = mpsc::channel(10);
+        let (bob_tx, mut bob_rx) = mpsc::channel(10);
+        shared.add_client(session_id, alice_connection, alice_tx);
+        shared.add_client(session_id, bob_connection, bob_tx);
+        shared.identify_client(&session_id, &alice_connection, "Alice");
+        shared.identify_client(&session_id, &bob_connection, "Bob");
+        {
+            let sessions = &shared.internal;
+            let mut session = sessions.get_mut(&session_id).unwrap();
+            session.state.status = TradeStatus::TransactionCreated;
+            session.state.user_acted = Some("Alice".to_string());
+            session.state.tx = Some(Transaction::default());
+        }
+
+        let offline = shared.remove_client(&session_id, &alice_connection);
+        assert_eq!(offline.as_deref(), Some("Alice"));
+        match bob_rx.try_recv() {
+            Ok(WebsocketMessage::Warning { message }) => assert!(message.contains("Alice")),
+            other => panic!("Expected a warning, got {:?}", other),
+        }
+
+        assert!(!shared.revert_if_offline(&session_id, "Bob"));
+        assert!(shared.revert_if_offline(&session_id, "Alice"));
+        let sessions = &shared.internal;
+        let session = sessions.get(&session_id).unwrap();
+        assert_eq!(session.state.status, TradeStatus::Trading);
+        assert!(session.state.tx.is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_balance_should_warn_on_next_offer() {
+        let token_amount_cache = Arc::new(TokenAmountCache::with_expiry(Duration::from_millis(50)));
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        let _ = shared.add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1));
+        match rx.try_recv() {
+            Ok(WebsocketMessage::Warning { message }) => {
+                assert_eq!(message, balance_expired_message("Alice"))
+            }
+            other => panic!("Expected a warning, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_balance_should_not_warn_unless_expired() {
+        let token_amount_cache = Arc::new(TokenAmountCache::with_expiry(Duration::from_millis(50)));
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        for user_address in ["Alice", "Bob", "Carol"] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                user_address.to_string(),
+                HashMap::from([("TokenA".to_string(), dec!(10))]),
+                TEST_MINT_DECIMALS,
+            );
+        }
+        let shared = SharedSessions::new(Arc::clone(&token_amount_cache), transaction_service);
+        let session_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        let error = shared
+            .add_tokens_offer(&session_id, "Dave", "TokenA".to_string(), dec!(1))
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SessionError>(),
+            Some(&SessionError::BalancesNotCached {
+                user_address: "Dave".to_string()
+            })
+        );
+        shared
+            .add_tokens_offer(&session_id, "Bob", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        // Expired, but refused as the session is full anyway
+        assert!(shared
+            .add_tokens_offer(&session_id, "Carol", "TokenA".to_string(), dec!(1))
+            .is_err());
+        assert!(token_amount_cache.has_expired("Carol"));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn sessions_should_change_while_another_session_is_locked() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = Arc::new(SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+        ));
+        let busy_session = Uuid::new_v4();
+        shared.add_client(busy_session, Uuid::new_v4(), mpsc::channel(10).0);
+        let busy = shared.internal.get_mut(&busy_session).unwrap();
+        // Sessions sharing the shard of the busy one wait for it, pick one that doesn't
+        let session_id = loop {
+            let session_id = Uuid::new_v4();
+            if !matches!(
+                shared.internal.try_get(&session_id),
+                dashmap::try_result::TryResult::Locked
+            ) {
+                break session_id;
+            }
+        };
+
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        std::thread::spawn({
+            let shared = Arc::clone(&shared);
+            move || {
+                shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+                let offered =
+                    shared.add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1));
+                done_tx.send(offered.is_ok()).unwrap();
+            }
+        });
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(5)), Ok(true));
+        drop(busy);
+        assert_eq!(
+            shared.get_offers(&session_id, "Alice").unwrap(),
+            HashMap::from([("TokenA".to_string(), dec!(1))])
+        );
+    }
+
+    #[tokio::test]
+    async fn offer_before_balances_were_fetched_should_ask_for_a_refresh() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Bob".to_string(),
+            HashMap::from([("TokenB".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(
+            Arc::clone(&token_amount_cache),
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+        );
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+
+        let error = shared
+            .add_tokens_offer(&session_id, "Alice", "TokenB".to_string(), dec!(1))
+            .unwrap_err();
+        assert_eq!(error_code(&error), "balances_not_cached");
+        assert_eq!(
+            error.to_string(),
+            "Balances of Alice aren't known, fetch the wallet's tokens before offering"
+        );
+        assert!(shared.get_offers(&session_id, "Alice").is_err());
+
+        // Fetched balances without the mint are a genuine zero, the offer is capped to it
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenB".to_string(), dec!(1))
+            .unwrap();
+        assert_eq!(
+            shared.get_offers(&session_id, "Alice").unwrap(),
+            HashMap::from([("TokenB".to_string(), dec!(0))])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accept_trade() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let user_address = String::from("Alice");
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+
+        token_amount_cache.insert_token_amounts_with_decimals(
+            user_address.clone(),
+            HashMap::from([("TokenA".to_string(), dec!(0.6))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let connection_id = Uuid::new_v4();
+
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, connection_id, tx);
+
+        // Add tokens for user "Alice"
+        let result = shared.add_tokens_offer(
+            &session_id,
+            &user_address,
+            "TokenA".to_string(),
+            dec!(0.1001),
+        );
+        assert!(result.is_ok());
+
+        let _ = shared.accept_trade(&session_id, &user_address);
+
+        {
+            let sessions = &shared.internal;
+            let session = sessions.get(&session_id).expect("Session not found");
+            let alice_tokens = session
+                .state
+                .ui_items()
+                .remove("Alice")
+                .expect("Alice not found in state");
+
+            assert_eq!(session.state.user_acted, Some(user_address));
+            assert_eq!(session.state.status, TradeStatus::OneUserAccepted);
+            assert_eq!(
+                *alice_tokens.get("TokenA").expect("TokenA not found"),
+                dec!(0.1001)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_offering_token_should_revert_accept() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let user_address = String::from("Alice");
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+
+        token_amount_cache.insert_token_amounts_with_decimals(
+            user_address.clone(),
+            HashMap::from([("TokenA".to_string(), dec!(15))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let connection_id = Uuid::new_v4();
+
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, connection_id, tx);
+
+        // Add tokens for user "Alice"
+        let result = shared.add_tokens_offer(
+            &session_id,
+            &user_address,
+            "TokenA".to_string(),
+            dec!(13.37),
+        );
+        assert!(result.is_ok());
+
+        let _ = shared.accept_trade(&session_id, &user_address);
+
+        {
+            let sessions = &shared.internal;
+            let session = sessions.get(&session_id).expect("Session not found");
+            let alice_tokens = session
+                .state
+                .ui_items()
+                .remove("Alice")
+                .expect("Alice not found in state");
+
+            assert_eq!(session.state.user_acted, Some(user_address.clone()));
+            assert_eq!(session.state.status, TradeStatus::OneUserAccepted);
+            assert_eq!(
+                *alice_tokens.get("TokenA").expect("TokenA not found"),
+                dec!(13.37)
+            );
+        }
+
+        let result =
+            shared.add_tokens_offer(&session_id, &user_address, "TokenA".to_string(), dec!(1.00));
+        assert!(result.is_ok());
+
+        {
+            let sessions = &shared.internal;
+            let session = sessions.get(&session_id).expect("Session not found");
+            let alice_tokens = session
+                .state
+                .ui_items()
+                .remove("Alice")
+                .expect("Alice not found in state");
+
+            assert_eq!(session.state.user_acted, None);
+            assert_eq!(session.state.status, TradeStatus::Trading);
+
+            assert_eq!(
+                *alice_tokens.get("TokenA").expect("TokenA not found"),
+                dec!(14.37)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn offer_change_after_accept_should_notify_clients() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        for user_address in ["Alice", "Bob"] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                user_address.to_string(),
+                HashMap::from([("TokenA".to_string(), dec!(15))]),
+                TEST_MINT_DECIMALS,
+            );
+        }
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(5))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Bob", "TokenA".to_string(), dec!(5))
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+
+        shared.accept_trade(&session_id, "Alice").unwrap();
+        shared
+            .withdraw_tokens(&session_id, "Bob", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        match rx.try_recv() {
+            Ok(WebsocketMessage::OfferChanged { by }) => assert_eq!(by, "Bob"),
+            other => panic!("Expected an offer change, got {:?}", other),
+        }
+
+        shared.accept_trade(&session_id, "Bob").unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        match rx.try_recv() {
+            Ok(WebsocketMessage::OfferChanged { by }) => assert_eq!(by, "Alice"),
+            other => panic!("Expected an offer change, got {:?}", other),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn counterparty_should_see_up_to_offers() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        for (user, mint) in [("Alice", "TokenA"), ("Bob", "TokenB")] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                user.to_string(),
+                HashMap::from([(mint.to_string(), dec!(10))]),
+                TEST_MINT_DECIMALS,
+            );
+        }
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let (bob_tx, mut bob_rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), bob_tx);
+        shared
+            .add_tokens_offer_from(
+                &session_id,
+                "Alice",
+                "TokenA".to_string(),
+                dec!(1),
+                None,
+                OfferMode::UpTo,
+            )
+            .unwrap();
+        shared.broadcast_current_state(&session_id);
+        let alice_up_to = HashMap::from([(
+            "Alice".to_string(),
+            std::collections::HashSet::from(["TokenA".to_string()]),
+        )]);
+        match bob_rx.try_recv() {
+            Ok(WebsocketMessage::TradeStateUpdate { up_to, .. }) => assert_eq!(up_to, alice_up_to),
+            other => panic!("Expected a state update, got {:?}", other),
+        }
+
+        shared
+            .add_tokens_offer(&session_id, "Bob", "TokenB".to_string(), dec!(1))
+            .unwrap();
+        shared.broadcast_current_state(&session_id);
+        match bob_rx.try_recv() {
+            Ok(WebsocketMessage::TradeStateUpdate { up_to, .. }) => assert_eq!(up_to, alice_up_to),
+            other => panic!("Expected a state update, got {:?}", other),
+        }
+
+        // Offering the mint again in the default mode takes it out of upTo
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared.broadcast_current_state(&session_id);
+        match bob_rx.try_recv() {
+            Ok(WebsocketMessage::TradeStateDelta { up_to, .. }) => {
+                assert_eq!(up_to, Some(UpToOffers::new()))
+            }
+            other => panic!("Expected a delta, got {:?}", other),
+        }
+        shared
+            .withdraw_tokens(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared.broadcast_current_state(&session_id);
+        match bob_rx.try_recv() {
+            Ok(WebsocketMessage::TradeStateDelta { up_to, .. }) => assert_eq!(up_to, None),
+            other => panic!("Expected a delta, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn late_joiner_should_receive_recent_events() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(1000))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let (alice_tx, _alice_rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), alice_tx);
+
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(5))
+            .unwrap();
+        shared
+            .withdraw_tokens(&session_id, "Alice", "TokenA".to_string(), dec!(2))
+            .unwrap();
+        shared.accept_trade(&session_id, "Alice").unwrap();
+
+        let late_connection = Uuid::new_v4();
+        let (late_tx, mut late_rx) = mpsc::channel(10);
+        shared.add_client(session_id, late_connection, late_tx);
+        shared.send_event_log(&session_id, &late_connection);
+        match late_rx.try_recv() {
+            Ok(WebsocketMessage::SessionEvents { events }) => assert_eq!(
+                events,
+                vec![
+                    SessionEvent::TokensOffered {
+                        user_address: "Alice".to_string(),
+                        token_mint: "TokenA".to_string(),
+                        amount: dec!(5),
+                    },
+                    SessionEvent::TokensWithdrawn {
+                        user_address: "Alice".to_string(),
+                        token_mint: "TokenA".to_string(),
+                        amount: dec!(2),
+                    },
+                    SessionEvent::TradeAccepted {
+                        user_address: "Alice".to_string(),
+                    },
+                ]
+            ),
+            other => panic!("Expected session events, got {:?}", other),
+        }
+
+        for _ in 0..SESSION_EVENT_LOG_SIZE {
+            shared
+                .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+                .unwrap();
+        }
+        let sessions = &shared.internal;
+        let events = &sessions.get(&session_id).unwrap().events;
+        assert_eq!(events.len(), SESSION_EVENT_LOG_SIZE);
+        assert!(events
+            .iter()
+            .all(|event| matches!(event, SessionEvent::TokensOffered { .. })));
+    }
+
+    #[tokio::test]
+    async fn events_should_record_the_clamped_amounts() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(8))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(5))
+            .unwrap();
+        shared
+            .withdraw_tokens(&session_id, "Alice", "TokenA".to_string(), dec!(25))
+            .unwrap();
+
+        let sessions = &shared.internal;
+        let events: Vec<_> = sessions
+            .get(&session_id)
+            .unwrap()
+            .events
+            .iter()
+            .cloned()
+            .collect();
+        let amounts: Vec<Decimal> = events
+            .iter()
+            .map(|event| match event {
+                SessionEvent::TokensOffered { amount, .. }
+                | SessionEvent::TokensWithdrawn { amount, .. } => *amount,
+                other => panic!("Unexpected event {:?}", other),
+            })
+            .collect();
+        assert_eq!(amounts, vec![dec!(8), dec!(2), dec!(10)]);
+    }
+
+    #[tokio::test]
+    async fn withdrawing_a_mint_never_offered_should_change_nothing() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        for (user_address, token_mint) in [("Alice", "TokenA"), ("Bob", "TokenC")] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                user_address.to_string(),
+                HashMap::from([(token_mint.to_string(), dec!(10))]),
+                TEST_MINT_DECIMALS,
+            );
+        }
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Bob", "TokenC".to_string(), dec!(1))
+            .unwrap();
+        shared.accept_trade(&session_id, "Bob").unwrap();
+        let (events, revision) = {
+            let session = shared.internal.get(&session_id).unwrap();
+            (session.events.len(), session.revision)
+        };
+        let mut lifecycle = shared.subscribe();
+
+        shared
+            .withdraw_tokens(&session_id, "Alice", "TokenB".to_string(), dec!(1))
+            .unwrap();
+
+        let session = shared.internal.get(&session_id).unwrap();
+        assert_eq!(session.state.status, TradeStatus::OneUserAccepted);
+        assert_eq!(session.state.user_acted.as_deref(), Some("Bob"));
+        assert_eq!(session.events.len(), events);
+        assert_eq!(session.revision, revision);
+        assert!(lifecycle.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn get_offers_should_return_offers_of_requested_user() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([
+                ("TokenA".to_string(), dec!(10)),
+                ("TokenB".to_string(), dec!(10)),
+            ]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Bob".to_string(),
+            HashMap::from([("TokenC".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenB".to_string(), dec!(2))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Bob", "TokenC".to_string(), dec!(3))
+            .unwrap();
+
+        assert_eq!(
+            shared.get_offers(&session_id, "Alice").unwrap(),
+            HashMap::from([
+                ("TokenA".to_string(), dec!(1)),
+                ("TokenB".to_string(), dec!(2))
+            ])
+        );
+        assert_eq!(
+            shared.get_offers(&session_id, "Bob").unwrap(),
+            HashMap::from([("TokenC".to_string(), dec!(3))])
+        );
+        assert!(shared.get_offers(&session_id, "Carol").is_err());
+        assert!(shared.get_offers(&Uuid::new_v4(), "Alice").is_err());
+    }
+
+    #[tokio::test]
+    async fn targeted_session_should_reject_uninvited_address() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        for user_address in ["Alice", "Bob", "Carol"] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                user_address.to_string(),
+                HashMap::from([("TokenA".to_string(), dec!(10))]),
+                TEST_MINT_DECIMALS,
+            );
+        }
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        shared.invite(session_id, "Alice".to_string(), "Bob".to_string());
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+
+        let result = shared.add_tokens_offer(&session_id, "Carol", "TokenA".to_string(), dec!(1));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "This trade is reserved for an invited counterparty"
+        );
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Bob", "TokenA".to_string(), dec!(1))
+            .unwrap();
+
+        let open_session_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(open_session_id, Uuid::new_v4(), tx);
+        shared
+            .add_tokens_offer(&open_session_id, "Carol", "TokenA".to_string(), dec!(1))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn state_updates_should_report_participant_roles() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        for user_address in ["Alice", "Bob"] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                user_address.to_string(),
+                HashMap::from([("TokenA".to_string(), dec!(10))]),
+                TEST_MINT_DECIMALS,
+            );
+        }
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let roles_after_broadcast =
+            |session_id: &SessionId, rx: &mut mpsc::Receiver<WebsocketMessage>| {
+                shared.broadcast_current_state(session_id);
+                match rx.try_recv() {
+                    Ok(WebsocketMessage::TradeStateUpdate { roles, .. }) => roles,
+                    other => panic!("Expected a TradeStateUpdate, got {:?}", other),
+                }
+            };
+
+        // The invited counterparty offering first doesn't make them the initiator
+        let targeted_session_id = Uuid::new_v4();
+        shared.invite(targeted_session_id, "Alice".to_string(), "Bob".to_string());
+        let (tx, mut rx) = mpsc::channel(10);
+        shared.add_client(targeted_session_id, Uuid::new_v4(), tx);
+        shared
+            .add_tokens_offer(&targeted_session_id, "Bob", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        assert_eq!(
+            roles_after_broadcast(&targeted_session_id, &mut rx),
+            HashMap::from([
+                ("Alice".to_string(), ParticipantRole::Initiator),
+                ("Bob".to_string(), ParticipantRole::Counterparty),
+            ])
+        );
+
+        let open_session_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(10);
+        shared.add_client(open_session_id, Uuid::new_v4(), tx);
+        shared
+            .add_tokens_offer(&open_session_id, "Bob", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        assert_eq!(
+            roles_after_broadcast(&open_session_id, &mut rx),
+            HashMap::from([("Bob".to_string(), ParticipantRole::Initiator)])
+        );
+        // A joining counterparty changes the roles, so a full update is sent instead of a delta
+        shared
+            .add_tokens_offer(&open_session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        assert_eq!(
+            roles_after_broadcast(&open_session_id, &mut rx),
+            HashMap::from([
+                ("Bob".to_string(), ParticipantRole::Initiator),
+                ("Alice".to_string(), ParticipantRole::Counterparty),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn roles_should_forbid_actions_outside_their_permissions() {
+        let alice = Pubkey::new_unique().to_string();
+        let bob = Pubkey::new_unique().to_string();
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        for user in [&alice, &bob] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                user.clone(),
+                HashMap::from([("TokenA".to_string(), dec!(10))]),
+                TEST_MINT_DECIMALS,
+            );
+        }
+        let shared = SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+        );
+        let session_id = Uuid::new_v4();
+        shared.invite(session_id, alice.clone(), bob.clone());
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+        shared
+            .add_tokens_offer(&session_id, &alice, "TokenA".to_string(), dec!(1))
+            .unwrap();
+        let forbidden = |error: Error| match error.downcast::<SessionError>() {
+            Ok(SessionError::Forbidden { role, action, .. }) => (role, action),
+            other => panic!("Expected a Forbidden error, got {:?}", other),
+        };
+
+        // The invited counterparty can't cancel before joining
+        let error = shared.cancel_trade(&session_id, &bob).unwrap_err();
+        assert_eq!(
+            forbidden(error),
+            (ParticipantRole::Counterparty, SessionAction::Cancel)
+        );
+        shared
+            .add_tokens_offer(&session_id, &bob, "TokenA".to_string(), dec!(2))
+            .unwrap();
+
+        // The initiator can't hand the fees to the counterparty, who can take them over
+        let error = shared.set_fee_payer(&session_id, &alice, &bob).unwrap_err();
+        assert_eq!(
+            forbidden(error),
+            (ParticipantRole::Initiator, SessionAction::PassOnFees)
+        );
+        shared.set_fee_payer(&session_id, &bob, &bob).unwrap();
+        shared.cancel_trade(&session_id, &bob).unwrap();
+    }
+
+    #[tokio::test]
+    async fn completed_session_should_refuse_changes_but_serve_snapshot() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared.internal.get_mut(&session_id).unwrap().state.status = TradeStatus::Completed;
+
+        let error = shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SessionError>(),
+            Some(&SessionError::SessionClosed {
+                status: TradeStatus::Completed
+            })
+        );
+        assert!(shared
+            .withdraw_tokens(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .is_err());
+        assert!(shared.accept_trade(&session_id, "Alice").is_err());
+        assert!(shared.set_memo(&session_id, "Alice", "note").is_err());
+        assert_eq!(
+            shared.get_offers(&session_id, "Alice").unwrap(),
+            HashMap::from([("TokenA".to_string(), dec!(1))])
+        );
+
+        let connection_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(10);
+        shared.add_client(session_id, connection_id, tx);
+        shared.send_current_state(&session_id, &connection_id);
+        match rx.try_recv() {
+            Ok(WebsocketMessage::TradeStateUpdate {
+                offers,
+                status,
+                read_only,
+                ..
+            }) => {
+                assert_eq!(status, TradeStatus::Completed);
+                assert!(read_only);
+                assert_eq!(offers["Alice"]["TokenA"], dec!(1));
+            }
+            other => panic!("Expected a TradeStateUpdate, got {:?}", other),
+        }
+    }
 
-#[derive(Clone, Debug, Display, Default, PartialEq, serde::Serialize, serde::Deserialize)]
-pub enum TradeStatus {
-    #[default]
-    Trading,
-    OneUserAccepted,
-    Accepted,
-    TransactionCreated,
-    OneUserSigned,
-    TransactionSent,
-}
+    #[tokio::test]
+    async fn cancelled_session_should_refuse_changes_and_finalize_the_trade() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let session_id = Uuid::new_v4();
+        let trade_store = Arc::new(InMemoryTradeStore::with_trades(vec![created_trade(
+            session_id,
+        )]));
+        let shared = Arc::new(SharedSessions::with_stores(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+            AuditLog::disabled(),
+            Some(Arc::clone(&trade_store) as Arc<dyn TradeStore>),
+        ));
+        spawn_session_writer(Arc::clone(&shared), CancellationToken::new());
+        let (tx, mut rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared.accept_trade(&session_id, "Alice").unwrap();
+        assert!(shared.cancel_trade(&session_id, "Mallory").is_err());
+
+        shared.cancel_trade(&session_id, "Alice").unwrap();
+        let error = shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SessionError>(),
+            Some(&SessionError::SessionClosed {
+                status: TradeStatus::Cancelled
+            })
+        );
+        assert!(shared.accept_trade(&session_id, "Alice").is_err());
+        assert!(shared.cancel_trade(&session_id, "Alice").is_err());
+        while rx.try_recv().is_ok() {}
+        shared.broadcast_current_state(&session_id);
+        match rx.try_recv() {
+            Ok(WebsocketMessage::TradeStateUpdate {
+                status, read_only, ..
+            }) => {
+                assert_eq!(status, TradeStatus::Cancelled);
+                assert!(read_only);
+            }
+            other => panic!("Expected a TradeStateUpdate, got {:?}", other),
+        }
+        shared.flushed().await;
+        let trade = trade_store.get_trade(&session_id).unwrap().unwrap();
+        assert_eq!(trade.status, "Cancelled");
+
+        let sent_session = Uuid::new_v4();
+        shared.add_client(sent_session, Uuid::new_v4(), mpsc::channel(10).0);
+        shared
+            .add_tokens_offer(&sent_session, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared.internal.get_mut(&sent_session).unwrap().state.status = TradeStatus::TransactionSent;
+        assert!(shared.cancel_trade(&sent_session, "Alice").is_err());
+    }
 
-#[cfg(test)]
-mod tests {
-    use crate::{chain_context::TestChainContext, token_amount_cache};
+    #[tokio::test]
+    async fn disabled_trading_should_refuse_transaction_to_sign() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::with_config(
+            Arc::new(TestChainContext {}),
+            TransactionConfig {
+                trading_enabled: false,
+                ..TransactionConfig::default()
+            },
+        ));
+        let shared = SharedSessions::new(token_amount_cache, Arc::clone(&transaction_service));
+        let session_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+        {
+            let sessions = &shared.internal;
+            let mut session = sessions.get_mut(&session_id).unwrap();
+            session.initiator = Some(Pubkey::new_unique().to_string());
+            session.state.items = Arc::new(HashMap::from([
+                (
+                    Pubkey::new_unique().to_string(),
+                    HashMap::from([(Pubkey::new_unique().to_string(), 1_000_000)]),
+                ),
+                (
+                    Pubkey::new_unique().to_string(),
+                    HashMap::from([(Pubkey::new_unique().to_string(), 1_000_000)]),
+                ),
+            ]));
+            session.state.status = TradeStatus::Accepted;
+        }
 
-    use super::*;
-    use solana_sdk::{pubkey::Pubkey, transaction};
-    use tokio::sync::mpsc;
-    use uuid::Uuid;
+        let result = shared.get_transaction_to_sign(&session_id, "Alice").await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            TRANSACTION_BUILDING_DISABLED
+        );
+        let result = transaction_service
+            .create_transaction(Arc::new(HashMap::new()), "Alice")
+            .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            TRANSACTION_BUILDING_DISABLED
+        );
+
+        let sessions = &shared.internal;
+        let session = sessions.get(&session_id).unwrap();
+        assert_eq!(session.state.status, TradeStatus::Accepted);
+        assert!(session.state.tx.is_none());
+    }
 
     #[tokio::test]
-    async fn test_get_transaction_to_sign_possible_in_accepted_state() {
+    async fn single_mint_change_should_broadcast_minimal_delta() {
         let token_amount_cache = Arc::new(TokenAmountCache::init());
-        let user_address1 = String::from("DuiJXfXdZdcJQko3LugHAAWR9RgQPNXVXk79y691rpHg");
-        let user_address2 = String::from("2qkf9i5rEjDJ53izfccdEmUhW1LkgMzgCDz1SG3zYYym");
-        let token_a = String::from("FKqe4pSujn57nL8JD62mYfwsnJ6bE9HCr5wr6C7nBzGM");
-        let token_b = String::from("HBc27s2MjdMK8Bg46KzKBuZAk1EvTioTKVaxxcnn1hJW");
-
         let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
             TestChainContext {},
         )));
-
-        token_amount_cache.insert_token_amounts(
-            user_address1.clone(),
-            HashMap::from([(token_a.clone(), dec!(0.6))]),
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([
+                ("TokenA".to_string(), dec!(10)),
+                ("TokenB".to_string(), dec!(10)),
+            ]),
+            TEST_MINT_DECIMALS,
         );
-        token_amount_cache.insert_token_amounts(
-            user_address2.clone(),
-            HashMap::from([(token_b.clone(), dec!(2.0))]),
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Bob".to_string(),
+            HashMap::from([("TokenC".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
         );
         let shared = SharedSessions::new(token_amount_cache, transaction_service);
         let session_id = Uuid::new_v4();
-        let connection_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenB".to_string(), dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Bob", "TokenC".to_string(), dec!(1))
+            .unwrap();
+        shared.broadcast_current_state(&session_id);
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(WebsocketMessage::TradeStateUpdate { .. })
+        ));
+
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(2))
+            .unwrap();
+        shared.broadcast_current_state(&session_id);
+        match rx.try_recv() {
+            Ok(WebsocketMessage::TradeStateDelta {
+                seq,
+                changed,
+                removed,
+                ..
+            }) => {
+                assert_eq!(seq, 2);
+                assert_eq!(
+                    changed,
+                    HashMap::from([(
+                        "Alice".to_string(),
+                        HashMap::from([("TokenA".to_string(), dec!(3))])
+                    )])
+                );
+                assert!(removed.is_empty());
+            }
+            other => panic!("Expected a delta, got {:?}", other),
+        }
+
+        shared
+            .withdraw_tokens(&session_id, "Bob", "TokenC".to_string(), dec!(1))
+            .unwrap();
+        shared.broadcast_current_state(&session_id);
+        match rx.try_recv() {
+            Ok(WebsocketMessage::TradeStateDelta {
+                seq,
+                changed,
+                removed,
+                ..
+            }) => {
+                assert_eq!(seq, 3);
+                assert!(changed.is_empty());
+                assert_eq!(
+                    removed,
+                    HashMap::from([("Bob".to_string(), vec!["TokenC".to_string()])])
+                );
+            }
+            other => panic!("Expected a delta, got {:?}", other),
+        }
+
+        shared.broadcast_current_state(&session_id);
+        assert!(rx.try_recv().is_err());
+    }
 
+    #[tokio::test]
+    async fn memo_should_be_stored_truncated_and_persisted() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let session_id = Uuid::new_v4();
+        let trade_store = Arc::new(InMemoryTradeStore::with_trades(vec![created_trade(
+            session_id,
+        )]));
+        let shared = Arc::new(SharedSessions::with_stores(
+            token_amount_cache,
+            transaction_service,
+            AuditLog::disabled(),
+            Some(Arc::clone(&trade_store) as Arc<dyn TradeStore>),
+        ));
+        spawn_session_writer(Arc::clone(&shared), CancellationToken::new());
         let (tx, _rx) = mpsc::channel(10);
-        shared.add_client(session_id, connection_id, tx);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
 
-        // Add tokens for user "Alice"
-        let result =
-            shared.add_tokens_offer(&session_id, &user_address1, token_a.clone(), dec!(0.1001));
-        assert!(result.is_ok());
+        assert!(shared.set_memo(&session_id, "Alice", "thanks").is_err());
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        let long_memo = "é".repeat(MAX_MEMO_LEN + 10);
+        shared.set_memo(&session_id, "Alice", &long_memo).unwrap();
 
-        let result =
-            shared.add_tokens_offer(&session_id, &user_address2, token_b.clone(), dec!(0.5));
-        assert!(result.is_ok());
+        let expected = "é".repeat(MAX_MEMO_LEN);
+        {
+            let sessions = &shared.internal;
+            let memos = &sessions.get(&session_id).unwrap().state.memos;
+            assert_eq!(memos.get("Alice"), Some(&expected));
+        }
+        shared.flushed().await;
+        let trade = trade_store.get_trade(&session_id).unwrap().unwrap();
+        let details = trade.status_details.unwrap();
+        assert_eq!(details["memos"], serde_json::json!({"Alice": expected}));
+        assert_eq!(
+            details["session"]["state"]["memos"],
+            serde_json::json!({"Alice": expected})
+        );
+    }
 
-        let _ = shared.accept_trade(&session_id, &user_address1);
-        let _ = shared.accept_trade(&session_id, &user_address2);
+    #[tokio::test]
+    async fn withdrawn_offer_should_survive_a_restart() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Bob".to_string(),
+            HashMap::from([("TokenB".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let session_id = Uuid::new_v4();
+        let finished_session = Uuid::new_v4();
+        let trade_store = Arc::new(InMemoryTradeStore::with_trades(vec![
+            created_trade(session_id),
+            TradeEntity {
+                status: "Completed".to_string(),
+                status_details: Some(serde_json::json!({"session": {}})),
+                ..created_trade(finished_session)
+            },
+        ]));
+        let sessions = |token_amount_cache| {
+            let shared = Arc::new(SharedSessions::with_stores(
+                token_amount_cache,
+                Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                    TestChainContext {},
+                ))),
+                AuditLog::disabled(),
+                Some(Arc::clone(&trade_store) as Arc<dyn TradeStore>),
+            ));
+            spawn_session_writer(Arc::clone(&shared), CancellationToken::new());
+            shared
+        };
+        let shared = sessions(Arc::clone(&token_amount_cache));
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(2))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Bob", "TokenB".to_string(), dec!(1))
+            .unwrap();
+        shared
+            .withdraw_tokens(&session_id, "Alice", "TokenA".to_string(), dec!(0.5))
+            .unwrap();
+        shared.accept_trade(&session_id, "Bob").unwrap();
+        shared.flushed().await;
+        let trade = trade_store.get_trade(&session_id).unwrap().unwrap();
+        assert_eq!(trade.counterparty.as_deref(), Some("Bob"));
+        assert_eq!(trade.status, "OneUserAccepted");
+        assert_eq!(
+            trade
+                .status_transitions()
+                .last()
+                .map(|transition| transition.status.as_str()),
+            Some("OneUserAccepted")
+        );
 
+        let restarted = sessions(token_amount_cache);
+        assert_eq!(restarted.restore_sessions().unwrap(), 1);
+        assert_eq!(restarted.session_ids(), vec![session_id]);
+        assert_eq!(
+            restarted.get_offers(&session_id, "Alice").unwrap(),
+            HashMap::from([("TokenA".to_string(), dec!(1.5))])
+        );
+        assert_eq!(
+            restarted.get_offers(&session_id, "Bob").unwrap(),
+            HashMap::from([("TokenB".to_string(), dec!(1))])
+        );
         {
-            let sessions = shared.internal.lock().unwrap();
-            let session = sessions.get(&session_id).expect("Session not found");
-            assert_eq!(session.state.user_acted, None);
-            assert_eq!(session.state.status, TradeStatus::Accepted);
+            let sessions = &restarted.internal;
+            let session = sessions.get(&session_id).unwrap();
+            assert_eq!(session.state.status, TradeStatus::OneUserAccepted);
+            assert_eq!(session.initiator.as_deref(), Some("Alice"));
         }
+        // Accepting completes where the previous process left off
+        restarted.accept_trade(&session_id, "Alice").unwrap();
+        let sessions = &restarted.internal;
+        assert_eq!(
+            sessions.get(&session_id).unwrap().state.status,
+            TradeStatus::Accepted
+        );
+    }
 
-        let result = shared
-            .get_transaction_to_sign(&session_id, &user_address1)
-            .await;
+    #[tokio::test]
+    async fn signed_transaction_should_survive_a_restart() {
+        use solana_sdk::signature::{Keypair, Signer};
+
+        let alice = Keypair::new();
+        let bob = Keypair::new();
+        let (alice_address, bob_address) = (alice.pubkey().to_string(), bob.pubkey().to_string());
+        let alice_mint = Pubkey::new_unique().to_string();
+        let bob_mint = Pubkey::new_unique().to_string();
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice_address.clone(),
+            HashMap::from([(alice_mint.clone(), dec!(5))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            bob_address.clone(),
+            HashMap::from([(bob_mint.clone(), dec!(5))]),
+            TEST_MINT_DECIMALS,
+        );
+        let session_id = Uuid::new_v4();
+        let trade_store = Arc::new(InMemoryTradeStore::with_trades(vec![created_trade(
+            session_id,
+        )]));
+        let sessions = || {
+            let shared = Arc::new(SharedSessions::with_stores(
+                Arc::clone(&token_amount_cache),
+                Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                    TestChainContext {},
+                ))),
+                AuditLog::disabled(),
+                Some(Arc::clone(&trade_store) as Arc<dyn TradeStore>),
+            ));
+            spawn_session_writer(Arc::clone(&shared), CancellationToken::new());
+            shared
+        };
+        let stored_status = || {
+            let trade = trade_store.get_trade(&session_id).unwrap().unwrap();
+            trade.status_details.unwrap()["session"]["state"]["status"].clone()
+        };
+        let shared = sessions();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(100).0);
+        shared
+            .add_tokens_offer(&session_id, &alice_address, alice_mint, dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, &bob_address, bob_mint, dec!(1))
+            .unwrap();
+        shared.accept_trade(&session_id, &alice_address).unwrap();
+        shared.accept_trade(&session_id, &bob_address).unwrap();
+        shared
+            .get_transaction_to_sign(&session_id, &alice_address)
+            .await
+            .unwrap();
+        shared.flushed().await;
+        assert_eq!(
+            stored_status(),
+            serde_json::json!(TradeStatus::TransactionCreated)
+        );
 
-        if let Err(e) = result {
-            println!("Error: {:#?}", e.backtrace());
+        let tx = shared
+            .internal
+            .get(&session_id)
+            .unwrap()
+            .state
+            .tx
+            .clone()
+            .unwrap();
+        let signature = alice.sign_message(&tx.message_data()).to_string();
+        shared
+            .sign_transaction(&session_id, &alice_address, signature.clone())
+            .unwrap();
+        shared.flushed().await;
+        assert_eq!(
+            stored_status(),
+            serde_json::json!(TradeStatus::OneUserSigned)
+        );
+
+        let restarted = sessions();
+        assert_eq!(restarted.restore_sessions().unwrap(), 1);
+        let restored_tx = {
+            let sessions = &restarted.internal;
+            let session = sessions.get(&session_id).unwrap();
+            assert_eq!(session.state.status, TradeStatus::OneUserSigned);
+            session.state.tx.clone().unwrap()
+        };
+        assert_eq!(restored_tx.message, tx.message);
+        assert!(restored_tx
+            .signatures
+            .contains(&Signature::from_str(&signature).unwrap()));
+        // Signing completes where the previous process left off
+        restarted
+            .sign_transaction(
+                &session_id,
+                &bob_address,
+                bob.sign_message(&tx.message_data()).to_string(),
+            )
+            .unwrap();
+        restarted.flushed().await;
+        assert_eq!(stored_status(), serde_json::json!(TradeStatus::FullySigned));
+    }
+
+    #[tokio::test]
+    async fn session_writer_should_write_pending_changes_before_stopping() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let session_id = Uuid::new_v4();
+        let trade_store = Arc::new(InMemoryTradeStore::with_trades(vec![created_trade(
+            session_id,
+        )]));
+        let shared = Arc::new(SharedSessions::with_stores(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+            AuditLog::disabled(),
+            Some(Arc::clone(&trade_store) as Arc<dyn TradeStore>),
+        ));
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        // Changed while no writer listened
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(2))
+            .unwrap();
+        assert_eq!(
+            trade_store
+                .get_trade(&session_id)
+                .unwrap()
+                .unwrap()
+                .status_details,
+            None
+        );
+
+        let shutdown = CancellationToken::new();
+        let writer = spawn_session_writer(Arc::clone(&shared), shutdown.clone());
+        shutdown.cancel();
+        writer.await.unwrap();
+        let trade = trade_store.get_trade(&session_id).unwrap().unwrap();
+        let session = &trade.status_details.unwrap()["session"];
+        assert_eq!(session["revision"], 1);
+        assert_eq!(
+            shared.internal.get(&session_id).unwrap().persisted_revision,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn session_state_should_fall_back_to_the_trade_record() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let session_id = Uuid::new_v4();
+        let expired_session = Uuid::new_v4();
+        let trade_store = Arc::new(InMemoryTradeStore::with_trades(vec![
+            created_trade(session_id),
+            created_trade(expired_session),
+        ]));
+        let sessions = || {
+            let shared = Arc::new(SharedSessions::with_stores(
+                Arc::clone(&token_amount_cache),
+                Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                    TestChainContext {},
+                ))),
+                AuditLog::disabled(),
+                Some(Arc::clone(&trade_store) as Arc<dyn TradeStore>),
+            ));
+            spawn_session_writer(Arc::clone(&shared), CancellationToken::new());
+            shared
+        };
+        let live_sessions = sessions();
+        live_sessions.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        live_sessions
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(2))
+            .unwrap();
+        live_sessions.flushed().await;
+        let live = live_sessions.session_state(&session_id).unwrap();
+
+        // A restarted process that didn't restore the session yet
+        let shared = sessions();
+        let stored = shared.session_state(&session_id).unwrap();
+        assert_eq!(
+            serde_json::to_value(&stored).unwrap(),
+            serde_json::to_value(&live).unwrap()
+        );
+        match stored {
+            Some(WebsocketMessage::TradeStateUpdate { offers, status, .. }) => {
+                assert_eq!(offers["Alice"]["TokenA"], dec!(2));
+                assert_eq!(status, TradeStatus::Trading);
+            }
+            other => panic!("Expected a TradeStateUpdate, got {:?}", other),
         }
-        {
-            let sessions = shared.internal.lock().unwrap();
-            let session = sessions.get(&session_id).expect("Session not found");
-            assert_eq!(
-                session.state.user_acted,
-                Some(user_address1.clone()),
-                "User acted missing after transaction created"
-            );
-            assert_eq!(
-                session.state.status,
-                TradeStatus::TransactionCreated,
-                "Transaction should be in TransactionCreated state"
-            );
-            let tx = session.state.tx.clone().unwrap();
-            assert_eq!(tx.message().account_keys.len(), 9);
-            assert!(tx
-                .message()
-                .account_keys
-                .contains(&Pubkey::from_str(&user_address1).unwrap()));
-            assert!(tx
-                .message()
-                .account_keys
-                .contains(&Pubkey::from_str(&user_address2).unwrap()));
-            assert!(tx
-                .message()
-                .account_keys
-                .contains(&Pubkey::from_str(&token_a).unwrap()));
-            assert!(tx
-                .message()
-                .account_keys
-                .contains(&Pubkey::from_str(&token_b).unwrap()));
 
-            println!("Tx message: {:#?}", tx.message());
+        trade_store
+            .update_trade_status(&session_id, &trade_repository::TradeStatus::Completed)
+            .unwrap();
+        assert!(matches!(
+            shared.session_state(&session_id).unwrap(),
+            Some(WebsocketMessage::TradeStateUpdate {
+                status: TradeStatus::Completed,
+                read_only: true,
+                ..
+            })
+        ));
+        // Reaped sessions are gone from memory, only the record tells they expired
+        trade_store
+            .update_trade_status(&expired_session, &trade_repository::TradeStatus::Expired)
+            .unwrap();
+        assert!(matches!(
+            shared.session_state(&expired_session).unwrap(),
+            Some(WebsocketMessage::TradeStateUpdate {
+                status: TradeStatus::Expired,
+                read_only: true,
+                ..
+            })
+        ));
+        assert!(shared.session_state(&Uuid::new_v4()).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn chosen_fee_payer_should_pay_for_the_transaction() {
+        let alice = Pubkey::new_unique().to_string();
+        let bob = Pubkey::new_unique().to_string();
+        let token_a = Pubkey::new_unique().to_string();
+        let token_b = Pubkey::new_unique().to_string();
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice.clone(),
+            HashMap::from([(token_a.clone(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            bob.clone(),
+            HashMap::from([(token_b.clone(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+        );
+        let session_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+        shared
+            .add_tokens_offer(&session_id, &alice, token_a, dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, &bob, token_b, dec!(1))
+            .unwrap();
+
+        assert!(shared
+            .set_fee_payer(&session_id, &alice, "Charlie")
+            .is_err());
+        shared.accept_trade(&session_id, &alice).unwrap();
+        shared.set_fee_payer(&session_id, &bob, &bob).unwrap();
+        {
+            let sessions = &shared.internal;
+            let state = &sessions.get(&session_id).unwrap().state;
+            assert_eq!(state.status, TradeStatus::Trading);
+            assert_eq!(state.fee_payer.as_deref(), Some(bob.as_str()));
         }
+        shared.accept_trade(&session_id, &alice).unwrap();
+        shared.accept_trade(&session_id, &bob).unwrap();
+        shared
+            .get_transaction_to_sign(&session_id, &alice)
+            .await
+            .unwrap();
+
+        let sessions = &shared.internal;
+        let built = sessions.get(&session_id).unwrap().state.tx.clone().unwrap();
+        assert_eq!(built.message.account_keys[0].to_string(), bob);
     }
 
     #[tokio::test]
-    async fn test_accept_trade_only_possible_in_trading_or_oneuseraccepted_status() {
+    async fn source_account_should_be_held_by_the_wallet_for_the_mint() {
+        use crate::token_amount_cache::HeldTokenAccount;
+
+        let own_account = Pubkey::new_unique().to_string();
+        let other_mint_account = Pubkey::new_unique().to_string();
         let token_amount_cache = Arc::new(TokenAmountCache::init());
-        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
-            TestChainContext {},
-        )));
-        let user_address1 = String::from("Alice");
+        token_amount_cache.insert_token_accounts_with_decimals(
+            "Alice".to_string(),
+            vec![
+                HeldTokenAccount {
+                    account: own_account.clone(),
+                    mint: "TokenA".to_string(),
+                    amount: dec!(10),
+                },
+                HeldTokenAccount {
+                    account: other_mint_account.clone(),
+                    mint: "TokenB".to_string(),
+                    amount: dec!(10),
+                },
+            ],
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+        );
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        let offer_from = |token_account: &str| {
+            shared.add_tokens_offer_from(
+                &session_id,
+                "Alice",
+                "TokenA".to_string(),
+                dec!(1),
+                Some(token_account.to_string()),
+                OfferMode::Exact,
+            )
+        };
 
-        token_amount_cache.insert_token_amounts(
-            user_address1.clone(),
-            HashMap::from([("TokenA".to_string(), dec!(0.6))]),
+        let foreign_account = Pubkey::new_unique().to_string();
+        let error = offer_from(&foreign_account).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{} is not a token account of Alice holding TokenA",
+                foreign_account
+            )
+        );
+        assert!(offer_from(&other_mint_account).is_err());
+        assert!(shared.get_offers(&session_id, "Alice").is_err());
+
+        offer_from(&own_account).unwrap();
+        let sessions = &shared.internal;
+        let state = &sessions.get(&session_id).unwrap().state;
+        assert_eq!(state.source_accounts["Alice"]["TokenA"], own_account);
+    }
+
+    #[tokio::test]
+    async fn fee_payer_should_not_be_chosen_when_the_server_pays() {
+        let alice = Pubkey::new_unique().to_string();
+        let bob = Pubkey::new_unique().to_string();
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice.clone(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            bob.clone(),
+            HashMap::from([("TokenB".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::with_config(
+                Arc::new(TestChainContext {}),
+                TransactionConfig {
+                    fee_payer: FeePayerPolicy::Server {
+                        keypair_path: server_keypair_file().1,
+                    },
+                    ..Default::default()
+                },
+            )),
+        );
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        shared
+            .add_tokens_offer(&session_id, &alice, "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, &bob, "TokenB".to_string(), dec!(1))
+            .unwrap();
+        shared.accept_trade(&session_id, &alice).unwrap();
+
+        let error = shared.set_fee_payer(&session_id, &alice, &bob).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Network fees are paid by the server, the fee payer can't be chosen"
+        );
+        let sessions = &shared.internal;
+        let state = &sessions.get(&session_id).unwrap().state;
+        assert_eq!(state.status, TradeStatus::OneUserAccepted);
+        assert!(state.fee_payer.is_none());
+    }
+
+    #[tokio::test]
+    async fn duplicate_connection_id_should_not_replace_sender() {
+        let shared = SharedSessions::new(
+            Arc::new(TokenAmountCache::init()),
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+        );
+        let session_id = Uuid::new_v4();
+        let connection_id = Uuid::new_v4();
+        let (tx1, mut rx1) = mpsc::channel(10);
+        let (tx2, mut rx2) = mpsc::channel(10);
+
+        assert!(shared.add_client(session_id, connection_id, tx1));
+        assert!(!shared.add_client(session_id, connection_id, tx2));
+
+        shared.send_current_state(&session_id, &connection_id);
+        assert!(rx1.try_recv().is_ok());
+        assert!(rx2.try_recv().is_err());
+    }
+
+    #[test]
+    fn late_older_snapshot_should_not_overwrite_newer_memos() {
+        let session_id = Uuid::new_v4();
+        let trade_store = InMemoryTradeStore::with_trades(vec![created_trade(session_id)]);
+        let update = |revision: u64, memo: &str| TradeUpdate {
+            counterparty: None,
+            revision,
+            status: match revision {
+                1 => trade_repository::TradeStatus::Trading,
+                _ => trade_repository::TradeStatus::Accepted,
+            },
+            session: serde_json::json!({ "revision": revision }),
+            memos: serde_json::json!({ "Alice": memo }),
+        };
+
+        trade_store
+            .update_trade(&session_id, &update(2, "thanks"))
+            .unwrap();
+        trade_store
+            .update_trade(&session_id, &update(1, "hi"))
+            .unwrap();
+
+        let trade = trade_store.get_trade(&session_id).unwrap().unwrap();
+        assert_eq!(trade.status, "Accepted");
+        assert_eq!(
+            trade.status_details.unwrap()["memos"],
+            serde_json::json!({ "Alice": "thanks" })
+        );
+    }
+
+    fn created_trade(id: Uuid) -> TradeEntity {
+        TradeEntity {
+            id,
+            initiator: "Alice".to_string(),
+            counterparty: None,
+            status: "Created".to_string(),
+            status_details: None,
+            status_history: serde_json::json!([]),
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    /// Status the session's trade settled with, waiting for `spawn_settlement_task` to get there.
+    async fn settled(
+        events: &mut broadcast::Receiver<LifecycleEvent>,
+        session_id: SessionId,
+    ) -> TradeStatus {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match events.recv().await {
+                    Ok(LifecycleEvent::Completed {
+                        session_id: settled,
+                        status,
+                    }) if settled == session_id => return status,
+                    Ok(_) => {}
+                    Err(e) => panic!("lifecycle events ended: {}", e),
+                }
+            }
+        })
+        .await
+        .expect("trade never settled")
+    }
+
+    #[tokio::test]
+    async fn negotiated_trade_should_submit_the_transaction_both_signed() {
+        use crate::chain_context::ScriptedChainContext;
+        use crate::config::ConfirmationConfig;
+        use base64::{engine::general_purpose, Engine as _};
+        use solana_sdk::signature::{Keypair, Signer};
+
+        let alice = Keypair::new();
+        let bob = Keypair::new();
+        let (alice_address, bob_address) = (alice.pubkey().to_string(), bob.pubkey().to_string());
+        let token_a = Pubkey::new_unique().to_string();
+        let token_b = Pubkey::new_unique().to_string();
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice_address.clone(),
+            HashMap::from([(token_a.clone(), dec!(5))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            bob_address.clone(),
+            HashMap::from([(token_b.clone(), dec!(5))]),
+            TEST_MINT_DECIMALS,
         );
-        let shared = SharedSessions::new(token_amount_cache, transaction_service);
         let session_id = Uuid::new_v4();
-        let connection_id = Uuid::new_v4();
-
-        let (tx, _rx) = mpsc::channel(10);
-        shared.add_client(session_id, connection_id, tx);
+        let trade_store = Arc::new(InMemoryTradeStore::with_trades(vec![created_trade(
+            session_id,
+        )]));
+        let chain_context: Arc<ScriptedChainContext> = Arc::default();
+        let shared = Arc::new(SharedSessions::with_stores(
+            token_amount_cache,
+            Arc::new(TransactionService::new(Arc::clone(&chain_context))),
+            AuditLog::disabled(),
+            Some(Arc::clone(&trade_store) as Arc<dyn TradeStore>),
+        ));
+        let mut events = shared.subscribe();
+        let shutdown = CancellationToken::new();
+        let settlement = spawn_settlement_task(
+            Arc::clone(&shared),
+            ConfirmationConfig {
+                poll_interval_ms: 0,
+                ..ConfirmationConfig::default()
+            },
+            shutdown.clone(),
+        );
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(100).0);
+
+        shared
+            .add_tokens_offer(&session_id, &alice_address, token_a.clone(), dec!(2))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, &bob_address, token_b.clone(), dec!(3))
+            .unwrap();
+        shared.accept_trade(&session_id, &alice_address).unwrap();
+        shared.accept_trade(&session_id, &bob_address).unwrap();
+        shared
+            .get_transaction_to_sign(&session_id, &alice_address)
+            .await
+            .unwrap();
+        shared.get_fee_estimate(&session_id).await.unwrap();
+
+        // Each wallet signs the transaction it was sent
+        let (_, encoded) = shared
+            .encoded_transaction(&session_id, Some(TransactionEncoding::Base64))
+            .unwrap();
+        let mut signed: Transaction =
+            bincode::deserialize(&general_purpose::STANDARD.decode(encoded).unwrap()).unwrap();
+        let blockhash = signed.message.recent_blockhash;
+        for (keypair, address) in [(&alice, &alice_address), (&bob, &bob_address)] {
+            signed.try_partial_sign(&[keypair], blockhash).unwrap();
+            let position = signed
+                .message
+                .account_keys
+                .iter()
+                .position(|key| *key == keypair.pubkey())
+                .unwrap();
+            shared
+                .sign_transaction(
+                    &session_id,
+                    address,
+                    signed.signatures[position].to_string(),
+                )
+                .unwrap();
+        }
+        signed.verify().unwrap();
 
-        // Add tokens for user "Alice"
-        let result = shared.add_tokens_offer(
-            &session_id,
-            &user_address1,
-            "TokenA".to_string(),
-            dec!(0.1001),
+        // The last signature alone gets the transaction sent and the trade settled
+        assert_eq!(
+            settled(&mut events, session_id).await,
+            TradeStatus::Completed
         );
-        assert!(result.is_ok());
+        shutdown.cancel();
+        settlement.await.unwrap();
+
+        let session_tx = {
+            let sessions = &shared.internal;
+            let session = sessions.get(&session_id).unwrap();
+            assert_eq!(session.state.status, TradeStatus::Completed);
+            session.state.tx.clone().unwrap()
+        };
+        assert_eq!(
+            *chain_context.fee_requests.lock().unwrap(),
+            vec![session_tx.message.clone()]
+        );
+        let sent = chain_context.sent.lock().unwrap();
+        assert_eq!(*sent, vec![signed]);
+        assert_eq!(sent[0].message, session_tx.message);
+        let trade = trade_store.get_trade(&session_id).unwrap().unwrap();
+        assert_eq!(trade.status, "Completed");
+        assert_eq!(
+            trade.status_details.unwrap()["signature"],
+            sent[0].signatures[0].to_string()
+        );
+    }
 
-        let _ = shared.accept_trade(&session_id, &user_address1);
+    /// What `signed_trade` leaves behind.
+    struct SignedTrade {
+        shared: Arc<SharedSessions<ScriptedChainContext>>,
+        session_id: SessionId,
+        /// Lifecycle events from before the last signature on.
+        events: broadcast::Receiver<LifecycleEvent>,
+        trade_store: Arc<InMemoryTradeStore>,
+        /// What a connected client receives.
+        received: mpsc::Receiver<WebsocketMessage>,
+    }
 
-        // states that should not allow changing token offers
-        for trade_status in vec![
-            TradeStatus::Accepted,
-            TradeStatus::TransactionCreated,
-            TradeStatus::OneUserSigned,
-            TradeStatus::TransactionSent,
-        ] {
-            //change trade status
-            {
-                let mut sessions = shared.internal.lock().unwrap();
-                let session = sessions.get_mut(&session_id).expect("Session not found");
-                session.state.status = trade_status;
-            }
+    /// A trade both participants signed, settled by a running `spawn_settlement_task`.
+    async fn signed_trade(
+        chain_context: Arc<ScriptedChainContext>,
+        confirmation: crate::config::ConfirmationConfig,
+    ) -> SignedTrade {
+        signed_trade_with_config(chain_context, TransactionConfig::default(), confirmation).await
+    }
 
-            let result = shared.accept_trade(&session_id, &user_address1);
-            assert!(result.is_err());
+    /// Like `signed_trade`, building the transaction as `transaction_config` says.
+    async fn signed_trade_with_config(
+        chain_context: Arc<ScriptedChainContext>,
+        transaction_config: TransactionConfig,
+        confirmation: crate::config::ConfirmationConfig,
+    ) -> SignedTrade {
+        use solana_sdk::signature::{Keypair, Signer};
+
+        let alice = Keypair::new();
+        let bob = Keypair::new();
+        let (alice_address, bob_address) = (alice.pubkey().to_string(), bob.pubkey().to_string());
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        for address in [&alice_address, &bob_address] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                address.clone(),
+                HashMap::from([(Pubkey::new_unique().to_string(), dec!(5))]),
+                TEST_MINT_DECIMALS,
+            );
+        }
+        let session_id = Uuid::new_v4();
+        let trade_store = Arc::new(InMemoryTradeStore::with_trades(vec![created_trade(
+            session_id,
+        )]));
+        let shared = Arc::new(SharedSessions::with_stores(
+            Arc::clone(&token_amount_cache),
+            Arc::new(TransactionService::with_config(
+                chain_context,
+                transaction_config,
+            )),
+            AuditLog::disabled(),
+            Some(Arc::clone(&trade_store) as Arc<dyn TradeStore>),
+        ));
+        let events = shared.subscribe();
+        spawn_settlement_task(Arc::clone(&shared), confirmation, CancellationToken::new());
+        let (client, received) = mpsc::channel(100);
+        shared.add_client(session_id, Uuid::new_v4(), client);
+        for address in [&alice_address, &bob_address] {
+            let mint = token_amount_cache
+                .get_token_amounts(address)
+                .unwrap()
+                .into_keys()
+                .next()
+                .unwrap();
+            shared
+                .add_tokens_offer(&session_id, address, mint, dec!(1))
+                .unwrap();
+        }
+        shared.accept_trade(&session_id, &alice_address).unwrap();
+        shared.accept_trade(&session_id, &bob_address).unwrap();
+        shared
+            .get_transaction_to_sign(&session_id, &alice_address)
+            .await
+            .unwrap();
+        let message_data = {
+            let sessions = &shared.internal;
+            sessions
+                .get(&session_id)
+                .unwrap()
+                .state
+                .tx
+                .as_ref()
+                .unwrap()
+                .message_data()
+        };
+        for (keypair, address) in [(&alice, &alice_address), (&bob, &bob_address)] {
+            shared
+                .sign_transaction(
+                    &session_id,
+                    address,
+                    keypair.sign_message(&message_data).to_string(),
+                )
+                .unwrap();
+        }
+        SignedTrade {
+            shared,
+            session_id,
+            events,
+            trade_store,
+            received,
         }
     }
 
     #[tokio::test]
-    async fn test_trade_must_be_mutable_only_in_trading_or_oneuseraccepted_status() {
-        let token_amount_cache = Arc::new(TokenAmountCache::init());
-        let user_address1 = String::from("Alice");
-        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
-            TestChainContext {},
-        )));
+    async fn server_paid_trade_should_be_settled_with_the_server_signature() {
+        use solana_sdk::signature::Signer;
+
+        let chain_context: Arc<ScriptedChainContext> = Arc::default();
+        let (server_payer, keypair_path) = server_keypair_file();
+        let SignedTrade {
+            session_id,
+            mut events,
+            ..
+        } = signed_trade_with_config(
+            Arc::clone(&chain_context),
+            TransactionConfig {
+                fee_payer: FeePayerPolicy::Server { keypair_path },
+                ..TransactionConfig::default()
+            },
+            Default::default(),
+        )
+        .await;
+
+        assert_eq!(
+            settled(&mut events, session_id).await,
+            TradeStatus::Completed
+        );
+        let sent = chain_context.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].message.account_keys[0], server_payer.pubkey());
+        assert!(sent[0].verify().is_ok());
+    }
 
-        token_amount_cache.insert_token_amounts(
-            user_address1.clone(),
-            HashMap::from([("TokenA".to_string(), dec!(0.6))]),
+    #[tokio::test(start_paused = true)]
+    async fn failed_settlement_should_be_retried() {
+        let chain_context: Arc<ScriptedChainContext> = Arc::default();
+        let SignedTrade {
+            shared,
+            session_id,
+            mut events,
+            trade_store,
+            ..
+        } = signed_trade(Arc::clone(&chain_context), Default::default()).await;
+        // The settlement task didn't get to run yet
+        *trade_store.failing_finalizations.lock().unwrap() = 1;
+
+        let status = tokio::time::timeout(Duration::from_secs(60), async {
+            loop {
+                if let Ok(LifecycleEvent::Completed { status, .. }) = events.recv().await {
+                    return status;
+                }
+            }
+        })
+        .await
+        .expect("trade never settled");
+        assert_eq!(status, TradeStatus::Completed);
+        assert_eq!(*trade_store.failing_finalizations.lock().unwrap(), 0);
+        assert_eq!(
+            trade_store.get_trade(&session_id).unwrap().unwrap().status,
+            "Completed"
         );
-        let shared = SharedSessions::new(token_amount_cache, transaction_service);
-        let session_id = Uuid::new_v4();
-        let connection_id = Uuid::new_v4();
+        // Sent once, the retry only awaited it
+        assert_eq!(chain_context.sent().len(), 1);
+        assert!(shared.unsettled_sessions().is_empty());
+    }
 
-        let (tx, _rx) = mpsc::channel(10);
-        shared.add_client(session_id, connection_id, tx);
+    #[tokio::test]
+    async fn transaction_failing_on_chain_should_fail_the_trade() {
+        use crate::chain_context::ScriptedChainContext;
+
+        let chain_context: Arc<ScriptedChainContext> =
+            Arc::new(ScriptedChainContext::with_statuses(vec![Some(Err(
+                "custom program error: 0x1".to_string(),
+            ))]));
+        let SignedTrade {
+            session_id,
+            mut events,
+            trade_store,
+            ..
+        } = signed_trade(Arc::clone(&chain_context), Default::default()).await;
+
+        assert_eq!(settled(&mut events, session_id).await, TradeStatus::Failed);
+        assert_eq!(chain_context.sent().len(), 1);
+        let trade = trade_store.get_trade(&session_id).unwrap().unwrap();
+        assert_eq!(trade.status, "Failed");
+        assert_eq!(
+            trade.status_details.unwrap()["failure_reason"],
+            "custom program error: 0x1"
+        );
+    }
 
-        // Add tokens for user "Alice"
-        let result = shared.add_tokens_offer(
-            &session_id,
-            &user_address1,
-            "TokenA".to_string(),
-            dec!(0.1001),
+    #[tokio::test]
+    async fn unseen_transaction_should_be_resubmitted_until_it_lands() {
+        use crate::chain_context::ScriptedChainContext;
+        use crate::config::ConfirmationConfig;
+
+        let chain_context: Arc<ScriptedChainContext> =
+            Arc::new(ScriptedChainContext::with_statuses(vec![
+                None,
+                None,
+                Some(Ok(())),
+            ]));
+        let confirmation = ConfirmationConfig {
+            poll_interval_ms: 0,
+            resubmit_after_ms: 0,
+            ..ConfirmationConfig::default()
+        };
+        let SignedTrade {
+            session_id,
+            mut events,
+            ..
+        } = signed_trade(Arc::clone(&chain_context), confirmation).await;
+
+        assert_eq!(
+            settled(&mut events, session_id).await,
+            TradeStatus::Completed
         );
-        assert!(result.is_ok());
+        let sent = chain_context.sent();
+        assert_eq!(sent.len(), 3);
+        assert!(sent.iter().all(|tx| *tx == sent[0]));
+    }
 
-        // states that allow mutability
-        for trade_status in vec![TradeStatus::Trading, TradeStatus::OneUserAccepted] {
-            //change trade status
-            {
-                let mut sessions = shared.internal.lock().unwrap();
-                let session = sessions.get_mut(&session_id).expect("Session not found");
-                session.state.status = trade_status;
-            }
+    #[tokio::test]
+    async fn transaction_expired_while_signing_should_be_rebuilt_instead_of_sent() {
+        use crate::chain_context::TEST_LAST_VALID_BLOCK_HEIGHT;
+
+        let chain_context: Arc<ScriptedChainContext> = Arc::new(ScriptedChainContext {
+            block_height: Some(TEST_LAST_VALID_BLOCK_HEIGHT + 1),
+            ..ScriptedChainContext::default()
+        });
+        let SignedTrade {
+            shared,
+            session_id,
+            mut received,
+            ..
+        } = signed_trade(Arc::clone(&chain_context), Default::default()).await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !matches!(
+                received.recv().await,
+                Some(WebsocketMessage::ResignRequired { .. })
+            ) {}
+        })
+        .await
+        .expect("no ResignRequired received");
+        assert!(chain_context.sent().is_empty());
+        let sessions = &shared.internal;
+        let session = sessions.get(&session_id).unwrap();
+        assert_eq!(session.state.status, TradeStatus::TransactionCreated);
+        assert!(!session.settling);
+        let tx = session.state.tx.as_ref().unwrap();
+        assert!(tx
+            .signatures
+            .iter()
+            .all(|signature| *signature == Signature::default()));
+    }
 
-            let result = shared.add_tokens_offer(
-                &session_id,
-                &user_address1,
-                "TokenA".to_string(),
-                dec!(0.1001),
-            );
-            assert!(result.is_ok());
+    #[tokio::test]
+    async fn split_trade_should_be_settled_one_confirmed_transaction_after_the_other() {
+        use crate::config::ConfirmationConfig;
+        use solana_sdk::signature::{Keypair, Signer};
 
-            let result = shared.withdraw_tokens(
-                &session_id,
-                &user_address1,
-                "TokenA".to_string(),
-                dec!(0.0501),
+        let alice = Keypair::new();
+        let bob = Keypair::new();
+        let (alice_address, bob_address) = (alice.pubkey().to_string(), bob.pubkey().to_string());
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        for address in [&alice_address, &bob_address] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                address.clone(),
+                HashMap::from([(Pubkey::new_unique().to_string(), dec!(5))]),
+                TEST_MINT_DECIMALS,
             );
-            assert!(result.is_ok());
         }
-
-        // states that should not allow changing token offers
-        for trade_status in vec![
-            TradeStatus::Accepted,
-            TradeStatus::TransactionCreated,
-            TradeStatus::OneUserSigned,
-            TradeStatus::TransactionSent,
-        ] {
-            //change trade status
-            {
-                let mut sessions = shared.internal.lock().unwrap();
-                let session = sessions.get_mut(&session_id).expect("Session not found");
-                session.state.status = trade_status;
+        let chain_context: Arc<ScriptedChainContext> = Arc::default();
+        let shared = Arc::new(SharedSessions::new(
+            Arc::clone(&token_amount_cache),
+            Arc::new(TransactionService::with_config(
+                Arc::clone(&chain_context),
+                TransactionConfig {
+                    max_transfers_per_transaction: Some(1),
+                    ..Default::default()
+                },
+            )),
+        ));
+        let mut events = shared.subscribe();
+        spawn_settlement_task(
+            Arc::clone(&shared),
+            ConfirmationConfig {
+                poll_interval_ms: 0,
+                ..ConfirmationConfig::default()
+            },
+            CancellationToken::new(),
+        );
+        let session_id = Uuid::new_v4();
+        let (client, mut received) = mpsc::channel(100);
+        shared.add_client(session_id, Uuid::new_v4(), client);
+        for address in [&alice_address, &bob_address] {
+            let mint = token_amount_cache
+                .get_token_amounts(address)
+                .unwrap()
+                .into_keys()
+                .next()
+                .unwrap();
+            shared
+                .add_tokens_offer(&session_id, address, mint, dec!(1))
+                .unwrap();
+        }
+        shared.accept_trade(&session_id, &alice_address).unwrap();
+        shared.accept_trade(&session_id, &bob_address).unwrap();
+        shared
+            .get_transaction_to_sign(&session_id, &alice_address)
+            .await
+            .unwrap();
+        let sign_current = || {
+            let tx = {
+                let sessions = &shared.internal;
+                sessions.get(&session_id).unwrap().state.tx.clone().unwrap()
+            };
+            let signers =
+                &tx.message.account_keys[..usize::from(tx.message.header.num_required_signatures)];
+            for (keypair, address) in [(&alice, &alice_address), (&bob, &bob_address)] {
+                if signers.contains(&keypair.pubkey()) {
+                    let signature = keypair.sign_message(&tx.message_data()).to_string();
+                    shared
+                        .sign_transaction(&session_id, address, signature)
+                        .unwrap();
+                }
             }
+        };
+        assert_eq!(
+            shared.transaction_batch(&session_id),
+            Some(TransactionBatch { index: 0, total: 2 })
+        );
 
-            let result = shared.add_tokens_offer(
-                &session_id,
-                &user_address1,
-                "TokenA".to_string(),
-                dec!(0.1001),
-            );
-            assert!(result.is_err());
+        sign_current();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !matches!(
+                received.recv().await,
+                Some(WebsocketMessage::ResignRequired { reason }) if reason == "Transaction 1 of 2 landed, sign the next one"
+            ) {}
+        })
+        .await
+        .expect("never moved on to the second transaction");
+        assert_eq!(chain_context.sent().len(), 1);
+        assert_eq!(
+            shared.transaction_batch(&session_id),
+            Some(TransactionBatch { index: 1, total: 2 })
+        );
 
-            let result = shared.withdraw_tokens(
-                &session_id,
-                &user_address1,
-                "TokenA".to_string(),
-                dec!(0.0801),
-            );
-            assert!(result.is_err());
-        }
+        sign_current();
+        assert_eq!(
+            settled(&mut events, session_id).await,
+            TradeStatus::Completed
+        );
+        let sent = chain_context.sent();
+        assert_eq!(sent.len(), 2);
+        assert_ne!(sent[0], sent[1]);
     }
 
     #[tokio::test]
-    async fn test_second_user_accepts() {
+    async fn only_signatures_over_the_built_transaction_should_be_accepted() {
+        use solana_sdk::signature::{Keypair, Signer};
+
+        let alice = Keypair::new();
+        let bob = Keypair::new();
+        let (alice_address, bob_address) = (alice.pubkey().to_string(), bob.pubkey().to_string());
+        let alice_mint = Pubkey::new_unique().to_string();
+        let bob_mint = Pubkey::new_unique().to_string();
         let token_amount_cache = Arc::new(TokenAmountCache::init());
-        let user_address1 = String::from("Alice");
-        let user_address2 = String::from("Bob");
-        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
-            TestChainContext {},
-        )));
-
-        token_amount_cache.insert_token_amounts(
-            user_address1.clone(),
-            HashMap::from([("TokenA".to_string(), dec!(0.6))]),
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice_address.clone(),
+            HashMap::from([(alice_mint.clone(), dec!(5))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            bob_address.clone(),
+            HashMap::from([(bob_mint.clone(), dec!(5))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
         );
-        let shared = SharedSessions::new(token_amount_cache, transaction_service);
         let session_id = Uuid::new_v4();
-        let connection_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(100).0);
+        shared
+            .add_tokens_offer(&session_id, &alice_address, alice_mint, dec!(1))
+            .unwrap();
+        assert!(shared
+            .sign_transaction(
+                &session_id,
+                &alice_address,
+                Signature::default().to_string()
+            )
+            .is_err());
+        shared
+            .add_tokens_offer(&session_id, &bob_address, bob_mint, dec!(1))
+            .unwrap();
+        shared.accept_trade(&session_id, &alice_address).unwrap();
+        shared.accept_trade(&session_id, &bob_address).unwrap();
+        shared
+            .get_transaction_to_sign(&session_id, &alice_address)
+            .await
+            .unwrap();
+        let message_data = {
+            let sessions = &shared.internal;
+            sessions
+                .get(&session_id)
+                .unwrap()
+                .state
+                .tx
+                .as_ref()
+                .unwrap()
+                .message_data()
+        };
 
-        let (tx, _rx) = mpsc::channel(10);
-        shared.add_client(session_id, connection_id, tx);
+        let mut other_message = message_data.clone();
+        other_message[0] ^= 1;
+        let error = shared
+            .sign_transaction(
+                &session_id,
+                &alice_address,
+                alice.sign_message(&other_message).to_string(),
+            )
+            .unwrap_err();
+        assert!(error.to_string().contains("does not match"), "{}", error);
 
-        // Add tokens for user "Alice"
-        let result = shared.add_tokens_offer(
-            &session_id,
-            &user_address1,
-            "TokenA".to_string(),
-            dec!(0.1001),
+        // Bob's key signing as Alice, and a signer outside the transaction
+        let error = shared
+            .sign_transaction(
+                &session_id,
+                &alice_address,
+                bob.sign_message(&message_data).to_string(),
+            )
+            .unwrap_err();
+        assert!(error.to_string().contains("does not match"), "{}", error);
+        let mallory = Keypair::new();
+        let error = shared
+            .sign_transaction(
+                &session_id,
+                &mallory.pubkey().to_string(),
+                mallory.sign_message(&message_data).to_string(),
+            )
+            .unwrap_err();
+        assert!(error.to_string().contains("is not a signer"), "{}", error);
+
+        let signature = alice.sign_message(&message_data);
+        shared
+            .sign_transaction(&session_id, &alice_address, signature.to_string())
+            .unwrap();
+        let sessions = &shared.internal;
+        let session = sessions.get(&session_id).unwrap();
+        assert!(session
+            .state
+            .tx
+            .as_ref()
+            .unwrap()
+            .signatures
+            .contains(&signature));
+        assert!(matches!(
+            session.events.back(),
+            Some(SessionEvent::TransactionSigned { user_address, .. }) if *user_address == alice_address
+        ));
+    }
+
+    #[tokio::test]
+    async fn signatures_should_advance_status_until_transaction_is_sent() {
+        use solana_sdk::signature::{Keypair, Signer};
+
+        let alice = Keypair::new();
+        let bob = Keypair::new();
+        let (alice_address, bob_address) = (alice.pubkey().to_string(), bob.pubkey().to_string());
+        let alice_mint = Pubkey::new_unique().to_string();
+        let bob_mint = Pubkey::new_unique().to_string();
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice_address.clone(),
+            HashMap::from([(alice_mint.clone(), dec!(5))]),
+            TEST_MINT_DECIMALS,
         );
-        assert!(result.is_ok());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            bob_address.clone(),
+            HashMap::from([(bob_mint.clone(), dec!(5))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+        );
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(100).0);
+        shared
+            .add_tokens_offer(&session_id, &alice_address, alice_mint, dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, &bob_address, bob_mint, dec!(1))
+            .unwrap();
+        shared.accept_trade(&session_id, &alice_address).unwrap();
+        shared.accept_trade(&session_id, &bob_address).unwrap();
+        shared
+            .get_transaction_to_sign(&session_id, &alice_address)
+            .await
+            .unwrap();
+        let message_data = {
+            let sessions = &shared.internal;
+            sessions
+                .get(&session_id)
+                .unwrap()
+                .state
+                .tx
+                .as_ref()
+                .unwrap()
+                .message_data()
+        };
+        let status = |shared: &SharedSessions<TestChainContext>| {
+            let sessions = &shared.internal;
+            let session = sessions.get(&session_id).unwrap();
+            (session.state.status.clone(), session.events.len())
+        };
 
-        let _ = shared.accept_trade(&session_id, &user_address1);
-        let _ = shared.accept_trade(&session_id, &user_address2);
+        let alice_signature = alice.sign_message(&message_data).to_string();
+        shared
+            .sign_transaction(&session_id, &alice_address, alice_signature.clone())
+            .unwrap();
+        let (signed_once, events) = status(&shared);
+        assert_eq!(signed_once, TradeStatus::OneUserSigned);
+        // The counterparty can still fetch the partially signed transaction
+        assert!(!shared
+            .get_transaction_to_sign(&session_id, &bob_address)
+            .await
+            .unwrap());
+
+        shared
+            .sign_transaction(&session_id, &alice_address, alice_signature.clone())
+            .unwrap();
+        assert_eq!(status(&shared), (TradeStatus::OneUserSigned, events));
+
+        let mallory = Keypair::new();
+        assert!(shared
+            .sign_transaction(
+                &session_id,
+                &mallory.pubkey().to_string(),
+                mallory.sign_message(&message_data).to_string(),
+            )
+            .is_err());
+        assert_eq!(status(&shared), (TradeStatus::OneUserSigned, events));
 
+        shared
+            .sign_transaction(
+                &session_id,
+                &bob_address,
+                bob.sign_message(&message_data).to_string(),
+            )
+            .unwrap();
+        assert_eq!(status(&shared), (TradeStatus::FullySigned, events + 1));
         {
-            let sessions = shared.internal.lock().unwrap();
-            let session = sessions.get(&session_id).expect("Session not found");
-            let alice_tokens = session
+            let sessions = &shared.internal;
+            sessions
+                .get(&session_id)
+                .unwrap()
                 .state
-                .items
-                .get("Alice")
-                .expect("Alice not found in state");
-
-            assert_eq!(session.state.user_acted, None);
-            assert_eq!(session.state.status, TradeStatus::Accepted);
-            assert_eq!(
-                *alice_tokens.get("TokenA").expect("TokenA not found"),
-                dec!(0.1001)
-            );
+                .tx
+                .as_ref()
+                .unwrap()
+                .verify()
+                .unwrap();
         }
+        let error = shared
+            .sign_transaction(&session_id, &alice_address, alice_signature)
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SessionError>(),
+            Some(&SessionError::SessionClosed {
+                status: TradeStatus::FullySigned
+            })
+        );
     }
 
     #[tokio::test]
-    async fn test_accept_trade() {
-        let token_amount_cache = Arc::new(TokenAmountCache::init());
-        let user_address = String::from("Alice");
-        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
-            TestChainContext {},
-        )));
-
-        token_amount_cache.insert_token_amounts(
-            user_address.clone(),
-            HashMap::from([("TokenA".to_string(), dec!(0.6))]),
+    async fn confirmation_should_end_trade_once_as_completed_or_failed() {
+        let confirmed_session = Uuid::new_v4();
+        let failed_session = Uuid::new_v4();
+        let trade_store = Arc::new(InMemoryTradeStore::with_trades(vec![
+            created_trade(confirmed_session),
+            created_trade(failed_session),
+        ]));
+        let shared = SharedSessions::with_stores(
+            Arc::new(TokenAmountCache::init()),
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+            AuditLog::disabled(),
+            Some(Arc::clone(&trade_store) as Arc<dyn TradeStore>),
         );
-        let shared = SharedSessions::new(token_amount_cache, transaction_service);
-        let session_id = Uuid::new_v4();
-        let connection_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(10);
+        shared.add_client(confirmed_session, Uuid::new_v4(), tx);
+        shared.add_client(failed_session, Uuid::new_v4(), mpsc::channel(10).0);
+        for session_id in [confirmed_session, failed_session] {
+            let sessions = &shared.internal;
+            sessions.get_mut(&session_id).unwrap().state.status = TradeStatus::TransactionSent;
+        }
 
-        let (tx, _rx) = mpsc::channel(10);
-        shared.add_client(session_id, connection_id, tx);
+        assert!(shared
+            .finish_trade(&confirmed_session, &ConfirmationOutcome::Confirmed)
+            .await
+            .unwrap());
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(WebsocketMessage::TradeStateUpdate {
+                status: TradeStatus::Completed,
+                ..
+            })
+        ));
+        // A repeated callback neither writes nor broadcasts again
+        assert!(!shared
+            .finish_trade(
+                &confirmed_session,
+                &ConfirmationOutcome::Failed("late".to_string())
+            )
+            .await
+            .unwrap());
+        assert!(rx.try_recv().is_err());
+        let trade = trade_store.get_trade(&confirmed_session).unwrap().unwrap();
+        assert_eq!(trade.status, "Completed");
+        let details = trade.status_details.unwrap();
+        assert_eq!(details.get("signature"), None);
+        assert_eq!(details["session"]["state"]["status"], "Completed");
+
+        assert!(shared
+            .finish_trade(
+                &failed_session,
+                &ConfirmationOutcome::Failed("custom program error".to_string())
+            )
+            .await
+            .unwrap());
+        let trade = trade_store.get_trade(&failed_session).unwrap().unwrap();
+        assert_eq!(trade.status, "Failed");
+        let details = trade.status_details.unwrap();
+        assert_eq!(details["failure_reason"], "custom program error");
+        assert_eq!(details["session"]["state"]["status"], "Failed");
+        let sessions = &shared.internal;
+        assert_eq!(
+            sessions.get(&failed_session).unwrap().state.status,
+            TradeStatus::Failed
+        );
+    }
 
-        // Add tokens for user "Alice"
-        let result = shared.add_tokens_offer(
-            &session_id,
-            &user_address,
-            "TokenA".to_string(),
-            dec!(0.1001),
+    #[tokio::test]
+    async fn finished_session_should_be_dropped_once_nobody_is_connected() {
+        let unwatched_session = Uuid::new_v4();
+        let watched_session = Uuid::new_v4();
+        let trade_store = Arc::new(InMemoryTradeStore::with_trades(vec![
+            created_trade(unwatched_session),
+            created_trade(watched_session),
+        ]));
+        let shared = SharedSessions::with_stores(
+            Arc::new(TokenAmountCache::init()),
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+            AuditLog::disabled(),
+            Some(Arc::clone(&trade_store) as Arc<dyn TradeStore>),
         );
-        assert!(result.is_ok());
+        let unwatched_client = Uuid::new_v4();
+        let watching_client = Uuid::new_v4();
+        shared.add_client(unwatched_session, unwatched_client, mpsc::channel(10).0);
+        shared.add_client(watched_session, watching_client, mpsc::channel(10).0);
+        for session_id in [unwatched_session, watched_session] {
+            let sessions = &shared.internal;
+            sessions.get_mut(&session_id).unwrap().state.status = TradeStatus::TransactionSent;
+        }
+        // Left while the transaction was awaited
+        shared.remove_client(&unwatched_session, &unwatched_client);
+        assert!(shared.internal.contains_key(&unwatched_session));
+
+        for session_id in [unwatched_session, watched_session] {
+            assert!(shared
+                .finish_trade(&session_id, &ConfirmationOutcome::Confirmed)
+                .await
+                .unwrap());
+        }
+        assert_eq!(shared.session_ids(), vec![watched_session]);
+        shared.remove_client(&watched_session, &watching_client);
+        assert!(shared.session_ids().is_empty());
+        // Still served from the trade record
+        assert!(matches!(
+            shared.session_state(&unwatched_session).unwrap(),
+            Some(WebsocketMessage::TradeStateUpdate {
+                status: TradeStatus::Completed,
+                read_only: true,
+                ..
+            })
+        ));
+    }
 
-        shared.accept_trade(&session_id, &user_address);
+    #[test]
+    fn idle_accepted_session_should_be_reaped() {
+        use crate::config::{SessionsConfig, TunableConfig};
 
-        {
-            let sessions = shared.internal.lock().unwrap();
-            let session = sessions.get(&session_id).expect("Session not found");
-            let alice_tokens = session
-                .state
-                .items
-                .get("Alice")
-                .expect("Alice not found in state");
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Bob".to_string(),
+            HashMap::from([("TokenB".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::with_config(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+            AuditLog::disabled(),
+            None,
+            Arc::new(RuntimeConfig::new(TunableConfig {
+                sessions: SessionsConfig {
+                    idle_timeout_secs: Some(1),
+                    ..SessionsConfig::default()
+                },
+                ..TunableConfig::default()
+            })),
+        );
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Bob", "TokenB".to_string(), dec!(1))
+            .unwrap();
+        shared.accept_trade(&session_id, "Alice").unwrap();
+        shared.accept_trade(&session_id, "Bob").unwrap();
+        assert_eq!(
+            shared.internal.get(&session_id).unwrap().state.status,
+            TradeStatus::Accepted
+        );
 
-            assert_eq!(session.state.user_acted, Some(user_address));
-            assert_eq!(session.state.status, TradeStatus::OneUserAccepted);
-            assert_eq!(
-                *alice_tokens.get("TokenA").expect("TokenA not found"),
-                dec!(0.1001)
-            );
+        let later = Instant::now() + Duration::from_secs(1);
+        assert_eq!(shared.reap_idle_sessions(later), vec![session_id]);
+        assert!(shared.session_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscriber_should_receive_lifecycle_events_in_order() {
+        use solana_sdk::signature::{Keypair, Signer};
+
+        let alice = Keypair::new();
+        let bob = Keypair::new();
+        let (alice_address, bob_address) = (alice.pubkey().to_string(), bob.pubkey().to_string());
+        let alice_mint = Pubkey::new_unique().to_string();
+        let bob_mint = Pubkey::new_unique().to_string();
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice_address.clone(),
+            HashMap::from([(alice_mint.clone(), dec!(5))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            bob_address.clone(),
+            HashMap::from([(bob_mint.clone(), dec!(5))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = Arc::new(SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+        ));
+        let mut events = shared.subscribe();
+        spawn_settlement_task(
+            Arc::clone(&shared),
+            ConfirmationConfig::default(),
+            CancellationToken::new(),
+        );
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(100).0);
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(100).0);
+        shared
+            .add_tokens_offer(&session_id, &alice_address, alice_mint, dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, &bob_address, bob_mint, dec!(1))
+            .unwrap();
+        shared.accept_trade(&session_id, &alice_address).unwrap();
+        shared.accept_trade(&session_id, &bob_address).unwrap();
+        shared
+            .get_transaction_to_sign(&session_id, &alice_address)
+            .await
+            .unwrap();
+        let message_data = {
+            let sessions = &shared.internal;
+            sessions
+                .get(&session_id)
+                .unwrap()
+                .state
+                .tx
+                .as_ref()
+                .unwrap()
+                .message_data()
+        };
+        for (keypair, address) in [(&alice, &alice_address), (&bob, &bob_address)] {
+            shared
+                .sign_transaction(
+                    &session_id,
+                    address,
+                    keypair.sign_message(&message_data).to_string(),
+                )
+                .unwrap();
+        }
+        let mut received = vec![];
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+                .await
+                .expect("trade never settled")
+                .unwrap();
+            let settled = matches!(event, LifecycleEvent::Completed { .. });
+            received.push(event);
+            if settled {
+                break;
+            }
         }
+        assert_eq!(
+            received,
+            vec![
+                LifecycleEvent::Created { session_id },
+                LifecycleEvent::OfferChanged {
+                    session_id,
+                    user_address: alice_address,
+                },
+                LifecycleEvent::OfferChanged {
+                    session_id,
+                    user_address: bob_address,
+                },
+                LifecycleEvent::Accepted { session_id },
+                LifecycleEvent::FullySigned { session_id },
+                LifecycleEvent::TransactionSent { session_id },
+                LifecycleEvent::Completed {
+                    session_id,
+                    status: TradeStatus::Completed,
+                },
+            ]
+        );
     }
 
     #[tokio::test]
-    async fn test_offering_token_should_revert_accept() {
+    async fn empty_session_should_be_dropped_when_last_client_leaves() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+        );
+        let empty_session = Uuid::new_v4();
+        let offered_session = Uuid::new_v4();
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        shared.add_client(empty_session, first, mpsc::channel(10).0);
+        shared.add_client(empty_session, second, mpsc::channel(10).0);
+        shared.add_client(offered_session, third, mpsc::channel(10).0);
+        shared
+            .add_tokens_offer(&offered_session, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+
+        shared.remove_client(&empty_session, &first);
+        assert!(shared.session_ids().contains(&empty_session));
+        shared.remove_client(&empty_session, &second);
+        assert!(!shared.session_ids().contains(&empty_session));
+
+        shared.remove_client(&offered_session, &third);
+        assert!(shared.session_ids().contains(&offered_session));
+    }
+
+    #[tokio::test]
+    async fn third_user_should_get_session_full_error() {
         let token_amount_cache = Arc::new(TokenAmountCache::init());
-        let user_address = String::from("Alice");
         let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
             TestChainContext {},
         )));
-
-        token_amount_cache.insert_token_amounts(
-            user_address.clone(),
-            HashMap::from([("TokenA".to_string(), dec!(15))]),
-        );
+        for user_address in ["Alice", "Bob", "Carol"] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                user_address.to_string(),
+                HashMap::from([("TokenA".to_string(), dec!(10))]),
+                TEST_MINT_DECIMALS,
+            );
+        }
         let shared = SharedSessions::new(token_amount_cache, transaction_service);
         let session_id = Uuid::new_v4();
-        let connection_id = Uuid::new_v4();
-
         let (tx, _rx) = mpsc::channel(10);
-        shared.add_client(session_id, connection_id, tx);
-
-        // Add tokens for user "Alice"
-        let result = shared.add_tokens_offer(
-            &session_id,
-            &user_address,
-            "TokenA".to_string(),
-            dec!(13.37),
-        );
-        assert!(result.is_ok());
-
-        shared.accept_trade(&session_id, &user_address);
-
-        {
-            let sessions = shared.internal.lock().unwrap();
-            let session = sessions.get(&session_id).expect("Session not found");
-            let alice_tokens = session
-                .state
-                .items
-                .get("Alice")
-                .expect("Alice not found in state");
-
-            assert_eq!(session.state.user_acted, Some(user_address.clone()));
-            assert_eq!(session.state.status, TradeStatus::OneUserAccepted);
-            assert_eq!(
-                *alice_tokens.get("TokenA").expect("TokenA not found"),
-                dec!(13.37)
-            );
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+        for user_address in ["Bob", "Alice"] {
+            shared
+                .add_tokens_offer(&session_id, user_address, "TokenA".to_string(), dec!(1))
+                .unwrap();
         }
 
-        let result =
-            shared.add_tokens_offer(&session_id, &user_address, "TokenA".to_string(), dec!(1.00));
-        assert!(result.is_ok());
-
-        {
-            let sessions = shared.internal.lock().unwrap();
-            let session = sessions.get(&session_id).expect("Session not found");
-            let alice_tokens = session
-                .state
-                .items
-                .get("Alice")
-                .expect("Alice not found in state");
-
-            assert_eq!(session.state.user_acted, None);
-            assert_eq!(session.state.status, TradeStatus::Trading);
-
-            assert_eq!(
-                *alice_tokens.get("TokenA").expect("TokenA not found"),
-                dec!(14.37)
-            );
-        }
+        let error = shared
+            .add_tokens_offer(&session_id, "Carol", "TokenA".to_string(), dec!(1))
+            .unwrap_err();
+        let session_error = error.downcast_ref::<SessionError>().unwrap();
+        assert_eq!(
+            *session_error,
+            SessionError::SessionFull {
+                participants: vec!["Alice".to_string(), "Bob".to_string()]
+            }
+        );
+        assert_eq!(session_error.code(), "session_full");
+        assert_eq!(
+            error.to_string(),
+            "There are already 2 users involved in this trade: Alice, Bob"
+        );
     }
 
     #[tokio::test]
@@ -707,9 +6728,10 @@ mod tests {
             TestChainContext {},
         )));
 
-        token_amount_cache.insert_token_amounts(
+        token_amount_cache.insert_token_amounts_with_decimals(
             user_address.clone(),
             HashMap::from([("TokenA".to_string(), dec!(14))]),
+            TEST_MINT_DECIMALS,
         );
         let shared = SharedSessions::new(token_amount_cache, transaction_service);
         let session_id = Uuid::new_v4();
@@ -727,15 +6749,15 @@ mod tests {
         );
         assert!(result.is_ok());
 
-        shared.accept_trade(&session_id, &user_address);
+        let _ = shared.accept_trade(&session_id, &user_address);
 
         {
-            let sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let session = sessions.get(&session_id).expect("Session not found");
             let alice_tokens = session
                 .state
-                .items
-                .get("Alice")
+                .ui_items()
+                .remove("Alice")
                 .expect("Alice not found in state");
 
             assert_eq!(session.state.user_acted, Some(user_address.clone()));
@@ -751,12 +6773,12 @@ mod tests {
         assert!(result.is_ok());
 
         {
-            let sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let session = sessions.get(&session_id).expect("Session not found");
             let alice_tokens = session
                 .state
-                .items
-                .get("Alice")
+                .ui_items()
+                .remove("Alice")
                 .expect("Alice not found in state");
 
             assert_eq!(session.state.user_acted, None);
@@ -776,9 +6798,10 @@ mod tests {
             TestChainContext {},
         )));
 
-        token_amount_cache.insert_token_amounts(
+        token_amount_cache.insert_token_amounts_with_decimals(
             user_address.clone(),
             HashMap::from([("TokenA".to_string(), dec!(0.6))]),
+            TEST_MINT_DECIMALS,
         );
         let shared = SharedSessions::new(token_amount_cache, transaction_service);
         let session_id = Uuid::new_v4();
@@ -796,15 +6819,15 @@ mod tests {
         );
         assert!(result.is_ok());
 
-        shared.accept_trade(&session_id, &user_address);
+        let _ = shared.accept_trade(&session_id, &user_address);
 
         {
-            let sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let session = sessions.get(&session_id).expect("Session not found");
             let alice_tokens = session
                 .state
-                .items
-                .get("Alice")
+                .ui_items()
+                .remove("Alice")
                 .expect("Alice not found in state");
 
             assert_eq!(session.state.user_acted, Some(user_address));
@@ -828,7 +6851,7 @@ mod tests {
         let (tx, _rx) = mpsc::channel(10);
         shared.add_client(session_id, connection_id, tx);
 
-        let sessions = shared.internal.lock().unwrap();
+        let sessions = &shared.internal;
         let session = sessions.get(&session_id).expect("Session not found");
         assert!(session.ws_clients.contains_key(&connection_id));
     }
@@ -845,11 +6868,13 @@ mod tests {
 
         let (tx, _rx) = mpsc::channel(10);
         shared.add_client(session_id, connection_id, tx);
+        // A second client keeps the otherwise empty session alive
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
 
         // Remove the client
         shared.remove_client(&session_id, &connection_id);
 
-        let sessions = shared.internal.lock().unwrap();
+        let sessions = &shared.internal;
         let session = sessions.get(&session_id).expect("Session not found");
         assert!(!session.ws_clients.contains_key(&connection_id));
     }
@@ -883,12 +6908,26 @@ mod tests {
                     user_acted: _,
                     status: _,
                     tx: _,
+                    memos: _,
+                    fee_payer: _,
+                    rounding: _,
+                    roles: _,
+                    read_only: _,
+                    up_to: _,
+                    seq: _,
                 },
                 WebsocketMessage::TradeStateUpdate {
                     offers: _,
                     user_acted: _,
                     status: _,
                     tx: _,
+                    memos: _,
+                    fee_payer: _,
+                    rounding: _,
+                    roles: _,
+                    read_only: _,
+                    up_to: _,
+                    seq: _,
                 },
             ) => {
                 // Just ensuring that both got the correct variant
@@ -904,9 +6943,15 @@ mod tests {
         )));
         let token_amount_cache = Arc::new(TokenAmountCache::init());
         let user_address = String::from("Alice");
-        token_amount_cache.insert_token_amounts(
+        token_amount_cache.insert_token_amounts_with_decimals(
             user_address.clone(),
             HashMap::from([("TokenA".to_string(), dec!(0.6))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Bob".to_string(),
+            HashMap::from([("TokenB".to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
         );
         let shared = SharedSessions::new(token_amount_cache, transaction_service);
         let session_id = Uuid::new_v4();
@@ -925,12 +6970,12 @@ mod tests {
         assert!(result.is_ok());
 
         {
-            let sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let session = sessions.get(&session_id).expect("Session not found");
             let alice_tokens = session
                 .state
-                .items
-                .get(&user_address)
+                .ui_items()
+                .remove(&user_address)
                 .expect("Alice not found in state");
             assert_eq!(
                 *alice_tokens.get("TokenA").expect("TokenA not found"),
@@ -947,12 +6992,12 @@ mod tests {
         assert!(result.is_ok());
 
         {
-            let sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let session = sessions.get(&session_id).expect("Session not found");
             let updated_alice_tokens = session
                 .state
-                .items
-                .get(&user_address)
+                .ui_items()
+                .remove(&user_address)
                 .expect("Alice not found in state");
             assert_eq!(
                 *updated_alice_tokens
@@ -971,6 +7016,510 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn withdrawn_mints_should_not_keep_their_decimals() {
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([
+                ("TokenA".to_string(), dec!(10)),
+                ("TokenB".to_string(), dec!(10)),
+            ]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+        );
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        for mint in ["TokenA", "TokenB"] {
+            shared
+                .add_tokens_offer(&session_id, "Alice", mint.to_string(), dec!(2))
+                .unwrap();
+        }
+
+        shared
+            .withdraw_tokens(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared
+            .withdraw_tokens(&session_id, "Alice", "TokenB".to_string(), dec!(2))
+            .unwrap();
+
+        let sessions = &shared.internal;
+        assert_eq!(
+            sessions.get(&session_id).unwrap().state.mint_decimals,
+            HashMap::from([("TokenA".to_string(), TEST_MINT_DECIMALS)])
+        );
+    }
+
+    #[tokio::test]
+    async fn fractional_nft_offer_should_be_rejected() {
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts(
+            "Alice".to_owned(),
+            HashMap::from([
+                ("Nft".to_string(), dec!(1)),
+                ("TokenA".to_string(), dec!(10)),
+            ]),
+        );
+        assert!(token_amount_cache.insert_mint_decimals("Nft".to_string(), 0));
+        assert!(token_amount_cache.insert_mint_decimals("TokenA".to_string(), 2));
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+
+        let error = shared
+            .add_tokens_offer(&session_id, "Alice", "Nft".to_string(), dec!(0.5))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Nft can only be offered in whole units");
+        assert!(shared
+            .validate_offer(&session_id, "Alice", "Nft", dec!(0.5))
+            .is_err());
+        assert!(shared
+            .validate_offer(&session_id, "Alice", "TokenA", dec!(0.001))
+            .is_err());
+        assert_eq!(
+            shared
+                .validate_offer(&session_id, "Alice", "TokenA", dec!(0.010))
+                .unwrap(),
+            dec!(0.01)
+        );
+
+        shared
+            .add_tokens_offer(&session_id, "Alice", "Nft".to_string(), dec!(1.0))
+            .unwrap();
+        assert_eq!(
+            shared.get_offers(&session_id, "Alice").unwrap(),
+            HashMap::from([("Nft".to_string(), dec!(1))])
+        );
+    }
+
+    #[tokio::test]
+    async fn offers_should_be_kept_in_base_units() {
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_owned(),
+            HashMap::from([("TokenA".to_string(), dec!(1))]),
+            TEST_MINT_DECIMALS,
+        );
+        token_amount_cache.insert_token_amounts(
+            "Bob".to_owned(),
+            HashMap::from([("TokenB".to_string(), dec!(1))]),
+        );
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+
+        for _ in 0..3 {
+            shared
+                .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(0.1))
+                .unwrap();
+        }
+        shared
+            .withdraw_tokens(&session_id, "Alice", "TokenA".to_string(), dec!(0.2))
+            .unwrap();
+        {
+            let sessions = &shared.internal;
+            assert_eq!(
+                sessions.get(&session_id).unwrap().state.items["Alice"]["TokenA"],
+                100_000
+            );
+        }
+        assert_eq!(
+            shared.get_offers(&session_id, "Alice").unwrap(),
+            HashMap::from([("TokenA".to_string(), dec!(0.1))])
+        );
+        assert_eq!(
+            shared
+                .withdraw_tokens(&session_id, "Alice", "TokenA".to_string(), dec!(0.0000001))
+                .unwrap_err()
+                .to_string(),
+            "0.0000001 of TokenA rounds to 0 at 6 decimals"
+        );
+
+        assert_eq!(
+            shared
+                .add_tokens_offer(&session_id, "Bob", "TokenB".to_string(), dec!(1))
+                .unwrap_err()
+                .to_string(),
+            "Decimals of TokenB are unknown, fetch the wallet's tokens again"
+        );
+    }
+
+    #[tokio::test]
+    async fn same_mint_on_both_sides_should_be_refused_when_disallowed() {
+        use crate::config::{SessionsConfig, TunableConfig};
+
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        for user_address in ["Alice", "Bob"] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                user_address.to_string(),
+                HashMap::from([
+                    ("TokenA".to_string(), dec!(10)),
+                    ("TokenB".to_string(), dec!(10)),
+                ]),
+                TEST_MINT_DECIMALS,
+            );
+        }
+        let shared = SharedSessions::with_config(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+            AuditLog::disabled(),
+            None,
+            Arc::new(RuntimeConfig::new(TunableConfig {
+                sessions: SessionsConfig {
+                    allow_same_mint_both_sides: false,
+                    ..SessionsConfig::default()
+                },
+                ..TunableConfig::default()
+            })),
+        );
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Bob", "TokenB".to_string(), dec!(1))
+            .unwrap();
+
+        let error = shared
+            .add_tokens_offer(&session_id, "Bob", "TokenA".to_string(), dec!(1))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "TokenA is already offered by the counterparty, the same mint can't be traded both ways"
+        );
+        assert!(shared
+            .validate_offer(&session_id, "Alice", "TokenB", dec!(1))
+            .is_err());
+        // Topping up an own offer stays possible
+        shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap();
+
+        // Once withdrawn, the mint is free to be offered by the other side
+        shared
+            .withdraw_tokens(&session_id, "Alice", "TokenA".to_string(), dec!(2))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Bob", "TokenA".to_string(), dec!(1))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn mint_beyond_the_per_user_cap_should_be_refused() {
+        use crate::config::{SessionsConfig, TunableConfig};
+
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        let mints: Vec<String> = (0..4).map(|i| format!("Token{}", i)).collect();
+        for user_address in ["Alice", "Bob"] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                user_address.to_string(),
+                mints.iter().map(|mint| (mint.clone(), dec!(10))).collect(),
+                TEST_MINT_DECIMALS,
+            );
+        }
+        let shared = SharedSessions::with_config(
+            token_amount_cache,
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+            AuditLog::disabled(),
+            None,
+            Arc::new(RuntimeConfig::new(TunableConfig {
+                sessions: SessionsConfig {
+                    max_mints_per_user: 3,
+                    ..SessionsConfig::default()
+                },
+                ..TunableConfig::default()
+            })),
+        );
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        for mint in &mints[..3] {
+            shared
+                .add_tokens_offer(&session_id, "Alice", mint.clone(), dec!(1))
+                .unwrap();
+        }
+
+        let error = shared
+            .add_tokens_offer(&session_id, "Alice", mints[3].clone(), dec!(1))
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SessionError>(),
+            Some(&SessionError::TooManyMints {
+                user_address: "Alice".to_string(),
+                max_mints: 3
+            })
+        );
+        assert!(shared
+            .validate_offer(&session_id, "Alice", &mints[3], dec!(1))
+            .is_err());
+        shared
+            .add_tokens_offer(&session_id, "Alice", mints[0].clone(), dec!(2))
+            .unwrap();
+        // The cap is per participant
+        shared
+            .add_tokens_offer(&session_id, "Bob", mints[3].clone(), dec!(1))
+            .unwrap();
+        let offers = shared.get_offers(&session_id, "Alice").unwrap();
+        assert_eq!(offers.len(), 3);
+        assert_eq!(offers[&mints[0]], dec!(3));
+
+        // Withdrawing a mint frees its slot
+        shared
+            .withdraw_tokens(&session_id, "Alice", mints[1].clone(), dec!(1))
+            .unwrap();
+        shared
+            .add_tokens_offer(&session_id, "Alice", mints[3].clone(), dec!(1))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn over_precise_offer_should_follow_rounding_policy() {
+        use crate::config::{SessionsConfig, TunableConfig};
+
+        let shared_with = |offer_rounding: OfferRounding| {
+            let token_amount_cache = Arc::new(TokenAmountCache::init());
+            token_amount_cache.insert_token_amounts_with_decimals(
+                "Alice".to_string(),
+                HashMap::from([("TokenA".to_string(), dec!(10))]),
+                TEST_MINT_DECIMALS,
+            );
+            SharedSessions::with_config(
+                token_amount_cache,
+                Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                    TestChainContext {},
+                ))),
+                AuditLog::disabled(),
+                None,
+                Arc::new(RuntimeConfig::new(TunableConfig {
+                    sessions: SessionsConfig {
+                        offer_rounding,
+                        ..SessionsConfig::default()
+                    },
+                    ..TunableConfig::default()
+                })),
+            )
+        };
+        let over_precise = dec!(1.2345675);
+
+        let shared = shared_with(OfferRounding::Reject);
+        let session_id = Uuid::new_v4();
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        assert_eq!(
+            shared
+                .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), over_precise)
+                .unwrap_err()
+                .to_string(),
+            "TokenA has at most 6 decimals"
+        );
+
+        for (offer_rounding, applied) in [
+            (OfferRounding::Floor, dec!(1.234567)),
+            (OfferRounding::HalfUp, dec!(1.234568)),
+        ] {
+            let shared = shared_with(offer_rounding);
+            let session_id = Uuid::new_v4();
+            let (tx, mut rx) = mpsc::channel(10);
+            shared.add_client(session_id, Uuid::new_v4(), tx);
+            shared
+                .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), over_precise)
+                .unwrap();
+            assert_eq!(
+                shared.get_offers(&session_id, "Alice").unwrap(),
+                HashMap::from([("TokenA".to_string(), applied)])
+            );
+
+            shared.broadcast_current_state(&session_id);
+            match rx.recv().await {
+                Some(WebsocketMessage::TradeStateUpdate { rounding, .. }) => assert_eq!(
+                    rounding,
+                    Some(Box::new(AppliedRounding {
+                        user_address: "Alice".to_string(),
+                        token_mint: "TokenA".to_string(),
+                        requested: over_precise,
+                        applied,
+                    }))
+                ),
+                other => panic!("Expected a TradeStateUpdate, got {:?}", other),
+            }
+
+            // An exact offer clears the rounding
+            shared
+                .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+                .unwrap();
+            shared.broadcast_current_state(&session_id);
+            match rx.recv().await {
+                Some(WebsocketMessage::TradeStateUpdate { rounding, .. }) => {
+                    assert_eq!(rounding, None)
+                }
+                other => panic!("Expected a TradeStateUpdate, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn keepalives_should_keep_idle_session_from_being_reaped() {
+        use crate::config::{SessionsConfig, TunableConfig};
+
+        let shared = SharedSessions::with_config(
+            Arc::new(TokenAmountCache::init()),
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+            AuditLog::disabled(),
+            None,
+            Arc::new(RuntimeConfig::new(TunableConfig {
+                sessions: SessionsConfig {
+                    idle_timeout_secs: Some(60),
+                    keepalive_min_interval_secs: 10,
+                    ..SessionsConfig::default()
+                },
+                ..TunableConfig::default()
+            })),
+        );
+        let kept_alive = Uuid::new_v4();
+        let idle = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(10);
+        shared.add_client(kept_alive, Uuid::new_v4(), tx);
+        shared.add_client(idle, Uuid::new_v4(), mpsc::channel(10).0);
+        let start = Instant::now();
+
+        for secs in [40, 80] {
+            assert!(shared.keep_alive(&kept_alive, start + Duration::from_secs(secs)));
+            // Too soon after the last one, doesn't count
+            assert!(!shared.keep_alive(&kept_alive, start + Duration::from_secs(secs + 5)));
+        }
+        assert_eq!(
+            shared.reap_idle_sessions(start + Duration::from_secs(100)),
+            vec![idle]
+        );
+        assert_eq!(shared.session_ids(), vec![kept_alive]);
+
+        assert_eq!(
+            shared.reap_idle_sessions(start + Duration::from_secs(140)),
+            vec![kept_alive]
+        );
+        assert!(shared.session_ids().is_empty());
+        match rx.recv().await {
+            Some(WebsocketMessage::SessionExpired { reason }) => {
+                assert_eq!(reason, "Session closed after 60 seconds without activity")
+            }
+            other => panic!("Expected SessionExpired, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn swept_session_should_be_expired_in_trade_record() {
+        use crate::config::{SessionsConfig, TunableConfig};
+
+        let idle = Uuid::new_v4();
+        let active = Uuid::new_v4();
+        let trade_store = Arc::new(InMemoryTradeStore::with_trades(vec![
+            created_trade(idle),
+            created_trade(active),
+        ]));
+        let shared = Arc::new(SharedSessions::with_config(
+            Arc::new(TokenAmountCache::init()),
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+            AuditLog::disabled(),
+            Some(Arc::clone(&trade_store) as Arc<dyn TradeStore>),
+            Arc::new(RuntimeConfig::new(TunableConfig {
+                sessions: SessionsConfig {
+                    idle_timeout_secs: Some(1),
+                    ..SessionsConfig::default()
+                },
+                ..TunableConfig::default()
+            })),
+        ));
+        spawn_session_writer(Arc::clone(&shared), CancellationToken::new());
+        let (tx, mut rx) = mpsc::channel(10);
+        shared.add_client(idle, Uuid::new_v4(), tx);
+        let start = Instant::now();
+        shared.add_client(active, Uuid::new_v4(), mpsc::channel(10).0);
+        shared.keep_alive(&active, start + Duration::from_secs(1));
+
+        assert_eq!(
+            shared.reap_idle_sessions(start + Duration::from_secs(1)),
+            vec![idle]
+        );
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(WebsocketMessage::SessionExpired { .. })
+        ));
+        shared.flushed().await;
+        let status = |trade_id| trade_store.get_trade(&trade_id).unwrap().unwrap().status;
+        assert_eq!(status(idle), "Expired");
+        assert_eq!(status(active), "Created");
+    }
+
+    #[tokio::test]
+    async fn validate_offer_should_return_clamped_amount_without_mutating_state() {
+        let user_address = "Alice";
+        let token_mint = "TokenA";
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            user_address.to_owned(),
+            HashMap::from([(token_mint.to_string(), dec!(10))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared = SharedSessions::new(token_amount_cache, transaction_service);
+        let session_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(10);
+        shared.add_client(session_id, Uuid::new_v4(), tx);
+
+        let result =
+            shared.add_tokens_offer(&session_id, user_address, token_mint.to_string(), dec!(4));
+        assert!(result.is_ok());
+
+        let validated = shared
+            .validate_offer(&session_id, user_address, token_mint, dec!(20))
+            .unwrap();
+        assert_eq!(validated, dec!(10));
+
+        {
+            let sessions = &shared.internal;
+            let session = sessions.get(&session_id).expect("Session not found");
+            let alice_tokens = session
+                .state
+                .ui_items()
+                .remove(user_address)
+                .expect("Alice not found in state");
+            assert_eq!(*alice_tokens.get(token_mint).unwrap(), dec!(4));
+        }
+
+        let _ = shared.add_tokens_offer(&session_id, "Bob", "TokenB".to_string(), dec!(1));
+        let result = shared.validate_offer(&session_id, "Charlie", "TokenC", dec!(1));
+        assert!(result.is_err());
+        assert!(!shared
+            .internal
+            .get(&session_id)
+            .unwrap()
+            .state
+            .items
+            .contains_key("Charlie"));
+    }
+
     #[tokio::test]
     async fn test_withdraw_tokens() {
         let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
@@ -982,17 +7531,19 @@ mod tests {
         let user_address = String::from("Alice");
         // Create a session with some tokens
         {
-            let mut sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let mut session = TradeSession::default();
             let mut map = HashMap::new();
-            map.insert("TokenA".to_string(), dec!(100));
+            map.insert("TokenA".to_string(), 100_000_000);
             let mut user_map = HashMap::new();
             user_map.insert("Alice".to_string(), map);
             session.state = TradeState {
                 items: Arc::new(user_map),
+                mint_decimals: HashMap::from([("TokenA".to_string(), TEST_MINT_DECIMALS)]),
                 user_acted: None,
                 status: TradeStatus::Trading,
                 tx: None,
+                ..Default::default()
             };
             sessions.insert(session_id, session);
         }
@@ -1003,9 +7554,13 @@ mod tests {
         assert!(result.is_ok());
 
         {
-            let sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let session = sessions.get(&session_id).expect("Session not found");
-            let alice_tokens = session.state.items.get("Alice").expect("Alice not found");
+            let alice_tokens = session
+                .state
+                .ui_items()
+                .remove("Alice")
+                .expect("Alice not found");
             let token_a_amount = alice_tokens.get("TokenA").expect("TokenA not found");
             assert_eq!(*token_a_amount, dec!(50));
         }
@@ -1016,25 +7571,32 @@ mod tests {
         assert!(result.is_ok());
 
         {
-            let sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let session = sessions.get(&session_id).expect("Session not found");
-            let alice_tokens = session.state.items.get("Alice").expect("Alice not found");
-            assert_eq!(*alice_tokens, HashMap::new());
+            let alice_tokens = session
+                .state
+                .ui_items()
+                .remove("Alice")
+                .expect("Alice not found");
+            assert_eq!(alice_tokens, HashMap::new());
         }
 
         // Withdrawing a token that does not exist
         let result: std::result::Result<(), Error> =
             shared.withdraw_tokens(&session_id, &user_address, "TokenB".to_string(), dec!(10));
-        // Should insert token with requested amount (but subtracting should yield 0)
+        // Is a no-op, as nothing of it was offered
         assert!(result.is_ok());
 
         {
-            let sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let session = sessions.get(&session_id).expect("Session not found");
-            let alice_tokens = session.state.items.get("Alice").expect("Alice not found");
+            let alice_tokens = session
+                .state
+                .ui_items()
+                .remove("Alice")
+                .expect("Alice not found");
             let token_b_maybe = alice_tokens.get("TokenB");
-            // TokenB didn't exist previously, now it should be max(0, 0 - 10) = 0 inserted
-            assert_eq!(token_b_maybe.is_none(), true);
+            assert!(token_b_maybe.is_none());
         }
     }
 
@@ -1047,9 +7609,10 @@ mod tests {
             TestChainContext {},
         )));
         let token_amount_cache = Arc::new(TokenAmountCache::init());
-        token_amount_cache.insert_token_amounts(
+        token_amount_cache.insert_token_amounts_with_decimals(
             user_address.to_owned(),
             HashMap::from([(token_mint.to_string(), available_tokens)]),
+            TEST_MINT_DECIMALS,
         );
         let shared = SharedSessions::new(token_amount_cache, transaction_service);
         let session_id = Uuid::new_v4();
@@ -1059,16 +7622,16 @@ mod tests {
         shared.add_client(session_id, connection_id, tx);
 
         let result =
-            shared.add_tokens_offer(&session_id, &user_address, token_mint.to_string(), dec!(12));
+            shared.add_tokens_offer(&session_id, user_address, token_mint.to_string(), dec!(12));
         assert!(result.is_ok());
 
         {
-            let sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let session = sessions.get(&session_id).expect("Session not found");
             let alice_tokens = session
                 .state
-                .items
-                .get(user_address)
+                .ui_items()
+                .remove(user_address)
                 .expect("Alice not found in state");
             assert_eq!(
                 *alice_tokens.get(token_mint).expect("TokenA not found"),
@@ -1086,9 +7649,10 @@ mod tests {
             TestChainContext {},
         )));
         let token_amount_cache = Arc::new(TokenAmountCache::init());
-        token_amount_cache.insert_token_amounts(
+        token_amount_cache.insert_token_amounts_with_decimals(
             user_address.to_owned(),
             HashMap::from([(token_mint.to_string(), available_tokens)]),
+            TEST_MINT_DECIMALS,
         );
         let shared = SharedSessions::new(token_amount_cache, transaction_service);
         let session_id = Uuid::new_v4();
@@ -1098,22 +7662,22 @@ mod tests {
         shared.add_client(session_id, connection_id, tx);
 
         let result =
-            shared.add_tokens_offer(&session_id, &user_address, token_mint.to_string(), dec!(4));
+            shared.add_tokens_offer(&session_id, user_address, token_mint.to_string(), dec!(4));
         assert!(result.is_ok());
         let result =
-            shared.add_tokens_offer(&session_id, &user_address, token_mint.to_string(), dec!(4));
+            shared.add_tokens_offer(&session_id, user_address, token_mint.to_string(), dec!(4));
         assert!(result.is_ok());
         let result =
-            shared.add_tokens_offer(&session_id, &user_address, token_mint.to_string(), dec!(4));
+            shared.add_tokens_offer(&session_id, user_address, token_mint.to_string(), dec!(4));
         assert!(result.is_ok());
 
         {
-            let sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let session = sessions.get(&session_id).expect("Session not found");
             let alice_tokens = session
                 .state
-                .items
-                .get(user_address)
+                .ui_items()
+                .remove(user_address)
                 .expect("Alice not found in state");
             assert_eq!(
                 *alice_tokens.get(token_mint).expect("TokenA not found"),
@@ -1131,9 +7695,10 @@ mod tests {
             TestChainContext {},
         )));
         let token_amount_cache = Arc::new(TokenAmountCache::init());
-        token_amount_cache.insert_token_amounts(
+        token_amount_cache.insert_token_amounts_with_decimals(
             user_address.to_owned(),
             HashMap::from([(token_mint.to_string(), available_tokens)]),
+            TEST_MINT_DECIMALS,
         );
         let shared = SharedSessions::new(token_amount_cache, transaction_service);
         let session_id = Uuid::new_v4();
@@ -1143,22 +7708,22 @@ mod tests {
         shared.add_client(session_id, connection_id, tx);
 
         let result =
-            shared.add_tokens_offer(&session_id, &user_address, token_mint.to_string(), dec!(4));
+            shared.add_tokens_offer(&session_id, user_address, token_mint.to_string(), dec!(4));
         assert!(result.is_ok());
         let result =
-            shared.add_tokens_offer(&session_id, &user_address, token_mint.to_string(), dec!(4));
+            shared.add_tokens_offer(&session_id, user_address, token_mint.to_string(), dec!(4));
         assert!(result.is_ok());
         let result =
-            shared.add_tokens_offer(&session_id, &user_address, token_mint.to_string(), dec!(-4));
-        assert!(result.is_ok());
+            shared.add_tokens_offer(&session_id, user_address, token_mint.to_string(), dec!(-4));
+        assert!(result.is_err());
 
         {
-            let sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let session = sessions.get(&session_id).expect("Session not found");
             let alice_tokens = session
                 .state
-                .items
-                .get(user_address)
+                .ui_items()
+                .remove(user_address)
                 .expect("Alice not found in state");
             assert_eq!(
                 *alice_tokens.get(token_mint).expect("TokenA not found"),
@@ -1167,6 +7732,58 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn zero_offer_should_fail_unless_configured_to_be_ignored() {
+        use crate::config::{SessionsConfig, TunableConfig};
+
+        let shared_with = |non_positive_offers: NonPositiveAmounts| {
+            let token_amount_cache = Arc::new(TokenAmountCache::init());
+            token_amount_cache.insert_token_amounts_with_decimals(
+                "Alice".to_string(),
+                HashMap::from([("TokenA".to_string(), dec!(10))]),
+                TEST_MINT_DECIMALS,
+            );
+            SharedSessions::with_config(
+                token_amount_cache,
+                Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                    TestChainContext {},
+                ))),
+                AuditLog::disabled(),
+                None,
+                Arc::new(RuntimeConfig::new(TunableConfig {
+                    sessions: SessionsConfig {
+                        non_positive_offers,
+                        ..SessionsConfig::default()
+                    },
+                    ..TunableConfig::default()
+                })),
+            )
+        };
+        let session_id = Uuid::new_v4();
+
+        let shared = shared_with(SessionsConfig::default().non_positive_offers);
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        let error = shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(0))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Offered amount must be positive, got 0");
+        assert!(shared
+            .validate_offer(&session_id, "Alice", "TokenA", dec!(0))
+            .is_err());
+        // Withdrawals keep being ignored by default
+        assert!(shared
+            .withdraw_tokens(&session_id, "Alice", "TokenA".to_string(), dec!(0))
+            .is_ok());
+
+        let shared = shared_with(NonPositiveAmounts::Ignore);
+        shared.add_client(session_id, Uuid::new_v4(), mpsc::channel(10).0);
+        assert!(shared
+            .add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(0))
+            .is_ok());
+        let sessions = &shared.internal;
+        assert!(sessions.get(&session_id).unwrap().state.items.is_empty());
+    }
+
     #[tokio::test]
     async fn add_then_withdraw_negative_amount() {
         let user_address = "Alice";
@@ -1176,9 +7793,10 @@ mod tests {
             TestChainContext {},
         )));
         let token_amount_cache = Arc::new(TokenAmountCache::init());
-        token_amount_cache.insert_token_amounts(
+        token_amount_cache.insert_token_amounts_with_decimals(
             user_address.to_owned(),
             HashMap::from([(token_mint.to_string(), available_tokens)]),
+            TEST_MINT_DECIMALS,
         );
         let shared = SharedSessions::new(token_amount_cache, transaction_service);
         let session_id = Uuid::new_v4();
@@ -1188,20 +7806,20 @@ mod tests {
         shared.add_client(session_id, connection_id, tx);
 
         let result =
-            shared.add_tokens_offer(&session_id, &user_address, token_mint.to_string(), dec!(4));
+            shared.add_tokens_offer(&session_id, user_address, token_mint.to_string(), dec!(4));
         assert!(result.is_ok());
         let result =
-            shared.withdraw_tokens(&session_id, &user_address, token_mint.to_string(), dec!(-4));
+            shared.withdraw_tokens(&session_id, user_address, token_mint.to_string(), dec!(-4));
 
         assert!(result.is_ok());
 
         {
-            let sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let session = sessions.get(&session_id).expect("Session not found");
             let alice_tokens = session
                 .state
-                .items
-                .get(user_address)
+                .ui_items()
+                .remove(user_address)
                 .expect("Alice not found in state");
             assert_eq!(
                 *alice_tokens.get(token_mint).expect("TokenA not found"),
@@ -1219,9 +7837,10 @@ mod tests {
             TestChainContext {},
         )));
         let token_amount_cache = Arc::new(TokenAmountCache::init());
-        token_amount_cache.insert_token_amounts(
+        token_amount_cache.insert_token_amounts_with_decimals(
             user_address.to_owned(),
             HashMap::from([(token_mint.to_string(), available_tokens)]),
+            TEST_MINT_DECIMALS,
         );
         let shared = SharedSessions::new(token_amount_cache, transaction_service);
         let session_id = Uuid::new_v4();
@@ -1231,11 +7850,29 @@ mod tests {
         shared.add_client(session_id, connection_id, tx);
 
         let result =
-            shared.withdraw_tokens(&session_id, &user_address, token_mint.to_string(), dec!(4));
+            shared.withdraw_tokens(&session_id, user_address, token_mint.to_string(), dec!(4));
 
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn withdraw_from_unknown_session_should_be_not_found() {
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let shared = SharedSessions::new(Arc::new(TokenAmountCache::init()), transaction_service);
+        let session_id = Uuid::new_v4();
+
+        let error = shared
+            .withdraw_tokens(&session_id, "Alice", "TokenA".to_string(), dec!(1))
+            .unwrap_err();
+
+        assert_eq!(
+            error.downcast_ref::<SessionError>(),
+            Some(&SessionError::NotFound { session_id })
+        );
+    }
+
     #[tokio::test]
     async fn withdraw_below_zero() {
         let user_address = "Alice";
@@ -1245,9 +7882,10 @@ mod tests {
             TestChainContext {},
         )));
         let token_amount_cache = Arc::new(TokenAmountCache::init());
-        token_amount_cache.insert_token_amounts(
+        token_amount_cache.insert_token_amounts_with_decimals(
             user_address.to_owned(),
             HashMap::from([(token_mint.to_string(), available_tokens)]),
+            TEST_MINT_DECIMALS,
         );
         let shared = SharedSessions::new(token_amount_cache, transaction_service);
         let session_id = Uuid::new_v4();
@@ -1257,30 +7895,30 @@ mod tests {
         shared.add_client(session_id, connection_id, tx);
 
         let result =
-            shared.add_tokens_offer(&session_id, &user_address, token_mint.to_string(), dec!(4));
+            shared.add_tokens_offer(&session_id, user_address, token_mint.to_string(), dec!(4));
         assert!(result.is_ok());
 
         let result =
-            shared.withdraw_tokens(&session_id, &user_address, token_mint.to_string(), dec!(3));
+            shared.withdraw_tokens(&session_id, user_address, token_mint.to_string(), dec!(3));
         assert!(result.is_ok());
 
         let result =
-            shared.withdraw_tokens(&session_id, &user_address, token_mint.to_string(), dec!(3));
+            shared.withdraw_tokens(&session_id, user_address, token_mint.to_string(), dec!(3));
         assert!(result.is_ok());
         let result =
-            shared.withdraw_tokens(&session_id, &user_address, token_mint.to_string(), dec!(3));
+            shared.withdraw_tokens(&session_id, user_address, token_mint.to_string(), dec!(3));
         assert!(result.is_ok());
 
         //should delete tokens state if amount drops to zero
         {
-            let sessions = shared.internal.lock().unwrap();
+            let sessions = &shared.internal;
             let session = sessions.get(&session_id).expect("Session not found");
             let alice_tokens = session
                 .state
-                .items
-                .get(user_address)
+                .ui_items()
+                .remove(user_address)
                 .expect("Alice not found in state");
-            assert_eq!(*alice_tokens, HashMap::new());
+            assert_eq!(alice_tokens, HashMap::new());
         }
     }
     //withdraw negative amount of tokens
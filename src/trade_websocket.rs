@@ -1,75 +1,254 @@
 use axum::extract::ws::{Message, WebSocket};
 use futures::{SinkExt, StreamExt};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use rust_decimal::prelude::*;
 use serde::{Deserialize, Serialize};
-use solana_sdk::transaction::Transaction;
-use std::{collections::HashMap, sync::Arc};
+use solana_sdk::{
+    instruction::AccountMeta, pubkey::Pubkey, signature::Signature, transaction::Transaction,
+};
+use std::{collections::HashMap, str::FromStr, sync::Arc};
 
-use tokio::sync::mpsc;
+use tokio::{sync::mpsc, time::Instant};
 use uuid::Uuid;
 
-use crate::{chain_context::ChainContext, trade_session::{SessionId, SharedSessions}};
+use crate::{
+    chain_context::ChainContext,
+    config::TransactionEncoding,
+    token_service::{MetadataView, TokenService},
+    trade_session::{
+        AppliedRounding, OfferAmounts, OfferMode, ParticipantRole, SessionError, SessionEvent,
+        SessionId, SharedSessions, TradeStatus, TransactionBatch, OFFLINE_PARTICIPANT_REVERT_AFTER,
+    },
+    transaction_service::{Memos, UpToOffers},
+};
+
+/// Version of the websocket protocol spoken by this server.
+///
+/// Versioning policy: adding new message variants or new optional fields is backwards compatible
+/// and does not bump the version, since unknown variants deserialize to `WebsocketMessage::Unknown`
+/// and unknown fields are ignored. Removing or changing the meaning of existing fields bumps the
+/// version, and the server keeps accepting every version down to `MIN_SUPPORTED_PROTOCOL_VERSION`.
+///
+/// Version 2 refuses messages on behalf of a wallet until the connection answered the
+/// `AuthChallenge`, which version 1 clients don't.
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const MIN_SUPPORTED_PROTOCOL_VERSION: u32 = 2;
+
+/// Serialized `TradeStateUpdate`s longer than this many bytes are sent as `StateChunk`s.
+pub const STATE_CHUNK_THRESHOLD: usize = 64 * 1024;
+
+/// Waits for the next ping to send, forever when pings are off.
+async fn next_ping(pings: &mut Option<tokio::time::Interval>) {
+    match pings {
+        Some(pings) => {
+            pings.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Checks that `signature`, base58 encoded, is a signature of the utf-8 bytes of `nonce` by the
+/// keypair of `user_address`.
+pub fn verify_challenge(
+    nonce: &str,
+    user_address: &str,
+    signature: &str,
+) -> Result<(), SessionError> {
+    let pubkey = Pubkey::from_str(user_address).map_err(|_| SessionError::InvalidAddress {
+        address: user_address.to_string(),
+    })?;
+    match Signature::from_str(signature) {
+        Ok(signature) if signature.verify(pubkey.as_ref(), nonce.as_bytes()) => Ok(()),
+        _ => Err(SessionError::AuthenticationFailed {
+            user_address: user_address.to_string(),
+        }),
+    }
+}
+
+/// Picks the protocol version to use with a client announcing `client_version` in its `Hello`,
+/// or `None` when the client is too old to be served.
+pub fn negotiate_protocol_version(client_version: u32) -> Option<u32> {
+    if client_version < MIN_SUPPORTED_PROTOCOL_VERSION {
+        None
+    } else {
+        Some(client_version.min(PROTOCOL_VERSION))
+    }
+}
 
 pub async fn handle_socket<T: ChainContext + Sync + Send + 'static>(
     socket: WebSocket,
     session_id: SessionId,
     sessions: Arc<SharedSessions<T>>,
+    token_service: Arc<TokenService>,
 ) {
-    let connection_id = Uuid::new_v4();
-
     let (tx, mut rx) = mpsc::channel(32);
 
-    sessions.add_client(session_id, connection_id, tx);
-    sessions.broadcast_current_state(&session_id);
+    let reply_tx = tx.clone();
+    let mut connection_id = Uuid::new_v4();
+    while !sessions.add_client(session_id, connection_id, tx.clone()) {
+        warn!(
+            "Connection id {} collided in session {}, regenerating",
+            connection_id, session_id
+        );
+        connection_id = Uuid::new_v4();
+    }
+    // Messages on behalf of a wallet are refused until the connection signed this
+    let nonce = Uuid::new_v4().simple().to_string();
+    let _ = reply_tx.try_send(WebsocketMessage::AuthChallenge {
+        nonce: nonce.clone(),
+    });
+    sessions.send_current_state(&session_id, &connection_id);
+    sessions.send_event_log(&session_id, &connection_id);
 
     let (mut ws_sink, mut ws_stream) = socket.split();
+    let heartbeat = sessions.heartbeat();
+    let last_seen = Arc::new(std::sync::Mutex::new(Instant::now()));
 
-    let write_handle = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            let msg_json_result = serde_json::to_string(&msg);
-            if let Ok(msg_json) = msg_json_result {
-                debug!("Sending ws message {:#?}", &msg_json);
-                if ws_sink.send(Message::Text(msg_json)).await.is_err() {
-                    // If send fails, client disconnected
+    let mut write_handle = tokio::spawn({
+        let last_seen = Arc::clone(&last_seen);
+        async move {
+            let mut pings = heartbeat
+                .map(|(interval, _)| tokio::time::interval_at(Instant::now() + interval, interval));
+            'messages: loop {
+                let msg = tokio::select! {
+                    msg = rx.recv() => msg,
+                    _ = next_ping(&mut pings) => {
+                        let pong_timeout = heartbeat.map(|(_, timeout)| timeout).unwrap_or_default();
+                        if last_seen.lock().unwrap().elapsed() > pong_timeout {
+                            info!("Client {} stopped answering pings, closing", connection_id);
+                            let _ = ws_sink.send(Message::Close(None)).await;
+                            break 'messages;
+                        }
+                        if ws_sink.send(Message::Ping(Vec::new())).await.is_err() {
+                            break 'messages;
+                        }
+                        continue;
+                    }
+                };
+                let Some(msg) = msg else {
                     break;
+                };
+                let frames_result = encode_frames(&msg, STATE_CHUNK_THRESHOLD);
+                if let Ok(frames) = frames_result {
+                    for msg_json in frames {
+                        debug!("Sending ws message {:#?}", &msg_json);
+                        if ws_sink.send(Message::Text(msg_json)).await.is_err() {
+                            // If send fails, client disconnected
+                            break 'messages;
+                        }
+                    }
                 }
             }
         }
     });
 
-    let read_handle = tokio::spawn({
+    let mut read_handle = tokio::spawn({
         let sessions = Arc::clone(&sessions);
         async move {
+            let mut authenticated: Option<String> = None;
             while let Some(Ok(msg)) = ws_stream.next().await {
+                *last_seen.lock().unwrap() = Instant::now();
                 match msg {
                     Message::Text(text) => {
                         info!("Received from client {}: {}", connection_id, text);
                         if let Ok(msg) = serde_json::from_str::<WebsocketMessage>(&text) {
+                            if let Err(e) = msg.validate_addresses() {
+                                sessions.send_error(&session_id, &connection_id, &e.into());
+                                continue;
+                            }
+                            if let Some(user_address) = msg.user_address() {
+                                if authenticated.as_deref() != Some(user_address) {
+                                    let e = SessionError::NotAuthenticated {
+                                        user_address: user_address.to_string(),
+                                    };
+                                    sessions.send_error(&session_id, &connection_id, &e.into());
+                                    continue;
+                                }
+                            }
                             match msg {
+                                WebsocketMessage::Hello { version } => {
+                                    match negotiate_protocol_version(version) {
+                                        Some(version) => {
+                                            let _ = reply_tx
+                                                .try_send(WebsocketMessage::Welcome { version });
+                                        }
+                                        None => {
+                                            warn!(
+                                                "Client {} uses unsupported protocol version {}",
+                                                connection_id, version
+                                            );
+                                            break;
+                                        }
+                                    }
+                                }
+                                WebsocketMessage::Authenticate {
+                                    user_address,
+                                    signature,
+                                } => match verify_challenge(&nonce, &user_address, &signature) {
+                                    Ok(()) => {
+                                        sessions.identify_client(
+                                            &session_id,
+                                            &connection_id,
+                                            &user_address,
+                                        );
+                                        let _ =
+                                            reply_tx.try_send(WebsocketMessage::Authenticated {
+                                                user_address: user_address.clone(),
+                                            });
+                                        authenticated = Some(user_address);
+                                    }
+                                    Err(e) => {
+                                        warn!(
+                                            "Client {} failed to authenticate: {}",
+                                            connection_id, e
+                                        );
+                                        sessions.send_error(&session_id, &connection_id, &e.into());
+                                    }
+                                },
                                 WebsocketMessage::OfferTokens {
                                     user_address,
                                     token_mint,
                                     amount,
+                                    token_account,
+                                    mode,
                                 } => {
-                                    //TODO handle errors
-                                    let result = sessions.add_tokens_offer(
+                                    let result = sessions.add_tokens_offer_from(
                                         &session_id,
                                         &user_address,
                                         token_mint,
                                         amount,
+                                        token_account,
+                                        mode,
                                     );
                                     if let Err(e) = result {
                                         error!("Error while adding tokens offer: {}", e);
+                                        sessions.send_error(&session_id, &connection_id, &e);
                                     }
                                     sessions.broadcast_current_state(&session_id);
                                 }
+                                WebsocketMessage::ValidateOffer {
+                                    user_address,
+                                    token_mint,
+                                    amount,
+                                } => {
+                                    let result = sessions.validate_offer(
+                                        &session_id,
+                                        &user_address,
+                                        &token_mint,
+                                        amount,
+                                    );
+                                    let _ = reply_tx.try_send(WebsocketMessage::OfferValidation {
+                                        token_mint,
+                                        requested_amount: amount,
+                                        offered_amount: result.as_ref().ok().copied(),
+                                        error: result.err().map(|e| e.to_string()),
+                                    });
+                                }
                                 WebsocketMessage::WithdrawTokens {
                                     user_address,
                                     token_mint,
                                     amount,
                                 } => {
-                                    //TODO handle errors
                                     let result = sessions.withdraw_tokens(
                                         &session_id,
                                         &user_address,
@@ -78,35 +257,186 @@ pub async fn handle_socket<T: ChainContext + Sync + Send + 'static>(
                                     );
                                     if let Err(e) = result {
                                         error!("Error while withdrawing tokens offer: {}", e);
+                                        sessions.send_error(&session_id, &connection_id, &e);
                                     }
                                     sessions.broadcast_current_state(&session_id);
                                 }
-                                WebsocketMessage::AcceptTrade { user_address
-                                 } => {
-                                    //TODO handle errors
+                                WebsocketMessage::AcceptTrade { user_address } => {
                                     let result = sessions.accept_trade(&session_id, &user_address);
                                     if let Err(e) = result {
                                         error!("Error while accepting offer: {}", e);
+                                        sessions.send_error(&session_id, &connection_id, &e);
                                     }
                                     sessions.broadcast_current_state(&session_id);
-                                 }
-                                 WebsocketMessage::GetTransactionToSign { user_address
-                                 } => {
-                                    //TODO handle errors
-                                    let result = sessions.get_transaction_to_sign(&session_id, &user_address).await;
-                                    if let Err(e) = result {
+                                }
+                                WebsocketMessage::CancelTrade { user_address } => {
+                                    if let Err(e) =
+                                        sessions.cancel_trade(&session_id, &user_address)
+                                    {
+                                        error!("Error while cancelling trade: {}", e);
+                                        sessions.send_error(&session_id, &connection_id, &e);
+                                    }
+                                    sessions.broadcast_current_state(&session_id);
+                                }
+                                WebsocketMessage::GetTransactionToSign {
+                                    user_address,
+                                    encoding,
+                                } => {
+                                    let result = sessions
+                                        .get_transaction_to_sign(&session_id, &user_address)
+                                        .await;
+                                    if let Err(e) = &result {
                                         error!("Error while getting transaction to sign: {}", e);
+                                        sessions.send_error(&session_id, &connection_id, e);
+                                    } else if matches!(result, Ok(false)) || encoding.is_some() {
+                                        // A just built transaction was sent to every client already
+                                        match sessions.encoded_transaction(&session_id, encoding) {
+                                            Ok((encoding, transaction)) => {
+                                                let _ = reply_tx.try_send(
+                                                    WebsocketMessage::TransactionToSign {
+                                                        encoding,
+                                                        transaction,
+                                                        batch: sessions
+                                                            .transaction_batch(&session_id),
+                                                    },
+                                                );
+                                            }
+                                            Err(e) => {
+                                                error!(
+                                                    "Error while encoding transaction to sign: {}",
+                                                    e
+                                                );
+                                                sessions.send_error(
+                                                    &session_id,
+                                                    &connection_id,
+                                                    &e,
+                                                );
+                                            }
+                                        }
                                     }
                                     sessions.broadcast_current_state(&session_id);
-                                 }
-                                 WebsocketMessage::SignedTransaction { user_address, signature
-                                 } => {
-                                    //TODO handle errors
-                                    let _ = sessions.sign_transaction(&session_id, signature);
+                                }
+                                WebsocketMessage::GetFeeEstimate { .. } => {
+                                    let result = sessions.get_fee_estimate(&session_id).await;
+                                    let _ = reply_tx.try_send(WebsocketMessage::FeeEstimate {
+                                        lamports: result.as_ref().ok().copied(),
+                                        error: result.err().map(|e| e.to_string()),
+                                    });
+                                }
+                                WebsocketMessage::WhoAmI => {
+                                    let user_address =
+                                        sessions.connection_user(&session_id, &connection_id);
+                                    let _ = reply_tx.try_send(WebsocketMessage::Identity {
+                                        error: user_address
+                                            .is_none()
+                                            .then(|| "Connection is not authenticated".to_string()),
+                                        user_address,
+                                        session_id,
+                                        connection_id,
+                                    });
+                                }
+                                WebsocketMessage::PreviewAccounts { .. } => {
+                                    let result = sessions.preview_accounts(&session_id).await;
+                                    let _ = reply_tx.try_send(WebsocketMessage::AccountsPreview {
+                                        accounts: result.as_ref().ok().map(|accounts| {
+                                            accounts.iter().map(AccountPreview::from).collect()
+                                        }),
+                                        error: result.err().map(|e| e.to_string()),
+                                    });
+                                }
+                                WebsocketMessage::SetMemo { user_address, memo } => {
+                                    if let Err(e) =
+                                        sessions.set_memo(&session_id, &user_address, &memo)
+                                    {
+                                        sessions.send_error(&session_id, &connection_id, &e);
+                                    }
+                                    sessions.broadcast_current_state(&session_id);
+                                }
+                                WebsocketMessage::SetFeePayer {
+                                    user_address,
+                                    fee_payer,
+                                } => {
+                                    if let Err(e) = sessions.set_fee_payer(
+                                        &session_id,
+                                        &user_address,
+                                        &fee_payer,
+                                    ) {
+                                        sessions.send_error(&session_id, &connection_id, &e);
+                                    }
+                                    sessions.broadcast_current_state(&session_id);
+                                }
+                                WebsocketMessage::Resync => {
+                                    sessions.send_current_state(&session_id, &connection_id);
+                                }
+                                WebsocketMessage::KeepAlive => {
+                                    // Rate limited keepalives are dropped silently
+                                    sessions.keep_alive(&session_id, Instant::now());
+                                }
+                                WebsocketMessage::GetAvailable { user_address } => {
+                                    let result = token_service
+                                        .get_available_amounts(&user_address)
+                                        .await
+                                        .map_err(|e| e.to_string());
+                                    let _ = reply_tx.try_send(WebsocketMessage::Available {
+                                        user_address,
+                                        tokens: result.as_ref().ok().cloned(),
+                                        error: result.err(),
+                                    });
+                                }
+                                WebsocketMessage::GetOffers { user_address } => {
+                                    // Only ever answered from the session this connection joined
+                                    let offers = match sessions
+                                        .get_offers(&session_id, &user_address)
+                                    {
+                                        Ok(offers) => {
+                                            let mut token_offers = Vec::with_capacity(offers.len());
+                                            for (mint, amount) in offers {
+                                                let metadata =
+                                                    token_service.get_token_metadata(&mint).await;
+                                                token_offers.push(TokenOffer {
+                                                    mint,
+                                                    amount,
+                                                    metadata,
+                                                });
+                                            }
+                                            Ok(token_offers)
+                                        }
+                                        Err(e) => Err(e),
+                                    };
+                                    let _ = reply_tx.try_send(WebsocketMessage::Offers {
+                                        user_address,
+                                        offers: offers.as_ref().ok().cloned(),
+                                        error: offers.err().map(|e| e.to_string()),
+                                    });
+                                }
+                                WebsocketMessage::RejectTransaction { user_address } => {
+                                    let result =
+                                        sessions.reject_transaction(&session_id, &user_address);
+                                    if let Err(e) = result {
+                                        error!("Error while rejecting transaction: {}", e);
+                                        sessions.send_error(&session_id, &connection_id, &e);
+                                    }
+                                    sessions.broadcast_current_state(&session_id);
+                                }
+                                WebsocketMessage::SignedTransaction {
+                                    user_address,
+                                    signature,
+                                } => {
+                                    if let Err(e) = sessions.sign_transaction(
+                                        &session_id,
+                                        &user_address,
+                                        signature,
+                                    ) {
+                                        sessions.send_error(&session_id, &connection_id, &e);
+                                    }
                                     sessions.broadcast_current_state(&session_id);
-                                 }
+                                }
                                 _ => {}
                             }
+                        } else {
+                            warn!("Client {} sent an invalid message", connection_id);
+                            let e = SessionError::InvalidMessage;
+                            sessions.send_error(&session_id, &connection_id, &e.into());
                         }
                     }
                     Message::Close(_frame) => {
@@ -122,24 +452,97 @@ pub async fn handle_socket<T: ChainContext + Sync + Send + 'static>(
         }
     });
 
-    let _ = tokio::join!(write_handle, read_handle);
-
-    sessions.remove_client(&session_id, &connection_id);
+    // A dead connection never ends the read task, the write task gives up on it when it
+    // stops answering pings
+    let write_ended = tokio::select! {
+        _ = &mut read_handle => false,
+        _ = &mut write_handle => {
+            read_handle.abort();
+            true
+        }
+    };
+    // Removing the client drops its sender, which ends the write task
+    let offline_user = sessions.remove_client(&session_id, &connection_id);
     info!(
         "Removed client {} from session {}",
         connection_id, session_id
     );
+    if !write_ended {
+        let _ = write_handle.await;
+    }
+
+    if let Some(user_address) = offline_user {
+        tokio::spawn(async move {
+            tokio::time::sleep(OFFLINE_PARTICIPANT_REVERT_AFTER).await;
+            if sessions.revert_if_offline(&session_id, &user_address) {
+                info!(
+                    "Participant {} did not come back, session {} returned to trading",
+                    user_address, session_id
+                );
+                sessions.broadcast_current_state(&session_id);
+            }
+        });
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum WebsocketMessage {
+    Hello {
+        version: u32,
+    },
+    Welcome {
+        version: u32,
+    },
+    /// Sent on connect, the client proves owning a wallet by answering with an `Authenticate`
+    /// carrying the wallet's signature of the utf-8 bytes of `nonce`.
+    AuthChallenge {
+        nonce: String,
+    },
+    /// Binds the connection to `user_address`, messages on behalf of other wallets are refused
+    /// with `not_authenticated`.
+    Authenticate {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+        /// Base58 encoded ed25519 signature of the challenge's nonce.
+        signature: String,
+    },
+    Authenticated {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+    },
     OfferTokens {
         #[serde(rename = "userAddress")]
         user_address: String,
         #[serde(rename = "tokenMint")]
         token_mint: String,
         amount: Decimal,
+        /// Source token account, the user's ATA for the mint when omitted.
+        #[serde(
+            rename = "tokenAccount",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        token_account: Option<String>,
+        /// `upTo` lets the server lower the amount to the counterparty's offer of the mint.
+        #[serde(default)]
+        mode: OfferMode,
+    },
+    ValidateOffer {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+        #[serde(rename = "tokenMint")]
+        token_mint: String,
+        amount: Decimal,
+    },
+    OfferValidation {
+        #[serde(rename = "tokenMint")]
+        token_mint: String,
+        #[serde(rename = "requestedAmount")]
+        requested_amount: Decimal,
+        #[serde(rename = "offeredAmount")]
+        offered_amount: Option<Decimal>,
+        error: Option<String>,
     },
     WithdrawTokens {
         #[serde(rename = "userAddress")]
@@ -152,33 +555,315 @@ pub enum WebsocketMessage {
         #[serde(rename = "userAddress")]
         user_address: String,
     },
+    /// Backs out of the trade, ending the session as `Cancelled` unless its transaction was
+    /// sent already.
+    CancelTrade {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+    },
     GetTransactionToSign {
         #[serde(rename = "userAddress")]
         user_address: String,
+        #[serde(default)]
+        encoding: Option<TransactionEncoding>,
+    },
+    TransactionToSign {
+        encoding: TransactionEncoding,
+        transaction: String,
+        /// Set when the trade was split into several transactions, which are signed one after
+        /// the other and settle independently.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        batch: Option<TransactionBatch>,
+    },
+    GetFeeEstimate {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+    },
+    FeeEstimate {
+        lamports: Option<u64>,
+        error: Option<String>,
+    },
+    RejectTransaction {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+    },
+    /// Asks for the accounts the transaction of the current offers would reference, e.g. to
+    /// simulate it in a wallet before it's built.
+    PreviewAccounts {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+    },
+    /// Accounts in the order of the transaction message.
+    AccountsPreview {
+        accounts: Option<Vec<AccountPreview>>,
+        error: Option<String>,
+    },
+    /// Sets the sender's trade note, at most `MAX_MEMO_LEN` characters are kept.
+    SetMemo {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+        memo: String,
+    },
+    /// Makes `fee_payer` pay the network fees, the sender or, when the counterparty sends it, the
+    /// initiator.
+    SetFeePayer {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+        #[serde(rename = "feePayer")]
+        fee_payer: String,
+    },
+    /// Asks for the mints the sender can offer right now.
+    GetAvailable {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+    },
+    Available {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+        tokens: Option<HashMap<String, Decimal>>,
+        error: Option<String>,
+    },
+    /// Asks for the current offers of one participant, the sender or the counterparty.
+    GetOffers {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+    },
+    Offers {
+        #[serde(rename = "userAddress")]
+        user_address: String,
+        offers: Option<Vec<TokenOffer>>,
+        error: Option<String>,
     },
     SignedTransaction {
         #[serde(rename = "userAddress")]
         user_address: String,
-        signature: String
+        signature: String,
+    },
+    /// Changes to the offers since the previous update: new amounts per user and mint, and the
+    /// mints each user no longer offers. `seq` is one more than that of the previous update, a
+    /// client seeing a gap missed one and should `Resync`.
+    TradeStateDelta {
+        seq: u64,
+        changed: OfferAmounts,
+        removed: HashMap<String, Vec<String>>,
+        /// Every participant's `upTo` mints, only sent when they changed.
+        #[serde(rename = "upTo", default, skip_serializing_if = "Option::is_none")]
+        up_to: Option<UpToOffers>,
+    },
+    /// Asks for a full `TradeStateUpdate`, e.g. after missing a delta.
+    Resync,
+    /// Keeps the session from being closed as idle while the participants are still deciding,
+    /// without changing anything. Only one per `sessions.keepalive_min_interval_secs` counts.
+    KeepAlive,
+    /// Part `seq` (from 0) of a `TradeStateUpdate` too large for a single frame. The client
+    /// concatenates the `data` of all `total` chunks sharing `id` and parses the result as the
+    /// `TradeStateUpdate`.
+    StateChunk {
+        id: Uuid,
+        seq: u32,
+        total: u32,
+        data: String,
     },
     TradeStateUpdate {
         offers: Arc<HashMap<String, HashMap<String, Decimal>>>,
         #[serde(rename = "userActed")]
         user_acted: Option<String>,
-        status: String,
-        tx: Option<Transaction>
+        status: TradeStatus,
+        tx: Option<Transaction>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        memos: Memos,
+        #[serde(rename = "feePayer", default, skip_serializing_if = "Option::is_none")]
+        fee_payer: Option<String>,
+        /// Set when the latest offer or withdrawal was rounded to the mint's decimals.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rounding: Option<Box<AppliedRounding>>,
+        /// Role of each participant, including an invited counterparty yet to offer.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        roles: HashMap<String, ParticipantRole>,
+        /// The session is closed, see `TradeStatus::is_closed`, changes to it are refused.
+        #[serde(
+            rename = "readOnly",
+            default,
+            skip_serializing_if = "std::ops::Not::not"
+        )]
+        read_only: bool,
+        /// Mints each participant offers in `OfferMode::UpTo`.
+        #[serde(rename = "upTo", default, skip_serializing_if = "HashMap::is_empty")]
+        up_to: UpToOffers,
+        /// Broadcast this state was last sent in, deltas continue from it.
+        #[serde(default)]
+        seq: u64,
+    },
+    Warning {
+        message: String,
+    },
+    /// A request of this connection failed, only sent to the connection that made it. `code`
+    /// is stable, e.g. `session_not_found`, `invalid_state` or `session_closed`, `message` is
+    /// meant for people.
+    Error {
+        code: String,
+        message: String,
+    },
+    /// The session was closed for inactivity, it's gone and no further messages follow.
+    SessionExpired {
+        reason: String,
     },
+    /// Recent events of the session, sent to a client after the snapshot when it connects.
+    SessionEvents {
+        events: Vec<SessionEvent>,
+    },
+    /// A participant changed the basket after an accept, which reverted the accept.
+    OfferChanged {
+        by: String,
+    },
+    /// The transaction was rebuilt, e.g. because its blockhash expired, signatures of the
+    /// previous one are void and the rebuilt one in the following `TradeStateUpdate` needs
+    /// signing.
+    ResignRequired {
+        reason: String,
+    },
+    /// Asks which participant the server associates with this connection, e.g. to confirm it
+    /// after a reconnect.
+    WhoAmI,
+    /// Identity of the connection, `user_address` is `None` until the connection authenticated.
+    Identity {
+        #[serde(rename = "userAddress")]
+        user_address: Option<String>,
+        #[serde(rename = "sessionId")]
+        session_id: Uuid,
+        #[serde(rename = "connectionId")]
+        connection_id: Uuid,
+        error: Option<String>,
+    },
+    /// Any message type this server does not know about, e.g. sent by a newer client.
+    #[serde(other)]
+    Unknown,
+}
+
+impl WebsocketMessage {
+    /// Participant a client message is sent on behalf of, only accepted from connections
+    /// authenticated as it.
+    pub fn user_address(&self) -> Option<&str> {
+        match self {
+            WebsocketMessage::OfferTokens { user_address, .. }
+            | WebsocketMessage::ValidateOffer { user_address, .. }
+            | WebsocketMessage::WithdrawTokens { user_address, .. }
+            | WebsocketMessage::AcceptTrade { user_address }
+            | WebsocketMessage::CancelTrade { user_address }
+            | WebsocketMessage::SetMemo { user_address, .. }
+            | WebsocketMessage::SetFeePayer { user_address, .. }
+            | WebsocketMessage::GetAvailable { user_address }
+            | WebsocketMessage::GetTransactionToSign { user_address, .. }
+            | WebsocketMessage::GetFeeEstimate { user_address }
+            | WebsocketMessage::RejectTransaction { user_address }
+            | WebsocketMessage::PreviewAccounts { user_address }
+            | WebsocketMessage::SignedTransaction { user_address, .. } => Some(user_address),
+            _ => None,
+        }
+    }
+
+    /// Refuses messages changing the offers or accepting them on behalf of a malformed wallet or
+    /// for a malformed mint, which would otherwise only fail once the transaction is built.
+    pub fn validate_addresses(&self) -> Result<(), SessionError> {
+        let addresses = match self {
+            WebsocketMessage::OfferTokens {
+                user_address,
+                token_mint,
+                ..
+            }
+            | WebsocketMessage::WithdrawTokens {
+                user_address,
+                token_mint,
+                ..
+            } => {
+                vec![user_address, token_mint]
+            }
+            WebsocketMessage::AcceptTrade { user_address } => vec![user_address],
+            _ => vec![],
+        };
+        match addresses
+            .into_iter()
+            .find(|address| Pubkey::from_str(address).is_err())
+        {
+            Some(address) => Err(SessionError::InvalidAddress {
+                address: address.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Serializes `msg` into the text frames to send, a single one unless `msg` is a
+/// `TradeStateUpdate` longer than `threshold` bytes, which is split into `StateChunk`s.
+pub fn encode_frames(msg: &WebsocketMessage, threshold: usize) -> serde_json::Result<Vec<String>> {
+    let msg_json = serde_json::to_string(msg)?;
+    if msg_json.len() <= threshold || !matches!(msg, WebsocketMessage::TradeStateUpdate { .. }) {
+        return Ok(vec![msg_json]);
+    }
+    // Escaping the quotes of the embedded json can nearly double a chunk, keep data at half the
+    // threshold so frames stay around it
+    let chunk_len = (threshold / 2).max(1);
+    let mut parts = Vec::new();
+    let mut rest = msg_json.as_str();
+    while !rest.is_empty() {
+        let mut end = chunk_len.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end += 1;
+        }
+        let (part, tail) = rest.split_at(end);
+        parts.push(part);
+        rest = tail;
+    }
+    let id = Uuid::new_v4();
+    let total = parts.len() as u32;
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(seq, data)| {
+            serde_json::to_string(&WebsocketMessage::StateChunk {
+                id,
+                seq: seq as u32,
+                total,
+                data: data.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccountPreview {
+    pub pubkey: String,
+    #[serde(rename = "isSigner")]
+    pub is_signer: bool,
+    #[serde(rename = "isWritable")]
+    pub is_writable: bool,
+}
+
+impl From<&AccountMeta> for AccountPreview {
+    fn from(account: &AccountMeta) -> Self {
+        AccountPreview {
+            pubkey: account.pubkey.to_string(),
+            is_signer: account.is_signer,
+            is_writable: account.is_writable,
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TokenOffer {
     pub mint: String,
     pub amount: Decimal,
+    #[serde(default)]
+    pub metadata: Option<MetadataView>,
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{chain_context::TestChainContext, token_amount_cache::TokenAmountCache, transaction_service::TransactionService};
+    use crate::{
+        chain_context::{TestChainContext, TEST_MINT_DECIMALS},
+        token_amount_cache::TokenAmountCache,
+        transaction_service::TransactionService,
+    };
 
     use super::*; // If your code is in the same module/crate. Otherwise, import appropriately.
     use axum::{
@@ -187,39 +872,238 @@ mod tests {
         Router,
     };
     use futures::{SinkExt, StreamExt};
-    use log::LevelFilter;
     use rust_decimal_macros::dec;
-    use std::{future::IntoFuture, sync::Arc};
+    use solana_sdk::signature::{Keypair, Signer};
+    use std::{future::IntoFuture, sync::Arc, time::Duration};
     use tokio::net::TcpListener;
-    use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+    use tokio_tungstenite::{
+        connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream,
+    };
     use uuid::Uuid;
 
+    type ClientSocket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    /// Answers the connection's challenge with `signer`'s signature, returning the reply.
+    async fn authenticate(
+        ws: &mut ClientSocket,
+        signer: &Keypair,
+    ) -> anyhow::Result<WebsocketMessage> {
+        loop {
+            let Some(Ok(Message::Text(payload))) = ws.next().await else {
+                anyhow::bail!("connection closed before the AuthChallenge");
+            };
+            if let Ok(WebsocketMessage::AuthChallenge { nonce }) = serde_json::from_str(&payload) {
+                let authenticate = WebsocketMessage::Authenticate {
+                    user_address: signer.pubkey().to_string(),
+                    signature: signer.sign_message(nonce.as_bytes()).to_string(),
+                };
+                ws.send(Message::Text(serde_json::to_string(&authenticate)?.into()))
+                    .await?;
+                break;
+            }
+        }
+        loop {
+            let Some(Ok(Message::Text(payload))) = ws.next().await else {
+                anyhow::bail!("connection closed before the Authenticate reply");
+            };
+            let reply = serde_json::from_str::<WebsocketMessage>(&payload)?;
+            if matches!(
+                reply,
+                WebsocketMessage::Authenticated { .. } | WebsocketMessage::Error { .. }
+            ) {
+                return Ok(reply);
+            }
+        }
+    }
+
+    #[test]
+    fn only_the_wallet_signature_of_the_nonce_should_verify() {
+        let alice = Keypair::new();
+        let alice_address = alice.pubkey().to_string();
+        let nonce = Uuid::new_v4().simple().to_string();
+        let signature = alice.sign_message(nonce.as_bytes()).to_string();
+        assert_eq!(verify_challenge(&nonce, &alice_address, &signature), Ok(()));
+
+        let other_nonce = Uuid::new_v4().simple().to_string();
+        let forgeries = [
+            Keypair::new().sign_message(nonce.as_bytes()).to_string(),
+            alice.sign_message(other_nonce.as_bytes()).to_string(),
+            "not a signature".to_string(),
+        ];
+        for forged in forgeries {
+            assert_eq!(
+                verify_challenge(&nonce, &alice_address, &forged),
+                Err(SessionError::AuthenticationFailed {
+                    user_address: alice_address.clone()
+                })
+            );
+        }
+        assert_eq!(
+            verify_challenge(&nonce, "Alice", &signature),
+            Err(SessionError::InvalidAddress {
+                address: "Alice".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_message_with_extra_fields_still_parses() {
+        let json = r#"{
+            "type": "OfferTokens",
+            "userAddress": "Alice",
+            "tokenMint": "TokenA",
+            "amount": "1.5",
+            "clientTimestamp": 1700000000
+        }"#;
+        let msg = serde_json::from_str::<WebsocketMessage>(json).unwrap();
+        match msg {
+            WebsocketMessage::OfferTokens {
+                user_address,
+                token_mint,
+                amount,
+                token_account,
+                mode,
+            } => {
+                assert_eq!(token_account, None);
+                assert_eq!(mode, OfferMode::Exact);
+                assert_eq!(user_address, "Alice");
+                assert_eq!(token_mint, "TokenA");
+                assert_eq!(amount, dec!(1.5));
+            }
+            _ => panic!("Unexpected message type"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_message_type_parses_as_unknown() {
+        let json = r#"{"type": "SomeFutureMessage", "payload": {"a": 1}}"#;
+        let msg = serde_json::from_str::<WebsocketMessage>(json).unwrap();
+        assert!(matches!(msg, WebsocketMessage::Unknown));
+    }
+
+    #[test]
+    fn test_negotiate_protocol_version() {
+        assert_eq!(
+            negotiate_protocol_version(PROTOCOL_VERSION),
+            Some(PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            negotiate_protocol_version(PROTOCOL_VERSION + 5),
+            Some(PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            negotiate_protocol_version(MIN_SUPPORTED_PROTOCOL_VERSION - 1),
+            None
+        );
+    }
+
+    #[test]
+    fn test_large_snapshot_is_chunked_and_reassembles() {
+        let offers: HashMap<String, HashMap<String, Decimal>> = HashMap::from([(
+            "Alice".to_string(),
+            (0..200)
+                .map(|i| (format!("Mint{}ß", i), Decimal::from(i)))
+                .collect(),
+        )]);
+        let snapshot = WebsocketMessage::TradeStateUpdate {
+            offers: Arc::new(offers.clone()),
+            user_acted: None,
+            status: TradeStatus::Trading,
+            tx: None,
+            memos: Memos::from([("Alice".to_string(), "\"quoted\" note".to_string())]),
+            fee_payer: None,
+            rounding: None,
+            roles: HashMap::new(),
+            read_only: false,
+            up_to: UpToOffers::new(),
+            seq: 0,
+        };
+        let threshold = 1024;
+
+        let frames = encode_frames(&snapshot, threshold).unwrap();
+        assert!(frames.len() > 1);
+        let mut data = String::new();
+        let mut chunk_id = None;
+        for (expected_seq, frame) in frames.iter().enumerate() {
+            assert!(frame.len() <= threshold + 128);
+            match serde_json::from_str::<WebsocketMessage>(frame).unwrap() {
+                WebsocketMessage::StateChunk {
+                    id,
+                    seq,
+                    total,
+                    data: part,
+                } => {
+                    assert_eq!(*chunk_id.get_or_insert(id), id);
+                    assert_eq!(seq as usize, expected_seq);
+                    assert_eq!(total as usize, frames.len());
+                    data.push_str(&part);
+                }
+                other => panic!("Expected a StateChunk, got {:?}", other),
+            }
+        }
+        match serde_json::from_str::<WebsocketMessage>(&data).unwrap() {
+            WebsocketMessage::TradeStateUpdate {
+                offers: reassembled,
+                memos,
+                ..
+            } => {
+                assert_eq!(*reassembled, offers);
+                assert_eq!(memos["Alice"], "\"quoted\" note");
+            }
+            other => panic!("Expected a TradeStateUpdate, got {:?}", other),
+        }
+
+        let small = WebsocketMessage::Warning {
+            message: "x".repeat(2 * threshold),
+        };
+        assert_eq!(encode_frames(&small, threshold).unwrap().len(), 1);
+        assert_eq!(encode_frames(&snapshot, usize::MAX).unwrap().len(), 1);
+    }
+
+    /// Amount of `mint` offered by `user_address` according to a full or incremental update.
+    fn offered_amount(payload: &str, user_address: &str, mint: &str) -> Option<Decimal> {
+        let offers = match serde_json::from_str::<WebsocketMessage>(payload).ok()? {
+            WebsocketMessage::TradeStateUpdate { offers, .. } => (*offers).clone(),
+            WebsocketMessage::TradeStateDelta { changed, .. } => changed,
+            _ => return None,
+        };
+        offers.get(user_address)?.get(mint).copied()
+    }
+
     #[tokio::test]
     async fn test_two_clients_add_tokens_and_both_receive_update() -> anyhow::Result<()> {
-        env_logger::Builder::new()
-            .filter(None, LevelFilter::Debug) // Set log level
-            .is_test(true) // Ensures output works correctly during tests
-            .init();
+        crate::test_logger::init();
         // 1. Create shared state
-        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(TestChainContext{})));
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
 
-        let alice_address = String::from("Alice");
-        let token_mint = String::from("TokenA");
+        let alice = Keypair::new();
+        let alice_address = alice.pubkey().to_string();
+        let token_mint = Pubkey::new_unique().to_string();
         let token_amount_cache = Arc::new(TokenAmountCache::init());
-        token_amount_cache.insert_token_amounts(
+        token_amount_cache.insert_token_amounts_with_decimals(
             alice_address.clone(),
             HashMap::from([(token_mint.clone(), dec!(200.0))]),
+            TEST_MINT_DECIMALS,
         );
 
-        let shared_sessions = Arc::new(SharedSessions::new(token_amount_cache, transaction_service));
+        let shared_sessions =
+            Arc::new(SharedSessions::new(token_amount_cache, transaction_service));
 
         // 2. Set up an Axum router with a WebSocket route
         let app = Router::new().route(
             "/ws/:session_id",
             get({
                 let sessions = Arc::clone(&shared_sessions);
+                let token_service = Arc::new(TokenService::with_metadata(
+                    vec![],
+                    Arc::new(TokenAmountCache::init()),
+                ));
                 move |ws: WebSocketUpgrade, Path(session_id): Path<Uuid>| async move {
-                    ws.on_upgrade(move |socket| handle_socket(socket, session_id, sessions))
+                    ws.on_upgrade(move |socket| {
+                        handle_socket(socket, session_id, sessions, token_service)
+                    })
                 }
             }),
         );
@@ -238,12 +1122,18 @@ mod tests {
 
         let (mut ws1, _resp1) = connect_async(url_1).await?;
         let (mut ws2, _resp2) = connect_async(url_2).await?;
+        assert!(matches!(
+            authenticate(&mut ws1, &alice).await?,
+            WebsocketMessage::Authenticated { .. }
+        ));
 
         // 6. Client1 sends an OfferTokens message
         let offer_tokens = WebsocketMessage::OfferTokens {
             user_address: alice_address.clone(),
             token_mint: token_mint.clone(),
             amount: dec!(100.1337),
+            token_account: None,
+            mode: OfferMode::Exact,
         };
         let offer_json = serde_json::to_string(&offer_tokens)?;
         info!("Offer json: {:#?}", &offer_json);
@@ -258,40 +1148,20 @@ mod tests {
         // Because each client might receive some messages in different orders, we'll attempt to read a few times.
 
         for _ in 0..3 {
-            if let Some(Ok(msg)) = ws1.next().await {
-                if let Message::Text(payload) = msg {
-                    if let Ok(parsed) = serde_json::from_str::<WebsocketMessage>(&payload) {
-                        if let WebsocketMessage::TradeStateUpdate { offers, user_acted, status, tx } = parsed {
-                            if let Some(alice_map) = offers.get(&alice_address) {
-                                received_update_ws1 = true;
-                                // Check the data if needed:
-                                // let maybe_alice = offers.get(&alice_address);
-                                // assert!(maybe_alice.is_some(), "No 'Alice' user in update");
-                                // let alice_map = alice.unwrap();
-                                assert_eq!(alice_map.get(&token_mint), Some(&dec!(100.1337)));
-                            }
-                            
-                        }
-                    }
+            if let Some(Ok(Message::Text(payload))) = ws1.next().await {
+                if let Some(alice_amount) = offered_amount(&payload, &alice_address, &token_mint) {
+                    received_update_ws1 = true;
+                    assert_eq!(alice_amount, dec!(100.1337));
+                    break;
                 }
             }
         }
 
-        for _ in 0..2 {           
-            if let Some(Ok(msg)) = ws2.next().await {
-                if let Message::Text(payload) = msg {
-                    if let Ok(parsed) = serde_json::from_str::<WebsocketMessage>(&payload) {
-                        if let WebsocketMessage::TradeStateUpdate { offers, user_acted, status, tx } = parsed {
-                            if let Some(alice_map) = offers.get(&alice_address) {
-                                received_update_ws2 = true;
-                                // Check the data if needed:
-                                // let maybe_alice = offers.get(&alice_address);
-                                // assert!(maybe_alice.is_some(), "No 'Alice' user in update");
-                                // let alice_map = alice.unwrap();
-                                assert_eq!(alice_map.get(&token_mint), Some(&dec!(100.1337)));
-                            }
-                        }
-                    }
+        for _ in 0..3 {
+            if let Some(Ok(Message::Text(payload))) = ws2.next().await {
+                if let Some(alice_amount) = offered_amount(&payload, &alice_address, &token_mint) {
+                    received_update_ws2 = true;
+                    assert_eq!(alice_amount, dec!(100.1337));
                 }
             }
 
@@ -313,4 +1183,525 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn failed_request_should_send_error_to_sender_only() -> anyhow::Result<()> {
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let shared_sessions = Arc::new(SharedSessions::new(
+            Arc::new(TokenAmountCache::init()),
+            transaction_service,
+        ));
+        let app = Router::new().route(
+            "/ws/:session_id",
+            get({
+                let sessions = Arc::clone(&shared_sessions);
+                let token_service = Arc::new(TokenService::with_metadata(
+                    vec![],
+                    Arc::new(TokenAmountCache::init()),
+                ));
+                move |ws: WebSocketUpgrade, Path(session_id): Path<Uuid>| async move {
+                    ws.on_upgrade(move |socket| {
+                        handle_socket(socket, session_id, sessions, token_service)
+                    })
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(axum::serve(listener, app).into_future());
+
+        let session_id = Uuid::new_v4();
+        let (mut ws1, _resp1) = connect_async(format!("ws://{}/ws/{}", addr, session_id)).await?;
+        let (mut ws2, _resp2) = connect_async(format!("ws://{}/ws/{}", addr, session_id)).await?;
+        // The challenge sent on connect tells both clients joined
+        let alice = Keypair::new();
+        authenticate(&mut ws1, &alice).await?;
+        ws2.next().await.expect("no challenge received")?;
+        shared_sessions
+            .finish_trade(
+                &session_id,
+                &crate::confirmation::ConfirmationOutcome::Confirmed,
+            )
+            .await?;
+
+        let accept = WebsocketMessage::AcceptTrade {
+            user_address: alice.pubkey().to_string(),
+        };
+        ws1.send(Message::Text(serde_json::to_string(&accept)?.into()))
+            .await?;
+        let mut error = None;
+        for _ in 0..5 {
+            if let Some(Ok(Message::Text(payload))) = ws1.next().await {
+                if let Ok(WebsocketMessage::Error { code, message }) =
+                    serde_json::from_str(&payload)
+                {
+                    error = Some((code, message));
+                    break;
+                }
+            }
+        }
+        let (code, message) = error.expect("no Error received by the sender");
+        assert_eq!(code, "session_closed");
+        assert!(message.contains("Completed"), "{}", message);
+
+        // Anything sent to ws2 for the failed accept would arrive before the Identity reply
+        ws2.send(Message::Text(
+            serde_json::to_string(&WebsocketMessage::WhoAmI)?.into(),
+        ))
+        .await?;
+        loop {
+            let Some(Ok(Message::Text(payload))) = ws2.next().await else {
+                panic!("ws2 closed before the Identity reply");
+            };
+            match serde_json::from_str::<WebsocketMessage>(&payload)? {
+                WebsocketMessage::Identity { .. } => break,
+                WebsocketMessage::Error { .. } => panic!("Error sent to the other client"),
+                _ => {}
+            }
+        }
+
+        ws1.send(Message::Close(None)).await?;
+        ws2.send(Message::Close(None)).await?;
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_not_answering_pings_should_be_removed() -> anyhow::Result<()> {
+        use crate::config::{RuntimeConfig, SessionsConfig, TunableConfig};
+        use crate::trade_event_repository::AuditLog;
+
+        let runtime_config = RuntimeConfig::new(TunableConfig {
+            sessions: SessionsConfig {
+                ping_interval_ms: 50,
+                pong_timeout_ms: 200,
+                ..SessionsConfig::default()
+            },
+            ..TunableConfig::default()
+        });
+        let shared_sessions = Arc::new(SharedSessions::with_config(
+            Arc::new(TokenAmountCache::init()),
+            Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+                TestChainContext {},
+            ))),
+            AuditLog::disabled(),
+            None,
+            Arc::new(runtime_config),
+        ));
+        let app = Router::new().route(
+            "/ws/:session_id",
+            get({
+                let sessions = Arc::clone(&shared_sessions);
+                let token_service = Arc::new(TokenService::with_metadata(
+                    vec![],
+                    Arc::new(TokenAmountCache::init()),
+                ));
+                move |ws: WebSocketUpgrade, Path(session_id): Path<Uuid>| async move {
+                    ws.on_upgrade(move |socket| {
+                        handle_socket(socket, session_id, sessions, token_service)
+                    })
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(axum::serve(listener, app).into_future());
+
+        let silent_session = Uuid::new_v4();
+        // Pongs are only sent while the stream is read, this client never reads it
+        let (_silent, _resp) =
+            connect_async(format!("ws://{}/ws/{}", addr, silent_session)).await?;
+        let answering_session = Uuid::new_v4();
+        let (mut answering, _resp) =
+            connect_async(format!("ws://{}/ws/{}", addr, answering_session)).await?;
+        let reader = tokio::spawn(async move { while let Some(Ok(_)) = answering.next().await {} });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut session_ids = shared_sessions.session_ids();
+        session_ids.sort();
+        let mut expected = vec![silent_session, answering_session];
+        expected.sort();
+        assert_eq!(session_ids, expected);
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(shared_sessions.session_ids(), vec![answering_session]);
+
+        reader.abort();
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn malformed_mint_should_be_refused_without_touching_the_session() -> anyhow::Result<()> {
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let alice = Keypair::new();
+        let alice_address = alice.pubkey().to_string();
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            alice_address.clone(),
+            HashMap::from([("TokenA0OIl".to_string(), dec!(200.0))]),
+            TEST_MINT_DECIMALS,
+        );
+        let shared_sessions =
+            Arc::new(SharedSessions::new(token_amount_cache, transaction_service));
+        let app = Router::new().route(
+            "/ws/:session_id",
+            get({
+                let sessions = Arc::clone(&shared_sessions);
+                let token_service = Arc::new(TokenService::with_metadata(
+                    vec![],
+                    Arc::new(TokenAmountCache::init()),
+                ));
+                move |ws: WebSocketUpgrade, Path(session_id): Path<Uuid>| async move {
+                    ws.on_upgrade(move |socket| {
+                        handle_socket(socket, session_id, sessions, token_service)
+                    })
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(axum::serve(listener, app).into_future());
+
+        let session_id = Uuid::new_v4();
+        let (mut ws, _resp) = connect_async(format!("ws://{}/ws/{}", addr, session_id)).await?;
+        authenticate(&mut ws, &alice).await?;
+        // 0, O, I and l aren't part of the base58 alphabet
+        let offer = WebsocketMessage::OfferTokens {
+            user_address: alice_address.clone(),
+            token_mint: "TokenA0OIl".to_string(),
+            amount: dec!(1),
+            token_account: None,
+            mode: OfferMode::Exact,
+        };
+        ws.send(Message::Text(serde_json::to_string(&offer)?.into()))
+            .await?;
+        let mut error = None;
+        for _ in 0..5 {
+            if let Some(Ok(Message::Text(payload))) = ws.next().await {
+                if let Ok(WebsocketMessage::Error { code, message }) =
+                    serde_json::from_str(&payload)
+                {
+                    error = Some((code, message));
+                    break;
+                }
+            }
+        }
+        assert_eq!(
+            error,
+            Some((
+                "invalid_address".to_string(),
+                "TokenA0OIl is not a valid Solana address".to_string()
+            ))
+        );
+        assert!(shared_sessions
+            .get_offers(&session_id, &alice_address)
+            .is_err());
+
+        ws.send(Message::Close(None)).await?;
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_message_should_be_answered_with_error() -> anyhow::Result<()> {
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let shared_sessions = Arc::new(SharedSessions::new(
+            Arc::new(TokenAmountCache::init()),
+            transaction_service,
+        ));
+        let app = Router::new().route(
+            "/ws/:session_id",
+            get({
+                let sessions = Arc::clone(&shared_sessions);
+                let token_service = Arc::new(TokenService::with_metadata(
+                    vec![],
+                    Arc::new(TokenAmountCache::init()),
+                ));
+                move |ws: WebSocketUpgrade, Path(session_id): Path<Uuid>| async move {
+                    ws.on_upgrade(move |socket| {
+                        handle_socket(socket, session_id, sessions, token_service)
+                    })
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(axum::serve(listener, app).into_future());
+
+        let session_id = Uuid::new_v4();
+        let (mut ws, _resp) = connect_async(format!("ws://{}/ws/{}", addr, session_id)).await?;
+        // Unknown types are tolerated, a known one missing its fields is not
+        ws.send(Message::Text(r#"{"type":"AcceptTrade"}"#.into()))
+            .await?;
+        let mut error = None;
+        for _ in 0..5 {
+            if let Some(Ok(Message::Text(payload))) = ws.next().await {
+                if let Ok(WebsocketMessage::Error { code, message }) =
+                    serde_json::from_str(&payload)
+                {
+                    error = Some((code, message));
+                    break;
+                }
+            }
+        }
+        assert_eq!(
+            error,
+            Some(("invalid_message".to_string(), "Invalid message".to_string()))
+        );
+
+        ws.send(Message::Close(None)).await?;
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn offers_should_need_a_connection_authenticated_as_the_offering_wallet(
+    ) -> anyhow::Result<()> {
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let alice = Keypair::new();
+        let alice_address = alice.pubkey().to_string();
+        let bob_address = Pubkey::new_unique().to_string();
+        let token_mint = Pubkey::new_unique().to_string();
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        for user_address in [&alice_address, &bob_address] {
+            token_amount_cache.insert_token_amounts_with_decimals(
+                user_address.clone(),
+                HashMap::from([(token_mint.clone(), dec!(200.0))]),
+                TEST_MINT_DECIMALS,
+            );
+        }
+        let shared_sessions =
+            Arc::new(SharedSessions::new(token_amount_cache, transaction_service));
+        let app = Router::new().route(
+            "/ws/:session_id",
+            get({
+                let sessions = Arc::clone(&shared_sessions);
+                let token_service = Arc::new(TokenService::with_metadata(
+                    vec![],
+                    Arc::new(TokenAmountCache::init()),
+                ));
+                move |ws: WebSocketUpgrade, Path(session_id): Path<Uuid>| async move {
+                    ws.on_upgrade(move |socket| {
+                        handle_socket(socket, session_id, sessions, token_service)
+                    })
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(axum::serve(listener, app).into_future());
+        let session_id = Uuid::new_v4();
+        let offer = |user_address: &str| {
+            let offer = WebsocketMessage::OfferTokens {
+                user_address: user_address.to_string(),
+                token_mint: token_mint.clone(),
+                amount: dec!(1),
+                token_account: None,
+                mode: OfferMode::Exact,
+            };
+            Message::Text(serde_json::to_string(&offer).unwrap().into())
+        };
+        async fn next_error(ws: &mut ClientSocket) -> Option<(String, String)> {
+            while let Some(Ok(Message::Text(payload))) = ws.next().await {
+                if let Ok(WebsocketMessage::Error { code, message }) =
+                    serde_json::from_str(&payload)
+                {
+                    return Some((code, message));
+                }
+            }
+            None
+        }
+
+        // Signed by another keypair than the claimed wallet's
+        let (mut forger, _resp) = connect_async(format!("ws://{}/ws/{}", addr, session_id)).await?;
+        let forged = Keypair::new();
+        let reply = loop {
+            let Some(Ok(Message::Text(payload))) = forger.next().await else {
+                panic!("connection closed before the AuthChallenge");
+            };
+            if let Ok(WebsocketMessage::AuthChallenge { nonce }) = serde_json::from_str(&payload) {
+                let authenticate = WebsocketMessage::Authenticate {
+                    user_address: alice_address.clone(),
+                    signature: forged.sign_message(nonce.as_bytes()).to_string(),
+                };
+                forger
+                    .send(Message::Text(serde_json::to_string(&authenticate)?.into()))
+                    .await?;
+                break next_error(&mut forger).await;
+            }
+        };
+        assert_eq!(
+            reply.map(|(code, _)| code).as_deref(),
+            Some("authentication_failed")
+        );
+        forger.send(offer(&alice_address)).await?;
+        assert_eq!(
+            next_error(&mut forger)
+                .await
+                .map(|(code, _)| code)
+                .as_deref(),
+            Some("not_authenticated")
+        );
+
+        let (mut ws, _resp) = connect_async(format!("ws://{}/ws/{}", addr, session_id)).await?;
+        match authenticate(&mut ws, &alice).await? {
+            WebsocketMessage::Authenticated { user_address } => {
+                assert_eq!(user_address, alice_address)
+            }
+            other => panic!("Expected Authenticated, got {:?}", other),
+        }
+        ws.send(offer(&bob_address)).await?;
+        assert_eq!(
+            next_error(&mut ws).await,
+            Some((
+                "not_authenticated".to_string(),
+                format!(
+                    "Connection is not authenticated as {}, answer the AuthChallenge first",
+                    bob_address
+                )
+            ))
+        );
+        ws.send(offer(&alice_address)).await?;
+        let mut offered = None;
+        for _ in 0..5 {
+            if let Some(Ok(Message::Text(payload))) = ws.next().await {
+                offered = offered_amount(&payload, &alice_address, &token_mint);
+                if offered.is_some() {
+                    break;
+                }
+            }
+        }
+        assert_eq!(offered, Some(dec!(1)));
+        assert!(shared_sessions
+            .get_offers(&session_id, &bob_address)
+            .is_err());
+
+        forger.send(Message::Close(None)).await?;
+        ws.send(Message::Close(None)).await?;
+        server.abort();
+        Ok(())
+    }
+
+    #[test]
+    fn addresses_should_be_validated_only_where_offers_change() {
+        let wallet = Pubkey::new_unique().to_string();
+        let withdraw = |user_address: &str, token_mint: &str| WebsocketMessage::WithdrawTokens {
+            user_address: user_address.to_string(),
+            token_mint: token_mint.to_string(),
+            amount: dec!(1),
+        };
+        assert_eq!(
+            withdraw(&wallet, &Pubkey::new_unique().to_string()).validate_addresses(),
+            Ok(())
+        );
+        assert_eq!(
+            withdraw("Alice", &Pubkey::new_unique().to_string()).validate_addresses(),
+            Err(SessionError::InvalidAddress {
+                address: "Alice".to_string()
+            })
+        );
+        assert!(WebsocketMessage::AcceptTrade {
+            user_address: "x".to_string()
+        }
+        .validate_addresses()
+        .is_err());
+        assert_eq!(
+            WebsocketMessage::GetOffers {
+                user_address: "Alice".to_string()
+            }
+            .validate_addresses(),
+            Ok(())
+        );
+    }
+
+    #[tokio::test]
+    async fn get_offers_should_reply_with_offers_and_metadata() -> anyhow::Result<()> {
+        let transaction_service = Arc::new(TransactionService::<TestChainContext>::new(Arc::new(
+            TestChainContext {},
+        )));
+        let token_amount_cache = Arc::new(TokenAmountCache::init());
+        token_amount_cache.insert_token_amounts_with_decimals(
+            "Alice".to_string(),
+            HashMap::from([("TokenA".to_string(), dec!(200.0))]),
+            TEST_MINT_DECIMALS,
+        );
+        let token_service = Arc::new(TokenService::with_metadata(
+            vec![crate::metadata_repository::MetadataEntity {
+                mint_address: "TokenA".to_string(),
+                name: Some("Token A".to_string()),
+                symbol: Some("TKA".to_string()),
+                uri: None,
+                image: None,
+            }],
+            Arc::clone(&token_amount_cache),
+        ));
+        let shared_sessions =
+            Arc::new(SharedSessions::new(token_amount_cache, transaction_service));
+        let session_id = Uuid::new_v4();
+        let (alice_tx, _alice_rx) = mpsc::channel(10);
+        shared_sessions.add_client(session_id, Uuid::new_v4(), alice_tx);
+        shared_sessions.add_tokens_offer(&session_id, "Alice", "TokenA".to_string(), dec!(12))?;
+
+        let app = Router::new().route(
+            "/ws/:session_id",
+            get({
+                let sessions = Arc::clone(&shared_sessions);
+                move |ws: WebSocketUpgrade, Path(session_id): Path<Uuid>| async move {
+                    ws.on_upgrade(move |socket| {
+                        handle_socket(socket, session_id, sessions, token_service)
+                    })
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(axum::serve(listener, app).into_future());
+
+        let (mut ws, _resp) = connect_async(format!("ws://{}/ws/{}", addr, session_id)).await?;
+        let get_offers = WebsocketMessage::GetOffers {
+            user_address: "Alice".to_string(),
+        };
+        ws.send(Message::Text(serde_json::to_string(&get_offers)?.into()))
+            .await?;
+
+        let mut offers = None;
+        for _ in 0..5 {
+            if let Some(Ok(Message::Text(payload))) = ws.next().await {
+                if let Ok(WebsocketMessage::Offers {
+                    user_address,
+                    offers: Some(received),
+                    error,
+                }) = serde_json::from_str::<WebsocketMessage>(&payload)
+                {
+                    assert_eq!(user_address, "Alice");
+                    assert_eq!(error, None);
+                    offers = Some(received);
+                    break;
+                }
+            }
+        }
+        let offers = offers.expect("no Offers reply received");
+        assert_eq!(offers.len(), 1);
+        assert_eq!(offers[0].mint, "TokenA");
+        assert_eq!(offers[0].amount, dec!(12));
+        assert_eq!(
+            offers[0]
+                .metadata
+                .as_ref()
+                .and_then(|m| m.symbol.as_deref()),
+            Some("TKA")
+        );
+
+        ws.send(Message::Close(None)).await?;
+        server.abort();
+        Ok(())
+    }
 }
@@ -1,119 +1,887 @@
 use anyhow::{anyhow, Error, Result};
-use rust_decimal::Decimal;
-use rust_decimal_macros::dec;
+use base64::{engine::general_purpose, Engine as _};
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::{debug, log_enabled, warn, Level};
+use rust_decimal::prelude::*;
 use solana_sdk::{
+    compute_budget::ComputeBudgetInstruction,
+    hash::hashv,
     instruction::{AccountMeta, Instruction},
+    message::Message,
+    packet::PACKET_DATA_SIZE,
     pubkey::Pubkey,
+    signature::{read_keypair_file, Keypair, Signer},
     transaction::Transaction,
 };
 use spl_associated_token_account::get_associated_token_address;
-use std::{collections::HashMap, str::FromStr, sync::Arc};
+use std::{
+    cmp,
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    chain_context::ChainContext,
+    config::{
+        FeePayerPolicy, PriorityFeeConfig, RuntimeConfig, TransactionConfig, TransactionEncoding,
+        TunableConfig,
+    },
+};
+
+/// Name of the trade instruction in the trade_with_me Anchor program.
+const TRADE_INSTRUCTION_NAME: &str = "trade";
+
+/// Anchor routes instructions by the first 8 bytes of `sha256("global:<instruction name>")`.
+pub fn trade_instruction_discriminator() -> [u8; 8] {
+    let hash = hashv(&[b"global:", TRADE_INSTRUCTION_NAME.as_bytes()]);
+    hash.to_bytes()[..8].try_into().unwrap()
+}
+
+/// Arguments of the trade instruction, borsh serialized after the discriminator.
+///
+/// Layout: `user1_transfers: u8`, `user2_transfers: u8`, then `amounts` as a borsh vec
+/// (`u32` length followed by little endian `u64`s). The first `user1_transfers` amounts are
+/// sent by the first participant and the remaining `user2_transfers` by the second, in the same
+/// order as the mint accounts. Amounts are in the mint's base units (`ui amount * 10^decimals`).
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct TradeInstructionData {
+    pub user1_transfers: u8,
+    pub user2_transfers: u8,
+    pub amounts: Vec<u64>,
+}
+
+pub const TRANSACTION_BUILDING_DISABLED: &str = "Transaction building is disabled on this server";
+
+/// Size of an SPL token account without extensions.
+const TOKEN_ACCOUNT_LEN: usize = 165;
+
+/// Bytes a transfer adds to the transaction: the mint, sender and receiver account keys, their
+/// indices in the trade instruction and the amount.
+const TRANSFER_LEN: usize = 3 * 32 + 3 + 8;
+
+/// Offered amounts in the mint's base units per user address and mint.
+pub type BaseUnitOffers = HashMap<String, HashMap<String, u64>>;
+
+/// Token account per user address and mint.
+pub type SourceAccounts = HashMap<String, HashMap<String, String>>;
+
+/// Token accounts each participant holds a mint in, with their balances in base units, as
+/// participant -> mint -> accounts.
+pub type HeldAccounts = HashMap<String, HashMap<String, Vec<(String, u64)>>>;
+
+/// Trade note per user address.
+pub type Memos = HashMap<String, String>;
+
+/// Mints each user offers "up to" the offered amount instead of exactly.
+pub type UpToOffers = HashMap<String, HashSet<String>>;
+
+/// A net transfer of one mint from one participant to the other.
+#[derive(Clone)]
+struct Transfer {
+    from_user1: bool,
+    sender: Pubkey,
+    mint: Pubkey,
+    sender_ata: Pubkey,
+    receiver_ata: Pubkey,
+    amount: u64,
+}
+
+/// Accounts the senders chose to send from and the ones they hold, see `transfer_sources`.
+#[derive(Clone, Copy)]
+struct SenderAccounts<'a> {
+    chosen: &'a SourceAccounts,
+    held: &'a HeldAccounts,
+}
 
-use crate::chain_context::ChainContext;
+/// Instructions of a trade transaction still missing its blockhash.
+struct TradeInstructions {
+    instructions: Vec<Instruction>,
+    fee_payer: Pubkey,
+    transfers: Vec<Transfer>,
+}
+
+/// Token accounts of the trade no longer hold what they have to send, e.g. because tokens
+/// were moved out of the wallet during the negotiation.
+#[derive(Debug, PartialEq)]
+pub struct BalanceChangedError {
+    pub shortfalls: Vec<Shortfall>,
+}
+
+/// A net transfer its token account can't cover, amounts in base units.
+#[derive(Debug, PartialEq)]
+pub struct Shortfall {
+    pub sender: Pubkey,
+    pub mint: Pubkey,
+    pub needed: u64,
+    pub held: u64,
+}
+
+impl std::fmt::Display for BalanceChangedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let shortfalls: Vec<String> = self
+            .shortfalls
+            .iter()
+            .map(|shortfall| {
+                format!(
+                    "{} holds {} of {} but has to send {}",
+                    shortfall.sender, shortfall.held, shortfall.mint, shortfall.needed
+                )
+            })
+            .collect();
+        write!(f, "Balance changed: {}", shortfalls.join(", "))
+    }
+}
+
+impl std::error::Error for BalanceChangedError {}
+
+/// The signed transaction would be larger than the `PACKET_DATA_SIZE` bytes Solana accepts.
+#[derive(Debug, PartialEq)]
+pub struct TransactionTooLargeError {
+    pub size: usize,
+    pub transfers: usize,
+    /// Transfers to drop for the transaction to fit.
+    pub excess_transfers: usize,
+}
+
+impl std::fmt::Display for TransactionTooLargeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Trade transaction would take {} bytes but Solana accepts at most {}, {} of its {} token transfers have to go",
+            self.size, PACKET_DATA_SIZE, self.excess_transfers, self.transfers
+        )
+    }
+}
+
+impl std::error::Error for TransactionTooLargeError {}
 
 pub struct TransactionService<T: ChainContext> {
     pub chain_context: Arc<T>,
+    runtime_config: Arc<RuntimeConfig>,
+    /// Keypair of the server fee payer with the path it was read from, re-read when a config
+    /// reload points elsewhere.
+    server_keypair: Mutex<Option<(String, Arc<Keypair>)>>,
 }
 
 impl<T: ChainContext> TransactionService<T> {
     pub fn new(chain_context: Arc<T>) -> Self {
-        TransactionService { chain_context }
+        TransactionService::with_config(chain_context, TransactionConfig::default())
+    }
+
+    pub fn with_config(chain_context: Arc<T>, config: TransactionConfig) -> Self {
+        TransactionService::with_runtime_config(
+            chain_context,
+            Arc::new(RuntimeConfig::new(TunableConfig {
+                transaction: config,
+                ..TunableConfig::default()
+            })),
+        )
+    }
+
+    /// Like `with_config`, reading the `transaction` settings from `runtime_config` for every
+    /// transaction built so they follow config reloads.
+    pub fn with_runtime_config(chain_context: Arc<T>, runtime_config: Arc<RuntimeConfig>) -> Self {
+        TransactionService {
+            chain_context,
+            runtime_config,
+            server_keypair: Mutex::new(None),
+        }
     }
 
     pub async fn create_transaction(
         &self,
-        items: Arc<HashMap<String, HashMap<String, Decimal>>>,
+        items: Arc<BaseUnitOffers>,
+        initiator: &str,
     ) -> Result<Transaction> {
+        self.create_transaction_with_sources(
+            items,
+            &SourceAccounts::new(),
+            &HeldAccounts::new(),
+            &Memos::new(),
+            &UpToOffers::new(),
+            initiator,
+        )
+        .await
+        .map(|(tx, _)| tx)
+    }
+
+    /// Like `create_transaction`, sending each offered mint from the token account given in
+    /// `source_accounts` and from the sender's ATA where none is given. Sessions only take
+    /// source accounts the sender's fetched tokens list for the mint. Without a chosen account,
+    /// an amount the ATA doesn't hold alone is drawn from the sender's `held_accounts` too.
+    ///
+    /// Returns the last block height the transaction's blockhash is valid at along with it.
+    pub async fn create_transaction_with_sources(
+        &self,
+        items: Arc<BaseUnitOffers>,
+        source_accounts: &SourceAccounts,
+        held_accounts: &HeldAccounts,
+        memos: &Memos,
+        up_to_offers: &UpToOffers,
+        initiator: &str,
+    ) -> Result<(Transaction, u64)> {
+        let (mut txs, last_valid_block_height) = self
+            .build_transactions(
+                items,
+                SenderAccounts {
+                    chosen: source_accounts,
+                    held: held_accounts,
+                },
+                memos,
+                up_to_offers,
+                initiator,
+                None,
+                0,
+            )
+            .await?;
+        Ok((txs.remove(0), last_valid_block_height))
+    }
+
+    /// Like `create_transaction_with_sources`, but splits the net transfers into transactions
+    /// of at most `max_transfers_per_transaction` transfers when that is set. Each transaction
+    /// settles on its own, so a split trade can end up partially settled when a later one fails.
+    /// The participants' notes are added to the first transaction only.
+    ///
+    /// The first `settled` transactions already landed and are left out, along with their
+    /// balance checks, at least the last transaction is always built.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create_transactions_with_sources(
+        &self,
+        items: Arc<BaseUnitOffers>,
+        source_accounts: &SourceAccounts,
+        held_accounts: &HeldAccounts,
+        memos: &Memos,
+        up_to_offers: &UpToOffers,
+        initiator: &str,
+        settled: usize,
+    ) -> Result<(Vec<Transaction>, u64)> {
+        self.build_transactions(
+            items,
+            SenderAccounts {
+                chosen: source_accounts,
+                held: held_accounts,
+            },
+            memos,
+            up_to_offers,
+            initiator,
+            self.runtime_config
+                .get()
+                .transaction
+                .max_transfers_per_transaction,
+            settled,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn build_transactions(
+        &self,
+        items: Arc<BaseUnitOffers>,
+        sender_accounts: SenderAccounts<'_>,
+        memos: &Memos,
+        up_to_offers: &UpToOffers,
+        initiator: &str,
+        max_transfers: Option<usize>,
+        settled: usize,
+    ) -> Result<(Vec<Transaction>, u64)> {
+        let mut batches = self
+            .build_instructions(
+                items,
+                sender_accounts,
+                memos,
+                up_to_offers,
+                initiator,
+                max_transfers,
+            )
+            .await?;
+        // Their transfers happened, the balances they emptied are not checked again
+        batches.drain(..cmp::min(settled, batches.len().saturating_sub(1)));
+        for batch in &batches {
+            check_transaction_size(batch)?;
+        }
+        let runtime_config = self.runtime_config.get();
+        let config = &runtime_config.transaction;
+        if config.check_token_balances {
+            let transfers: Vec<&Transfer> =
+                batches.iter().flat_map(|batch| &batch.transfers).collect();
+            self.check_token_balances(&transfers).await?;
+        }
+
+        let (recent_blockhash, last_valid_block_height) =
+            self.chain_context.get_latest_blockhash().await?;
+        let mut txs = Vec::with_capacity(batches.len());
+        let mut receiver_atas = Vec::new();
+        let mut fee_payer = None;
+        for batch in batches {
+            let mut tx = Transaction::new_with_payer(&batch.instructions, Some(&batch.fee_payer));
+            tx.message.recent_blockhash = recent_blockhash;
+            txs.push(tx);
+            receiver_atas.extend(batch.transfers.iter().map(|transfer| transfer.receiver_ata));
+            fee_payer = Some(batch.fee_payer);
+        }
+        // A mint sent from several accounts has one receiving account
+        receiver_atas.sort();
+        receiver_atas.dedup();
+        if let (true, Some(fee_payer)) = (config.check_fee_payer_balance, fee_payer) {
+            self.check_fee_payer_balance(&txs, &fee_payer, &receiver_atas)
+                .await?;
+        }
+        Ok((txs, last_valid_block_height))
+    }
+
+    /// Accounts the transaction built from the same arguments would reference, in the order of
+    /// its message, without fetching a blockhash or checking the fee payer's balance.
+    pub async fn preview_accounts(
+        &self,
+        items: Arc<BaseUnitOffers>,
+        source_accounts: &SourceAccounts,
+        held_accounts: &HeldAccounts,
+        memos: &Memos,
+        up_to_offers: &UpToOffers,
+        initiator: &str,
+    ) -> Result<Vec<AccountMeta>> {
+        let TradeInstructions {
+            instructions,
+            fee_payer,
+            ..
+        } = self
+            .build_instructions(
+                items,
+                SenderAccounts {
+                    chosen: source_accounts,
+                    held: held_accounts,
+                },
+                memos,
+                up_to_offers,
+                initiator,
+                None,
+            )
+            .await?
+            .remove(0);
+        let message = Message::new(&instructions, Some(&fee_payer));
+        Ok(message
+            .account_keys
+            .iter()
+            .enumerate()
+            .map(|(i, pubkey)| AccountMeta {
+                pubkey: *pubkey,
+                is_signer: message.is_signer(i),
+                is_writable: message.is_maybe_writable(i, None),
+            })
+            .collect())
+    }
+
+    /// Instructions of the trade, one set per transaction of at most `max_transfers` transfers,
+    /// a single one when `max_transfers` is `None`.
+    async fn build_instructions(
+        &self,
+        items: Arc<BaseUnitOffers>,
+        sender_accounts: SenderAccounts<'_>,
+        memos: &Memos,
+        up_to_offers: &UpToOffers,
+        initiator: &str,
+        max_transfers: Option<usize>,
+    ) -> Result<Vec<TradeInstructions>> {
+        let runtime_config = self.runtime_config.get();
+        let config = &runtime_config.transaction;
+        if !config.trading_enabled {
+            return Err(Error::msg(TRANSACTION_BUILDING_DISABLED));
+        }
         if items.len() != 2 {
             return Err(Error::msg("Invalid number of users in trade state"));
         }
-        let mut users = items.keys();
-        let user1 = users.next().unwrap();
-        let user2 = users.next().unwrap();
-        let user1_offers = items.get(user1).unwrap();
-        let user2_offers = items.get(user2).unwrap();
+        let initiator_pubkey = Pubkey::from_str(initiator)?;
+        let mut participants = items
+            .keys()
+            .map(|user| Ok((Pubkey::from_str(user)?, user)))
+            .collect::<Result<Vec<(Pubkey, &String)>>>()?;
+        if !participants
+            .iter()
+            .any(|(pubkey, _)| *pubkey == initiator_pubkey)
+        {
+            return Err(anyhow!(
+                "Trade initiator {} is not a participant",
+                initiator
+            ));
+        }
+        // The message layout must not depend on HashMap iteration order, so participants
+        // and mints are always laid out sorted by pubkey
+        participants.sort_by_key(|(pubkey, _)| *pubkey);
+        let (user1, user1_address) = participants[0];
+        let (user2, user2_address) = participants[1];
+        let user1_offers = items.get(user1_address).unwrap();
+        let user2_offers = items.get(user2_address).unwrap();
 
-        let (offers1, offers2) = cancel_out_trade_tokens(user1_offers, user2_offers);
+        let no_up_to_offers = HashSet::new();
+        let (user1_offers, user2_offers) = match_up_to_offers(
+            user1_offers,
+            up_to_offers.get(user1_address).unwrap_or(&no_up_to_offers),
+            user2_offers,
+            up_to_offers.get(user2_address).unwrap_or(&no_up_to_offers),
+        );
+        let (offers1, offers2) = cancel_out_trade_tokens(&user1_offers, &user2_offers);
 
         if offers1.is_empty() && offers2.is_empty() {
             return Err(anyhow!("No point creating a transaction, no offers"));
         }
-        let mut sender_atas: Vec<Pubkey> = vec![];
-        let mut receiver_atas: Vec<Pubkey> = vec![];
-        let mut token_mints: Vec<Pubkey> = vec![];
-        let mut amounts: Vec<&Decimal> = vec![];
-
-        for (token, amount) in &offers1 {
-            let sender_ata =
-                get_associated_token_address(&Pubkey::from_str(user1)?, &Pubkey::from_str(token)?);
-            let receiver_ata =
-                get_associated_token_address(&Pubkey::from_str(user2)?, &Pubkey::from_str(token)?);
-
-            sender_atas.push(sender_ata);
-            receiver_atas.push(receiver_ata);
-            token_mints.push(Pubkey::from_str(token)?);
-            amounts.push(amount);
-        }
-        for (token, amount) in &offers2 {
-            let sender_ata =
-                get_associated_token_address(&Pubkey::from_str(user2)?, &Pubkey::from_str(token)?);
-            let receiver_ata =
-                get_associated_token_address(&Pubkey::from_str(user1)?, &Pubkey::from_str(token)?);
-
-            sender_atas.push(sender_ata);
-            receiver_atas.push(receiver_ata);
-            token_mints.push(Pubkey::from_str(token)?);
-            amounts.push(amount);
-        }
-
-        // dbg!("Senders: {}", sender_atas.len());
-        // dbg!("Receivers: {}", receiver_atas.len());
-        // dbg!("Token mints: {}", token_mints.len());
-        // dbg!("Amounts: {}", amounts.len());
-
-        let mut accounts = vec![
-            AccountMeta::new(Pubkey::from_str(user1)?, true),
-            AccountMeta::new(Pubkey::from_str(user2)?, true),
-        ];
-        let remaining_accounts: Vec<AccountMeta> = [
-            token_mints
-                .iter()
-                .map(|acc| AccountMeta::new_readonly(*acc, false))
-                .collect::<Vec<AccountMeta>>(),
-            [sender_atas, receiver_atas]
-                .concat()
-                .iter()
-                .map(|acc| AccountMeta::new(*acc, false))
-                .collect(),
-        ]
-        .concat();
+        let mut transfers: Vec<Transfer> = vec![];
+        for (from_user1, sender, sender_address, receiver, offers) in [
+            (true, user1, user1_address, user2, &offers1),
+            (false, user2, user2_address, user1, &offers2),
+        ] {
+            for (token, amount) in sorted_by_mint(offers)? {
+                let chosen_source = sender_accounts
+                    .chosen
+                    .get(sender_address)
+                    .and_then(|sources| sources.get(&token.to_string()));
+                let held = sender_accounts
+                    .held
+                    .get(sender_address)
+                    .and_then(|held| held.get(&token.to_string()));
+                for (sender_ata, amount) in
+                    transfer_sources(&sender, &token, amount, chosen_source, held)?
+                {
+                    transfers.push(Transfer {
+                        from_user1,
+                        sender,
+                        sender_ata,
+                        receiver_ata: get_associated_token_address(&receiver, &token),
+                        mint: token,
+                        amount,
+                    });
+                }
+            }
+        }
 
-        // dbg!("Remaining acocunts len: {}", remaining_accounts.len());
-        // dbg!("Remaining acocunts: {}", &remaining_accounts);
+        let fee_payer = match &config.fee_payer {
+            FeePayerPolicy::Initiator => initiator_pubkey,
+            FeePayerPolicy::Server { keypair_path } => self.server_keypair(keypair_path)?.pubkey(),
+        };
+        let compute_unit_price = match &config.priority_fee {
+            Some(priority_fee) => {
+                let mut writable = vec![user1, user2];
+                writable.extend(
+                    transfers
+                        .iter()
+                        .flat_map(|transfer| [transfer.sender_ata, transfer.receiver_ata]),
+                );
+                self.compute_unit_price(priority_fee, &writable).await
+            }
+            None => 0,
+        };
+        let batch_size = max_transfers.unwrap_or(transfers.len()).max(1);
+        let mut batches = vec![];
+        for (batch, transfers) in transfers.chunks(batch_size).enumerate() {
+            let instruction = self.trade_instruction(user1, user2, transfers)?;
+            if config.log_details && log_enabled!(Level::Debug) {
+                debug!("Trade instruction: {}", describe_instruction(&instruction));
+            }
 
-        accounts.extend(remaining_accounts);
-        // dbg!("All accounts len: {}", accounts.len());
-        // dbg!("All accounts: {}", &accounts);
+            let mut instructions = vec![];
+            if let Some(priority_fee) = &config.priority_fee {
+                instructions.push(ComputeBudgetInstruction::set_compute_unit_limit(
+                    priority_fee.compute_unit_limit,
+                ));
+                instructions.push(ComputeBudgetInstruction::set_compute_unit_price(
+                    compute_unit_price,
+                ));
+            }
+            instructions.push(instruction);
+            if config.include_memos && batch == 0 {
+                // Signed by the author, who signs the trade anyway
+                for (user, user_address) in [(user1, user1_address), (user2, user2_address)] {
+                    if let Some(memo) = memos.get(user_address) {
+                        instructions.push(spl_memo::build_memo(memo.as_bytes(), &[&user]));
+                    }
+                }
+            }
+            batches.push(TradeInstructions {
+                instructions,
+                fee_payer,
+                transfers: transfers.to_vec(),
+            });
+        }
+        Ok(batches)
+    }
 
-        let data = amounts.into_iter().flat_map(|d| d.serialize()).collect();
+    /// The configured compute unit price, raised to the median price recent slots paid to
+    /// write `writable` when estimating from recent fees, up to the configured cap.
+    async fn compute_unit_price(
+        &self,
+        priority_fee: &PriorityFeeConfig,
+        writable: &[Pubkey],
+    ) -> u64 {
+        let configured = priority_fee.compute_unit_price_micro_lamports;
+        if !priority_fee.estimate_from_recent_fees {
+            return configured;
+        }
+        let mut fees = match self
+            .chain_context
+            .get_recent_prioritization_fees(writable)
+            .await
+        {
+            Ok(fees) => fees,
+            Err(e) => {
+                warn!(
+                    "Unable to fetch recent prioritization fees, paying {}: {}",
+                    configured, e
+                );
+                return configured;
+            }
+        };
+        fees.sort_unstable();
+        let price = fees
+            .get(fees.len() / 2)
+            .copied()
+            .unwrap_or(0)
+            .max(configured);
+        priority_fee
+            .max_compute_unit_price_micro_lamports
+            .map_or(price, |max| price.min(max.max(configured)))
+    }
 
-        let instruction = Instruction {
+    /// Trade instruction moving `transfers`, the ones sent by `user1` coming first.
+    fn trade_instruction(
+        &self,
+        user1: Pubkey,
+        user2: Pubkey,
+        transfers: &[Transfer],
+    ) -> Result<Instruction> {
+        let user1_transfers = transfers
+            .iter()
+            .filter(|transfer| transfer.from_user1)
+            .count();
+        let mut accounts = vec![AccountMeta::new(user1, true), AccountMeta::new(user2, true)];
+        accounts.extend(
+            transfers
+                .iter()
+                .map(|transfer| AccountMeta::new_readonly(transfer.mint, false)),
+        );
+        accounts.extend(
+            transfers
+                .iter()
+                .map(|transfer| AccountMeta::new(transfer.sender_ata, false)),
+        );
+        accounts.extend(
+            transfers
+                .iter()
+                .map(|transfer| AccountMeta::new(transfer.receiver_ata, false)),
+        );
+
+        let instruction_data = TradeInstructionData {
+            user1_transfers: u8::try_from(user1_transfers)?,
+            user2_transfers: u8::try_from(transfers.len() - user1_transfers)?,
+            amounts: transfers.iter().map(|transfer| transfer.amount).collect(),
+        };
+        let mut data = trade_instruction_discriminator().to_vec();
+        data.extend(borsh::to_vec(&instruction_data)?);
+
+        Ok(Instruction {
             program_id: self.chain_context.get_trade_with_me_program_id(),
             accounts,
             data,
+        })
+    }
+
+    /// Re-reads the token accounts the transfers are sent from, one RPC call per sender, since
+    /// the balances offers were checked against may be outdated by now.
+    async fn check_token_balances(&self, transfers: &[&Transfer]) -> Result<()> {
+        let mut shortfalls = vec![];
+        for from_user1 in [true, false] {
+            let sent: Vec<&Transfer> = transfers
+                .iter()
+                .copied()
+                .filter(|transfer| transfer.from_user1 == from_user1)
+                .collect();
+            if sent.is_empty() {
+                continue;
+            }
+            let accounts: Vec<Pubkey> = sent.iter().map(|transfer| transfer.sender_ata).collect();
+            let balances = self
+                .chain_context
+                .get_token_account_balances(&accounts)
+                .await?;
+            for (transfer, held) in sent.into_iter().zip(balances) {
+                let held = held.unwrap_or(0);
+                if held < transfer.amount {
+                    shortfalls.push(Shortfall {
+                        sender: transfer.sender,
+                        mint: transfer.mint,
+                        needed: transfer.amount,
+                        held,
+                    });
+                }
+            }
+        }
+        if shortfalls.is_empty() {
+            Ok(())
+        } else {
+            Err(BalanceChangedError { shortfalls }.into())
+        }
+    }
+
+    async fn check_fee_payer_balance(
+        &self,
+        txs: &[Transaction],
+        fee_payer: &Pubkey,
+        receiver_atas: &[Pubkey],
+    ) -> Result<()> {
+        let missing_accounts = self
+            .chain_context
+            .get_missing_accounts(receiver_atas)
+            .await?;
+        let rent = if missing_accounts.is_empty() {
+            0
+        } else {
+            self.chain_context
+                .get_minimum_balance_for_rent(TOKEN_ACCOUNT_LEN)
+                .await?
+                * missing_accounts.len() as u64
         };
+        let mut fee = 0;
+        for tx in txs {
+            fee += self.estimate_fee(tx).await?;
+        }
+        let balance = self.chain_context.get_balance(fee_payer).await?;
+        if balance < rent + fee {
+            return Err(anyhow!(
+                "Insufficient SOL for fees: fee payer {} holds {} lamports but needs {} ({} for fees, {} rent for {} new token accounts)",
+                fee_payer,
+                balance,
+                rent + fee,
+                fee,
+                rent,
+                missing_accounts.len()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn trading_enabled(&self) -> bool {
+        self.runtime_config.get().transaction.trading_enabled
+    }
+
+    /// Whether a configured server wallet pays the fees, leaving participants nothing to choose.
+    pub fn server_pays_fees(&self) -> bool {
+        matches!(
+            self.runtime_config.get().transaction.fee_payer,
+            FeePayerPolicy::Server { .. }
+        )
+    }
+
+    /// Wallet paying the fees under `FeePayerPolicy::Server`, failing when its keypair can't be
+    /// read. `None` when the initiator pays.
+    pub fn server_fee_payer(&self) -> Result<Option<Pubkey>> {
+        match &self.runtime_config.get().transaction.fee_payer {
+            FeePayerPolicy::Initiator => Ok(None),
+            FeePayerPolicy::Server { keypair_path } => {
+                Ok(Some(self.server_keypair(keypair_path)?.pubkey()))
+            }
+        }
+    }
+
+    /// Adds the server wallet's signature to `tx` when the server pays its fees, transactions a
+    /// participant pays for are left alone. Signing again after the blockhash changed replaces
+    /// the signature.
+    pub fn sign_as_fee_payer(&self, tx: &mut Transaction) -> Result<()> {
+        let runtime_config = self.runtime_config.get();
+        let FeePayerPolicy::Server { keypair_path } = &runtime_config.transaction.fee_payer else {
+            return Ok(());
+        };
+        let keypair = self.server_keypair(keypair_path)?;
+        if tx.message.account_keys.first() == Some(&keypair.pubkey()) {
+            let recent_blockhash = tx.message.recent_blockhash;
+            tx.try_partial_sign(&[keypair.as_ref()], recent_blockhash)?;
+        }
+        Ok(())
+    }
+
+    fn server_keypair(&self, keypair_path: &str) -> Result<Arc<Keypair>> {
+        let mut server_keypair = self.server_keypair.lock().unwrap();
+        if let Some((path, keypair)) = server_keypair.as_ref() {
+            if path == keypair_path {
+                return Ok(Arc::clone(keypair));
+            }
+        }
+        let keypair = read_keypair_file(keypair_path)
+            .map_err(|e| anyhow!("Unable to read fee payer keypair {}: {}", keypair_path, e))?;
+        let keypair = Arc::new(keypair);
+        *server_keypair = Some((keypair_path.to_string(), Arc::clone(&keypair)));
+        Ok(keypair)
+    }
 
-        let recent_blockhash = self.chain_context.get_latest_blockhash().await?;
-        let mut tx = Transaction::new_with_payer(&[instruction], Some(&Pubkey::from_str(user1)?));
-        tx.message.recent_blockhash = recent_blockhash;
-        Ok(tx)
+    pub fn default_encoding(&self) -> TransactionEncoding {
+        self.runtime_config.get().transaction.encoding
     }
+
+    pub async fn estimate_fee(&self, tx: &Transaction) -> Result<u64> {
+        self.chain_context.get_fee_for_message(tx.message()).await
+    }
+}
+
+/// Fails with a `TransactionTooLargeError` when the signed transaction of `batch` wouldn't fit
+/// into a packet.
+fn check_transaction_size(batch: &TradeInstructions) -> Result<()> {
+    let message = Message::new(&batch.instructions, Some(&batch.fee_payer));
+    // Unsigned transactions carry a placeholder for every required signature
+    let size = bincode::serialized_size(&Transaction::new_unsigned(message))? as usize;
+    if size <= PACKET_DATA_SIZE {
+        return Ok(());
+    }
+    Err(TransactionTooLargeError {
+        size,
+        transfers: batch.transfers.len(),
+        excess_transfers: (size - PACKET_DATA_SIZE).div_ceil(TRANSFER_LEN),
+    }
+    .into())
+}
+
+pub fn encode_transaction(tx: &Transaction, encoding: TransactionEncoding) -> Result<String> {
+    let bytes = bincode::serialize(tx)?;
+    Ok(match encoding {
+        TransactionEncoding::Base58 => bs58::encode(bytes).into_string(),
+        TransactionEncoding::Base64 => general_purpose::STANDARD.encode(bytes),
+    })
+}
+
+/// Ordered accounts, program id and data length of an instruction. Only public keys and sizes
+/// are included, the instruction data itself is never printed.
+pub fn describe_instruction(instruction: &Instruction) -> String {
+    let accounts = instruction
+        .accounts
+        .iter()
+        .map(|meta| {
+            format!(
+                "{}{}{}",
+                meta.pubkey,
+                if meta.is_signer { " (signer)" } else { "" },
+                if meta.is_writable { " (writable)" } else { "" }
+            )
+        })
+        .collect::<Vec<String>>();
+    format!(
+        "program_id: {}, data_len: {}, accounts ({}): [{}]",
+        instruction.program_id,
+        instruction.data.len(),
+        accounts.len(),
+        accounts.join(", ")
+    )
+}
+
+/// Most decimals a mint can have for a whole token to fit into u64 base units.
+pub const MAX_MINT_DECIMALS: u8 = 19;
+
+/// Converts a ui amount into the mint's base units, failing if the amount has more decimal
+/// places than the mint supports or a whole token of the mint doesn't fit into u64.
+pub fn to_base_units(amount: Decimal, decimals: u8) -> Result<u64> {
+    let unit = 10u64
+        .checked_pow(u32::from(decimals))
+        .ok_or_else(|| anyhow!("Mints with {} decimals are not supported", decimals))?;
+    let scaled = Decimal::from(unit)
+        .checked_mul(amount)
+        .ok_or_else(|| anyhow!("Amount {} is too large", amount))?;
+    if !scaled.fract().is_zero() {
+        return Err(anyhow!(
+            "Amount {} has more than {} decimal places",
+            amount,
+            decimals
+        ));
+    }
+    scaled
+        .to_u64()
+        .ok_or_else(|| anyhow!("Amount {} does not fit into u64 base units", amount))
+}
+
+/// Converts an amount in the mint's base units back into a ui amount, failing for more
+/// decimals than `Decimal` can scale by.
+pub fn from_base_units(amount: u64, decimals: u8) -> Result<Decimal> {
+    Decimal::try_from_i128_with_scale(i128::from(amount), u32::from(decimals))
+        .map(|amount| amount.normalize())
+        .map_err(|_| anyhow!("Mints with {} decimals are not supported", decimals))
+}
+
+fn sorted_by_mint(offers: &HashMap<String, u64>) -> Result<Vec<(Pubkey, u64)>> {
+    let mut sorted = offers
+        .iter()
+        .map(|(token, amount)| Ok((Pubkey::from_str(token)?, *amount)))
+        .collect::<Result<Vec<(Pubkey, u64)>>>()?;
+    sorted.sort_by_key(|(token, _)| *token);
+    Ok(sorted)
+}
+
+/// Token accounts `amount` of `mint` is sent from and how much from each. A chosen source sends
+/// all of it. Otherwise the ATA does when it holds enough, or when `held` doesn't cover the
+/// amount either, and the remainder comes from the largest other accounts.
+fn transfer_sources(
+    sender: &Pubkey,
+    mint: &Pubkey,
+    amount: u64,
+    chosen_source: Option<&String>,
+    held: Option<&Vec<(String, u64)>>,
+) -> Result<Vec<(Pubkey, u64)>> {
+    if let Some(source) = chosen_source {
+        return Ok(vec![(Pubkey::from_str(source)?, amount)]);
+    }
+    let ata = get_associated_token_address(sender, mint);
+    let mut held = held
+        .into_iter()
+        .flatten()
+        .map(|(account, balance)| Ok((Pubkey::from_str(account)?, *balance)))
+        .collect::<Result<Vec<(Pubkey, u64)>>>()?;
+    // The ATA is drawn on first, then the largest balances, ties in pubkey order
+    held.sort_by_key(|(account, balance)| (*account != ata, std::cmp::Reverse(*balance), *account));
+    let ata_balance = held
+        .first()
+        .filter(|(account, _)| *account == ata)
+        .map_or(0, |(_, balance)| *balance);
+    let total = held
+        .iter()
+        .fold(0u64, |total, (_, balance)| total.saturating_add(*balance));
+    if ata_balance >= amount || total < amount {
+        return Ok(vec![(ata, amount)]);
+    }
+    let mut remaining = amount;
+    let mut sources = vec![];
+    for (account, balance) in held {
+        if remaining == 0 {
+            break;
+        }
+        let sent = balance.min(remaining);
+        if sent > 0 {
+            sources.push((account, sent));
+            remaining -= sent;
+        }
+    }
+    Ok(sources)
+}
+
+/// Lowers every "up to" offer of a mint the counterparty offers too down to the counterparty's
+/// amount, so netting cancels the mint out instead of transferring the difference.
+fn match_up_to_offers(
+    user1_offers: &HashMap<String, u64>,
+    user1_up_to: &HashSet<String>,
+    user2_offers: &HashMap<String, u64>,
+    user2_up_to: &HashSet<String>,
+) -> (HashMap<String, u64>, HashMap<String, u64>) {
+    let mut offers1 = user1_offers.clone();
+    let mut offers2 = user2_offers.clone();
+    for (token, amount) in &mut offers1 {
+        if let Some(amount2) = offers2.get_mut(token) {
+            if user1_up_to.contains(token) && *amount > *amount2 {
+                *amount = *amount2;
+            } else if user2_up_to.contains(token) && *amount2 > *amount {
+                *amount2 = *amount;
+            }
+        }
+    }
+    (offers1, offers2)
 }
 
 fn cancel_out_trade_tokens(
-    user1_offers: &HashMap<String, Decimal>,
-    user2_offers: &HashMap<String, Decimal>,
-) -> (HashMap<String, Decimal>, HashMap<String, Decimal>) {
+    user1_offers: &HashMap<String, u64>,
+    user2_offers: &HashMap<String, u64>,
+) -> (HashMap<String, u64>, HashMap<String, u64>) {
     let mut offers1 = user1_offers.clone();
     let mut offers2 = user2_offers.clone();
 
@@ -121,27 +889,41 @@ fn cancel_out_trade_tokens(
         if let Some(amount2) = offers2.get_mut(token) {
             if amount2 > amount {
                 *amount2 -= *amount;
-                *amount = dec!(0.0);
+                *amount = 0;
             } else if amount2 < amount {
                 *amount -= *amount2;
-                *amount2 = dec!(0.0);
+                *amount2 = 0;
             } else {
-                *amount = dec!(0.0);
-                *amount2 = dec!(0.0);
+                *amount = 0;
+                *amount2 = 0;
             }
         }
     }
-    offers1.retain(|_, amount| *amount > dec!(0.0));
-    offers2.retain(|_, amount| *amount > dec!(0.0));
+    offers1.retain(|_, amount| *amount > 0);
+    offers2.retain(|_, amount| *amount > 0);
 
     (offers1, offers2)
 }
 
+/// A new keypair saved as a solana-keygen file for `FeePayerPolicy::Server`, with its path.
+#[cfg(test)]
+pub fn server_keypair_file() -> (Keypair, String) {
+    let keypair = Keypair::new();
+    let path = std::env::temp_dir().join(format!("fee-payer-{}.json", uuid::Uuid::new_v4()));
+    solana_sdk::signature::write_keypair_file(&keypair, &path).unwrap();
+    (keypair, path.to_string_lossy().into_owned())
+}
+
 #[cfg(test)]
 mod test {
     use rust_decimal_macros::dec;
+    use solana_sdk::signature::Signature;
 
-    use crate::chain_context::TestChainContext;
+    use crate::{
+        chain_context::{ScriptedChainContext, TestChainContext, TEST_LAMPORTS_PER_SIGNATURE},
+        config::PriorityFeeConfig,
+        metadata_cache::WRAPPED_SOL_MINT,
+    };
 
     use super::*;
 
@@ -168,59 +950,921 @@ mod test {
         println!("Token7: {}", &token7);
 
         let user1_offers = HashMap::from([
-            (token1, dec!(10.0)),
-            (token2.clone(), dec!(3.5)),
-            (token3, dec!(4.0)),
-            (token6.clone(), dec!(4.0)),
-            (token7.clone(), dec!(4.0)),
+            (token1, 10_000_000),
+            (token2.clone(), 3_500_000),
+            (token3, 4_000_000),
+            (token6.clone(), 4_000_000),
+            (token7.clone(), 4_000_000),
         ]);
         let user2_offers = HashMap::from([
-            (token2, dec!(10.0)),
-            (token4, dec!(1.0)),
-            (token5, dec!(4.0)),
-            (token6, dec!(4.0)),
-            (token7, dec!(0.2)),
+            (token2, 10_000_000),
+            (token4, 1_000_000),
+            (token5, 4_000_000),
+            (token6, 4_000_000),
+            (token7, 200_000),
         ]);
         let items = HashMap::from([
-            (user1, user1_offers),
-            (user2, user2_offers)
+            (user1.clone(), user1_offers.clone()),
+            (user2.clone(), user2_offers.clone()),
         ]);
-        let program_id= Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
         println!("Program ID: {}", &program_id);
 
-        let transaction_service = TransactionService::<TestChainContext>::new(Arc::new(TestChainContext{}));
-        let tx = transaction_service.create_transaction(Arc::new(items)).await.unwrap();
+        let transaction_service =
+            TransactionService::<TestChainContext>::new(Arc::new(TestChainContext {}));
+        let tx = transaction_service
+            .create_transaction(Arc::new(items), &user1)
+            .await
+            .unwrap();
         println!("Tx message: {:#?}", tx.message());
 
+        // same trade built from maps with a different insertion order yields the same message
+        let reordered_items = HashMap::from([
+            (user2.clone(), user2_offers.into_iter().collect()),
+            (user1.clone(), user1_offers.into_iter().collect()),
+        ]);
+        let reordered_tx = transaction_service
+            .create_transaction(Arc::new(reordered_items), &user1)
+            .await
+            .unwrap();
+        assert_eq!(tx.message(), reordered_tx.message());
+
+        let instruction_accounts: Vec<Pubkey> = tx.message.instructions[0]
+            .accounts
+            .iter()
+            .map(|index| tx.message.account_keys[*index as usize])
+            .collect();
+        let (first, second) = (
+            Pubkey::from_str(&user1).unwrap(),
+            Pubkey::from_str(&user2).unwrap(),
+        );
+        assert_eq!(
+            instruction_accounts[..2],
+            [first.min(second), first.max(second)]
+        );
+        // after netting each user sends three mints (user1: token1, token3, token7;
+        // user2: token2, token4, token5), each block sorted by mint
+        let first_user_mints = &instruction_accounts[2..5];
+        let second_user_mints = &instruction_accounts[5..8];
+        assert!(first_user_mints.windows(2).all(|w| w[0] < w[1]));
+        assert!(second_user_mints.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    /// Serialized message of a fixed trade, checked in so any change to the account order or
+    /// instruction data layout fails a test. Regenerate after an intended change with
+    /// `UPDATE_GOLDEN=1 cargo test transaction_message_should_match_golden`.
+    const GOLDEN_MESSAGE_PATH: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/trade_message.golden");
+
+    #[tokio::test]
+    async fn transaction_message_should_match_golden() {
+        let user1 = Pubkey::new_from_array([1; 32]).to_string();
+        let user2 = Pubkey::new_from_array([2; 32]).to_string();
+        let [mint_a, mint_b, mint_c] =
+            [3, 4, 5].map(|byte| Pubkey::new_from_array([byte; 32]).to_string());
+        // mint_b is offered by both sides and nets to user1 sending 1.5
+        let items = HashMap::from([
+            (
+                user1.clone(),
+                HashMap::from([(mint_a, 10_000_000), (mint_b.clone(), 2_000_000)]),
+            ),
+            (user2, HashMap::from([(mint_b, 500_000), (mint_c, 1)])),
+        ]);
+        let transaction_service =
+            TransactionService::<TestChainContext>::new(Arc::new(TestChainContext {}));
+
+        let tx = transaction_service
+            .create_transaction(Arc::new(items), &user1)
+            .await
+            .unwrap();
+        let message = general_purpose::STANDARD.encode(bincode::serialize(tx.message()).unwrap());
+
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(GOLDEN_MESSAGE_PATH, format!("{}\n", message)).unwrap();
+        }
+        let golden = std::fs::read_to_string(GOLDEN_MESSAGE_PATH).unwrap();
+        assert_eq!(
+            message,
+            golden.trim(),
+            "trade message changed to {:#?}",
+            tx.message()
+        );
+    }
+
+    fn two_user_items(user1: &str, user2: &str) -> Arc<BaseUnitOffers> {
+        Arc::new(HashMap::from([
+            (
+                user1.to_string(),
+                HashMap::from([(Pubkey::new_unique().to_string(), 1_000_000)]),
+            ),
+            (
+                user2.to_string(),
+                HashMap::from([(Pubkey::new_unique().to_string(), 2_000_000)]),
+            ),
+        ]))
+    }
+
+    #[tokio::test]
+    async fn underfunded_fee_payer_should_be_refused() {
+        let user1 = Pubkey::new_unique().to_string();
+        let user2 = Pubkey::new_unique().to_string();
+        let config = TransactionConfig {
+            check_fee_payer_balance: true,
+            ..Default::default()
+        };
+        // Two receiver token accounts to create plus two signatures
+        let needed = 2 * 2_039_280 + 2 * TEST_LAMPORTS_PER_SIGNATURE;
+
+        let transaction_service = TransactionService::with_config(
+            Arc::new(ScriptedChainContext::underfunded(needed - 1)),
+            config.clone(),
+        );
+        let error = transaction_service
+            .create_transaction(two_user_items(&user1, &user2), &user1)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("Insufficient SOL for fees"));
+        assert!(error.to_string().contains(&needed.to_string()));
+
+        let transaction_service = TransactionService::with_config(
+            Arc::new(ScriptedChainContext::underfunded(needed)),
+            config,
+        );
+        assert!(transaction_service
+            .create_transaction(two_user_items(&user1, &user2), &user1)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn fee_payer_should_be_initiator_regardless_of_map_order() {
+        let user1 = Pubkey::new_unique().to_string();
+        let user2 = Pubkey::new_unique().to_string();
+        let transaction_service =
+            TransactionService::<TestChainContext>::new(Arc::new(TestChainContext {}));
+
+        for initiator in [&user1, &user2] {
+            let tx = transaction_service
+                .create_transaction(two_user_items(&user1, &user2), initiator)
+                .await
+                .unwrap();
+            assert_eq!(
+                tx.message().account_keys[0],
+                Pubkey::from_str(initiator).unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fee_payer_should_be_server_wallet_when_configured() {
+        let user1 = Pubkey::new_unique().to_string();
+        let user2 = Pubkey::new_unique().to_string();
+        let (server_payer, keypair_path) = server_keypair_file();
+        let transaction_service = TransactionService::<TestChainContext>::with_config(
+            Arc::new(TestChainContext {}),
+            TransactionConfig {
+                fee_payer: FeePayerPolicy::Server { keypair_path },
+                ..Default::default()
+            },
+        );
+
+        let mut tx = transaction_service
+            .create_transaction(two_user_items(&user1, &user2), &user1)
+            .await
+            .unwrap();
+        assert_eq!(tx.message().account_keys[0], server_payer.pubkey());
+        assert_eq!(tx.message().header.num_required_signatures, 3);
+        assert_eq!(
+            transaction_service.server_fee_payer().unwrap(),
+            Some(server_payer.pubkey())
+        );
+
+        transaction_service.sign_as_fee_payer(&mut tx).unwrap();
+        assert!(tx.signatures[0].verify(server_payer.pubkey().as_ref(), &tx.message_data()));
+        assert_eq!(tx.signatures[1..], [Signature::default(); 2]);
+    }
+
+    #[tokio::test]
+    async fn unreadable_server_keypair_should_refuse_to_build() {
+        let user1 = Pubkey::new_unique().to_string();
+        let user2 = Pubkey::new_unique().to_string();
+        let transaction_service = TransactionService::<TestChainContext>::with_config(
+            Arc::new(TestChainContext {}),
+            TransactionConfig {
+                fee_payer: FeePayerPolicy::Server {
+                    keypair_path: "/nonexistent/fee-payer.json".to_string(),
+                },
+                ..Default::default()
+            },
+        );
+
+        assert!(transaction_service.server_fee_payer().is_err());
+        let error = transaction_service
+            .create_transaction(two_user_items(&user1, &user2), &user1)
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Unable to read fee payer keypair /nonexistent/fee-payer.json"));
+    }
+
+    #[tokio::test]
+    async fn transaction_paid_by_the_initiator_should_not_be_signed_by_the_server() {
+        let user1 = Pubkey::new_unique().to_string();
+        let user2 = Pubkey::new_unique().to_string();
+        let tx = TransactionService::<TestChainContext>::new(Arc::new(TestChainContext {}))
+            .create_transaction(two_user_items(&user1, &user2), &user1)
+            .await
+            .unwrap();
+        let (_, keypair_path) = server_keypair_file();
+        let transaction_service = TransactionService::<TestChainContext>::with_config(
+            Arc::new(TestChainContext {}),
+            TransactionConfig {
+                fee_payer: FeePayerPolicy::Server { keypair_path },
+                ..Default::default()
+            },
+        );
+
+        let mut signed = tx.clone();
+        transaction_service.sign_as_fee_payer(&mut signed).unwrap();
+        assert_eq!(signed, tx);
+    }
+
+    #[tokio::test]
+    async fn instruction_data_should_start_with_anchor_discriminator() {
+        let user1 = Pubkey::new_unique().to_string();
+        let user2 = Pubkey::new_unique().to_string();
+        let transaction_service =
+            TransactionService::<TestChainContext>::new(Arc::new(TestChainContext {}));
+
+        let tx = transaction_service
+            .create_transaction(two_user_items(&user1, &user2), &user1)
+            .await
+            .unwrap();
+        let data = &tx.message.instructions[0].data;
+
+        let expected = hashv(&[b"global:trade"]).to_bytes();
+        assert_eq!(data[..8], expected[..8]);
+        assert_eq!(data[..8], trade_instruction_discriminator());
+
+        let instruction_data = TradeInstructionData::try_from_slice(&data[8..]).unwrap();
+        assert_eq!(instruction_data.user1_transfers, 1);
+        assert_eq!(instruction_data.user2_transfers, 1);
+        assert_eq!(instruction_data.amounts.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fee_estimate_should_include_signatures_and_priority_fee() {
+        let signers = [
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        ];
+        let priority_fee = [
+            ComputeBudgetInstruction::set_compute_unit_limit(200_000),
+            ComputeBudgetInstruction::set_compute_unit_price(1_000),
+        ];
+        let cases = [
+            (&signers[..2], &[][..], 2 * TEST_LAMPORTS_PER_SIGNATURE),
+            (
+                &signers[..2],
+                &priority_fee[..],
+                2 * TEST_LAMPORTS_PER_SIGNATURE + 200,
+            ),
+            (
+                &signers[..],
+                &priority_fee[..],
+                3 * TEST_LAMPORTS_PER_SIGNATURE + 200,
+            ),
+        ];
+        let transaction_service = TransactionService::new(Arc::new(TestChainContext {}));
+
+        for (signers, priority_fee, expected_fee) in cases {
+            let mut instructions = priority_fee.to_vec();
+            instructions.push(Instruction::new_with_bytes(
+                TestChainContext {}.get_trade_with_me_program_id(),
+                &[],
+                signers
+                    .iter()
+                    .map(|signer| AccountMeta::new_readonly(*signer, true))
+                    .collect(),
+            ));
+            let tx = Transaction::new_with_payer(&instructions, Some(&signers[0]));
+            assert_eq!(
+                transaction_service.estimate_fee(&tx).await.unwrap(),
+                expected_fee
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn compute_budget_instructions_should_lead_and_follow_recent_fees() {
+        use solana_sdk::compute_budget;
+
+        let user1 = Pubkey::new_unique().to_string();
+        let user2 = Pubkey::new_unique().to_string();
+        let priority_fee = |estimate_from_recent_fees, max_compute_unit_price_micro_lamports| {
+            Some(PriorityFeeConfig {
+                compute_unit_limit: 300_000,
+                compute_unit_price_micro_lamports: 1_000,
+                estimate_from_recent_fees,
+                max_compute_unit_price_micro_lamports,
+            })
+        };
+        let cases = [
+            (priority_fee(false, None), vec![5_000, 7_000, 9_000], 1_000),
+            (priority_fee(true, None), vec![9_000, 5_000, 7_000], 7_000),
+            (priority_fee(true, None), vec![10, 0, 20], 1_000),
+            (priority_fee(true, None), vec![], 1_000),
+            (
+                priority_fee(true, Some(6_000)),
+                vec![9_000, 5_000, 7_000],
+                6_000,
+            ),
+        ];
+
+        for (priority_fee, recent_prioritization_fees, expected_price) in cases {
+            let chain_context: ScriptedChainContext = ScriptedChainContext {
+                recent_prioritization_fees: Some(recent_prioritization_fees),
+                ..ScriptedChainContext::default()
+            };
+            let transaction_service = TransactionService::with_config(
+                Arc::new(chain_context),
+                TransactionConfig {
+                    priority_fee,
+                    ..Default::default()
+                },
+            );
+            let tx = transaction_service
+                .create_transaction(two_user_items(&user1, &user2), &user1)
+                .await
+                .unwrap();
+            let message = &tx.message;
+            let program_ids: Vec<Pubkey> = message
+                .instructions
+                .iter()
+                .map(|instruction| message.account_keys[usize::from(instruction.program_id_index)])
+                .collect();
+            assert_eq!(
+                program_ids,
+                vec![
+                    compute_budget::id(),
+                    compute_budget::id(),
+                    TestChainContext {}.get_trade_with_me_program_id()
+                ]
+            );
+            let budget: Vec<ComputeBudgetInstruction> = message.instructions[..2]
+                .iter()
+                .map(|instruction| borsh::from_slice(&instruction.data).unwrap())
+                .collect();
+            assert_eq!(
+                budget,
+                vec![
+                    ComputeBudgetInstruction::SetComputeUnitLimit(300_000),
+                    ComputeBudgetInstruction::SetComputeUnitPrice(expected_price),
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_send_from_non_ata_source_account() {
+        let user1 = Pubkey::new_unique();
+        let user2 = Pubkey::new_unique();
+        let mint1 = Pubkey::new_unique();
+        let mint2 = Pubkey::new_unique();
+        let source_account = Pubkey::new_unique();
+        let items = Arc::new(HashMap::from([
+            (
+                user1.to_string(),
+                HashMap::from([(mint1.to_string(), 1_000_000)]),
+            ),
+            (
+                user2.to_string(),
+                HashMap::from([(mint2.to_string(), 1_000_000)]),
+            ),
+        ]));
+        let source_accounts = HashMap::from([(
+            user1.to_string(),
+            HashMap::from([(mint1.to_string(), source_account.to_string())]),
+        )]);
+        let transaction_service =
+            TransactionService::<TestChainContext>::new(Arc::new(TestChainContext {}));
+
+        let (tx, _) = transaction_service
+            .create_transaction_with_sources(
+                items,
+                &source_accounts,
+                &HeldAccounts::new(),
+                &Memos::new(),
+                &UpToOffers::new(),
+                &user1.to_string(),
+            )
+            .await
+            .unwrap();
+        let account_keys = &tx.message().account_keys;
+        assert!(account_keys.contains(&source_account));
+        assert!(!account_keys.contains(&get_associated_token_address(&user1, &mint1)));
+        assert!(account_keys.contains(&get_associated_token_address(&user2, &mint1)));
+        assert!(account_keys.contains(&get_associated_token_address(&user2, &mint2)));
+    }
+
+    #[tokio::test]
+    async fn memos_should_be_added_as_signed_memo_instructions() {
+        let user1 = Pubkey::new_unique();
+        let user2 = Pubkey::new_unique();
+        let memos = Memos::from([(user2.to_string(), "thanks for the swap".to_string())]);
+        let transaction_service = TransactionService::with_config(
+            Arc::new(TestChainContext {}),
+            TransactionConfig {
+                include_memos: true,
+                ..Default::default()
+            },
+        );
+
+        let (tx, _) = transaction_service
+            .create_transaction_with_sources(
+                two_user_items(&user1.to_string(), &user2.to_string()),
+                &SourceAccounts::new(),
+                &HeldAccounts::new(),
+                &memos,
+                &UpToOffers::new(),
+                &user1.to_string(),
+            )
+            .await
+            .unwrap();
+        let message = tx.message();
+        let memo_instruction = message.instructions.last().unwrap();
+        assert_eq!(
+            message.account_keys[usize::from(memo_instruction.program_id_index)],
+            spl_memo::id()
+        );
+        assert_eq!(memo_instruction.data, b"thanks for the swap");
+        assert_eq!(
+            message.account_keys[usize::from(memo_instruction.accounts[0])],
+            user2
+        );
+        assert_eq!(message.header.num_required_signatures, 2);
+    }
+
+    #[tokio::test]
+    async fn large_net_transfer_set_should_be_split_into_batches() {
+        let user1 = Pubkey::new_unique();
+        let user2 = Pubkey::new_unique();
+        let (first, second) = (user1.min(user2), user1.max(user2));
+        let offers = |count: u64| -> HashMap<String, u64> {
+            (1..=count)
+                .map(|amount| (Pubkey::new_unique().to_string(), amount))
+                .collect()
+        };
+        let items = Arc::new(HashMap::from([
+            (first.to_string(), offers(7)),
+            (second.to_string(), offers(3)),
+        ]));
+        let memos = Memos::from([(second.to_string(), "in parts".to_string())]);
+        let transaction_service = TransactionService::with_config(
+            Arc::new(TestChainContext {}),
+            TransactionConfig {
+                include_memos: true,
+                max_transfers_per_transaction: Some(4),
+                ..Default::default()
+            },
+        );
+
+        let (txs, _) = transaction_service
+            .create_transactions_with_sources(
+                Arc::clone(&items),
+                &SourceAccounts::new(),
+                &HeldAccounts::new(),
+                &memos,
+                &UpToOffers::new(),
+                &first.to_string(),
+                0,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(txs.len(), 3);
+        let instruction_data: Vec<TradeInstructionData> = txs
+            .iter()
+            .map(|tx| {
+                TradeInstructionData::try_from_slice(&tx.message.instructions[0].data[8..]).unwrap()
+            })
+            .collect();
+        let transfers: Vec<(u8, u8)> = instruction_data
+            .iter()
+            .map(|data| (data.user1_transfers, data.user2_transfers))
+            .collect();
+        assert_eq!(transfers, vec![(4, 0), (3, 1), (0, 2)]);
+        let batched_amounts: u64 = instruction_data
+            .iter()
+            .flat_map(|data| data.amounts.iter())
+            .sum();
+        assert_eq!(batched_amounts, (1..=7).sum::<u64>() + (1..=3).sum::<u64>());
+        for (batch, tx) in txs.iter().enumerate() {
+            let transfers = instruction_data[batch].amounts.len();
+            // both users, then the mint, sender and receiver account of every transfer
+            assert_eq!(tx.message.instructions[0].accounts.len(), 2 + 3 * transfers);
+            assert_eq!(
+                tx.message.instructions.len(),
+                if batch == 0 { 2 } else { 1 }
+            );
+        }
+
+        // Unsplit, the same transfers don't fit into one transaction
+        let error = transaction_service
+            .create_transaction_with_sources(
+                items,
+                &SourceAccounts::new(),
+                &HeldAccounts::new(),
+                &memos,
+                &UpToOffers::new(),
+                &first.to_string(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error
+                .downcast_ref::<TransactionTooLargeError>()
+                .map(|e| e.transfers),
+            Some(10)
+        );
+    }
+
+    #[tokio::test]
+    async fn transaction_over_the_packet_size_should_be_refused() {
+        let user1 = Pubkey::new_unique();
+        let user2 = Pubkey::new_unique();
+        let offers: HashMap<String, u64> = (1..=12)
+            .map(|amount| (Pubkey::new_unique().to_string(), amount))
+            .collect();
+        let transaction_service = TransactionService::new(Arc::new(TestChainContext {}));
+        let initiator = user1.to_string();
+        let create = |offers: HashMap<String, u64>| {
+            transaction_service.create_transaction(
+                Arc::new(HashMap::from([
+                    (user1.to_string(), offers),
+                    (user2.to_string(), HashMap::new()),
+                ])),
+                &initiator,
+            )
+        };
+
+        let error = create(offers.clone()).await.unwrap_err();
+        let too_large = error.downcast_ref::<TransactionTooLargeError>().unwrap();
+        assert!(too_large.size > PACKET_DATA_SIZE);
+        assert_eq!(too_large.transfers, 12);
+        assert!(too_large.excess_transfers > 0);
+        assert!(
+            error.to_string().contains("token transfers have to go"),
+            "{}",
+            error
+        );
+
+        let fitting: HashMap<String, u64> = offers
+            .into_iter()
+            .take(12 - too_large.excess_transfers)
+            .collect();
+        let tx = create(fitting).await.unwrap();
+        assert!(bincode::serialized_size(&tx).unwrap() as usize <= PACKET_DATA_SIZE);
+    }
+
+    #[tokio::test]
+    async fn preview_accounts_should_match_transaction_accounts() {
+        let user1 = Pubkey::new_unique().to_string();
+        let user2 = Pubkey::new_unique().to_string();
+        let memos = Memos::from([(user1.clone(), "gg".to_string())]);
+        let transaction_service = TransactionService::with_config(
+            Arc::new(TestChainContext {}),
+            TransactionConfig {
+                include_memos: true,
+                priority_fee: Some(PriorityFeeConfig {
+                    compute_unit_limit: 200_000,
+                    compute_unit_price_micro_lamports: 1000,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        let items = two_user_items(&user1, &user2);
+
+        let preview = transaction_service
+            .preview_accounts(
+                Arc::clone(&items),
+                &SourceAccounts::new(),
+                &HeldAccounts::new(),
+                &memos,
+                &UpToOffers::new(),
+                &user2,
+            )
+            .await
+            .unwrap();
+        let (tx, _) = transaction_service
+            .create_transaction_with_sources(
+                items,
+                &SourceAccounts::new(),
+                &HeldAccounts::new(),
+                &memos,
+                &UpToOffers::new(),
+                &user2,
+            )
+            .await
+            .unwrap();
+
+        let expected: Vec<AccountMeta> = tx
+            .message
+            .account_keys
+            .iter()
+            .enumerate()
+            .map(|(i, pubkey)| AccountMeta {
+                pubkey: *pubkey,
+                is_signer: tx.message.is_signer(i),
+                is_writable: tx.message.is_maybe_writable(i, None),
+            })
+            .collect();
+        assert_eq!(preview, expected);
+        assert_eq!(preview[0].pubkey.to_string(), user2);
+        assert!(preview[0].is_signer && preview[0].is_writable);
+    }
+
+    #[tokio::test]
+    async fn wrapped_sol_should_be_tradeable() {
+        let user1 = Pubkey::new_unique();
+        let user2 = Pubkey::new_unique();
+        let wsol = Pubkey::from_str(WRAPPED_SOL_MINT).unwrap();
+        let items = Arc::new(HashMap::from([
+            (
+                user1.to_string(),
+                HashMap::from([(WRAPPED_SOL_MINT.to_string(), 500_000)]),
+            ),
+            (
+                user2.to_string(),
+                HashMap::from([(Pubkey::new_unique().to_string(), 1_000_000)]),
+            ),
+        ]));
+        let transaction_service =
+            TransactionService::<TestChainContext>::new(Arc::new(TestChainContext {}));
+
+        let tx = transaction_service
+            .create_transaction(items, &user1.to_string())
+            .await
+            .unwrap();
+        let account_keys = &tx.message().account_keys;
+        assert!(account_keys.contains(&wsol));
+        assert!(account_keys.contains(&get_associated_token_address(&user1, &wsol)));
+        assert!(account_keys.contains(&get_associated_token_address(&user2, &wsol)));
+    }
+
+    #[tokio::test]
+    async fn both_encodings_should_decode_to_same_transaction_bytes() {
+        let user1 = Pubkey::new_unique().to_string();
+        let user2 = Pubkey::new_unique().to_string();
+        let transaction_service =
+            TransactionService::<TestChainContext>::new(Arc::new(TestChainContext {}));
+        let tx = transaction_service
+            .create_transaction(two_user_items(&user1, &user2), &user1)
+            .await
+            .unwrap();
+        let tx_bytes = bincode::serialize(&tx).unwrap();
+
+        let base58 = encode_transaction(&tx, TransactionEncoding::Base58).unwrap();
+        let base64 = encode_transaction(&tx, TransactionEncoding::Base64).unwrap();
+        assert_eq!(bs58::decode(base58).into_vec().unwrap(), tx_bytes);
+        assert_eq!(general_purpose::STANDARD.decode(base64).unwrap(), tx_bytes);
+        assert_eq!(
+            transaction_service.default_encoding(),
+            TransactionEncoding::Base64
+        );
+    }
+
+    #[tokio::test]
+    async fn instruction_details_should_be_logged_only_when_enabled() {
+        crate::test_logger::init();
+        for log_details in [false, true] {
+            let user1 = Pubkey::new_unique().to_string();
+            let user2 = Pubkey::new_unique().to_string();
+            let transaction_service = TransactionService::with_config(
+                Arc::new(TestChainContext {}),
+                TransactionConfig {
+                    log_details,
+                    ..Default::default()
+                },
+            );
+            let tx = transaction_service
+                .create_transaction(two_user_items(&user1, &user2), &user1)
+                .await
+                .unwrap();
+
+            let logged = crate::test_logger::lines_containing(&user1);
+            if !log_details {
+                assert!(logged.is_empty());
+                continue;
+            }
+            assert_eq!(logged.len(), 1);
+            let trade_instruction = tx.message.instructions.last().unwrap();
+            assert!(logged[0].contains("Trade instruction: "));
+            assert!(logged[0].contains(&format!("accounts ({})", trade_instruction.accounts.len())));
+        }
+    }
+
+    #[test]
+    fn should_describe_instruction_without_its_data() {
+        let signer = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
+        let instruction = Instruction {
+            program_id,
+            accounts: vec![
+                AccountMeta::new(signer, true),
+                AccountMeta::new_readonly(mint, false),
+            ],
+            data: vec![42; 20],
+        };
+
+        let description = describe_instruction(&instruction);
+        assert_eq!(
+            description,
+            format!(
+                "program_id: {}, data_len: 20, accounts (2): [{} (signer) (writable), {}]",
+                program_id, signer, mint
+            )
+        );
+        assert!(!description.contains("42, 42"));
+    }
+
+    #[tokio::test]
+    async fn instruction_data_should_round_trip_in_base_units() {
+        let user1 = Pubkey::new_unique();
+        let user2 = Pubkey::new_unique();
+        let (first, second) = (user1.min(user2), user1.max(user2));
+        let items = Arc::new(HashMap::from([
+            (
+                first.to_string(),
+                HashMap::from([(Pubkey::new_unique().to_string(), 1_500_000)]),
+            ),
+            (
+                second.to_string(),
+                HashMap::from([
+                    (Pubkey::new_unique().to_string(), 1),
+                    (Pubkey::new_unique().to_string(), 42_000_000),
+                ]),
+            ),
+        ]));
+        let transaction_service =
+            TransactionService::<TestChainContext>::new(Arc::new(TestChainContext {}));
+
+        let tx = transaction_service
+            .create_transaction(items, &first.to_string())
+            .await
+            .unwrap();
+        let data = &tx.message.instructions[0].data;
+        let instruction_data = TradeInstructionData::try_from_slice(&data[8..]).unwrap();
+
+        assert_eq!(instruction_data.user1_transfers, 1);
+        assert_eq!(instruction_data.user2_transfers, 2);
+        assert_eq!(instruction_data.amounts[0], 1_500_000);
+        let mut second_amounts = instruction_data.amounts[1..].to_vec();
+        second_amounts.sort();
+        assert_eq!(second_amounts, vec![1, 42_000_000]);
+        assert_eq!(
+            borsh::to_vec(&instruction_data).unwrap(),
+            data[8..].to_vec()
+        );
+    }
+
+    #[test]
+    fn should_convert_to_base_units() {
+        assert_eq!(to_base_units(dec!(1.5), 6).unwrap(), 1_500_000);
+        assert_eq!(to_base_units(dec!(1), 0).unwrap(), 1);
+        assert_eq!(to_base_units(dec!(0.123456789), 9).unwrap(), 123_456_789);
+        assert!(to_base_units(dec!(0.0000001), 6).is_err());
+        assert!(to_base_units(dec!(-1), 6).is_err());
+        assert_eq!(
+            to_base_units(dec!(1), 19).unwrap(),
+            10_000_000_000_000_000_000
+        );
+        assert!(to_base_units(dec!(1), 20).is_err());
+        assert!(to_base_units(dec!(1), u8::MAX).is_err());
+
+        assert_eq!(from_base_units(1_500_000, 6).unwrap().to_string(), "1.5");
+        assert_eq!(from_base_units(1, 0).unwrap(), dec!(1));
+        assert_eq!(from_base_units(123_456_789, 9).unwrap(), dec!(0.123456789));
+        assert_eq!(
+            from_base_units(u64::MAX, 28).unwrap(),
+            dec!(0.0000000018446744073709551615)
+        );
+        assert!(from_base_units(1, 29).is_err());
+        for amount in [dec!(0.1001), dec!(200.0), dec!(0.000001)] {
+            assert_eq!(
+                from_base_units(to_base_units(amount, 6).unwrap(), 6).unwrap(),
+                amount
+            );
+        }
+    }
+
+    #[test]
+    fn shortfall_in_the_ata_should_be_sent_from_other_held_accounts() {
+        let sender = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let ata = get_associated_token_address(&sender, &mint);
+        let (small, large) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let held = vec![
+            (small.to_string(), 100_000),
+            (ata.to_string(), 400_000),
+            (large.to_string(), 700_000),
+        ];
+
+        let sources = transfer_sources(&sender, &mint, 1_000_000, None, Some(&held)).unwrap();
+        assert_eq!(sources, vec![(ata, 400_000), (large, 600_000)]);
+
+        // The ATA alone covers it, or nothing does and the transfer fails on chain as before
+        let sources = transfer_sources(&sender, &mint, 400_000, None, Some(&held)).unwrap();
+        assert_eq!(sources, vec![(ata, 400_000)]);
+        let sources = transfer_sources(&sender, &mint, 2_000_000, None, Some(&held)).unwrap();
+        assert_eq!(sources, vec![(ata, 2_000_000)]);
+
+        let chosen = small.to_string();
+        let sources =
+            transfer_sources(&sender, &mint, 1_000_000, Some(&chosen), Some(&held)).unwrap();
+        assert_eq!(sources, vec![(small, 1_000_000)]);
+    }
+
+    #[tokio::test]
+    async fn should_reject_initiator_that_is_not_a_participant() {
+        let user1 = Pubkey::new_unique().to_string();
+        let user2 = Pubkey::new_unique().to_string();
+        let transaction_service =
+            TransactionService::<TestChainContext>::new(Arc::new(TestChainContext {}));
+
+        let result = transaction_service
+            .create_transaction(
+                two_user_items(&user1, &user2),
+                &Pubkey::new_unique().to_string(),
+            )
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn up_to_offer_should_net_to_matched_amount() {
+        let user1_offers = HashMap::from([
+            ("token1".to_string(), 10_000_000),
+            ("token2".to_string(), 1_000_000),
+        ]);
+        let user2_offers = HashMap::from([
+            ("token1".to_string(), 4_000_000),
+            ("token2".to_string(), 3_000_000),
+            ("token3".to_string(), 2_000_000),
+        ]);
+        let user1_up_to = HashSet::from(["token1".to_string(), "token2".to_string()]);
+
+        let (matched1, matched2) =
+            match_up_to_offers(&user1_offers, &user1_up_to, &user2_offers, &HashSet::new());
+        assert_eq!(matched1["token1"], 4_000_000);
+        // Up to offers are never raised, user2 still sends the difference of token2
+        assert_eq!(matched1["token2"], 1_000_000);
+        let (offers1, offers2) = cancel_out_trade_tokens(&matched1, &matched2);
+        assert_eq!(offers1, HashMap::new());
+        assert_eq!(
+            offers2,
+            HashMap::from([
+                ("token2".to_string(), 2_000_000),
+                ("token3".to_string(), 2_000_000),
+            ])
+        );
+
+        // The exact default transfers the full asymmetric amount
+        let (offers1, _) = cancel_out_trade_tokens(&user1_offers, &user2_offers);
+        assert_eq!(offers1["token1"], 6_000_000);
     }
 
     #[test]
     fn should_cancel_out_same_token_transfers() {
         let user1_offers = HashMap::from([
-            ("token1".to_string(), dec!(10.0)),
-            ("token2".to_string(), dec!(3.5)),
-            ("token3".to_string(), dec!(4.0)),
-            ("token6".to_string(), dec!(4.0)),
-            ("token7".to_string(), dec!(4.0)),
+            ("token1".to_string(), 10_000_000),
+            ("token2".to_string(), 3_500_000),
+            ("token3".to_string(), 4_000_000),
+            ("token6".to_string(), 4_000_000),
+            ("token7".to_string(), 4_000_000),
         ]);
         let user2_offers = HashMap::from([
-            ("token2".to_string(), dec!(10.0)),
-            ("token4".to_string(), dec!(1.0)),
-            ("token5".to_string(), dec!(4.0)),
-            ("token6".to_string(), dec!(4.0)),
-            ("token7".to_string(), dec!(0.2)),
+            ("token2".to_string(), 10_000_000),
+            ("token4".to_string(), 1_000_000),
+            ("token5".to_string(), 4_000_000),
+            ("token6".to_string(), 4_000_000),
+            ("token7".to_string(), 200_000),
         ]);
         let (offers1, offers2) = cancel_out_trade_tokens(&user1_offers, &user2_offers);
 
-        assert_eq!(*offers1.get("token1").unwrap(), dec!(10.0));
+        assert_eq!(*offers1.get("token1").unwrap(), 10_000_000);
         assert_eq!(offers1.get("token2"), None);
-        assert_eq!(*offers1.get("token3").unwrap(), dec!(4.0));
-        assert_eq!(*offers2.get("token2").unwrap(), dec!(6.5));
-        assert_eq!(*offers2.get("token4").unwrap(), dec!(1.0));
-        assert_eq!(*offers2.get("token5").unwrap(), dec!(4.0));
+        assert_eq!(*offers1.get("token3").unwrap(), 4_000_000);
+        assert_eq!(*offers2.get("token2").unwrap(), 6_500_000);
+        assert_eq!(*offers2.get("token4").unwrap(), 1_000_000);
+        assert_eq!(*offers2.get("token5").unwrap(), 4_000_000);
         assert_eq!(offers1.get("token6"), None);
         assert_eq!(offers2.get("token6"), None);
-        assert_eq!(*offers1.get("token7").unwrap(), dec!(3.8));
+        assert_eq!(*offers1.get("token7").unwrap(), 3_800_000);
         assert_eq!(offers2.get("token7"), None);
     }
 }
@@ -0,0 +1,218 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use log::debug;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    Url,
+};
+
+use crate::config::MetadataConfig;
+
+/// Decides which urls found in token metadata may be fetched. Metadata is written by whoever
+/// minted the token, so without this check it could point the server at internal services.
+///
+/// Only http(s) urls are fetched, and only when their host doesn't resolve to a private,
+/// loopback or link-local address. `denied_hosts` are never fetched, `allowed_hosts` skip the
+/// address check.
+#[derive(Clone, Debug, Default)]
+pub struct UrlPolicy {
+    allowed_hosts: Vec<String>,
+    denied_hosts: Vec<String>,
+}
+
+impl UrlPolicy {
+    pub fn new(config: &MetadataConfig) -> Self {
+        UrlPolicy {
+            allowed_hosts: config.allowed_hosts.clone(),
+            denied_hosts: config.denied_hosts.clone(),
+        }
+    }
+
+    pub async fn permits(&self, url: &str) -> bool {
+        let Ok(url) = Url::parse(url) else {
+            return false;
+        };
+        if !matches!(url.scheme(), "http" | "https") {
+            debug!("Refusing to fetch {}: unsupported scheme", url);
+            return false;
+        }
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Some(allowed) = self.listed(host) {
+            if !allowed {
+                debug!("Refusing to fetch {}: host is denied", url);
+            }
+            return allowed;
+        }
+        let addresses: Vec<IpAddr> = match host.parse::<IpAddr>() {
+            Ok(address) => vec![address],
+            Err(_) => {
+                match tokio::net::lookup_host((host, url.port_or_known_default().unwrap_or(80)))
+                    .await
+                {
+                    Ok(addresses) => addresses.map(|address| address.ip()).collect(),
+                    Err(e) => {
+                        debug!("Refusing to fetch {}: unable to resolve host: {}", url, e);
+                        return false;
+                    }
+                }
+            }
+        };
+        if addresses.is_empty() || addresses.iter().any(|address| !is_public(address)) {
+            debug!(
+                "Refusing to fetch {}: host resolves to a non public address",
+                url
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Redirect target check usable from reqwest's synchronous redirect policy, only hosts given
+    /// as addresses can be checked there. Named hosts are checked when the client resolves them,
+    /// see the `Resolve` impl.
+    pub fn permits_redirect(&self, url: &Url) -> bool {
+        if !matches!(url.scheme(), "http" | "https") {
+            return false;
+        }
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        self.listed(host).unwrap_or_else(|| {
+            host.parse::<IpAddr>()
+                .map_or(true, |address| is_public(&address))
+        })
+    }
+
+    /// Whether `host` is allowed or denied by the lists, `None` when it's on neither.
+    fn listed(&self, host: &str) -> Option<bool> {
+        if self
+            .denied_hosts
+            .iter()
+            .any(|denied| denied.eq_ignore_ascii_case(host))
+        {
+            Some(false)
+        } else if self
+            .allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+        {
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+/// Installed as the http client's resolver so every host it connects to, redirect targets
+/// included, only resolves to public addresses. Checking the url up front alone would let a
+/// redirect, or a second lookup of the same name, land on an internal address.
+impl Resolve for UrlPolicy {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            let listed = policy.listed(host);
+            if listed == Some(false) {
+                return Err(format!("{} is denied", host).into());
+            }
+            let addresses = tokio::net::lookup_host((host, 0)).await?;
+            let addresses: Vec<SocketAddr> = addresses
+                .filter(|address| listed == Some(true) || is_public(&address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(format!("{} doesn't resolve to a public address", host).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public(address: &IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => is_public_v4(address),
+        IpAddr::V6(address) => match address.to_ipv4_mapped() {
+            Some(mapped) => is_public_v4(&mapped),
+            None => is_public_v6(address),
+        },
+    }
+}
+
+fn is_public_v4(address: &Ipv4Addr) -> bool {
+    let [first, second, ..] = address.octets();
+    let shared = first == 100 && (64..128).contains(&second);
+    !(address.is_private()
+        || address.is_loopback()
+        || address.is_link_local()
+        || address.is_unspecified()
+        || address.is_broadcast()
+        || address.is_documentation()
+        || shared)
+}
+
+fn is_public_v6(address: &Ipv6Addr) -> bool {
+    let first_segment = address.segments()[0];
+    let unique_local = first_segment & 0xfe00 == 0xfc00;
+    let link_local = first_segment & 0xffc0 == 0xfe80;
+    !(address.is_loopback() || address.is_unspecified() || unique_local || link_local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn should_refuse_internal_addresses_and_other_schemes() {
+        let policy = UrlPolicy::default();
+
+        for url in [
+            "http://169.254.169.254/latest/meta-data/iam",
+            "http://127.0.0.1:8080/image.png",
+            "http://10.0.0.7/image.png",
+            "http://[::1]/image.png",
+            "http://[::ffff:192.168.1.1]/image.png",
+            "http://[fd00::1]/image.png",
+            "file:///etc/passwd",
+            "ftp://93.184.215.14/image.png",
+            "not a url",
+        ] {
+            assert!(!policy.permits(url).await, "{} should be refused", url);
+        }
+        assert!(policy.permits("https://93.184.215.14/image.png").await);
+    }
+
+    #[tokio::test]
+    async fn allow_and_deny_lists_should_override_address_check() {
+        let policy = UrlPolicy::new(&MetadataConfig {
+            allowed_hosts: vec!["127.0.0.1".to_string()],
+            denied_hosts: vec!["93.184.215.14".to_string()],
+            ..MetadataConfig::default()
+        });
+
+        assert!(policy.permits("http://127.0.0.1:8080/image.png").await);
+        assert!(!policy.permits("https://93.184.215.14/image.png").await);
+        assert!(!policy.permits_redirect(&Url::parse("http://169.254.169.254/").unwrap()));
+        assert!(policy.permits_redirect(&Url::parse("http://127.0.0.1/").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn resolver_should_drop_non_public_addresses() {
+        let policy = UrlPolicy::default();
+        assert!(policy.resolve("localhost".parse().unwrap()).await.is_err());
+
+        let policy = UrlPolicy::new(&MetadataConfig {
+            allowed_hosts: vec!["localhost".to_string()],
+            ..MetadataConfig::default()
+        });
+        let addresses: Vec<SocketAddr> = policy
+            .resolve("localhost".parse().unwrap())
+            .await
+            .unwrap()
+            .collect();
+        assert!(addresses.iter().all(|address| address.ip().is_loopback()));
+        assert!(!addresses.is_empty());
+    }
+}
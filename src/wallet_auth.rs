@@ -0,0 +1,154 @@
+use std::{collections::HashMap, fmt, sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::{trade_session::SessionError, trade_websocket::verify_challenge};
+
+/// How long a challenge can be answered after it was issued.
+pub const CHALLENGE_TTL: Duration = Duration::from_secs(300);
+
+/// How long a wallet token is accepted after it was issued.
+pub const WALLET_TOKEN_TTL: Duration = Duration::from_secs(3600);
+
+/// Expired challenges and tokens are pruned once this many are kept.
+const PRUNE_THRESHOLD: usize = 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum WalletAuthError {
+    /// The nonce was never issued, already answered or expired.
+    UnknownChallenge,
+    /// The signature is not the wallet's signature of the nonce.
+    Rejected(SessionError),
+}
+
+impl fmt::Display for WalletAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletAuthError::UnknownChallenge => {
+                write!(f, "Unknown or expired challenge, request a new one")
+            }
+            WalletAuthError::Rejected(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Proof of wallet ownership for the http endpoints, the counterpart of the websocket
+/// `AuthChallenge`. A client gets a nonce from `challenge`, signs its utf-8 bytes with the
+/// wallet and trades the signature for a bearer token bound to the wallet. Nonces answer once.
+#[derive(Default)]
+pub struct WalletAuth {
+    /// Unanswered nonces with the time they were issued.
+    challenges: Mutex<HashMap<String, Instant>>,
+    /// Wallet every token is bound to, with the time it was issued.
+    tokens: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl WalletAuth {
+    pub fn challenge(&self) -> String {
+        let now = Instant::now();
+        let nonce = Uuid::new_v4().simple().to_string();
+        let mut challenges = self.challenges.lock().unwrap();
+        if challenges.len() >= PRUNE_THRESHOLD {
+            challenges.retain(|_, issued| now.duration_since(*issued) < CHALLENGE_TTL);
+        }
+        challenges.insert(nonce.clone(), now);
+        nonce
+    }
+
+    /// A token for `user_address` when `signature` is its signature of an unanswered `nonce`.
+    /// The nonce is used up either way.
+    pub fn issue_token(
+        &self,
+        nonce: &str,
+        user_address: &str,
+        signature: &str,
+    ) -> Result<String, WalletAuthError> {
+        let now = Instant::now();
+        let issued = self.challenges.lock().unwrap().remove(nonce);
+        if issued.is_none_or(|issued| now.duration_since(issued) >= CHALLENGE_TTL) {
+            return Err(WalletAuthError::UnknownChallenge);
+        }
+        verify_challenge(nonce, user_address, signature).map_err(WalletAuthError::Rejected)?;
+        let token = Uuid::new_v4().simple().to_string();
+        let mut tokens = self.tokens.lock().unwrap();
+        if tokens.len() >= PRUNE_THRESHOLD {
+            tokens.retain(|_, (_, issued)| now.duration_since(*issued) < WALLET_TOKEN_TTL);
+        }
+        tokens.insert(token.clone(), (user_address.to_string(), now));
+        Ok(token)
+    }
+
+    /// Wallet the token was issued for, `None` for unknown and expired tokens.
+    pub fn wallet(&self, token: &str) -> Option<String> {
+        let tokens = self.tokens.lock().unwrap();
+        let (wallet, issued) = tokens.get(token)?;
+        (issued.elapsed() < WALLET_TOKEN_TTL).then(|| wallet.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::signature::{Keypair, Signer};
+
+    use super::*;
+
+    #[test]
+    fn token_should_be_bound_to_the_wallet_that_signed_the_nonce() {
+        let wallet_auth = WalletAuth::default();
+        let alice = Keypair::new();
+        let alice_address = alice.pubkey().to_string();
+
+        let nonce = wallet_auth.challenge();
+        let forged = Keypair::new().sign_message(nonce.as_bytes()).to_string();
+        assert_eq!(
+            wallet_auth.issue_token(&nonce, &alice_address, &forged),
+            Err(WalletAuthError::Rejected(
+                SessionError::AuthenticationFailed {
+                    user_address: alice_address.clone(),
+                }
+            ))
+        );
+        // Used up by the failed attempt
+        let signature = alice.sign_message(nonce.as_bytes()).to_string();
+        assert_eq!(
+            wallet_auth.issue_token(&nonce, &alice_address, &signature),
+            Err(WalletAuthError::UnknownChallenge)
+        );
+
+        let nonce = wallet_auth.challenge();
+        let signature = alice.sign_message(nonce.as_bytes()).to_string();
+        let token = wallet_auth
+            .issue_token(&nonce, &alice_address, &signature)
+            .unwrap();
+        assert_eq!(wallet_auth.wallet(&token), Some(alice_address.clone()));
+        assert_eq!(
+            wallet_auth.issue_token(&nonce, &alice_address, &signature),
+            Err(WalletAuthError::UnknownChallenge)
+        );
+        assert_eq!(wallet_auth.wallet("made-up"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn challenges_and_tokens_should_expire() {
+        let wallet_auth = WalletAuth::default();
+        let alice = Keypair::new();
+        let alice_address = alice.pubkey().to_string();
+
+        let nonce = wallet_auth.challenge();
+        tokio::time::advance(CHALLENGE_TTL).await;
+        let signature = alice.sign_message(nonce.as_bytes()).to_string();
+        assert_eq!(
+            wallet_auth.issue_token(&nonce, &alice_address, &signature),
+            Err(WalletAuthError::UnknownChallenge)
+        );
+
+        let nonce = wallet_auth.challenge();
+        let signature = alice.sign_message(nonce.as_bytes()).to_string();
+        let token = wallet_auth
+            .issue_token(&nonce, &alice_address, &signature)
+            .unwrap();
+        tokio::time::advance(WALLET_TOKEN_TTL).await;
+        assert_eq!(wallet_auth.wallet(&token), None);
+    }
+}